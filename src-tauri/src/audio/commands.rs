@@ -1,18 +1,63 @@
+use crate::audio::device::{self, OutputDevice, OUTPUT_DEVICE_SETTING};
+use crate::audio::envelope::MAX_TRANSPORT_FADE;
+use crate::audio::equalizer::{builtin_presets, find_preset, EqBand, EqPreset, EqScope};
+use crate::audio::gain::{Normalization, NormalizationMode, MAX_PREAMP_DB};
 use crate::audio::player::{AudioCommand, AudioPlayerState};
+use crate::audio::queue::{QueueState, RepeatMode, ShuffleMode};
+use crate::audio::resume::RESUME_THRESHOLD_SETTING;
+use crate::audio::session::load_session;
+use crate::audio::sleep::{SleepMode, SleepTimer};
+use crate::audio::spectrum::SpectrumConfig;
+use crate::database::operations::ResumePosition;
 use crate::database::{operations, AppState};
-use crate::scanner::parser::parse_file;
+use crate::scanner::analysis::AnalysisState;
+use crate::scanner::library::RescanSummary;
+use crate::scanner::scan::ScanState;
+use crate::scanner::watcher::LibraryWatcher;
+use crate::scanner::waveform::{self, Waveform, WaveformState};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use tauri::{command, AppHandle, Manager, State};
-use walkdir::WalkDir;
 
 /// # Errors
 ///
-/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+/// Returns an error if the database cannot be read, the audio command channel is
+/// disconnected or a mutex is poisoned.
 #[command]
 #[allow(clippy::needless_pass_by_value)]
-pub fn play(path: String, state: State<'_, AudioPlayerState>) -> Result<(), String> {
-    let tx = state.tx.lock().map_err(|e| e.to_string())?;
-    tx.send(AudioCommand::Play(path)).map_err(|e| e.to_string())
+pub fn play(
+    path: String,
+    state: State<'_, AudioPlayerState>,
+    library: State<'_, AppState>,
+) -> Result<(), String> {
+    let artists = library_artists(&library, std::slice::from_ref(&path))?;
+    send_with_artists(&state, artists, AudioCommand::Play(path))
+}
+
+/// The library artists of `paths`, read before the command channel is locked.
+fn library_artists(
+    library: &AppState,
+    paths: &[String],
+) -> Result<HashMap<String, String>, String> {
+    let conn = library.db.lock().map_err(|e| e.to_string())?;
+    operations::get_artists(&conn, paths).map_err(|e| e.to_string())
+}
+
+/// Sends `cmd` after handing the player the artists of the tracks it queues, so smart
+/// shuffle does not have to read tags on the audio thread.
+fn send_with_artists(
+    player: &AudioPlayerState,
+    artists: HashMap<String, String>,
+    cmd: AudioCommand,
+) -> Result<(), String> {
+    let tx = player.tx.lock().map_err(|e| e.to_string())?;
+    if !artists.is_empty() {
+        tx.send(AudioCommand::SetArtists(artists))
+            .map_err(|e| e.to_string())?;
+    }
+    tx.send(cmd).map_err(|e| e.to_string())
 }
 
 /// # Errors
@@ -56,6 +101,33 @@ pub fn seek(seconds: f32, state: State<'_, AudioPlayerState>) -> Result<(), Stri
         .map_err(|e| e.to_string())
 }
 
+/// Repeats the region between `start` and `end` seconds of the current track.
+///
+/// # Errors
+///
+/// Returns an error if the region is empty or negative, the audio command channel is
+/// disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_loop(start: f32, end: f32, state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    if !(start.is_finite() && end.is_finite() && start >= 0.0 && start < end) {
+        return Err(format!("Invalid loop region: {start} - {end}"));
+    }
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetLoop { start, end })
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn clear_loop(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::ClearLoop).map_err(|e| e.to_string())
+}
+
 /// # Errors
 ///
 /// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
@@ -67,9 +139,444 @@ pub fn set_volume(volume: f32, state: State<'_, AudioPlayerState>) -> Result<(),
         .map_err(|e| e.to_string())
 }
 
+/// Sets the length of the volume ramps around pause, resume, stop and seek, in
+/// milliseconds (0 - 2000, 0 cuts instantly).
+///
 /// # Errors
 ///
-/// Returns an error if the database connection lock fails or the operation fails.
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_transport_fade(
+    milliseconds: u32,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    let ramp = Duration::from_millis(u64::from(milliseconds)).min(MAX_TRANSPORT_FADE);
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetTransportFade(ramp))
+        .map_err(|e| e.to_string())
+}
+
+/// Sets the crossfade length in seconds (0 - 12, 0 disables it). With `gapless_albums`,
+/// consecutive tracks from the same album keep playing gaplessly instead.
+///
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_crossfade(
+    seconds: f32,
+    gapless_albums: bool,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetCrossfade {
+        seconds,
+        gapless_albums,
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_repeat(mode: RepeatMode, state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetRepeat(mode))
+        .map_err(|e| e.to_string())
+}
+
+/// Sets the shuffle mode. The same `seed` and queue always give the same order.
+///
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_shuffle(
+    mode: ShuffleMode,
+    seed: Option<u64>,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetShuffle(mode, seed))
+        .map_err(|e| e.to_string())
+}
+
+/// Sets how `ReplayGain` is applied. `preamp_db` (-15 - 15) is added to the stored gain
+/// and `prevent_clipping` keeps the stored peak below full scale.
+///
+/// # Errors
+///
+/// Returns an error if `preamp_db` is not a number, the audio command channel is
+/// disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_normalization(
+    mode: NormalizationMode,
+    preamp_db: f32,
+    prevent_clipping: bool,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    if !preamp_db.is_finite() {
+        return Err("Pre-amp must be a number of dB".to_string());
+    }
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetNormalization(Normalization {
+        mode,
+        preamp_db: preamp_db.clamp(-MAX_PREAMP_DB, MAX_PREAMP_DB),
+        prevent_clipping,
+    }))
+    .map_err(|e| e.to_string())
+}
+
+/// Stops playback after `amount` minutes, at the end of the current track, or after the
+/// current track and `amount` more. The volume fades out over the final `fade_seconds`.
+///
+/// # Errors
+///
+/// Returns an error if `amount` does not suit the mode, `fade_seconds` is negative or not
+/// a number, the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_sleep_timer(
+    mode: SleepMode,
+    amount: Option<f32>,
+    fade_seconds: Option<f32>,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    let fade = Duration::try_from_secs_f32(fade_seconds.unwrap_or(0.0))
+        .map_err(|_| "Sleep fade needs a non-negative number of seconds")?;
+    let timer = SleepTimer::new(mode, amount, fade, Instant::now())?;
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetSleepTimer(Some(timer)))
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_sleep_timer(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetSleepTimer(None))
+        .map_err(|e| e.to_string())
+}
+
+/// Starts emitting `player-spectrum` `rate` times per second (1 - 60, default 30) with
+/// `bins` bands (1 - 256, default 64). Calling it again changes the settings.
+///
+/// # Errors
+///
+/// Returns an error if `rate` or `bins` is out of range, the audio command channel is
+/// disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn enable_spectrum(
+    rate: Option<f32>,
+    bins: Option<usize>,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    let config = SpectrumConfig::new(rate, bins)?;
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetSpectrum(Some(config)))
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn disable_spectrum(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetSpectrum(None))
+        .map_err(|e| e.to_string())
+}
+
+/// Changes the playback speed (0.5 - 3.0) without changing the pitch.
+///
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_speed(speed: f32, state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetSpeed(speed))
+        .map_err(|e| e.to_string())
+}
+
+/// Shifts the pitch by `semitones` (-12 - 12) without changing the speed.
+///
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_pitch(semitones: f32, state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetPitch(semitones))
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio host cannot enumerate its devices.
+#[command]
+pub fn list_output_devices() -> Result<Vec<OutputDevice>, String> {
+    device::list_output_devices()
+}
+
+/// Switches playback to the named device, or back to the system default with `None`.
+/// The choice is remembered across restarts.
+///
+/// # Errors
+///
+/// Returns an error if the device does not exist, the database connection lock fails or
+/// the audio command channel is disconnected.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_output_device(
+    name: Option<String>,
+    state: State<'_, AppState>,
+    player: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    if let Some(name) = &name {
+        if device::find_output_device(name).is_none() {
+            return Err(format!("Output device not found: {name}"));
+        }
+    }
+    {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        operations::set_setting(&conn, OUTPUT_DEVICE_SETTING, name.as_deref())
+            .map_err(|e| e.to_string())?;
+    }
+    let tx = player.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetOutputDevice(name))
+        .map_err(|e| e.to_string())
+}
+
+/// Replaces the global equalizer bands; any number of bands is allowed.
+///
+/// # Errors
+///
+/// Returns an error if a band is out of range, the audio command channel is disconnected
+/// or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_equalizer(bands: Vec<EqBand>, state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    bands.iter().try_for_each(EqBand::validate)?;
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetEqualizer(bands))
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the band is out of range, the audio command channel is
+/// disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_eq_band(
+    index: usize,
+    frequency: f32,
+    gain_db: f32,
+    q: f32,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    let band = EqBand {
+        frequency,
+        gain_db,
+        q,
+    };
+    band.validate()?;
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetEqBand(index, band))
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the database cannot be read, the audio command channel is
+/// disconnected or a mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn enqueue(
+    paths: Vec<String>,
+    state: State<'_, AudioPlayerState>,
+    library: State<'_, AppState>,
+) -> Result<(), String> {
+    let artists = library_artists(&library, &paths)?;
+    send_with_artists(&state, artists, AudioCommand::Enqueue(paths))
+}
+
+/// # Errors
+///
+/// Returns an error if the database cannot be read, the audio command channel is
+/// disconnected or a mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn enqueue_next(
+    paths: Vec<String>,
+    state: State<'_, AudioPlayerState>,
+    library: State<'_, AppState>,
+) -> Result<(), String> {
+    let artists = library_artists(&library, &paths)?;
+    send_with_artists(&state, artists, AudioCommand::EnqueueNext(paths))
+}
+
+/// Jumps to the next chapter of the current track, or to the next track after the last
+/// chapter.
+///
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn next_chapter(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::NextChapter)
+        .map_err(|e| e.to_string())
+}
+
+/// Restarts the current chapter, or goes to the previous one within its first seconds.
+///
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn previous_chapter(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::PreviousChapter)
+        .map_err(|e| e.to_string())
+}
+
+/// Jumps to the start of a chapter of the current track, by index.
+///
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn seek_chapter(index: usize, state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SeekChapter(index))
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn next(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::Next).map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn previous(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::Previous).map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn clear_queue(state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::ClearQueue).map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn move_in_queue(
+    from: usize,
+    to: usize,
+    state: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::MoveInQueue { from, to })
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected or the mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn remove_from_queue(index: usize, state: State<'_, AudioPlayerState>) -> Result<(), String> {
+    let tx = state.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::RemoveFromQueue(index))
+        .map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the audio command channel is disconnected, the mutex is poisoned,
+/// or the audio thread does not answer in time.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn get_queue(state: State<'_, AudioPlayerState>) -> Result<QueueState, String> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    {
+        let tx = state.tx.lock().map_err(|e| e.to_string())?;
+        tx.send(AudioCommand::GetQueue(reply_tx))
+            .map_err(|e| e.to_string())?;
+    }
+    reply_rx
+        .recv_timeout(Duration::from_secs(1))
+        .map_err(|e| e.to_string())
+}
+
+/// Reloads the stored playback session, paused at its saved position.
+///
+/// Returns `false` if no session has been stored.
+///
+/// # Errors
+///
+/// Returns an error if the database cannot be read, the audio command channel is
+/// disconnected or a mutex is poisoned.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn restore_session(
+    player: State<'_, AudioPlayerState>,
+    state: State<'_, AppState>,
+) -> Result<bool, String> {
+    let session = {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        load_session(&conn)?
+    };
+    let Some(session) = session else {
+        return Ok(false);
+    };
+    let artists = library_artists(&state, &session.queue.tracks)?;
+    send_with_artists(&player, artists, AudioCommand::RestoreSession(session))?;
+    Ok(true)
+}
+
+/// Adds a local folder and starts scanning it in the background; see
+/// [`ScanState::start`] for the events it emits. Returns the new folder's ID right away.
+///
+/// # Errors
+///
+/// Returns an error if the directory does not exist, the database connection lock fails
+/// or the folder cannot be stored.
 #[command]
 #[allow(clippy::needless_pass_by_value)]
 pub fn add_folder(
@@ -77,6 +584,7 @@ pub fn add_folder(
     name: String,
     path: String,
     state: State<'_, AppState>,
+    scans: State<'_, ScanState>,
 ) -> Result<String, String> {
     let path_obj = Path::new(&path);
     if !path_obj.exists() {
@@ -87,69 +595,64 @@ pub fn add_folder(
         .path()
         .app_cache_dir()
         .map_err(|e| format!("Failed to get app cache dir: {e}"))?;
-    let images_dir = cache_dir.join("images");
-
-    let mut tracks = Vec::new();
-    let supported_extensions = ["mp3", "flac", "wav", "ogg", "m4a", "aac"];
-
-    for entry in WalkDir::new(&path).into_iter().filter_map(Result::ok) {
-        let file_path = entry.path();
-        if file_path.is_file() {
-            if let Some(extension) = file_path.extension() {
-                if let Some(ext_str) = extension.to_str() {
-                    if supported_extensions.contains(&ext_str.to_lowercase().as_str()) {
-                        match parse_file(file_path.to_str().unwrap_or_default(), Some(&images_dir))
-                        {
-                            Ok(metadata) => tracks.push(metadata),
-                            Err(e) => println!("Error parsing file {file_path:?}: {e}"), // Log error but continue
-                        }
-                    }
-                }
-            }
-        }
-    }
 
-    let song_count = tracks.len() as i32;
-    let mut conn = state.db.lock().map_err(|e| e.to_string())?;
+    let folder = {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        let folder_id =
+            operations::add_folder(&conn, &name, &path, 0).map_err(|e| e.to_string())?;
+        operations::get_folder(&conn, &folder_id)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Folder {folder_id} vanished"))?
+    };
+    let folder_id = folder.id.clone();
+    scans.start(app_handle.clone(), folder, cache_dir.join("images"))?;
+    Ok(folder_id)
+}
 
-    // 1. Add tracks to the general tracks table
-    operations::add_tracks(&mut conn, &tracks).map_err(|e| e.to_string())?;
-
-    // 2. Find the "Default" playlist ID
-    let playlists = operations::get_playlists(&conn).map_err(|e| e.to_string())?;
-    let default_playlist = playlists.iter().find(|p| p.name == "Default");
-
-    if let Some(playlist) = default_playlist {
-        // 3. Get the IDs of the tracks we just added/processed
-        // We query by path to get the database-assigned IDs
-        let paths: Vec<String> = tracks.into_iter().map(|t| t.path).collect();
-        if !paths.is_empty() {
-            let track_ids: Vec<i64> = {
-                let placeholders = paths.iter().map(|_| "?").collect::<Vec<_>>().join(",");
-                let mut stmt = conn
-                    .prepare(&format!(
-                        "SELECT id FROM tracks WHERE path IN ({placeholders})"
-                    ))
-                    .map_err(|e| e.to_string())?;
-                let rows = stmt
-                    .query_map(rusqlite::params_from_iter(paths), |row| row.get(0))
-                    .map_err(|e| e.to_string())?;
-                let mut ids = Vec::new();
-                for r in rows {
-                    ids.push(r.map_err(|e| e.to_string())?);
-                }
-                ids
-            };
-
-            // 4. Add tracks to the Default playlist
-            if !track_ids.is_empty() {
-                operations::add_tracks_to_playlist(&mut conn, &playlist.id, &track_ids)
-                    .map_err(|e| e.to_string())?;
-            }
+/// Stops the scan of a folder, or with no ID every running scan. Tracks found so far
+/// stay in the library.
+///
+/// # Errors
+///
+/// Returns an error if the scan state lock fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_scan(folder_id: Option<String>, state: State<'_, ScanState>) -> Result<(), String> {
+    state.cancel(folder_id.as_deref())
+}
+
+/// Brings a local folder in line with its files on disk off the main thread, re-reading
+/// only the files that changed since the last scan; see [`ScanState::rescan`].
+///
+/// # Errors
+///
+/// Returns an error if the folder is unknown, missing from disk or being scanned, the
+/// database connection lock fails or the database cannot be updated.
+#[command]
+pub async fn rescan_folder(
+    app_handle: AppHandle,
+    folder_id: String,
+) -> Result<RescanSummary, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let folder = {
+            let state = app_handle.state::<AppState>();
+            let conn = state.db.lock().map_err(|e| e.to_string())?;
+            operations::get_folder(&conn, &folder_id).map_err(|e| e.to_string())?
         }
-    }
+        .ok_or_else(|| format!("Unknown folder: {folder_id}"))?;
 
-    operations::add_folder(&conn, &name, &path, song_count).map_err(|e| e.to_string())
+        let cache_dir = app_handle
+            .path()
+            .app_cache_dir()
+            .map_err(|e| format!("Failed to get app cache dir: {e}"))?;
+        let changes = app_handle
+            .state::<ScanState>()
+            .rescan(&app_handle, &folder, &cache_dir.join("images"))?
+            .ok_or_else(|| format!("Folder is being scanned: {}", folder.path))?;
+        Ok(RescanSummary::from(&changes))
+    })
+    .await
+    .map_err(|e| e.to_string())?
 }
 
 /// # Errors
@@ -183,9 +686,16 @@ pub fn get_tracks(
 /// Returns an error if the database connection lock fails or the operation fails.
 #[command]
 #[allow(clippy::needless_pass_by_value)]
-pub fn delete_folders(ids: Vec<String>, state: State<'_, AppState>) -> Result<(), String> {
-    let mut conn = state.db.lock().map_err(|e| e.to_string())?;
-    operations::delete_folders(&mut conn, &ids).map_err(|e| e.to_string())
+pub fn delete_folders(
+    ids: Vec<String>,
+    state: State<'_, AppState>,
+    watcher: State<'_, LibraryWatcher>,
+) -> Result<(), String> {
+    {
+        let mut conn = state.db.lock().map_err(|e| e.to_string())?;
+        operations::delete_folders(&mut conn, &ids).map_err(|e| e.to_string())?;
+    }
+    watcher.sync()
 }
 
 /// # Errors
@@ -260,3 +770,335 @@ pub fn delete_playlist(playlist_id: String, state: State<'_, AppState>) -> Resul
     let conn = state.db.lock().map_err(|e| e.to_string())?;
     operations::delete_playlist(&conn, &playlist_id).map_err(|e| e.to_string())
 }
+
+/// Saves a named loop region for the track at `path` and returns its ID.
+///
+/// # Errors
+///
+/// Returns an error if the region is empty or negative, the database connection lock
+/// fails or the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn save_loop_region(
+    path: String,
+    name: String,
+    start: f64,
+    end: f64,
+    state: State<'_, AppState>,
+) -> Result<i64, String> {
+    if !(start.is_finite() && end.is_finite() && start >= 0.0 && start < end) {
+        return Err(format!("Invalid loop region: {start} - {end}"));
+    }
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::save_loop_region(&conn, &path, &name, start, end).map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the database connection lock fails or the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn get_loop_regions(
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<operations::LoopRegion>, String> {
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::get_loop_regions(&conn, &path).map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the database connection lock fails or the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn delete_loop_region(id: i64, state: State<'_, AppState>) -> Result<(), String> {
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::delete_loop_region(&conn, id).map_err(|e| e.to_string())
+}
+
+/// Lists the long-form tracks that were left part way through, most recent first.
+///
+/// # Errors
+///
+/// Returns an error if the database connection lock fails or the query fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn get_in_progress_tracks(state: State<'_, AppState>) -> Result<Vec<ResumePosition>, String> {
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::get_in_progress_tracks(&conn).map_err(|e| e.to_string())
+}
+
+/// Marks a track as finished, so it plays from the start next time.
+///
+/// # Errors
+///
+/// Returns an error if the database connection lock fails or the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn mark_track_finished(path: String, state: State<'_, AppState>) -> Result<(), String> {
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::mark_track_finished(&conn, &path, None).map_err(|e| e.to_string())
+}
+
+/// Forgets where a track was left, finished or not.
+///
+/// # Errors
+///
+/// Returns an error if the database connection lock fails or the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn reset_resume_position(path: String, state: State<'_, AppState>) -> Result<(), String> {
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::delete_resume_position(&conn, &path).map_err(|e| e.to_string())
+}
+
+/// Sets the shortest track, in minutes, that remembers its position. `0` leaves it to
+/// flagged folders and `None` restores the default.
+///
+/// # Errors
+///
+/// Returns an error if `minutes` is negative or not finite, or the setting cannot be stored.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_resume_threshold(
+    minutes: Option<f64>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    if minutes.is_some_and(|m| !m.is_finite() || m < 0.0) {
+        return Err("Resume threshold must be zero or more minutes".to_string());
+    }
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::set_setting(
+        &conn,
+        RESUME_THRESHOLD_SETTING,
+        minutes.map(|m| m.to_string()).as_deref(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Makes the tracks of a local folder remember their position whatever their length.
+///
+/// # Errors
+///
+/// Returns an error if the folder does not exist or the database operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_folder_remember_position(
+    folder_id: String,
+    remember: bool,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::set_folder_remember_position(&conn, &folder_id, remember).map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => format!("No folder with ID {folder_id}"),
+        e => e.to_string(),
+    })
+}
+
+/// Turns watching a local folder for changes on disk on or off.
+///
+/// # Errors
+///
+/// Returns an error if the folder does not exist, the database operation fails or the
+/// watcher has stopped.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_folder_watch(
+    folder_id: String,
+    watch: bool,
+    state: State<'_, AppState>,
+    watcher: State<'_, LibraryWatcher>,
+) -> Result<(), String> {
+    {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        operations::set_folder_watch(&conn, &folder_id, watch).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => format!("No folder with ID {folder_id}"),
+            e => e.to_string(),
+        })?;
+    }
+    watcher.sync()
+}
+
+/// Starts the background loudness analysis of tracks without `ReplayGain` tags.
+///
+/// Returns `false` if an analysis is already running.
+///
+/// # Errors
+///
+/// Returns an error if the analysis state lock fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn start_loudness_analysis(
+    app_handle: AppHandle,
+    state: State<'_, AnalysisState>,
+) -> Result<bool, String> {
+    state.start(app_handle)
+}
+
+/// # Errors
+///
+/// Returns an error if the analysis state lock fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_loudness_analysis(state: State<'_, AnalysisState>) -> Result<(), String> {
+    state.cancel()
+}
+
+/// Returns the seek bar overview of a track in `buckets` min/max pairs (default 1000),
+/// generating it on the waveform worker unless it is cached.
+///
+/// # Errors
+///
+/// Returns an error if `buckets` is out of range, the worker is gone, or the track cannot
+/// be decoded.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub async fn get_waveform(
+    path: String,
+    buckets: Option<usize>,
+    state: State<'_, WaveformState>,
+) -> Result<Waveform, String> {
+    let buckets = waveform::bucket_count(buckets)?;
+    let result = state.request(path, buckets)?;
+    result.await.map_err(|e| e.to_string())?
+}
+
+/// Generates the waveforms of every library track in a folder in the background, and
+/// returns how many tracks are waiting.
+///
+/// # Errors
+///
+/// Returns an error if `buckets` is out of range, the database connection lock fails or
+/// the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn generate_folder_waveforms(
+    folder_id: String,
+    buckets: Option<usize>,
+    state: State<'_, AppState>,
+    waveforms: State<'_, WaveformState>,
+) -> Result<usize, String> {
+    let buckets = waveform::bucket_count(buckets)?;
+    let paths = {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        operations::get_folder_track_paths(&conn, &folder_id).map_err(|e| e.to_string())?
+    };
+    waveforms.queue_batch(paths, buckets)
+}
+
+/// # Errors
+///
+/// Returns an error if the waveform job queue lock fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_waveforms(state: State<'_, WaveformState>) -> Result<(), String> {
+    state.cancel_batch()
+}
+
+/// Lists the built-in presets followed by the user-saved ones.
+///
+/// # Errors
+///
+/// Returns an error if the database connection lock fails or the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn get_eq_presets(state: State<'_, AppState>) -> Result<Vec<EqPreset>, String> {
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    let mut presets = builtin_presets();
+    presets.extend(operations::get_eq_presets(&conn).map_err(|e| e.to_string())?);
+    Ok(presets)
+}
+
+/// # Errors
+///
+/// Returns an error if `name` is a built-in preset, a band is out of range, the database
+/// connection lock fails or the operation fails.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn save_eq_preset(
+    name: String,
+    bands: Vec<EqBand>,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    if builtin_presets().iter().any(|p| p.name == name) {
+        return Err(format!("Cannot overwrite built-in preset: {name}"));
+    }
+    bands.iter().try_for_each(EqBand::validate)?;
+    let conn = state.db.lock().map_err(|e| e.to_string())?;
+    operations::save_eq_preset(&conn, &name, &bands).map_err(|e| e.to_string())
+}
+
+/// # Errors
+///
+/// Returns an error if the database connection lock fails, the operation fails or the
+/// audio command channel is disconnected.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn delete_eq_preset(
+    name: String,
+    state: State<'_, AppState>,
+    player: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        operations::delete_eq_preset(&conn, &name).map_err(|e| e.to_string())?;
+    }
+    // Overrides that used the preset are gone
+    let tx = player.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::RefreshEqualizer)
+        .map_err(|e| e.to_string())
+}
+
+/// Applies a built-in or user preset as the global bands and returns them.
+///
+/// # Errors
+///
+/// Returns an error if the preset does not exist, the database connection lock fails or
+/// the audio command channel is disconnected.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn load_eq_preset(
+    name: String,
+    state: State<'_, AppState>,
+    player: State<'_, AudioPlayerState>,
+) -> Result<Vec<EqBand>, String> {
+    let bands = {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        find_preset(&conn, &name)?.ok_or_else(|| format!("Preset not found: {name}"))?
+    };
+    let tx = player.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::SetEqualizer(bands.clone()))
+        .map_err(|e| e.to_string())?;
+    Ok(bands)
+}
+
+/// Makes a track (keyed by path) or an album (keyed by name) always play with `preset`.
+/// Passing no preset removes the override.
+///
+/// # Errors
+///
+/// Returns an error if the preset does not exist, the database connection lock fails or
+/// the audio command channel is disconnected.
+#[command]
+#[allow(clippy::needless_pass_by_value)]
+pub fn set_eq_override(
+    scope: EqScope,
+    key: String,
+    preset: Option<String>,
+    state: State<'_, AppState>,
+    player: State<'_, AudioPlayerState>,
+) -> Result<(), String> {
+    {
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        if let Some(name) = &preset {
+            if find_preset(&conn, name)?.is_none() {
+                return Err(format!("Preset not found: {name}"));
+            }
+        }
+        operations::set_eq_override(&conn, scope, &key, preset.as_deref())
+            .map_err(|e| e.to_string())?;
+    }
+    let tx = player.tx.lock().map_err(|e| e.to_string())?;
+    tx.send(AudioCommand::RefreshEqualizer)
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,43 @@
+use rodio::cpal::traits::HostTrait;
+use rodio::{Device, DeviceTrait};
+use serde::Serialize;
+
+/// Settings key under which the chosen output device name is stored.
+pub const OUTPUT_DEVICE_SETTING: &str = "output_device";
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct OutputDevice {
+    pub name: String,
+    #[serde(rename = "isDefault")]
+    pub is_default: bool,
+}
+
+/// Lists the output devices of the default host.
+///
+/// # Errors
+///
+/// Returns an error if the host cannot enumerate its devices.
+pub fn list_output_devices() -> Result<Vec<OutputDevice>, String> {
+    let host = rodio::cpal::default_host();
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+    let devices = host
+        .output_devices()
+        .map_err(|e| format!("Failed to list output devices: {e}"))?;
+
+    Ok(devices
+        .filter_map(|d| d.name().ok())
+        .map(|name| OutputDevice {
+            is_default: default_name.as_deref() == Some(name.as_str()),
+            name,
+        })
+        .collect())
+}
+
+/// Finds an output device by the name reported in `list_output_devices`.
+#[must_use]
+pub fn find_output_device(name: &str) -> Option<Device> {
+    rodio::cpal::default_host()
+        .output_devices()
+        .ok()?
+        .find(|d| d.name().is_ok_and(|n| n == name))
+}
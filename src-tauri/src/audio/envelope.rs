@@ -0,0 +1,319 @@
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Default length of the ramps around pause, resume, stop and seek.
+pub const DEFAULT_TRANSPORT_FADE: Duration = Duration::from_millis(30);
+
+/// Longest accepted transport ramp.
+pub const MAX_TRANSPORT_FADE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Playing,
+    /// Ramp down, then hold without reading the source.
+    Paused,
+    /// Ramp down, then end the source.
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    transport: Transport,
+    ramp: Duration,
+    // Track id, position and a counter so every seek is seen once
+    seek: Option<(u64, Duration, u64)>,
+}
+
+/// Shared by every `Envelope`, so a pause holds both decks of a crossfade at once.
+#[derive(Debug)]
+pub struct EnvelopeControl {
+    generation: AtomicU64,
+    request: Mutex<Request>,
+}
+
+impl Default for EnvelopeControl {
+    fn default() -> Self {
+        Self {
+            generation: AtomicU64::new(0),
+            request: Mutex::new(Request {
+                transport: Transport::Playing,
+                ramp: DEFAULT_TRANSPORT_FADE,
+                seek: None,
+            }),
+        }
+    }
+}
+
+impl EnvelopeControl {
+    pub fn set_transport(&self, transport: Transport) {
+        self.update(|r| r.transport = transport);
+    }
+
+    /// Length of the ramps started from now on.
+    pub fn set_ramp(&self, ramp: Duration) {
+        self.update(|r| r.ramp = ramp.min(MAX_TRANSPORT_FADE));
+    }
+
+    /// Seeks the source of track `id` between a ramp down and a ramp back up.
+    pub fn seek(&self, id: u64, position: Duration) {
+        self.update(|r| {
+            let count = r.seek.map_or(0, |(_, _, count)| count + 1);
+            r.seek = Some((id, position, count));
+        });
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Request)) {
+        if let Ok(mut request) = self.request.lock() {
+            change(&mut request);
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    fn request(&self) -> Option<Request> {
+        self.request.lock().ok().map(|r| *r)
+    }
+}
+
+/// Sample-level gain envelope around transport changes, so they never cut the waveform.
+///
+/// The gain moves linearly and only changes between frames, keeping channels in step.
+/// Pausing holds the source once the gain reaches zero, so the track position stops
+/// there too.
+pub struct Envelope<S> {
+    inner: S,
+    id: u64,
+    control: Arc<EnvelopeControl>,
+    seen: u64,
+    transport: Transport,
+    ramp_frames: u64,
+    seen_seek: Option<u64>,
+    pending_seek: Option<Duration>,
+    gain: f32,
+    frame_pos: u16,
+    holding: bool,
+    ended: bool,
+}
+
+impl<S: Source> Envelope<S> {
+    /// Wraps the source of track `id`; it starts in the control's current state.
+    pub fn new(inner: S, id: u64, control: Arc<EnvelopeControl>) -> Self {
+        let mut envelope = Self {
+            inner,
+            id,
+            control,
+            seen: 0,
+            transport: Transport::Playing,
+            ramp_frames: 0,
+            seen_seek: None,
+            pending_seek: None,
+            gain: 1.0,
+            frame_pos: 0,
+            holding: false,
+            ended: false,
+        };
+        envelope.seen = envelope.control.generation.load(Ordering::Acquire);
+        if let Some(request) = envelope.control.request() {
+            envelope.apply(request);
+            // Seeks requested before this source existed were meant for an older one
+            envelope.pending_seek = None;
+            if envelope.transport != Transport::Playing {
+                envelope.gain = 0.0;
+            }
+        }
+        envelope
+    }
+
+    fn apply(&mut self, request: Request) {
+        self.transport = request.transport;
+        let rate = u128::from(self.inner.sample_rate());
+        self.ramp_frames =
+            u64::try_from(request.ramp.as_nanos() * rate / 1_000_000_000).unwrap_or(u64::MAX);
+        if let Some((id, position, count)) = request.seek {
+            if id == self.id && self.seen_seek != Some(count) {
+                self.pending_seek = Some(position);
+            }
+            self.seen_seek = Some(count);
+        }
+    }
+
+    fn target(&self) -> f32 {
+        if self.transport == Transport::Playing && self.pending_seek.is_none() {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Moves the gain one frame towards the target and acts on reaching silence.
+    /// Returns false while the source is held.
+    fn start_frame(&mut self) -> bool {
+        let generation = self.control.generation.load(Ordering::Acquire);
+        if generation != self.seen {
+            self.seen = generation;
+            if let Some(request) = self.control.request() {
+                self.apply(request);
+            }
+        }
+
+        let target = self.target();
+        if self.ramp_frames == 0 {
+            self.gain = target;
+        } else {
+            #[allow(clippy::cast_precision_loss)]
+            let step = 1.0 / self.ramp_frames as f32;
+            self.gain = if self.gain < target {
+                (self.gain + step).min(target)
+            } else {
+                (self.gain - step).max(target)
+            };
+        }
+
+        if self.gain > 0.0 || target > 0.0 {
+            return true;
+        }
+        if let Some(position) = self.pending_seek.take() {
+            if let Err(e) = self.inner.try_seek(position) {
+                eprintln!("Seek failed: {e}");
+            }
+            return true;
+        }
+        match self.transport {
+            Transport::Playing => true,
+            Transport::Paused => false,
+            Transport::Stopped => {
+                self.ended = true;
+                false
+            }
+        }
+    }
+}
+
+impl<S: Source> Iterator for Envelope<S> {
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        if self.ended {
+            return None;
+        }
+        if self.frame_pos == 0 {
+            self.holding = !self.start_frame();
+            if self.ended {
+                return None;
+            }
+        }
+        // Held silence is produced in whole frames so the channels stay aligned
+        let sample = if self.holding {
+            0.0
+        } else {
+            self.inner.next()? * self.gain
+        };
+        self.frame_pos = (self.frame_pos + 1) % self.inner.channels().max(1);
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Envelope<S> {
+    #[inline]
+    fn current_span_len(&self) -> Option<usize> {
+        self.inner.current_span_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.frame_pos = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    /// Stereo at 1 kHz with an 8-frame ramp, so every gain step is exact.
+    fn envelope(frames: usize) -> (Envelope<SamplesBuffer>, Arc<EnvelopeControl>) {
+        let control = Arc::new(EnvelopeControl::default());
+        control.set_ramp(Duration::from_millis(8));
+        let samples = (0..frames * 2)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect::<Vec<_>>();
+        let source = SamplesBuffer::new(2, 1000, samples);
+        (Envelope::new(source, 1, control.clone()), control)
+    }
+
+    #[test]
+    fn test_pause_ramps_down_and_holds() {
+        let (mut envelope, control) = envelope(100);
+        assert_eq!(envelope.by_ref().take(2).count(), 2);
+
+        control.set_transport(Transport::Paused);
+        let ramp: Vec<f32> = envelope.by_ref().take(40).collect();
+        // Both channels of a frame get the same gain
+        assert!(ramp.chunks(2).all(|f| f[0] == -f[1]));
+        assert_eq!(ramp[0], 0.5 * 0.875);
+        assert!(ramp[14..].iter().all(|s| *s == 0.0));
+
+        // Held samples were not read from the source
+        control.set_transport(Transport::Playing);
+        let rest: Vec<f32> = envelope.collect();
+        assert_eq!(rest.len(), 200 - 2 - 14);
+        assert_eq!(rest[0], 0.5 * 0.125);
+        assert_eq!(rest.last(), Some(&-0.5));
+    }
+
+    #[test]
+    fn test_stop_ends_after_ramp() {
+        let (mut envelope, control) = envelope(100);
+        control.set_transport(Transport::Stopped);
+        let played: Vec<f32> = envelope.by_ref().collect();
+        assert_eq!(played.len(), 14);
+        assert_eq!(played[12], 0.5 * 0.125);
+        assert!(envelope.next().is_none());
+    }
+
+    #[test]
+    fn test_seek_between_ramps() {
+        let (mut envelope, control) = envelope(1000);
+        assert_eq!(envelope.by_ref().take(100).count(), 100);
+
+        // Another track's seek changes nothing
+        control.seek(2, Duration::from_millis(900));
+        assert!(envelope.by_ref().take(20).all(|s| s.abs() == 0.5));
+
+        control.seek(1, Duration::from_millis(900));
+        let rest: Vec<f32> = envelope.collect();
+        // Ramp down, then the last 100 frames starting from silence
+        assert_eq!(rest.len(), 14 + 200);
+        assert_eq!(rest[14], 0.0);
+        assert_eq!(rest[16], 0.5 * 0.125);
+        assert_eq!(rest.last(), Some(&-0.5));
+    }
+
+    #[test]
+    fn test_new_source_starts_held_while_paused() {
+        let control = Arc::new(EnvelopeControl::default());
+        control.set_transport(Transport::Paused);
+        let mut envelope = Envelope::new(SamplesBuffer::new(1, 1000, vec![0.5; 10]), 1, control);
+        assert!(envelope.by_ref().take(50).all(|s| s == 0.0));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::database::operations;
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Centre frequencies of the default 10-band layout, in Hz.
+pub const DEFAULT_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// Q of the default bands, roughly one octave wide.
+pub const DEFAULT_Q: f32 = 1.41;
+
+/// Largest boost or cut of a band, in dB.
+pub const MAX_BAND_GAIN_DB: f32 = 24.0;
+
+/// Lowest centre frequency of a band, in Hz.
+pub const MIN_BAND_FREQUENCY: f32 = 10.0;
+
+/// Highest centre frequency of a band, in Hz.
+pub const MAX_BAND_FREQUENCY: f32 = 20_000.0;
+
+/// One peaking filter.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    pub frequency: f32,
+    #[serde(rename = "gainDb")]
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl EqBand {
+    /// Checks that the band describes a filter that can be played.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the frequency, gain or q is out of range or not a number.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_BAND_FREQUENCY..=MAX_BAND_FREQUENCY).contains(&self.frequency) {
+            return Err(format!(
+                "Band frequency must be between {MIN_BAND_FREQUENCY} and {MAX_BAND_FREQUENCY} Hz"
+            ));
+        }
+        if !(-MAX_BAND_GAIN_DB..=MAX_BAND_GAIN_DB).contains(&self.gain_db) {
+            return Err(format!(
+                "Band gain must be between -{MAX_BAND_GAIN_DB} and {MAX_BAND_GAIN_DB} dB"
+            ));
+        }
+        if !(self.q.is_finite() && self.q > 0.0) {
+            return Err("Band q must be a positive number".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EqPreset {
+    pub name: String,
+    pub bands: Vec<EqBand>,
+    #[serde(rename = "builtIn")]
+    pub built_in: bool,
+}
+
+/// What an override preset applies to.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EqScope {
+    /// Keyed by track path.
+    Track,
+    /// Keyed by album name.
+    Album,
+}
+
+impl EqScope {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Track => "track",
+            Self::Album => "album",
+        }
+    }
+}
+
+/// The default layout with the given gains.
+#[must_use]
+pub fn ten_band(gains: [f32; 10]) -> Vec<EqBand> {
+    DEFAULT_FREQUENCIES
+        .iter()
+        .zip(gains)
+        .map(|(&frequency, gain_db)| EqBand {
+            frequency,
+            gain_db,
+            q: DEFAULT_Q,
+        })
+        .collect()
+}
+
+/// Presets that ship with the player.
+#[must_use]
+pub fn builtin_presets() -> Vec<EqPreset> {
+    [
+        ("Flat", [0.0; 10]),
+        (
+            "Bass Boost",
+            [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ),
+        (
+            "Treble Boost",
+            [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 4.0, 5.0, 6.0],
+        ),
+        (
+            "Vocal",
+            [-2.0, -2.0, -1.0, 1.0, 3.0, 3.0, 2.0, 1.0, 0.0, -1.0],
+        ),
+        ("Rock", [4.0, 3.0, 2.0, 0.0, -1.0, -1.0, 1.0, 2.0, 3.0, 4.0]),
+        (
+            "Classical",
+            [3.0, 2.0, 1.0, 0.0, 0.0, 0.0, -1.0, -1.0, 1.0, 2.0],
+        ),
+    ]
+    .into_iter()
+    .map(|(name, gains)| EqPreset {
+        name: name.to_string(),
+        bands: ten_band(gains),
+        built_in: true,
+    })
+    .collect()
+}
+
+/// Looks up a preset by name, built-in presets first.
+///
+/// # Errors
+///
+/// Returns an error if the user preset query fails.
+pub fn find_preset(conn: &Connection, name: &str) -> Result<Option<Vec<EqBand>>, String> {
+    if let Some(preset) = builtin_presets().into_iter().find(|p| p.name == name) {
+        return Ok(Some(preset.bands));
+    }
+    operations::get_eq_preset(conn, name).map_err(|e| e.to_string())
+}
+
+/// Shared between the audio thread and an `Equalizer` that has been moved into a sink.
+#[derive(Debug, Default)]
+pub struct EqControl {
+    pending: AtomicBool,
+    bands: Mutex<Vec<EqBand>>,
+}
+
+impl EqControl {
+    /// Replaces the bands; the equalizer picks them up at its next sample.
+    pub fn set_bands(&self, bands: Vec<EqBand>) {
+        if let Ok(mut current) = self.bands.lock() {
+            *current = bands;
+            self.pending.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Normalized biquad coefficients (`a0` = 1).
+#[derive(Debug, Clone, Copy)]
+struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// Peaking filter from the RBJ audio EQ cookbook.
+    fn peaking(band: &EqBand, sample_rate: f32) -> Self {
+        // Keep the centre below Nyquist so the filter stays stable
+        let frequency = band.frequency.clamp(10.0, sample_rate * 0.45);
+        let a = 10f32.powf(band.gain_db / 40.0);
+        let w0 = TAU * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * band.q.max(0.05));
+        let cos_w0 = w0.cos();
+
+        let a0 = 1.0 + alpha / a;
+        Self {
+            b0: (1.0 + alpha * a) / a0,
+            b1: (-2.0 * cos_w0) / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: (-2.0 * cos_w0) / a0,
+            a2: (1.0 - alpha / a) / a0,
+        }
+    }
+}
+
+/// Transposed direct form II state of one filter on one channel.
+#[derive(Debug, Clone, Copy, Default)]
+struct FilterState {
+    z1: f32,
+    z2: f32,
+}
+
+impl FilterState {
+    #[inline]
+    fn process(&mut self, c: &Coefficients, x: f32) -> f32 {
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y
+    }
+}
+
+/// Runs every sample of the inner source through a chain of peaking filters.
+///
+/// Bands with zero gain are skipped, so a flat equalizer passes samples through untouched.
+pub struct Equalizer<S> {
+    inner: S,
+    control: Arc<EqControl>,
+    filters: Vec<Coefficients>,
+    // One state per filter per channel, filter-major
+    states: Vec<FilterState>,
+    channel: usize,
+}
+
+impl<S: Source> Equalizer<S> {
+    pub fn new(inner: S, bands: &[EqBand]) -> Self {
+        let mut eq = Self {
+            inner,
+            control: Arc::new(EqControl::default()),
+            filters: Vec::new(),
+            states: Vec::new(),
+            channel: 0,
+        };
+        eq.configure(bands);
+        eq
+    }
+
+    /// Handle used by the audio thread once this source has been moved into the sink.
+    #[must_use]
+    pub fn control(&self) -> Arc<EqControl> {
+        self.control.clone()
+    }
+
+    fn configure(&mut self, bands: &[EqBand]) {
+        #[allow(clippy::cast_precision_loss)]
+        let rate = self.inner.sample_rate() as f32;
+        self.filters = bands
+            .iter()
+            .filter(|b| b.gain_db.abs() > f32::EPSILON)
+            .map(|b| Coefficients::peaking(b, rate))
+            .collect();
+        let channels = usize::from(self.inner.channels());
+        // Keep the running state where possible so a change does not click
+        self.states
+            .resize(self.filters.len() * channels, FilterState::default());
+    }
+}
+
+impl<S: Source> Iterator for Equalizer<S> {
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        if self.control.pending.swap(false, Ordering::Acquire) {
+            let bands = self
+                .control
+                .bands
+                .lock()
+                .map(|b| b.clone())
+                .unwrap_or_default();
+            self.configure(&bands);
+        }
+
+        let mut sample = self.inner.next()?;
+        let channels = usize::from(self.inner.channels()).max(1);
+        if self.channel >= channels {
+            self.channel = 0;
+        }
+        for (i, filter) in self.filters.iter().enumerate() {
+            if let Some(state) = self.states.get_mut(i * channels + self.channel) {
+                sample = state.process(filter, sample);
+            }
+        }
+        self.channel = (self.channel + 1) % channels;
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Equalizer<S> {
+    #[inline]
+    fn current_span_len(&self) -> Option<usize> {
+        self.inner.current_span_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.states.fill(FilterState::default());
+        self.channel = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn sine(frequency: f32) -> SamplesBuffer {
+        let rate = 48_000;
+        #[allow(clippy::cast_precision_loss)]
+        let samples = (0..rate)
+            .map(|i| 0.25 * (TAU * frequency * i as f32 / rate as f32).sin())
+            .collect::<Vec<_>>();
+        SamplesBuffer::new(1, 48_000, samples)
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        // Skip the filter's settling time
+        samples[4800..].iter().fold(0.0, |m, s| m.max(s.abs()))
+    }
+
+    #[test]
+    fn test_flat_is_transparent() {
+        let input: Vec<f32> = sine(440.0).collect();
+        let output: Vec<f32> = Equalizer::new(sine(440.0), &ten_band([0.0; 10])).collect();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_band_boosts_its_frequency_only() {
+        let band = [EqBand {
+            frequency: 1000.0,
+            gain_db: 6.0,
+            q: DEFAULT_Q,
+        }];
+        let boosted: Vec<f32> = Equalizer::new(sine(1000.0), &band).collect();
+        let far: Vec<f32> = Equalizer::new(sine(60.0), &band).collect();
+
+        // +6 dB doubles the amplitude at the centre frequency
+        assert!((peak(&boosted) - 0.5).abs() < 0.01);
+        assert!((peak(&far) - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_bands_change_live() {
+        let mut eq = Equalizer::new(sine(1000.0), &[]);
+        let control = eq.control();
+        let before: Vec<f32> = eq.by_ref().take(9600).collect();
+        assert!((peak(&before) - 0.25).abs() < 0.01);
+
+        control.set_bands(vec![EqBand {
+            frequency: 1000.0,
+            gain_db: -6.0,
+            q: DEFAULT_Q,
+        }]);
+        let after: Vec<f32> = eq.collect();
+        assert!((peak(&after) - 0.125).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_builtin_presets_use_default_layout() {
+        for preset in builtin_presets() {
+            assert_eq!(preset.bands.len(), DEFAULT_FREQUENCIES.len());
+            assert!(preset.built_in);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::scanner::parser::ReplayGain;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// Album gain while playing in order, track gain while shuffling.
+    Auto,
+}
+
+/// Largest pre-amp boost or cut, in dB.
+pub const MAX_PREAMP_DB: f32 = 15.0;
+
+/// Loudness normalization settings applied by the audio thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalization {
+    pub mode: NormalizationMode,
+    /// Extra gain in dB added on top of the stored gain.
+    pub preamp_db: f32,
+    /// Lowers the gain so the stored peak does not exceed full scale.
+    pub prevent_clipping: bool,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self {
+            mode: NormalizationMode::Off,
+            preamp_db: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl Normalization {
+    /// Linear gain for a track. `shuffled` decides between album and track gain in
+    /// auto mode. Tracks without `ReplayGain` values play unchanged.
+    #[must_use]
+    pub fn factor(&self, gain: &ReplayGain, shuffled: bool) -> f32 {
+        let prefer_album = match self.mode {
+            NormalizationMode::Off => return 1.0,
+            NormalizationMode::Track => false,
+            NormalizationMode::Album => true,
+            NormalizationMode::Auto => !shuffled,
+        };
+
+        let track = gain.track_gain.map(|g| (g, gain.track_peak));
+        let album = gain.album_gain.map(|g| (g, gain.album_peak));
+        let chosen = if prefer_album {
+            album.or(track)
+        } else {
+            track.or(album)
+        };
+        let Some((db, peak)) = chosen else {
+            return 1.0;
+        };
+
+        let factor = 10f32.powf((db + self.preamp_db) / 20.0);
+        match peak {
+            Some(peak) if self.prevent_clipping && peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged() -> ReplayGain {
+        ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.5),
+            album_gain: Some(-12.0),
+            album_peak: Some(0.9),
+        }
+    }
+
+    fn settings(mode: NormalizationMode, preamp_db: f32) -> Normalization {
+        Normalization {
+            mode,
+            preamp_db,
+            prevent_clipping: true,
+        }
+    }
+
+    #[test]
+    fn test_modes_pick_gain() {
+        let gain = tagged();
+        assert!((settings(NormalizationMode::Off, 0.0).factor(&gain, false) - 1.0).abs() < 1e-6);
+        assert!(
+            (settings(NormalizationMode::Track, 0.0).factor(&gain, false) - 0.501).abs() < 1e-3
+        );
+        assert!(
+            (settings(NormalizationMode::Album, 0.0).factor(&gain, false) - 0.251).abs() < 1e-3
+        );
+        assert!((settings(NormalizationMode::Auto, 0.0).factor(&gain, false) - 0.251).abs() < 1e-3);
+        assert!((settings(NormalizationMode::Auto, 0.0).factor(&gain, true) - 0.501).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_missing_album_gain_falls_back_to_track() {
+        let gain = ReplayGain {
+            album_gain: None,
+            ..tagged()
+        };
+        let factor = settings(NormalizationMode::Album, 0.0).factor(&gain, false);
+        assert!((factor - 0.501).abs() < 1e-3);
+        assert!(
+            (settings(NormalizationMode::Album, 0.0).factor(&ReplayGain::default(), false) - 1.0)
+                .abs()
+                < 1e-6
+        );
+    }
+
+    #[test]
+    fn test_peak_prevents_clipping() {
+        // -6 dB + 18 dB pre-amp would push a 0.5 peak to ~2.0 full scale; it is held at 1 / 0.5
+        let gain = tagged();
+        let limited = settings(NormalizationMode::Track, 18.0).factor(&gain, false);
+        assert!((limited - 2.0).abs() < 1e-6);
+
+        let unlimited = Normalization {
+            prevent_clipping: false,
+            ..settings(NormalizationMode::Track, 18.0)
+        };
+        assert!((unlimited.factor(&gain, false) - 3.981).abs() < 1e-3);
+    }
+}
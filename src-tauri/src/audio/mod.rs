@@ -1,4 +1,17 @@
 pub mod commands;
+pub mod device;
+pub mod envelope;
+pub mod equalizer;
+pub mod gain;
+pub mod output;
 pub mod player;
+pub mod queue;
+pub mod resume;
+pub mod segment;
+pub mod session;
+pub mod sleep;
+pub mod source;
+pub mod spectrum;
+pub mod stretch;
 #[cfg(test)]
 mod tests;
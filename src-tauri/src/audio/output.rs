@@ -0,0 +1,352 @@
+use super::device::find_output_device;
+use rodio::cpal::traits::HostTrait;
+use rodio::mixer::{self, Mixer, MixerSource};
+use rodio::{cpal, ChannelCount, DeviceTrait, OutputStream, OutputStreamBuilder, SampleRate};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Called by an output whose device has disappeared.
+pub type LostCallback = Arc<dyn Fn() + Send + Sync>;
+
+/// Channel count of the rendering backends.
+pub const RENDER_CHANNELS: ChannelCount = 2;
+
+/// Sample rate of the rendering backends.
+pub const RENDER_SAMPLE_RATE: SampleRate = 44_100;
+
+/// Audio rendered per step of a rendering backend.
+const RENDER_BLOCK: Duration = Duration::from_millis(10);
+
+/// Opens the outputs the player mixes into.
+pub trait OutputBackend: Send + 'static {
+    /// Opens the named device, or the default one with `None`. `on_lost` is called if
+    /// the device goes away while the output is open.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device does not exist or cannot be opened.
+    fn open(
+        &mut self,
+        device: Option<&str>,
+        on_lost: LostCallback,
+    ) -> Result<Box<dyn Output>, String>;
+}
+
+/// An open output; closed when dropped.
+pub trait Output {
+    /// Sinks connected to this mixer are played.
+    fn mixer(&self) -> &Mixer;
+
+    /// Name of the device the output plays on, if it has one.
+    fn device_name(&self) -> Option<String>;
+
+    /// Channel count the output plays at.
+    fn channels(&self) -> ChannelCount;
+
+    /// Sample rate the output plays at.
+    fn sample_rate(&self) -> SampleRate;
+}
+
+/// Plays through the sound card with rodio.
+#[derive(Debug, Default)]
+pub struct RodioBackend;
+
+struct RodioOutput {
+    stream: OutputStream,
+    name: Option<String>,
+}
+
+impl OutputBackend for RodioBackend {
+    fn open(
+        &mut self,
+        device: Option<&str>,
+        on_lost: LostCallback,
+    ) -> Result<Box<dyn Output>, String> {
+        let device = match device {
+            Some(name) => {
+                find_output_device(name).ok_or_else(|| format!("Device not found: {name}"))?
+            }
+            None => cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| "No default output device".to_string())?,
+        };
+        let name = device.name().ok();
+        let mut stream = OutputStreamBuilder::from_device(device)
+            .map_err(|e| e.to_string())?
+            .with_error_callback(move |e| match e {
+                cpal::StreamError::DeviceNotAvailable => on_lost(),
+                cpal::StreamError::BackendSpecific { err } => {
+                    eprintln!("Output stream error: {err}");
+                }
+            })
+            .open_stream_or_fallback()
+            .map_err(|e| e.to_string())?;
+        stream.log_on_drop(false);
+        Ok(Box::new(RodioOutput { stream, name }))
+    }
+}
+
+impl Output for RodioOutput {
+    fn mixer(&self) -> &Mixer {
+        self.stream.mixer()
+    }
+
+    fn device_name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.stream.config().channel_count()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.stream.config().sample_rate()
+    }
+}
+
+/// Discards everything it plays, for running without an audio device.
+#[derive(Debug)]
+pub struct NullBackend {
+    speed: f32,
+}
+
+impl NullBackend {
+    /// Renders at `speed` times real time.
+    #[must_use]
+    pub fn new(speed: f32) -> Self {
+        Self { speed }
+    }
+}
+
+impl Default for NullBackend {
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
+
+impl OutputBackend for NullBackend {
+    fn open(
+        &mut self,
+        _device: Option<&str>,
+        _on_lost: LostCallback,
+    ) -> Result<Box<dyn Output>, String> {
+        Ok(Box::new(RenderedOutput::start(self.speed, |_| Ok(()))))
+    }
+}
+
+/// Writes everything it plays to a 32-bit float WAV file.
+///
+/// Each opened output starts the file over, so after a device change it holds what was
+/// played since then.
+#[derive(Debug)]
+pub struct WavBackend {
+    path: PathBuf,
+    speed: f32,
+}
+
+impl WavBackend {
+    /// Writes to `path`, rendering at `speed` times real time.
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>, speed: f32) -> Self {
+        Self {
+            path: path.into(),
+            speed,
+        }
+    }
+}
+
+impl OutputBackend for WavBackend {
+    fn open(
+        &mut self,
+        _device: Option<&str>,
+        _on_lost: LostCallback,
+    ) -> Result<Box<dyn Output>, String> {
+        let file = File::create(&self.path)
+            .map_err(|e| format!("Failed to create {}: {e}", self.path.display()))?;
+        let mut writer =
+            WavWriter::new(file, RENDER_CHANNELS, RENDER_SAMPLE_RATE).map_err(|e| e.to_string())?;
+        Ok(Box::new(RenderedOutput::start(self.speed, move |block| {
+            writer.write(block)
+        })))
+    }
+}
+
+/// Pulls samples out of a mixer on its own thread, paced against the wall clock.
+struct RenderedOutput {
+    mixer: Mixer,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RenderedOutput {
+    fn start<F>(speed: f32, mut consume: F) -> Self
+    where
+        F: FnMut(&[f32]) -> std::io::Result<()> + Send + 'static,
+    {
+        let (mixer, mut source) = mixer::mixer(RENDER_CHANNELS, RENDER_SAMPLE_RATE);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let speed = f64::from(speed.max(0.01));
+
+        let thread = thread::spawn(move || {
+            let block_len = block_len();
+            let mut block = Vec::with_capacity(block_len);
+            let started = Instant::now();
+            let mut rendered = Duration::ZERO;
+            while !stopped.load(Ordering::Relaxed) {
+                render_block(&mut source, &mut block, block_len);
+                if let Err(e) = consume(&block) {
+                    eprintln!("Rendered output failed: {e}");
+                    return;
+                }
+                rendered += RENDER_BLOCK;
+                let due = rendered.div_f64(speed);
+                if let Some(wait) = due.checked_sub(started.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+        });
+
+        Self {
+            mixer,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+fn block_len() -> usize {
+    let frames = u128::from(RENDER_SAMPLE_RATE) * RENDER_BLOCK.as_millis() / 1000;
+    usize::try_from(frames).unwrap_or(0) * usize::from(RENDER_CHANNELS)
+}
+
+/// Fills `block` from the mixer, with silence while nothing is connected to it.
+fn render_block(source: &mut MixerSource, block: &mut Vec<f32>, len: usize) {
+    block.clear();
+    block.extend((0..len).map(|_| source.next().unwrap_or(0.0)));
+}
+
+impl Output for RenderedOutput {
+    fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    fn device_name(&self) -> Option<String> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        RENDER_CHANNELS
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        RENDER_SAMPLE_RATE
+    }
+}
+
+impl Drop for RenderedOutput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Minimal streaming WAV encoder of 32-bit float samples. The header is kept current after
+/// every block, so the file can be read while it is still being written.
+pub(crate) struct WavWriter {
+    out: BufWriter<File>,
+    data_len: u32,
+    channels: ChannelCount,
+    sample_rate: SampleRate,
+}
+
+impl WavWriter {
+    const HEADER_LEN: u32 = 44;
+
+    pub(crate) fn new(
+        file: File,
+        channels: ChannelCount,
+        sample_rate: SampleRate,
+    ) -> std::io::Result<Self> {
+        let mut writer = Self {
+            out: BufWriter::new(file),
+            data_len: 0,
+            channels,
+            sample_rate,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    /// Appends interleaved samples.
+    pub(crate) fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        let added = u32::try_from(samples.len() * 4).unwrap_or(u32::MAX);
+        self.data_len = self.data_len.saturating_add(added);
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let channels = self.channels;
+        let block_align = channels * 4;
+        let byte_rate = self.sample_rate * u32::from(block_align);
+
+        let out = &mut self.out;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(Self::HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // Format 3 is IEEE float
+        out.write_all(&3u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&self.sample_rate.to_le_bytes())?;
+        out.write_all(&byte_rate.to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&32u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&self.data_len.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+    use rodio::Decoder;
+    use std::io::BufReader;
+
+    #[test]
+    fn test_wav_backend_renders_mixer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        let mut backend = WavBackend::new(&path, 50.0);
+        let output = backend.open(None, Arc::new(|| {})).unwrap();
+
+        output.mixer().add(SamplesBuffer::new(
+            RENDER_CHANNELS,
+            RENDER_SAMPLE_RATE,
+            vec![0.5; 4410],
+        ));
+        // 50 ms of audio takes a millisecond at this speed
+        thread::sleep(Duration::from_millis(200));
+        drop(output);
+
+        let decoded: Vec<f32> = Decoder::new(BufReader::new(File::open(&path).unwrap()))
+            .unwrap()
+            .collect();
+        let played = decoded.iter().filter(|s| (**s - 0.5).abs() < 1e-6).count();
+        assert_eq!(played, 4410);
+    }
+}
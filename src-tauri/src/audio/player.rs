@@ -1,171 +1,1524 @@
-use rodio::{Decoder, OutputStreamBuilder, Sink, Source};
-use std::fs::File;
-use std::io::BufReader;
-use std::sync::{mpsc, Mutex};
+use super::device::OUTPUT_DEVICE_SETTING;
+use super::envelope::{Envelope, EnvelopeControl, Transport};
+use super::equalizer::{find_preset, ten_band, EqBand, EqControl, Equalizer};
+use super::gain::Normalization;
+use super::output::{LostCallback, Output, OutputBackend};
+use super::queue::{PlayQueue, QueueState, RepeatMode, ShuffleMode, ShuffleRng};
+use super::resume::{remembers_position, resume_point, store_position};
+use super::segment::{open_segment, FileDecoder, Segment};
+use super::session::{save_session, Session, SESSION_SAVE_INTERVAL};
+use super::sleep::SleepTimer;
+use super::source::{FadeDirection, FadeRequest, TrackControl, TrackSource};
+use super::spectrum::{SpectrumAnalyzer, SpectrumConfig, SpectrumTap, Tapped};
+use super::stretch::{SpeedControl, TimeStretch};
+use crate::database::{operations, AppState};
+use crate::scanner::chapters::{chapter_at, read_chapters, Chapter};
+use crate::scanner::cue::{find_range, split_virtual_path, CueRange};
+use crate::scanner::parser::{parse_file, ReplayGain};
+use rodio::{mixer, Sink, Source};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, Weak};
 use std::thread;
-use std::time::Duration;
-use tauri::{AppHandle, Emitter};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+/// Pressing "previous" after this much playback restarts the current track instead.
+const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+
+/// Longest crossfade the player accepts.
+pub const MAX_CROSSFADE: Duration = Duration::from_secs(12);
+
+/// Slack for positions that land just short of a chapter start after seeking there.
+const CHAPTER_TOLERANCE: f64 = 0.25;
+
+/// Crossfade used when the user skips instead of letting the track end.
+const SKIP_CROSSFADE: Duration = Duration::from_millis(800);
+
+/// Everything between the decoder and the `TrackSource` that reports track boundaries.
+type TrackChain = Equalizer<Segment<FileDecoder>>;
+/// What is appended to a sink: the track, stretched, under the transport envelope.
+type Playable = Envelope<TimeStretch<TrackSource<TrackChain>>>;
 
 #[derive(Debug)]
 pub enum AudioCommand {
-    Play(String),   // Play new file
-    Pause,          // Pause
-    Resume,         // Resume
-    Stop,           // Stop and clear
-    Seek(f32),      // Seek to seconds
-    SetVolume(f32), // 0.0 - 1.0
+    Play(String),                                        // Play new file
+    Pause,                                               // Pause
+    Resume,                                              // Resume
+    Stop,                                                // Stop and clear
+    Seek(f32),                                           // Seek to seconds
+    NextChapter,                            // Next chapter, or next track after the last
+    PreviousChapter,                        // Restart or go to the previous chapter
+    SeekChapter(usize),                     // Jump to a chapter by index
+    SetLoop { start: f32, end: f32 },       // Repeat a region of the current track
+    ClearLoop,                              // Play on past the loop end
+    SetVolume(f32),                         // 0.0 - 1.0
+    Enqueue(Vec<String>),                   // Append to the end of the queue
+    EnqueueNext(Vec<String>),               // Insert after the current track
+    Next,                                   // Skip to the next queued track
+    Previous,                               // Restart or go to the previous track
+    ClearQueue,                             // Drop everything but the current track
+    MoveInQueue { from: usize, to: usize }, // Reorder a queue entry
+    RemoveFromQueue(usize),                 // Remove a queue entry by index
+    GetQueue(mpsc::Sender<QueueState>),     // Reply with a queue snapshot
+    SetCrossfade { seconds: f32, gapless_albums: bool }, // 0 - 12 s, 0 disables
+    SetRepeat(RepeatMode),                  // Off, one or all
+    SetShuffle(ShuffleMode, Option<u64>),   // Mode and optional seed
+    SetNormalization(Normalization),        // ReplayGain mode, pre-amp, clipping
+    SetSpeed(f32),                          // 0.5 - 3.0, pitch unchanged
+    SetPitch(f32),                          // Semitones, speed unchanged
+    SetSleepTimer(Option<SleepTimer>),      // `None` cancels
+    SetTransportFade(Duration),             // Ramp around pause, resume, stop, seek
+    SetSpectrum(Option<SpectrumConfig>),    // `None` stops `player-spectrum`
+    SetOutputDevice(Option<String>),        // Device name, `None` for the default
+    SetEqualizer(Vec<EqBand>),              // Replace all bands
+    SetEqBand(usize, EqBand),               // Change one band by index
+    RefreshEqualizer,                       // Preset overrides were edited
+    RestoreSession(Session),                // Load a saved queue, paused
+    SetArtists(HashMap<String, String>),    // Library artists of tracks about to be queued
+    Shutdown(mpsc::Sender<Result<(), String>>), // Store the session, reply and exit
+    TrackEnding(u64),                       // Sent by a `TrackSource` when its crossfade starts
+    TrackEnded(u64),                        // Sent by a `TrackSource` when it runs out
+    StreamLost(u64), // Sent by the stream error callback when its device disappears
 }
 
 pub struct AudioPlayerState {
     pub tx: Mutex<mpsc::Sender<AudioCommand>>,
+    // The only strong handle behind every `EventSender`, so dropping the state closes
+    // the channel and ends the audio thread
+    events: Arc<mpsc::Sender<AudioCommand>>,
 }
 
-/// Initializes the audio thread and returns the state to be managed by Tauri.
-///
-/// # Panics
+impl AudioPlayerState {
+    /// State whose commands are sent to `tx`.
+    #[must_use]
+    pub fn new(tx: mpsc::Sender<AudioCommand>) -> Self {
+        Self {
+            events: Arc::new(tx.clone()),
+            tx: Mutex::new(tx),
+        }
+    }
+
+    /// A sender for track and stream events that does not keep the channel open.
+    #[must_use]
+    pub fn events(&self) -> EventSender {
+        EventSender::new(&self.events)
+    }
+
+    /// Has the audio thread store the playback session and exit, waiting until it has.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the audio thread is gone, does not answer in time, or fails
+    /// to write the session.
+    pub fn shutdown(&self) -> Result<(), String> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        {
+            let tx = self.tx.lock().map_err(|e| e.to_string())?;
+            tx.send(AudioCommand::Shutdown(reply_tx))
+                .map_err(|e| e.to_string())?;
+        }
+        reply_rx
+            .recv_timeout(Duration::from_secs(1))
+            .map_err(|e| e.to_string())?
+    }
+}
+
+/// Sends track and stream events back into the command loop of the audio thread.
 ///
-/// Panics if the default audio stream cannot be opened.
-#[must_use]
-pub fn init_audio_thread(app_handle: AppHandle) -> AudioPlayerState {
-    let (tx, rx) = mpsc::channel();
+/// Sources and stream callbacks live as long as the playback chain, which the audio thread
+/// owns. Holding a full sender there would keep the channel open forever, so events only
+/// get through while the `AudioPlayerState` they came from exists.
+#[derive(Clone)]
+pub struct EventSender(Weak<mpsc::Sender<AudioCommand>>);
 
-    thread::spawn(move || {
-        // Lazy initialization: only create stream when needed (first Play command)
-        // This avoids initializing CoreAudio during app startup on macOS
-        let mut stream = None;
-        let mut sink = None;
-        let mut current_duration = Duration::from_secs(0);
+impl EventSender {
+    /// A sender that delivers to `tx` while it is alive.
+    #[must_use]
+    pub fn new(tx: &Arc<mpsc::Sender<AudioCommand>>) -> Self {
+        Self(Arc::downgrade(tx))
+    }
 
-        loop {
-            // Wait for commands with a timeout to allow for periodic status updates
-            match rx.recv_timeout(Duration::from_millis(200)) {
-                Ok(cmd) => match cmd {
-                    AudioCommand::Play(path) => {
-                        // Initialize stream and sink on first use
-                        if stream.is_none() {
-                            match OutputStreamBuilder::open_default_stream() {
-                                Ok(s) => {
-                                    let new_sink = Sink::connect_new(s.mixer());
-                                    stream = Some(s);
-                                    sink = Some(new_sink);
-                                }
-                                Err(e) => {
-                                    eprintln!("Audio thread failed to open output stream: {e}");
-                                    app_handle
-                                        .emit(
-                                            "player-error",
-                                            format!("Failed to initialize audio: {e}"),
-                                        )
-                                        .ok();
-                                    continue;
-                                }
-                            }
-                        }
+    /// Sends `cmd` unless the player state is gone.
+    pub fn send(&self, cmd: AudioCommand) {
+        if let Some(tx) = self.0.upgrade() {
+            // The audio thread may already be gone during shutdown
+            tx.send(cmd).ok();
+        }
+    }
+}
 
-                        let sink_ref = sink.as_ref().unwrap();
-
-                        match File::open(&path) {
-                            Ok(file) => {
-                                let reader = BufReader::new(file);
-                                match Decoder::new(reader) {
-                                    Ok(source) => {
-                                        // Store total duration if available
-                                        if let Some(d) = source.total_duration() {
-                                            current_duration = d;
-                                        } else {
-                                            current_duration = Duration::from_secs(0);
-                                        }
-
-                                        sink_ref.clear();
-                                        sink_ref.append(source);
-                                        sink_ref.play();
-
-                                        // Notify frontend
-                                        app_handle
-                                            .emit(
-                                                "player-status",
-                                                serde_json::json!({ "status": "playing", "path": path, "duration": current_duration.as_secs_f64() }),
-                                            )
-                                            .ok();
-                                    }
-                                    Err(e) => {
-                                        eprintln!("Failed to decode audio: {e}");
-                                        app_handle
-                                            .emit("player-error", format!("Failed to decode: {e}"))
-                                            .ok();
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to open file: {e}");
-                                app_handle
-                                    .emit("player-error", format!("Failed to open file: {e}"))
-                                    .ok();
-                            }
-                        }
+/// Why a track stopped playing, as reported by `track-ended` and `queue-finished`.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EndReason {
+    /// Played to its end.
+    Finished,
+    /// Replaced by another track before its end.
+    Skipped,
+    /// Could not be opened or played.
+    Error,
+    /// Playback was stopped.
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlaybackStatus {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// A track that has been handed to the playback chain.
+struct LoadedTrack {
+    id: u64,
+    path: String,
+    duration: Duration,
+    control: Arc<TrackControl>,
+    replay_gain: ReplayGain,
+    eq: Arc<EqControl>,
+    // Plays with a per-track or per-album preset instead of the global bands
+    eq_override: bool,
+    // Long-form audio that saves where it was left and resumes from there
+    remember: bool,
+    chapters: Vec<Chapter>,
+}
+
+/// State owned by the audio thread.
+struct Player<R: Runtime> {
+    app_handle: AppHandle<R>,
+    // Handed to every `TrackSource` so track boundaries come back through the loop
+    events: EventSender,
+    // Lazy initialization: only create stream when needed (first Play command)
+    // This avoids initializing CoreAudio during app startup on macOS
+    stream: Option<Box<dyn Output>>,
+    backend: Box<dyn OutputBackend>,
+    // Chosen device name, read from the settings the first time the stream is opened
+    output_device: Option<String>,
+    output_device_loaded: bool,
+    // Bumped for every stream so errors from a replaced stream are ignored
+    stream_id: u64,
+    volume: f32,
+    // Two sinks on the same mixer so an incoming track can overlap the outgoing one
+    decks: Vec<Sink>,
+    active: usize,
+    queue: PlayQueue,
+    status: PlaybackStatus,
+    next_track_id: u64,
+    current: Option<LoadedTrack>,
+    // The next queue entry, opened ahead of time. For gapless transitions it is already
+    // appended behind `current`; for crossfades it waits in `pending` until the fade starts.
+    preloaded: Option<LoadedTrack>,
+    pending: Option<TrackSource<TrackChain>>,
+    crossfade: Duration,
+    gapless_albums: bool,
+    repeat: RepeatMode,
+    shuffle: ShuffleMode,
+    rng: ShuffleRng,
+    // Library artists sent ahead of queued tracks for smart shuffle, by path
+    artists: HashMap<String, String>,
+    normalization: Normalization,
+    equalizer: Vec<EqBand>,
+    // Shared with every `TimeStretch` so changes apply to both decks at once
+    speed: Arc<SpeedControl>,
+    // Shared with every `Envelope` so transport ramps apply to both decks at once
+    envelope: Arc<EnvelopeControl>,
+    sleep: Option<SleepTimer>,
+    // Fade-out of the sleep timer, applied on top of `volume`
+    sleep_volume: f32,
+    // A-B loop of the current track; cleared when the track changes
+    loop_region: Option<(Duration, Duration)>,
+    // Track id and chapter index last announced by `chapter-changed`
+    chapter: Option<(u64, Option<usize>)>,
+    // Sees the mix of both decks; only collects while `spectrum` runs
+    spectrum_tap: Arc<SpectrumTap>,
+    spectrum: Option<SpectrumAnalyzer>,
+    // Last session written to the database, and when the session was last checked
+    saved_session: Option<Session>,
+    session_checked: Instant,
+}
+
+impl<R: Runtime> Player<R> {
+    fn new(app_handle: AppHandle<R>, events: EventSender, backend: Box<dyn OutputBackend>) -> Self {
+        let mut player = Self {
+            app_handle,
+            events,
+            stream: None,
+            backend,
+            output_device: None,
+            output_device_loaded: false,
+            stream_id: 0,
+            volume: 1.0,
+            decks: Vec::new(),
+            active: 0,
+            queue: PlayQueue::new(),
+            status: PlaybackStatus::Stopped,
+            next_track_id: 0,
+            current: None,
+            preloaded: None,
+            pending: None,
+            crossfade: Duration::ZERO,
+            gapless_albums: true,
+            repeat: RepeatMode::Off,
+            shuffle: ShuffleMode::Off,
+            rng: ShuffleRng::new(time_seed()),
+            artists: HashMap::new(),
+            normalization: Normalization::default(),
+            equalizer: ten_band([0.0; 10]),
+            speed: Arc::new(SpeedControl::default()),
+            envelope: Arc::new(EnvelopeControl::default()),
+            sleep: None,
+            sleep_volume: 1.0,
+            loop_region: None,
+            chapter: None,
+            spectrum_tap: Arc::new(SpectrumTap::default()),
+            spectrum: None,
+            saved_session: None,
+            session_checked: Instant::now(),
+        };
+        // A player that has done nothing yet must not overwrite the stored session
+        player.saved_session = Some(player.session());
+        player
+    }
+
+    fn handle(&mut self, cmd: AudioCommand) {
+        match cmd {
+            AudioCommand::Play(path) => {
+                self.queue.play_now(path);
+                self.emit_queue();
+                self.play_current();
+            }
+            AudioCommand::Pause => {
+                if self.status == PlaybackStatus::Stopped || self.decks.is_empty() {
+                    return;
+                }
+                self.envelope.set_transport(Transport::Paused);
+                self.status = PlaybackStatus::Paused;
+                self.emit_status(serde_json::json!({ "status": "paused" }));
+            }
+            AudioCommand::Resume => {
+                if self.status == PlaybackStatus::Stopped || self.decks.is_empty() {
+                    return;
+                }
+                self.envelope.set_transport(Transport::Playing);
+                self.status = PlaybackStatus::Playing;
+                self.emit_status(serde_json::json!({ "status": "playing" }));
+            }
+            AudioCommand::Stop => self.stop(),
+            AudioCommand::Seek(secs) => {
+                // Ignore positions too large to represent
+                if let Ok(position) = Duration::try_from_secs_f32(secs.max(0.0)) {
+                    self.seek(position);
+                }
+            }
+            AudioCommand::NextChapter => self.next_chapter(),
+            AudioCommand::PreviousChapter => self.previous_chapter(),
+            AudioCommand::SeekChapter(index) => {
+                let start = self
+                    .current
+                    .as_ref()
+                    .and_then(|t| t.chapters.get(index))
+                    .map(|c| c.start);
+                match start {
+                    Some(start) => self.seek(Duration::from_secs_f64(start)),
+                    None => {
+                        self.app_handle
+                            .emit("player-error", format!("No chapter {index}"))
+                            .ok();
                     }
-                    AudioCommand::Pause => {
-                        if let Some(s) = &sink {
-                            s.pause();
-                            app_handle
-                                .emit("player-status", serde_json::json!({ "status": "paused" }))
-                                .ok();
-                        }
+                }
+            }
+            AudioCommand::SetLoop { start, end } => self.set_loop(start, end),
+            AudioCommand::ClearLoop => {
+                if self.loop_region.take().is_some() {
+                    if let Some(track) = &self.current {
+                        track.control.set_loop(None);
                     }
-                    AudioCommand::Resume => {
-                        if let Some(s) = &sink {
-                            s.play();
-                            app_handle
-                                .emit("player-status", serde_json::json!({ "status": "playing" }))
-                                .ok();
-                        }
+                    self.emit_mode();
+                }
+            }
+            AudioCommand::SetVolume(vol) => {
+                // Kept for the sinks of a stream that is reopened later
+                self.volume = vol;
+                self.apply_volume();
+            }
+            AudioCommand::Enqueue(paths) => {
+                let start = self.queue.len();
+                self.queue.enqueue(paths);
+                if self.queue.is_shuffled() {
+                    // New entries are shuffled among themselves, after what is already queued
+                    self.shuffle_from(start);
+                }
+                self.queue_changed();
+            }
+            AudioCommand::EnqueueNext(paths) => {
+                self.queue.enqueue_next(paths);
+                self.queue_changed();
+            }
+            AudioCommand::Next => self.advance(),
+            AudioCommand::Previous => self.previous(),
+            AudioCommand::ClearQueue => {
+                self.queue.clear();
+                self.queue_changed();
+            }
+            AudioCommand::MoveInQueue { from, to } => {
+                if self.queue.move_item(from, to) {
+                    self.queue_changed();
+                }
+            }
+            AudioCommand::RemoveFromQueue(index) => {
+                let Some(removed_current) = self.queue.remove(index) else {
+                    return;
+                };
+                if !removed_current {
+                    self.queue_changed();
+                    return;
+                }
+                self.emit_queue();
+                if self.queue.current().is_none() || self.status == PlaybackStatus::Stopped {
+                    self.stop();
+                } else if self.status == PlaybackStatus::Paused {
+                    // Removing a row must not start playback
+                    self.end_current(EndReason::Skipped);
+                    self.load_paused(None);
+                    if self.current.is_none() {
+                        self.stop();
                     }
-                    AudioCommand::Stop => {
-                        if let Some(s) = &sink {
-                            s.stop();
-                            s.clear();
-                            app_handle
-                                .emit("player-status", serde_json::json!({ "status": "stopped" }))
-                                .ok();
+                } else {
+                    self.play_current();
+                }
+            }
+            AudioCommand::GetQueue(reply) => {
+                // The requester may have timed out already
+                reply.send(self.queue.snapshot()).ok();
+            }
+            AudioCommand::SetCrossfade {
+                seconds,
+                gapless_albums,
+            } => {
+                // `max` and `min` also turn NaN into 0
+                self.crossfade =
+                    Duration::from_secs_f32(seconds.max(0.0).min(MAX_CROSSFADE.as_secs_f32()));
+                self.gapless_albums = gapless_albums;
+                // The transition to the preloaded track may change
+                self.drop_preloaded();
+                self.preload_next();
+            }
+            AudioCommand::SetRepeat(mode) => {
+                self.repeat = mode;
+                // Repeat decides which track follows the current one
+                self.queue_changed();
+                self.emit_mode();
+            }
+            AudioCommand::SetShuffle(mode, seed) => {
+                self.rng = ShuffleRng::new(seed.unwrap_or_else(time_seed));
+                self.shuffle = mode;
+                self.queue.unshuffle();
+                if mode != ShuffleMode::Off {
+                    self.shuffle_from(self.queue.current_index().map_or(0, |i| i + 1));
+                }
+                self.queue_changed();
+                // Auto normalization follows the shuffle mode
+                self.apply_normalization();
+                self.emit_mode();
+            }
+            AudioCommand::SetNormalization(normalization) => {
+                self.normalization = normalization;
+                self.apply_normalization();
+            }
+            AudioCommand::SetSpeed(speed) => self.speed.set_speed(speed),
+            AudioCommand::SetPitch(semitones) => self.speed.set_pitch(semitones),
+            AudioCommand::SetTransportFade(ramp) => self.envelope.set_ramp(ramp),
+            AudioCommand::SetSpectrum(config) => {
+                // The running analyzer stops when dropped
+                self.spectrum = None;
+                if let Some(config) = config {
+                    let app_handle = self.app_handle.clone();
+                    self.spectrum = Some(SpectrumAnalyzer::start(
+                        self.spectrum_tap.clone(),
+                        config,
+                        move |spectrum| {
+                            app_handle.emit("player-spectrum", spectrum).ok();
+                        },
+                    ));
+                }
+            }
+            AudioCommand::SetSleepTimer(timer) => {
+                self.sleep = timer;
+                self.sleep_volume = 1.0;
+                self.apply_volume();
+                // Nothing is lined up after the last track before sleep
+                self.drop_preloaded();
+                self.preload_next();
+            }
+            AudioCommand::SetOutputDevice(name) => {
+                self.output_device = name;
+                self.output_device_loaded = true;
+                if self.stream.is_some() {
+                    self.change_device("selected");
+                }
+            }
+            AudioCommand::SetEqualizer(bands) => {
+                self.equalizer = bands;
+                self.apply_equalizer();
+            }
+            AudioCommand::SetEqBand(index, band) => {
+                let Some(slot) = self.equalizer.get_mut(index) else {
+                    self.app_handle
+                        .emit("player-error", format!("No equalizer band {index}"))
+                        .ok();
+                    return;
+                };
+                *slot = band;
+                self.apply_equalizer();
+            }
+            AudioCommand::RefreshEqualizer => {
+                for track in self.current.iter_mut().chain(&mut self.preloaded) {
+                    let (bands, is_override) =
+                        eq_bands_for(&self.app_handle, &self.equalizer, &track.path);
+                    track.eq_override = is_override;
+                    track.eq.set_bands(bands);
+                }
+            }
+            AudioCommand::RestoreSession(session) => self.restore_session(session),
+            AudioCommand::SetArtists(artists) => self.artists.extend(artists),
+            // Answered by the command loop, which exits after it
+            AudioCommand::Shutdown(_) => {}
+            AudioCommand::TrackEnding(id) => self.track_ending(id),
+            AudioCommand::TrackEnded(id) => self.track_ended(id),
+            AudioCommand::StreamLost(id) => {
+                if id == self.stream_id && self.stream.is_some() {
+                    eprintln!("Output device disappeared, reopening on the default device");
+                    self.change_device("lost");
+                }
+            }
+        }
+    }
+
+    /// Periodic update: send current position.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.session_checked) >= SESSION_SAVE_INTERVAL {
+            if let Some(track) = self.current.as_ref() {
+                if self.status == PlaybackStatus::Playing {
+                    self.store_resume_position(track, track.control.position());
+                }
+            }
+            if let Err(e) = self.store_session() {
+                eprintln!("Failed to save the playback session: {e}");
+            }
+        }
+        if let Some(timer) = self.sleep {
+            if timer.expired(now) {
+                self.sleep_finished();
+                return;
+            }
+            let factor = timer.volume(now, self.track_left());
+            if (factor - self.sleep_volume).abs() > f32::EPSILON {
+                self.sleep_volume = factor;
+                self.apply_volume();
+            }
+        }
+
+        self.update_chapter();
+
+        let Some(s) = self.sink() else {
+            return;
+        };
+
+        // Safety net for an end signal that never came: both decks drained while playing
+        if self.status == PlaybackStatus::Playing && self.decks.iter().all(Sink::empty) {
+            if let Some(id) = self.current.as_ref().map(|t| t.id) {
+                self.track_ended(id);
+            }
+            return;
+        }
+
+        if !s.empty() && self.status == PlaybackStatus::Playing {
+            let pos = self.position().as_secs_f64();
+            self.app_handle
+                .emit(
+                    "player-progress",
+                    serde_json::json!({
+                        "position": pos,
+                        "duration": self.current_duration().as_secs_f64(),
+                        "sleep": self.sleep.map(|t| t.countdown(now, self.track_left()))
+                    }),
+                )
+                .ok();
+        }
+    }
+
+    fn sink(&self) -> Option<&Sink> {
+        self.decks.get(self.active)
+    }
+
+    fn idle_sink(&self) -> Option<&Sink> {
+        self.decks.get(1 - self.active)
+    }
+
+    /// Position of the current track in source time, whatever the playback speed.
+    fn position(&self) -> Duration {
+        self.current
+            .as_ref()
+            .map_or(Duration::ZERO, |t| t.control.position())
+    }
+
+    fn current_duration(&self) -> Duration {
+        self.current.as_ref().map_or(Duration::ZERO, |t| t.duration)
+    }
+
+    /// Wall-clock time left in the current track, if its duration is known.
+    fn track_left(&self) -> Option<Duration> {
+        let track = self.current.as_ref().filter(|t| !t.duration.is_zero())?;
+        let left = track.duration.saturating_sub(track.control.position());
+        Some(left.div_f32(self.speed.speed()))
+    }
+
+    fn apply_volume(&self) {
+        for s in &self.decks {
+            s.set_volume(self.volume * self.sleep_volume);
+        }
+    }
+
+    /// Counts a natural track end against the sleep timer; true if playback should stop.
+    fn sleep_track_finished(&mut self) -> bool {
+        self.sleep.as_mut().is_some_and(SleepTimer::track_finished)
+    }
+
+    fn sleep_finished(&mut self) {
+        self.sleep = None;
+        self.stop();
+        self.sleep_volume = 1.0;
+        self.apply_volume();
+        self.app_handle.emit("sleep-timer-finished", ()).ok();
+    }
+
+    fn is_current(&self, id: u64) -> bool {
+        self.current.as_ref().map(|t| t.id) == Some(id)
+    }
+
+    /// Handles the current track starting its natural-end fade-out.
+    fn track_ending(&mut self, id: u64) {
+        if !self.is_current(id) {
+            return;
+        }
+        let (Some(source), Some(next)) = (self.pending.take(), self.preloaded.take()) else {
+            return;
+        };
+
+        self.start_on_idle_deck(source, self.crossfade);
+        self.step_queue(true);
+        self.end_current(EndReason::Finished);
+        self.sleep_track_finished();
+        self.current = Some(next);
+        self.emit_playing();
+        self.emit_queue();
+        self.preload_next();
+    }
+
+    /// Handles the sink running out of the current track.
+    fn track_ended(&mut self, id: u64) {
+        // Ignore tracks that were replaced before their end signal arrived
+        if !self.is_current(id) {
+            return;
+        }
+
+        let Some(next) = self.preloaded.take() else {
+            let ended = self.end_current(EndReason::Finished);
+            if self.sleep_track_finished() {
+                self.sleep_finished();
+                return;
+            }
+            if self.step_queue(true) {
+                self.emit_queue();
+                self.play_current();
+            } else {
+                self.stop();
+                self.emit_queue_finished(
+                    ended.as_ref().map(|t| t.path.as_str()),
+                    EndReason::Finished,
+                );
+            }
+            return;
+        };
+
+        // A crossfade that never started (unknown duration) falls back to a plain cut
+        if let (Some(source), Some(s)) = (self.pending.take(), self.sink()) {
+            s.append(self.playable(source));
+        }
+
+        // The preloaded track is already playing in the sink
+        self.step_queue(true);
+        self.end_current(EndReason::Finished);
+        self.sleep_track_finished();
+        self.current = Some(next);
+        self.emit_playing();
+        self.emit_queue();
+        self.preload_next();
+    }
+
+    /// Skips to the next track; repeat-one does not hold a manual skip back.
+    fn advance(&mut self) {
+        if self.step_queue(false) {
+            self.emit_queue();
+            self.play_current();
+        } else {
+            let ended = self.end_current(EndReason::Skipped);
+            self.stop();
+            if let Some(track) = ended {
+                self.emit_queue_finished(Some(&track.path), EndReason::Skipped);
+            }
+        }
+    }
+
+    /// Moves the queue to the track that follows the current one under the repeat mode.
+    ///
+    /// `natural` is true when the current track played to its end.
+    fn step_queue(&mut self, natural: bool) -> bool {
+        match self.repeat {
+            RepeatMode::One if natural => self.queue.current().is_some(),
+            RepeatMode::One | RepeatMode::All => self.queue.advance_wrapping().is_some(),
+            RepeatMode::Off => self.queue.advance().is_some(),
+        }
+    }
+
+    /// The track a natural end of the current one leads to.
+    fn upcoming(&self) -> Option<&str> {
+        match self.repeat {
+            RepeatMode::One => self.queue.current(),
+            RepeatMode::All => self.queue.peek_next_wrapping(),
+            RepeatMode::Off => self.queue.peek_next(),
+        }
+    }
+
+    /// Shuffles the queue from `start` on using the current shuffle mode.
+    fn shuffle_from(&mut self, start: usize) {
+        if self.shuffle == ShuffleMode::Smart {
+            let artists = &self.artists;
+            let mut artist_of = |path: &str| artists.get(path).cloned();
+            self.queue
+                .shuffle_from(start, &mut self.rng, Some(&mut artist_of));
+        } else {
+            self.queue.shuffle_from(start, &mut self.rng, None);
+        }
+    }
+
+    /// Makes the current track repeat the region between `start` and `end` seconds.
+    fn set_loop(&mut self, start: f32, end: f32) {
+        let Some(track) = &self.current else {
+            self.app_handle
+                .emit("player-error", "Nothing is playing to loop")
+                .ok();
+            return;
+        };
+        let finite = start.is_finite() && end.is_finite();
+        // Seconds beyond what a duration holds are past the end of any track
+        let seconds =
+            |secs: f32| Duration::try_from_secs_f32(secs.max(0.0)).unwrap_or(Duration::MAX);
+        let start = seconds(start);
+        let mut end = seconds(end);
+        if !track.duration.is_zero() {
+            end = end.min(track.duration);
+        }
+        if !finite || start >= end {
+            self.app_handle
+                .emit("player-error", "Loop start must be before its end")
+                .ok();
+            return;
+        }
+        track.control.set_loop(Some((start, end)));
+        self.loop_region = Some((start, end));
+        self.emit_mode();
+    }
+
+    fn session(&self) -> Session {
+        Session {
+            queue: self.queue.save(),
+            position: self.position().as_secs_f64(),
+            volume: self.volume,
+            repeat: self.repeat,
+            shuffle: self.shuffle,
+        }
+    }
+
+    /// Writes the session to the database if it changed since the last write.
+    fn store_session(&mut self) -> Result<(), String> {
+        self.session_checked = Instant::now();
+        let session = self.session();
+        if self.saved_session.as_ref() == Some(&session) {
+            return Ok(());
+        }
+        let Some(state) = self.app_handle.try_state::<AppState>() else {
+            return Ok(());
+        };
+        let conn = state.db.lock().map_err(|e| e.to_string())?;
+        save_session(&conn, &session)?;
+        self.saved_session = Some(session);
+        Ok(())
+    }
+
+    /// Replaces the queue and modes with a saved session and loads its current track
+    /// paused at the saved position.
+    fn restore_session(&mut self, session: Session) {
+        let position = session.position();
+        let Some(queue) = PlayQueue::restore(session.queue) else {
+            self.app_handle
+                .emit("player-error", "The saved session is damaged")
+                .ok();
+            return;
+        };
+        if self.current.is_some() {
+            self.stop();
+        }
+        self.queue = queue;
+        self.volume = session.volume.max(0.0);
+        self.apply_volume();
+        self.repeat = session.repeat;
+        self.shuffle = session.shuffle;
+        self.emit_queue();
+
+        if self.queue.current().is_none() || !self.ensure_output() {
+            self.emit_mode();
+            return;
+        }
+        self.load_paused(Some(position));
+    }
+
+    /// Loads the current queue entry as if playback had been paused at `position`, or
+    /// wherever the track opens without one.
+    fn load_paused(&mut self, position: Option<Duration>) {
+        let Some(path) = self.queue.current().map(str::to_string) else {
+            return;
+        };
+        let (source, track) = match self.open_track(&path) {
+            Ok(opened) => opened,
+            Err(e) => {
+                eprintln!("{e}");
+                self.app_handle.emit("player-error", e).ok();
+                self.emit_mode();
+                return;
+            }
+        };
+
+        self.drop_preloaded();
+        // The new source starts held, so nothing is heard before the seek lands
+        self.envelope.set_transport(Transport::Paused);
+        for s in &self.decks {
+            s.clear();
+        }
+        let sink_ref = &self.decks[self.active];
+        sink_ref.append(self.playable(source));
+        sink_ref.play();
+        let position = match position {
+            Some(position) => {
+                let position = if track.duration.is_zero() {
+                    position
+                } else {
+                    position.min(track.duration)
+                };
+                self.envelope.seek(track.id, position);
+                position
+            }
+            None => track.control.position(),
+        };
+
+        self.status = PlaybackStatus::Paused;
+        self.emit_status(serde_json::json!({
+            "status": "paused",
+            "path": track.path,
+            "duration": track.duration.as_secs_f64(),
+            "position": position.as_secs_f64(),
+            "chapters": track.chapters
+        }));
+        self.current = Some(track);
+        // Auto normalization follows the shuffle mode
+        self.apply_normalization();
+        self.preload_next();
+    }
+
+    fn seek(&self, position: Duration) {
+        if let Some(track) = &self.current {
+            self.envelope.seek(track.id, position);
+        }
+        // Drop the tail of a crossfade that was still running
+        if let Some(s) = self.idle_sink() {
+            s.clear();
+        }
+    }
+
+    /// Jumps to the next chapter; past the last one, or without chapters, skips the track.
+    fn next_chapter(&mut self) {
+        let position = self.position().as_secs_f64();
+        let next = self.current.as_ref().and_then(|track| {
+            track
+                .chapters
+                .iter()
+                .find(|c| c.start > position + CHAPTER_TOLERANCE)
+                .map(|c| c.start)
+        });
+        match next {
+            Some(start) => self.seek(Duration::from_secs_f64(start)),
+            None => self.advance(),
+        }
+    }
+
+    /// Restarts the current chapter, or goes to the previous one when close to its start.
+    /// Before the first chapter, or without chapters, this is `previous`.
+    fn previous_chapter(&mut self) {
+        let position = self.position().as_secs_f64();
+        let target = self.current.as_ref().and_then(|track| {
+            let index = chapter_at(&track.chapters, position + CHAPTER_TOLERANCE)?;
+            let start = track.chapters[index].start;
+            if position - start > RESTART_THRESHOLD.as_secs_f64() {
+                Some(start)
+            } else {
+                index.checked_sub(1).map(|i| track.chapters[i].start)
+            }
+        });
+        match target {
+            Some(start) => self.seek(Duration::from_secs_f64(start)),
+            None => self.previous(),
+        }
+    }
+
+    /// Emits `chapter-changed` when playback has moved into another chapter.
+    fn update_chapter(&mut self) {
+        let Some(track) = self.current.as_ref().filter(|t| !t.chapters.is_empty()) else {
+            return;
+        };
+        let index = chapter_at(&track.chapters, track.control.position().as_secs_f64());
+        if self.chapter == Some((track.id, index)) {
+            return;
+        }
+        self.chapter = Some((track.id, index));
+        self.app_handle
+            .emit(
+                "chapter-changed",
+                serde_json::json!({
+                    "path": track.path,
+                    "index": index,
+                    "chapter": index.map(|i| &track.chapters[i])
+                }),
+            )
+            .ok();
+    }
+
+    fn previous(&mut self) {
+        let restart = self
+            .sink()
+            .is_some_and(|s| !s.empty() && self.position() > RESTART_THRESHOLD);
+
+        if restart || self.queue.previous().is_none() {
+            if let Some(track) = &self.current {
+                self.envelope.seek(track.id, Duration::ZERO);
+            }
+            return;
+        }
+
+        self.emit_queue();
+        self.play_current();
+    }
+
+    fn stop(&mut self) {
+        self.status = PlaybackStatus::Stopped;
+        self.end_current(EndReason::Stopped);
+        // Whatever is still playing ramps down and ends; nothing may follow it
+        self.drop_preloaded();
+        self.envelope.set_transport(Transport::Stopped);
+        if !self.decks.is_empty() {
+            self.emit_status(serde_json::json!({ "status": "stopped" }));
+        }
+    }
+
+    /// Initializes stream and sinks on first use.
+    fn ensure_output(&mut self) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        match self.open_stream() {
+            Ok(s) => {
+                // The decks are mixed at the output's own format, so the tap costs no
+                // conversion
+                let (decks_mixer, decks_mix) = mixer::mixer(s.channels(), s.sample_rate());
+                self.decks = vec![
+                    Sink::connect_new(&decks_mixer),
+                    Sink::connect_new(&decks_mixer),
+                ];
+                s.mixer()
+                    .add(Tapped::new(decks_mix, self.spectrum_tap.clone()));
+                self.active = 0;
+                self.stream = Some(s);
+                self.apply_volume();
+                true
+            }
+            Err(e) => {
+                eprintln!("Audio thread failed to open output stream: {e}");
+                self.app_handle
+                    .emit("player-error", format!("Failed to initialize audio: {e}"))
+                    .ok();
+                false
+            }
+        }
+    }
+
+    /// Opens the chosen output device, or the default one if it is gone.
+    fn open_stream(&mut self) -> Result<Box<dyn Output>, String> {
+        if !self.output_device_loaded {
+            self.output_device_loaded = true;
+            self.output_device = self.app_handle.try_state::<AppState>().and_then(|state| {
+                let conn = state.db.lock().ok()?;
+                operations::get_setting(&conn, OUTPUT_DEVICE_SETTING)
+                    .ok()
+                    .flatten()
+            });
+        }
+
+        // Reports the disappearance of this stream, and only this one, to the loop
+        self.stream_id += 1;
+        let events = self.events.clone();
+        let stream_id = self.stream_id;
+        let on_lost: LostCallback = Arc::new(move || {
+            events.send(AudioCommand::StreamLost(stream_id));
+        });
+
+        if let Some(name) = &self.output_device {
+            match self.backend.open(Some(name), on_lost.clone()) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    eprintln!("Failed to open output device {name}: {e}");
+                    self.app_handle
+                        .emit(
+                            "player-error",
+                            format!(
+                                "Output device {name} is unavailable, using the default device"
+                            ),
+                        )
+                        .ok();
+                }
+            }
+        }
+        self.backend.open(None, on_lost)
+    }
+
+    fn device_name(&self) -> Option<String> {
+        self.stream.as_ref().and_then(|s| s.device_name())
+    }
+
+    /// Reopens the output and tells the UI which device is playing now.
+    fn change_device(&mut self, reason: &str) {
+        let previous = self.device_name();
+        self.reopen_output();
+        if self.stream.is_some() {
+            self.app_handle
+                .emit(
+                    "device-changed",
+                    serde_json::json!({
+                        "device": self.device_name(),
+                        "previous": previous,
+                        "reason": reason
+                    }),
+                )
+                .ok();
+        }
+    }
+
+    /// Moves playback to a freshly opened stream, continuing where the current track was.
+    ///
+    /// Sources cannot be taken back out of a sink, so the current track is opened again
+    /// and seeked to its position.
+    fn reopen_output(&mut self) {
+        let resume = self
+            .current
+            .as_ref()
+            .map(|t| (t.path.clone(), t.control.position()));
+        for s in &self.decks {
+            s.stop();
+        }
+        self.decks.clear();
+        self.stream = None;
+        self.current = None;
+        self.preloaded = None;
+        self.pending = None;
+
+        if !self.ensure_output() {
+            self.status = PlaybackStatus::Stopped;
+            self.emit_status(serde_json::json!({ "status": "stopped" }));
+            return;
+        }
+        let Some((path, position)) = resume else {
+            return;
+        };
+        match self.open_track(&path) {
+            Ok((mut source, track)) => {
+                if let Err(e) = source.try_seek(position) {
+                    eprintln!("Seek failed: {e}");
+                }
+                track.control.set_loop(self.loop_region);
+                // Starts held if playback is paused
+                self.decks[self.active].append(self.playable(source));
+                self.current = Some(track);
+                self.preload_next();
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                self.app_handle.emit("player-error", e).ok();
+                self.emit_track_ended(&path, position, EndReason::Error);
+                self.stop();
+            }
+        }
+    }
+
+    /// Wraps an opened track in the stages that sit between it and the sink.
+    fn playable(&self, source: TrackSource<TrackChain>) -> Playable {
+        let id = source.id();
+        Envelope::new(
+            TimeStretch::new(source, self.speed.clone()),
+            id,
+            self.envelope.clone(),
+        )
+    }
+
+    /// Opens `path` and wraps it so the sink reports when it runs out.
+    fn open_track(&mut self, path: &str) -> Result<(TrackSource<TrackChain>, LoadedTrack), String> {
+        let (bands, eq_override) = eq_bands_for(&self.app_handle, &self.equalizer, path);
+        let range = cue_range_for(&self.app_handle, path)?;
+        let source = Equalizer::new(open_segment(path, range.as_ref())?, &bands);
+        let eq = source.control();
+        self.next_track_id += 1;
+        let mut source = TrackSource::new(source, self.next_track_id, self.events.clone());
+        // Store total duration if available
+        let duration = source.total_duration().unwrap_or_default();
+        let chapters = chapters_for(&self.app_handle, path, duration);
+        let (remember, resume_at) = resume_for(&self.app_handle, path, duration);
+        if let Some(position) = resume_at.map(|p| resume_point(p, &chapters)) {
+            if let Err(e) = source.try_seek(position) {
+                eprintln!("Failed to resume {path}: {e}");
+            }
+        }
+        let track = LoadedTrack {
+            id: self.next_track_id,
+            path: path.to_string(),
+            duration,
+            control: source.control(),
+            replay_gain: self.replay_gain(path),
+            eq,
+            eq_override,
+            remember,
+            chapters,
+        };
+        track.control.set_gain(self.gain_for(&track));
+        Ok((source, track))
+    }
+
+    /// `ReplayGain` values from the library, or from the file's tags for tracks outside it.
+    fn replay_gain(&self, path: &str) -> ReplayGain {
+        let stored = self.app_handle.try_state::<AppState>().and_then(|state| {
+            let conn = state.db.lock().ok()?;
+            operations::get_replay_gain(&conn, path).ok().flatten()
+        });
+        stored.unwrap_or_else(|| {
+            parse_file(path, None)
+                .map(|m| m.replay_gain)
+                .unwrap_or_default()
+        })
+    }
+
+    fn gain_for(&self, track: &LoadedTrack) -> f32 {
+        self.normalization
+            .factor(&track.replay_gain, self.shuffle != ShuffleMode::Off)
+    }
+
+    /// Re-applies the normalization settings to the loaded tracks.
+    fn apply_normalization(&self) {
+        for track in self.current.iter().chain(&self.preloaded) {
+            track.control.set_gain(self.gain_for(track));
+        }
+    }
+
+    /// Sends the global bands to the loaded tracks that have no preset override.
+    fn apply_equalizer(&self) {
+        for track in self.current.iter().chain(&self.preloaded) {
+            if !track.eq_override {
+                track.eq.set_bands(self.equalizer.clone());
+            }
+        }
+        self.app_handle
+            .emit("equalizer-changed", &self.equalizer)
+            .ok();
+    }
+
+    /// Loads the current queue entry, skipping entries that fail to open.
+    ///
+    /// While something is playing and crossfade is enabled the switch uses a short fade,
+    /// otherwise the sinks are cleared and the new track starts immediately.
+    fn play_current(&mut self) {
+        if !self.ensure_output() {
+            return;
+        }
+
+        let mut failed = None;
+        while let Some(path) = self.queue.current().map(str::to_string) {
+            match self.open_track(&path) {
+                Ok((source, track)) => {
+                    self.drop_preloaded();
+                    let outgoing = self.end_current(EndReason::Skipped);
+                    // Also picks up from a pause or a stop that is still ramping down
+                    self.envelope.set_transport(Transport::Playing);
+
+                    match outgoing {
+                        Some(old)
+                            if !self.crossfade.is_zero()
+                                && self.status == PlaybackStatus::Playing =>
+                        {
+                            let fade = SKIP_CROSSFADE.min(self.crossfade);
+                            old.control.fade(FadeRequest {
+                                direction: FadeDirection::Out,
+                                duration: fade,
+                                end_when_silent: true,
+                            });
+                            self.start_on_idle_deck(source, fade);
                         }
-                    }
-                    AudioCommand::Seek(secs) => {
-                        if let Some(s) = &sink {
-                            if let Err(e) = s.try_seek(Duration::from_secs_f32(secs)) {
-                                eprintln!("Seek failed: {e}");
+                        _ => {
+                            for s in &self.decks {
+                                s.clear();
                             }
+                            // Clearing pauses a sink
+                            let sink_ref = &self.decks[self.active];
+                            sink_ref.append(self.playable(source));
+                            sink_ref.play();
                         }
                     }
-                    AudioCommand::SetVolume(vol) => {
-                        if let Some(s) = &sink {
-                            s.set_volume(vol);
-                        }
+
+                    self.status = PlaybackStatus::Playing;
+                    self.current = Some(track);
+
+                    // Notify frontend
+                    self.emit_playing();
+                    self.preload_next();
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    self.app_handle.emit("player-error", e).ok();
+                    self.emit_track_ended(&path, Duration::ZERO, EndReason::Error);
+                    failed = Some(path);
+                    if !self.step_queue(false) {
+                        break;
                     }
-                },
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    // Periodic update: send current position
-                    if let Some(s) = &sink {
-                        if !s.empty() && !s.is_paused() {
-                            let pos = s.get_pos().as_secs_f64();
-                            app_handle
-                                .emit(
-                                    "player-progress",
-                                    serde_json::json!({
-                                        "position": pos,
-                                        "duration": current_duration.as_secs_f64()
-                                    }),
-                                )
-                                .ok();
-                        }
+                    self.emit_queue();
+                }
+            }
+        }
+
+        self.stop();
+        if let Some(path) = failed {
+            self.emit_queue_finished(Some(&path), EndReason::Error);
+        }
+    }
+
+    /// Fades `source` in on the deck that is not playing and makes that deck active.
+    fn start_on_idle_deck(&mut self, mut source: TrackSource<TrackChain>, fade: Duration) {
+        let idle = 1 - self.active;
+        let Some(s) = self.decks.get(idle) else {
+            return;
+        };
+        // Anything left on the idle deck is the tail of an earlier fade
+        s.clear();
+        source.fade_in_from_silence(fade);
+        s.append(self.playable(source));
+        s.play();
+        self.active = idle;
+    }
+
+    /// Opens the next queue entry ahead of time so the transition needs no disk access.
+    fn preload_next(&mut self) {
+        let Some(current) = &self.current else {
+            return;
+        };
+        if self.sleep.is_some_and(|t| t.on_last_track()) {
+            return;
+        }
+        let Some(path) = self.upcoming().map(str::to_string) else {
+            return;
+        };
+        let fade = self.crossfade_between(&current.path, &path);
+        let control = current.control.clone();
+
+        match self.open_track(&path) {
+            Ok((source, track)) => {
+                control.set_crossfade(fade);
+                if fade.is_zero() {
+                    if let Some(s) = self.sink() {
+                        s.append(self.playable(source));
                     }
+                } else {
+                    self.pending = Some(source);
                 }
-                Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    // Channel closed, exit thread
+                self.preloaded = Some(track);
+            }
+            // Reported by `play_current` if the entry is still next when the current track ends
+            Err(e) => eprintln!("Failed to preload {path}: {e}"),
+        }
+    }
+
+    /// Forgets the preloaded track, pulling it back out of the sink if needed.
+    fn drop_preloaded(&mut self) {
+        if let Some(stale) = self.preloaded.take() {
+            stale.control.cancel();
+        }
+        self.pending = None;
+        if let Some(current) = &self.current {
+            current.control.set_crossfade(Duration::ZERO);
+        }
+    }
+
+    /// Album and album artist from the library; tracks outside it belong to no album.
+    fn album(&self, path: &str) -> Option<(String, Option<String>)> {
+        let state = self.app_handle.try_state::<AppState>()?;
+        let conn = state.db.lock().ok()?;
+        operations::get_album(&conn, path).ok().flatten()
+    }
+
+    /// Crossfade length for the transition between two tracks.
+    fn crossfade_between(&self, from: &str, to: &str) -> Duration {
+        if self.crossfade.is_zero() {
+            return Duration::ZERO;
+        }
+        if self.gapless_albums {
+            // Tracks cut from one file by a CUE sheet are one continuous recording
+            if let (Some((from_file, _)), Some((to_file, _))) =
+                (split_virtual_path(from), split_virtual_path(to))
+            {
+                if from_file == to_file {
+                    return Duration::ZERO;
+                }
+            }
+            if let Some(from_album) = self.album(from) {
+                if self.album(to).as_ref() == Some(&from_album) {
+                    return Duration::ZERO;
+                }
+            }
+        }
+        self.crossfade
+    }
+
+    /// Keeps the preloaded track in line with the queue after it was edited.
+    fn queue_changed(&mut self) {
+        self.emit_queue();
+        if self.current.is_none() {
+            return;
+        }
+
+        let next = self.upcoming();
+        if self.preloaded.as_ref().map(|t| t.path.as_str()) == next {
+            return;
+        }
+        self.drop_preloaded();
+        self.preload_next();
+    }
+
+    /// Takes the current track out of the player and reports that it ended.
+    fn end_current(&mut self, reason: EndReason) -> Option<LoadedTrack> {
+        let track = self.current.take()?;
+        self.loop_region = None;
+        // A track that played out is reported at its end even if the fade cut it short
+        let position = if reason == EndReason::Finished && !track.duration.is_zero() {
+            track.duration
+        } else {
+            track.control.position()
+        };
+        if reason != EndReason::Error {
+            self.store_resume_position(&track, position);
+        }
+        self.emit_track_ended(&track.path, position, reason);
+        Some(track)
+    }
+
+    /// Remembers where a long-form track was left; other tracks are not stored.
+    fn store_resume_position(&self, track: &LoadedTrack, position: Duration) {
+        if !track.remember {
+            return;
+        }
+        let Some(state) = self.app_handle.try_state::<AppState>() else {
+            return;
+        };
+        let Ok(conn) = state.db.lock() else {
+            return;
+        };
+        if let Err(e) = store_position(&conn, &track.path, position, track.duration) {
+            eprintln!("Failed to save the position of {}: {e}", track.path);
+        }
+    }
+
+    fn emit_track_ended(&self, path: &str, position: Duration, reason: EndReason) {
+        self.app_handle
+            .emit(
+                "track-ended",
+                serde_json::json!({
+                    "path": path,
+                    "reason": reason,
+                    "position": position.as_secs_f64()
+                }),
+            )
+            .ok();
+    }
+
+    /// Reports that playback ran past the end of the queue; `path` is the last track.
+    fn emit_queue_finished(&self, path: Option<&str>, reason: EndReason) {
+        self.app_handle
+            .emit(
+                "queue-finished",
+                serde_json::json!({ "path": path, "reason": reason }),
+            )
+            .ok();
+    }
+
+    fn emit_playing(&self) {
+        if let Some(track) = &self.current {
+            self.emit_status(serde_json::json!({
+                "status": "playing",
+                "path": track.path,
+                "duration": track.duration.as_secs_f64(),
+                "chapters": track.chapters
+            }));
+        }
+    }
+
+    /// Re-announces the current status after repeat, shuffle or the loop changed.
+    fn emit_mode(&self) {
+        match self.status {
+            PlaybackStatus::Playing => self.emit_playing(),
+            PlaybackStatus::Paused => self.emit_status(serde_json::json!({ "status": "paused" })),
+            PlaybackStatus::Stopped => self.emit_status(serde_json::json!({ "status": "stopped" })),
+        }
+    }
+
+    /// Emits `player-status`, adding the repeat and shuffle modes and the loop to `payload`.
+    fn emit_status(&self, mut payload: serde_json::Value) {
+        payload["repeat"] = serde_json::json!(self.repeat);
+        payload["shuffle"] = serde_json::json!(self.shuffle);
+        payload["loop"] = serde_json::json!(self.loop_region.map(|(start, end)| {
+            serde_json::json!({ "start": start.as_secs_f64(), "end": end.as_secs_f64() })
+        }));
+        self.app_handle.emit("player-status", payload).ok();
+    }
+
+    fn emit_queue(&self) {
+        self.app_handle
+            .emit("queue-changed", self.queue.snapshot())
+            .ok();
+    }
+}
+
+/// Bands for the track at `path`: its preset override if it has one, else `global`.
+///
+/// The flag tells whether an override applies.
+fn eq_bands_for<R: Runtime>(
+    app_handle: &AppHandle<R>,
+    global: &[EqBand],
+    path: &str,
+) -> (Vec<EqBand>, bool) {
+    let overridden = app_handle.try_state::<AppState>().and_then(|state| {
+        let conn = state.db.lock().ok()?;
+        let preset = operations::get_eq_override(&conn, path).ok().flatten()?;
+        find_preset(&conn, &preset).ok().flatten()
+    });
+    match overridden {
+        Some(bands) => (bands, true),
+        None => (global.to_vec(), false),
+    }
+}
+
+/// Chapters from the library, or from the file itself for tracks outside it.
+fn chapters_for<R: Runtime>(
+    app_handle: &AppHandle<R>,
+    path: &str,
+    duration: Duration,
+) -> Vec<Chapter> {
+    let stored = app_handle.try_state::<AppState>().and_then(|state| {
+        let conn = state.db.lock().ok()?;
+        operations::get_chapters(&conn, path).ok().flatten()
+    });
+    stored.unwrap_or_else(|| read_chapters(Path::new(path), duration).unwrap_or_default())
+}
+
+/// The part of its file that the track at `path` covers: from the library, or else from
+/// the CUE sheet of its parent file for virtual tracks outside it.
+fn cue_range_for<R: Runtime>(
+    app_handle: &AppHandle<R>,
+    path: &str,
+) -> Result<Option<CueRange>, String> {
+    let stored = app_handle.try_state::<AppState>().and_then(|state| {
+        let conn = state.db.lock().ok()?;
+        operations::get_cue_range(&conn, path).ok().flatten()
+    });
+    match stored {
+        Some(range) => Ok(range),
+        None => find_range(path),
+    }
+}
+
+/// Whether the track at `path` remembers its position, and where to resume it from.
+fn resume_for<R: Runtime>(
+    app_handle: &AppHandle<R>,
+    path: &str,
+    duration: Duration,
+) -> (bool, Option<Duration>) {
+    let Some(state) = app_handle.try_state::<AppState>() else {
+        return (false, None);
+    };
+    let Ok(conn) = state.db.lock() else {
+        return (false, None);
+    };
+    if !remembers_position(&conn, path, duration).unwrap_or(false) {
+        return (false, None);
+    }
+    let resume_at = operations::get_resume_position(&conn, path)
+        .ok()
+        .flatten()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+    (true, resume_at)
+}
+
+/// Seed for shuffles that were not given one.
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_nanos()).unwrap_or(u64::MAX))
+}
+
+/// Initializes the audio thread and returns the state to be managed by Tauri.
+///
+/// The output is opened through `backend` on first playback: `RodioBackend` for the sound
+/// card, or a `NullBackend`/`WavBackend` to run without one.
+#[must_use]
+pub fn init_audio_thread<R: Runtime>(
+    app_handle: AppHandle<R>,
+    backend: impl OutputBackend,
+) -> AudioPlayerState {
+    let (tx, rx) = mpsc::channel();
+    let state = AudioPlayerState::new(tx);
+    let events = state.events();
+
+    thread::spawn(move || {
+        let mut player = Player::new(app_handle, events, Box::new(backend));
+
+        loop {
+            // Wait for commands with a timeout to allow for periodic status updates
+            match rx.recv_timeout(Duration::from_millis(200)) {
+                Ok(AudioCommand::Shutdown(reply)) => {
+                    reply.send(player.store_session()).ok();
                     break;
                 }
+                Ok(cmd) => player.handle(cmd),
+                Err(mpsc::RecvTimeoutError::Timeout) => player.tick(),
+                // The player state was dropped without a shutdown, exit thread
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
             }
         }
     });
 
-    AudioPlayerState { tx: Mutex::new(tx) }
+    state
 }
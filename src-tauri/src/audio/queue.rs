@@ -0,0 +1,629 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Snapshot of the play queue sent to the frontend.
+#[derive(Debug, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct QueueState {
+    pub tracks: Vec<String>,
+    #[serde(rename = "currentIndex")]
+    pub current_index: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    Random,
+    /// Random, but avoids the same artist twice in a row where possible.
+    Smart,
+}
+
+/// Everything needed to rebuild a `PlayQueue`, including the unshuffled order.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SavedQueue {
+    pub tracks: Vec<String>,
+    /// Position of each track in the unshuffled order.
+    pub order: Vec<u64>,
+    pub current_index: Option<usize>,
+    pub shuffled: bool,
+}
+
+/// Small deterministic PRNG (`SplitMix64`) so a shuffle can be replayed from its seed.
+#[derive(Debug, Clone)]
+pub struct ShuffleRng(u64);
+
+impl ShuffleRng {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform index in `0..n`. `n` must be non-zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        // Modulo bias is irrelevant for queue sizes
+        usize::try_from(self.next_u64() % n as u64).unwrap_or(0)
+    }
+}
+
+/// Returns the artist of the track at a path, used by smart shuffle.
+pub type ArtistLookup<'a> = dyn FnMut(&str) -> Option<String> + 'a;
+
+#[derive(Debug, Clone)]
+struct QueueEntry {
+    path: String,
+    // Position in the unshuffled order; ties keep their relative order
+    order: u64,
+}
+
+/// Ordered list of track paths owned by the audio thread.
+///
+/// `current` points at the track that is loaded in the sink (playing or paused).
+/// Entries before it are history, entries after it are upcoming. Shuffling reorders
+/// the upcoming entries in place and `unshuffle` puts everything back in the order
+/// it was added.
+#[derive(Debug, Default)]
+pub struct PlayQueue {
+    entries: Vec<QueueEntry>,
+    current: Option<usize>,
+    shuffled: bool,
+    next_order: u64,
+}
+
+impl PlayQueue {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn current(&self) -> Option<&str> {
+        self.current.map(|i| self.entries[i].path.as_str())
+    }
+
+    #[must_use]
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    #[must_use]
+    pub fn tracks(&self) -> Vec<&str> {
+        self.entries.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[must_use]
+    pub fn is_shuffled(&self) -> bool {
+        self.shuffled
+    }
+
+    fn new_entry(&mut self, path: String) -> QueueEntry {
+        let order = self.next_order;
+        self.next_order += 1;
+        QueueEntry { path, order }
+    }
+
+    /// Entry inserted right after the current one sorts right after it when unshuffled.
+    fn next_to_current(&mut self, path: String) -> QueueEntry {
+        match self.current {
+            Some(i) if self.shuffled => QueueEntry {
+                path,
+                order: self.entries[i].order,
+            },
+            _ => self.new_entry(path),
+        }
+    }
+
+    /// Keeps `order` equal to the position while the queue is not shuffled.
+    fn renumber(&mut self) {
+        if self.shuffled {
+            return;
+        }
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            entry.order = i as u64;
+        }
+        self.next_order = self.entries.len() as u64;
+    }
+
+    /// Inserts `path` right after the current track and makes it current.
+    pub fn play_now(&mut self, path: String) {
+        let index = self.current.map_or(self.entries.len(), |i| i + 1);
+        let entry = self.next_to_current(path);
+        self.entries.insert(index, entry);
+        self.current = Some(index);
+        self.renumber();
+    }
+
+    /// Appends tracks to the end of the queue.
+    pub fn enqueue(&mut self, paths: Vec<String>) {
+        for path in paths {
+            let entry = self.new_entry(path);
+            self.entries.push(entry);
+        }
+    }
+
+    /// Inserts tracks right after the current one, keeping their order.
+    pub fn enqueue_next(&mut self, paths: Vec<String>) {
+        let index = self.current.map_or(self.entries.len(), |i| i + 1);
+        let new: Vec<QueueEntry> = paths.into_iter().map(|p| self.next_to_current(p)).collect();
+        self.entries.splice(index..index, new);
+        self.renumber();
+    }
+
+    /// The track that `advance` would move to.
+    #[must_use]
+    pub fn peek_next(&self) -> Option<&str> {
+        let next = self.current.map_or(0, |i| i + 1);
+        self.entries.get(next).map(|e| e.path.as_str())
+    }
+
+    /// Like `peek_next`, but wraps around to the first track at the end.
+    #[must_use]
+    pub fn peek_next_wrapping(&self) -> Option<&str> {
+        self.peek_next()
+            .or_else(|| self.entries.first().map(|e| e.path.as_str()))
+    }
+
+    /// Moves to the next track, or to the first one if nothing is current.
+    ///
+    /// Returns `None` (and leaves the queue untouched) at the end of the queue.
+    pub fn advance(&mut self) -> Option<&str> {
+        let next = self.current.map_or(0, |i| i + 1);
+        if next >= self.entries.len() {
+            return None;
+        }
+        self.current = Some(next);
+        self.current()
+    }
+
+    /// Like `advance`, but wraps around to the first track at the end.
+    pub fn advance_wrapping(&mut self) -> Option<&str> {
+        if self.peek_next().is_none() && !self.entries.is_empty() {
+            self.current = Some(0);
+            return self.current();
+        }
+        self.advance()
+    }
+
+    /// Moves to the previous track. Returns `None` if already at the start.
+    pub fn previous(&mut self) -> Option<&str> {
+        let prev = self.current?.checked_sub(1)?;
+        self.current = Some(prev);
+        self.current()
+    }
+
+    /// Drops every track except the current one.
+    pub fn clear(&mut self) {
+        if let Some(i) = self.current {
+            let current = self.entries.swap_remove(i);
+            self.entries = vec![current];
+            self.current = Some(0);
+        } else {
+            self.entries.clear();
+        }
+        self.renumber();
+    }
+
+    /// Moves the track at `from` to position `to`, keeping `current` on the same track.
+    ///
+    /// Returns `false` if either index is out of range.
+    pub fn move_item(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.entries.len() || to >= self.entries.len() {
+            return false;
+        }
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+
+        if let Some(cur) = self.current {
+            self.current = Some(if cur == from {
+                to
+            } else if from < cur && to >= cur {
+                cur - 1
+            } else if from > cur && to <= cur {
+                cur + 1
+            } else {
+                cur
+            });
+        }
+        self.renumber();
+        true
+    }
+
+    /// Removes the track at `index`.
+    ///
+    /// Returns `None` if the index is out of range, otherwise whether the removed
+    /// track was the current one. In that case the track that took its place (if any)
+    /// becomes current.
+    pub fn remove(&mut self, index: usize) -> Option<bool> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        self.entries.remove(index);
+        self.renumber();
+
+        let Some(cur) = self.current else {
+            return Some(false);
+        };
+        if index < cur {
+            self.current = Some(cur - 1);
+            Some(false)
+        } else if index == cur {
+            self.current = (cur < self.entries.len()).then_some(cur);
+            Some(true)
+        } else {
+            Some(false)
+        }
+    }
+
+    /// Shuffles every upcoming entry (or the whole queue if nothing is current).
+    ///
+    /// With `artist_of`, consecutive tracks by the same artist are avoided where the
+    /// queue allows it.
+    pub fn shuffle(&mut self, rng: &mut ShuffleRng, artist_of: Option<&mut ArtistLookup<'_>>) {
+        let start = self.current.map_or(0, |i| i + 1);
+        self.shuffle_from(start, rng, artist_of);
+    }
+
+    /// Shuffles the entries from `start` to the end, leaving earlier ones in place.
+    pub fn shuffle_from(
+        &mut self,
+        start: usize,
+        rng: &mut ShuffleRng,
+        artist_of: Option<&mut ArtistLookup<'_>>,
+    ) {
+        self.shuffled = true;
+        if start >= self.entries.len() {
+            return;
+        }
+        let mut tail = self.entries.split_off(start);
+        for i in (1..tail.len()).rev() {
+            let j = rng.below(i + 1);
+            tail.swap(i, j);
+        }
+
+        if let Some(artist_of) = artist_of {
+            let previous = start
+                .checked_sub(1)
+                .and_then(|i| artist_of(&self.entries[i].path));
+            tail = spread_artists(tail, previous, artist_of);
+        }
+        self.entries.extend(tail);
+    }
+
+    /// Restores the order the entries were added in, keeping the current track current.
+    pub fn unshuffle(&mut self) {
+        if !self.shuffled {
+            return;
+        }
+        let current_order = self.current.map(|i| (self.entries[i].order, i));
+        let mut indexed: Vec<(usize, QueueEntry)> = self.entries.drain(..).enumerate().collect();
+        indexed.sort_by_key(|(_, e)| e.order);
+        if let Some((_, old_index)) = current_order {
+            self.current = indexed.iter().position(|(i, _)| *i == old_index);
+        }
+        self.entries = indexed.into_iter().map(|(_, e)| e).collect();
+        self.shuffled = false;
+        self.renumber();
+    }
+
+    #[must_use]
+    pub fn save(&self) -> SavedQueue {
+        SavedQueue {
+            tracks: self.entries.iter().map(|e| e.path.clone()).collect(),
+            order: self.entries.iter().map(|e| e.order).collect(),
+            current_index: self.current,
+            shuffled: self.shuffled,
+        }
+    }
+
+    /// Rebuilds a queue from `save`; `None` if the saved state does not hold together.
+    #[must_use]
+    pub fn restore(saved: SavedQueue) -> Option<Self> {
+        if saved.order.len() != saved.tracks.len()
+            || saved.current_index.is_some_and(|i| i >= saved.tracks.len())
+        {
+            return None;
+        }
+        let next_order = saved.order.iter().max().map_or(0, |o| o + 1);
+        let entries = saved
+            .tracks
+            .into_iter()
+            .zip(saved.order)
+            .map(|(path, order)| QueueEntry { path, order })
+            .collect();
+        let mut queue = Self {
+            entries,
+            current: saved.current_index,
+            shuffled: saved.shuffled,
+            next_order,
+        };
+        queue.renumber();
+        Some(queue)
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> QueueState {
+        QueueState {
+            tracks: self.entries.iter().map(|e| e.path.clone()).collect(),
+            current_index: self.current,
+        }
+    }
+}
+
+/// Reorders already shuffled entries so the same artist does not play twice in a row.
+///
+/// Picks the first entry whose artist differs from the previous one, except when one
+/// artist makes up more than half of what is left: then that artist has to go now or
+/// it would be forced back-to-back later. Unknown artists never clash.
+fn spread_artists(
+    entries: Vec<QueueEntry>,
+    mut previous: Option<String>,
+    artist_of: &mut ArtistLookup<'_>,
+) -> Vec<QueueEntry> {
+    let mut remaining: Vec<(QueueEntry, Option<String>)> = entries
+        .into_iter()
+        .map(|e| {
+            let artist = artist_of(&e.path);
+            (e, artist)
+        })
+        .collect();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for artist in remaining.iter().filter_map(|(_, a)| a.clone()) {
+        *counts.entry(artist).or_default() += 1;
+    }
+
+    let mut result = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let dominant = counts
+            .iter()
+            .filter(|(artist, count)| {
+                **count * 2 > remaining.len() && previous.as_ref() != Some(*artist)
+            })
+            .map(|(artist, _)| artist.clone())
+            .next();
+
+        let pick = match dominant {
+            Some(artist) => remaining
+                .iter()
+                .position(|(_, a)| a.as_ref() == Some(&artist)),
+            None => remaining
+                .iter()
+                .position(|(_, a)| a.is_none() || *a != previous),
+        }
+        .unwrap_or(0);
+
+        let (entry, artist) = remaining.remove(pick);
+        if let Some(a) = &artist {
+            if let Some(count) = counts.get_mut(a) {
+                *count -= 1;
+            }
+        }
+        previous = artist;
+        result.push(entry);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| (*s).to_string()).collect()
+    }
+
+    #[test]
+    fn test_enqueue_and_advance() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b"]));
+        assert_eq!(queue.current(), None);
+
+        assert_eq!(queue.peek_next(), Some("a"));
+        assert_eq!(queue.advance(), Some("a"));
+        assert_eq!(queue.peek_next(), Some("b"));
+        assert_eq!(queue.advance(), Some("b"));
+        assert_eq!(queue.peek_next(), None);
+        assert_eq!(queue.advance(), None);
+        assert_eq!(queue.current(), Some("b"));
+
+        assert_eq!(queue.previous(), Some("a"));
+        assert_eq!(queue.previous(), None);
+        assert_eq!(queue.current(), Some("a"));
+    }
+
+    #[test]
+    fn test_wrapping() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b"]));
+        queue.advance();
+        queue.advance();
+
+        assert_eq!(queue.peek_next(), None);
+        assert_eq!(queue.peek_next_wrapping(), Some("a"));
+        assert_eq!(queue.advance_wrapping(), Some("a"));
+        assert_eq!(queue.current_index(), Some(0));
+    }
+
+    #[test]
+    fn test_play_now_and_enqueue_next() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b"]));
+        queue.advance();
+
+        queue.enqueue_next(paths(&["x", "y"]));
+        assert_eq!(queue.tracks(), vec!["a", "x", "y", "b"]);
+
+        queue.play_now("z".to_string());
+        assert_eq!(queue.current(), Some("z"));
+        assert_eq!(queue.current_index(), Some(1));
+        assert_eq!(queue.advance(), Some("x"));
+    }
+
+    #[test]
+    fn test_clear_keeps_current() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b", "c"]));
+        queue.advance();
+        queue.advance();
+
+        queue.clear();
+        assert_eq!(queue.tracks(), vec!["b"]);
+        assert_eq!(queue.current_index(), Some(0));
+    }
+
+    #[test]
+    fn test_move_item_tracks_current() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b", "c", "d"]));
+        queue.advance();
+        queue.advance(); // current = "b"
+
+        assert!(queue.move_item(0, 3));
+        assert_eq!(queue.tracks(), vec!["b", "c", "d", "a"]);
+        assert_eq!(queue.current(), Some("b"));
+
+        assert!(queue.move_item(0, 2));
+        assert_eq!(queue.current(), Some("b"));
+        assert_eq!(queue.current_index(), Some(2));
+
+        assert!(!queue.move_item(0, 4));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b", "c"]));
+        queue.advance();
+        queue.advance(); // current = "b"
+
+        assert_eq!(queue.remove(0), Some(false));
+        assert_eq!(queue.current(), Some("b"));
+
+        assert_eq!(queue.remove(0), Some(true));
+        assert_eq!(queue.current(), Some("c"));
+
+        assert_eq!(queue.remove(0), Some(true));
+        assert_eq!(queue.current(), None);
+        assert_eq!(queue.remove(0), None);
+    }
+
+    #[test]
+    fn test_shuffle_is_reproducible_and_reversible() {
+        let names = paths(&["a", "b", "c", "d", "e", "f", "g", "h"]);
+        let shuffled = |seed| {
+            let mut queue = PlayQueue::new();
+            queue.enqueue(names.clone());
+            queue.advance();
+            queue.shuffle(&mut ShuffleRng::new(seed), None);
+            queue
+        };
+
+        let mut first = shuffled(42);
+        let second = shuffled(42);
+        assert_eq!(first.tracks(), second.tracks());
+        assert_eq!(first.current(), Some("a"));
+        assert_ne!(
+            first.tracks(),
+            names.iter().map(String::as_str).collect::<Vec<_>>()
+        );
+
+        first.advance();
+        let playing = first.current().unwrap().to_string();
+        first.unshuffle();
+        assert_eq!(
+            first.tracks(),
+            names.iter().map(String::as_str).collect::<Vec<_>>()
+        );
+        assert_eq!(first.current(), Some(playing.as_str()));
+    }
+
+    #[test]
+    fn test_save_and_restore_keep_the_unshuffled_order() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b", "c", "d", "e"]));
+        queue.advance();
+        queue.shuffle(&mut ShuffleRng::new(7), None);
+        let shuffled = queue.snapshot();
+
+        let mut restored = PlayQueue::restore(queue.save()).unwrap();
+        assert_eq!(restored.snapshot(), shuffled);
+        assert!(restored.is_shuffled());
+        restored.unshuffle();
+        assert_eq!(restored.tracks(), ["a", "b", "c", "d", "e"]);
+        assert_eq!(restored.current(), Some("a"));
+
+        let mut broken = queue.save();
+        broken.current_index = Some(5);
+        assert!(PlayQueue::restore(broken).is_none());
+        let mut broken = queue.save();
+        broken.order.pop();
+        assert!(PlayQueue::restore(broken).is_none());
+    }
+
+    #[test]
+    fn test_enqueue_next_while_shuffled_stays_after_current() {
+        let mut queue = PlayQueue::new();
+        queue.enqueue(paths(&["a", "b", "c", "d"]));
+        queue.advance();
+        queue.shuffle(&mut ShuffleRng::new(7), None);
+
+        queue.enqueue_next(paths(&["x"]));
+        assert_eq!(queue.peek_next(), Some("x"));
+
+        queue.unshuffle();
+        assert_eq!(queue.tracks(), vec!["a", "x", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_smart_shuffle_spreads_artists() {
+        let artists: HashMap<&str, &str> = [
+            ("a1", "A"),
+            ("a2", "A"),
+            ("a3", "A"),
+            ("b1", "B"),
+            ("b2", "B"),
+            ("c1", "C"),
+        ]
+        .into_iter()
+        .collect();
+
+        for seed in 0..50 {
+            let mut queue = PlayQueue::new();
+            queue.enqueue(paths(&["a1", "a2", "a3", "b1", "b2", "c1"]));
+            let mut artist_of = |p: &str| artists.get(p).map(|a| (*a).to_string());
+            queue.shuffle(&mut ShuffleRng::new(seed), Some(&mut artist_of));
+
+            let order: Vec<&str> = queue.tracks().iter().map(|p| artists[p]).collect();
+            assert!(
+                order.windows(2).all(|w| w[0] != w[1]),
+                "seed {seed} produced {order:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::database::operations;
+use crate::scanner::chapters::{chapter_at, Chapter};
+use rusqlite::Connection;
+use std::time::Duration;
+
+/// Settings key for the shortest track that remembers its position, in minutes.
+pub const RESUME_THRESHOLD_SETTING: &str = "resume_threshold_minutes";
+
+/// Tracks at least this long remember their position unless the setting says otherwise.
+pub const DEFAULT_RESUME_THRESHOLD: Duration = Duration::from_secs(20 * 60);
+
+/// Stopping this close to the end counts as having finished the track.
+pub const FINISHED_MARGIN: Duration = Duration::from_secs(15);
+
+/// Resuming this soon after a chapter started goes back to the start of the chapter.
+pub const CHAPTER_SNAP: Duration = Duration::from_secs(30);
+
+/// Shortest track length that remembers its position; `None` if only flagged folders do.
+///
+/// # Errors
+///
+/// Returns an error if the settings table cannot be read.
+pub fn resume_threshold(conn: &Connection) -> Result<Option<Duration>, String> {
+    let stored =
+        operations::get_setting(conn, RESUME_THRESHOLD_SETTING).map_err(|e| e.to_string())?;
+    let Some(minutes) = stored.and_then(|m| m.parse::<f64>().ok()) else {
+        return Ok(Some(DEFAULT_RESUME_THRESHOLD));
+    };
+    Ok(Duration::try_from_secs_f64(minutes * 60.0)
+        .ok()
+        .filter(|d| !d.is_zero()))
+}
+
+/// Whether the track at `path` saves its position and resumes from it.
+///
+/// # Errors
+///
+/// Returns an error if the settings or folders cannot be read.
+pub fn remembers_position(
+    conn: &Connection,
+    path: &str,
+    duration: Duration,
+) -> Result<bool, String> {
+    if resume_threshold(conn)?.is_some_and(|min| !duration.is_zero() && duration >= min) {
+        return Ok(true);
+    }
+    operations::in_remember_position_folder(conn, path).map_err(|e| e.to_string())
+}
+
+/// Stores where a remembering track was left; near its end it is marked finished instead.
+///
+/// # Errors
+///
+/// Returns an error if the position cannot be written.
+pub fn store_position(
+    conn: &Connection,
+    path: &str,
+    position: Duration,
+    duration: Duration,
+) -> Result<(), String> {
+    let finished = !duration.is_zero() && position + FINISHED_MARGIN >= duration;
+    if finished {
+        operations::mark_track_finished(conn, path, Some(duration.as_secs_f64()))
+    } else {
+        operations::save_resume_position(conn, path, position.as_secs_f64(), duration.as_secs_f64())
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Where to resume a track left at `position`: the start of its chapter when only the
+/// first moments of the chapter were heard.
+#[must_use]
+pub fn resume_point(position: Duration, chapters: &[Chapter]) -> Duration {
+    let seconds = position.as_secs_f64();
+    chapter_at(chapters, seconds)
+        .map(|i| chapters[i].start)
+        .filter(|start| seconds - start <= CHAPTER_SNAP.as_secs_f64())
+        .and_then(|start| Duration::try_from_secs_f64(start).ok())
+        .unwrap_or(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema::create_tables;
+
+    fn setup_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_threshold_setting() {
+        let conn = setup_db();
+        let hour = Duration::from_secs(3600);
+        assert_eq!(
+            resume_threshold(&conn).unwrap(),
+            Some(DEFAULT_RESUME_THRESHOLD)
+        );
+        assert!(remembers_position(&conn, "/mix.mp3", hour).unwrap());
+        assert!(!remembers_position(&conn, "/song.mp3", Duration::from_secs(200)).unwrap());
+        // Unknown length never passes the threshold
+        assert!(!remembers_position(&conn, "/stream.mp3", Duration::ZERO).unwrap());
+
+        operations::set_setting(&conn, RESUME_THRESHOLD_SETTING, Some("0")).unwrap();
+        assert_eq!(resume_threshold(&conn).unwrap(), None);
+        assert!(!remembers_position(&conn, "/mix.mp3", hour).unwrap());
+
+        let folder = operations::add_folder(&conn, "Mixes", "/mixes", 0).unwrap();
+        operations::set_folder_remember_position(&conn, &folder, true).unwrap();
+        assert!(remembers_position(&conn, "/mixes/short.mp3", Duration::from_secs(60)).unwrap());
+    }
+
+    #[test]
+    fn test_resume_point_snaps_to_chapter_start() {
+        let chapters = vec![
+            Chapter {
+                title: None,
+                start: 0.0,
+                end: 600.0,
+            },
+            Chapter {
+                title: None,
+                start: 600.0,
+                end: 1200.0,
+            },
+        ];
+        let at = Duration::from_secs;
+        assert_eq!(resume_point(at(610), &chapters), at(600));
+        assert_eq!(resume_point(at(700), &chapters), at(700));
+        assert_eq!(resume_point(at(10), &chapters), at(0));
+        assert_eq!(resume_point(at(610), &[]), at(610));
+    }
+
+    #[test]
+    fn test_near_the_end_counts_as_finished() {
+        let conn = setup_db();
+        let duration = Duration::from_secs(3600);
+        store_position(&conn, "/mix.mp3", Duration::from_secs(600), duration).unwrap();
+        assert_eq!(
+            operations::get_resume_position(&conn, "/mix.mp3").unwrap(),
+            Some(600.0)
+        );
+
+        store_position(&conn, "/mix.mp3", Duration::from_secs(3590), duration).unwrap();
+        assert_eq!(
+            operations::get_resume_position(&conn, "/mix.mp3").unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use crate::scanner::cue::CueRange;
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Decoder, Sample, SampleRate, Source};
+use std::fs::File;
+use std::io::BufReader;
+use std::time::Duration;
+
+pub type FileDecoder = Decoder<BufReader<File>>;
+
+/// Opens the file behind `path` for decoding: the part given by `range` for a virtual
+/// CUE track, else the whole file.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or decoded, or the range start cannot be
+/// reached.
+pub fn open_segment(path: &str, range: Option<&CueRange>) -> Result<Segment<FileDecoder>, String> {
+    let file = File::open(range.map_or(path, |r| r.file.as_str()))
+        .map_err(|e| format!("Failed to open file: {e}"))?;
+    // Knowing the file length lets the decoder seek backwards, which loops rely on
+    let decoder = Decoder::try_from(file).map_err(|e| format!("Failed to decode: {e}"))?;
+    let Some(range) = range else {
+        return Ok(Segment::whole(decoder));
+    };
+    let secs = |secs: f64| Duration::try_from_secs_f64(secs).unwrap_or_default();
+    Segment::new(decoder, secs(range.start), range.end.map(secs))
+        .map_err(|e| format!("Failed to seek to the start of the track: {e}"))
+}
+
+/// Plays the part of a source between two points as if it were a whole track.
+///
+/// Positions, seeks and the total duration are relative to the start of the part, and
+/// the source ends on the frame where the part does, so the next part of the same file
+/// can follow it without a gap.
+pub struct Segment<S> {
+    inner: S,
+    start: Duration,
+    // Samples from `start` to the end, on a frame boundary; `None` plays to the end
+    len: Option<u64>,
+    played: u64,
+}
+
+impl<S: Source> Segment<S> {
+    /// The whole of `inner`.
+    #[must_use]
+    pub fn whole(inner: S) -> Self {
+        Self {
+            inner,
+            start: Duration::ZERO,
+            len: None,
+            played: 0,
+        }
+    }
+
+    /// The part of `inner` from `start` to `end`, or to its end for `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `inner` cannot seek to `start`.
+    pub fn new(mut inner: S, start: Duration, end: Option<Duration>) -> Result<Self, SeekError> {
+        if !start.is_zero() {
+            inner.try_seek(start)?;
+        }
+        let mut segment = Self::whole(inner);
+        segment.start = start;
+        segment.len = end.map(|end| segment.frame_at(end.saturating_sub(start)));
+        Ok(segment)
+    }
+
+    fn samples_in(&self, duration: Duration) -> u64 {
+        let rate = u64::from(self.inner.sample_rate()) * u64::from(self.inner.channels());
+        u64::try_from(duration.as_nanos() * u128::from(rate) / 1_000_000_000).unwrap_or(u64::MAX)
+    }
+
+    /// First sample of the frame that `duration` falls in.
+    fn frame_at(&self, duration: Duration) -> u64 {
+        let samples = self.samples_in(duration);
+        samples - samples % u64::from(self.inner.channels().max(1))
+    }
+
+    fn remaining(&self) -> Option<u64> {
+        self.len.map(|len| len.saturating_sub(self.played))
+    }
+}
+
+impl<S: Source> Iterator for Segment<S> {
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        if self.remaining() == Some(0) {
+            return None;
+        }
+        let sample = self.inner.next()?;
+        self.played += 1;
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Segment<S> {
+    #[inline]
+    fn current_span_len(&self) -> Option<usize> {
+        let remaining = self
+            .remaining()
+            .map(|r| usize::try_from(r).unwrap_or(usize::MAX));
+        match (self.inner.current_span_len(), remaining) {
+            (Some(span), Some(remaining)) => Some(span.min(remaining)),
+            (span, remaining) => span.or(remaining),
+        }
+    }
+
+    #[inline]
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        match self.len {
+            Some(len) => {
+                let rate = u64::from(self.inner.sample_rate()) * u64::from(self.inner.channels());
+                (rate > 0).then(|| {
+                    Duration::from_secs(len / rate)
+                        + Duration::from_nanos((len % rate) * 1_000_000_000 / rate)
+                })
+            }
+            None => self
+                .inner
+                .total_duration()
+                .map(|d| d.saturating_sub(self.start)),
+        }
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(self.start + pos)?;
+        self.played = self.samples_in(pos);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    /// Stereo frames numbered 0..100 at 1 kHz.
+    fn frames() -> SamplesBuffer {
+        let samples = (0..200u16).map(|i| f32::from(i / 2)).collect::<Vec<_>>();
+        SamplesBuffer::new(2, 1000, samples)
+    }
+
+    #[test]
+    fn test_plays_only_the_range() {
+        let segment = Segment::new(
+            frames(),
+            Duration::from_millis(20),
+            Some(Duration::from_millis(30)),
+        )
+        .unwrap();
+        assert_eq!(segment.total_duration(), Some(Duration::from_millis(10)));
+
+        let played: Vec<f32> = segment.step_by(2).collect();
+        let expected: Vec<f32> = (20..30u16).map(f32::from).collect();
+        assert_eq!(played, expected);
+    }
+
+    #[test]
+    fn test_seeks_within_the_range() {
+        let mut segment = Segment::new(
+            frames(),
+            Duration::from_millis(50),
+            Some(Duration::from_millis(60)),
+        )
+        .unwrap();
+        segment.try_seek(Duration::from_millis(8)).unwrap();
+        assert_eq!(segment.current_span_len(), Some(4));
+        assert_eq!(segment.collect::<Vec<_>>(), [58.0, 58.0, 59.0, 59.0]);
+    }
+
+    #[test]
+    fn test_open_range_runs_to_the_end() {
+        let segment = Segment::new(frames(), Duration::from_millis(90), None).unwrap();
+        assert_eq!(segment.total_duration(), Some(Duration::from_millis(10)));
+        assert_eq!(segment.count(), 20);
+
+        let whole = Segment::whole(frames());
+        assert_eq!(whole.total_duration(), Some(Duration::from_millis(100)));
+        assert_eq!(whole.count(), 200);
+    }
+}
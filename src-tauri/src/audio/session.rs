@@ -0,0 +1,98 @@
+use super::queue::{RepeatMode, SavedQueue, ShuffleMode};
+use crate::database::operations;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Settings key under which the playback session is stored, as JSON.
+pub const SESSION_SETTING: &str = "playback_session";
+
+/// How often the audio thread stores the session while it changes.
+pub const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the player needs to pick up where it left off after a restart.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Session {
+    pub queue: SavedQueue,
+    /// Seconds into the current track.
+    pub position: f64,
+    pub volume: f32,
+    pub repeat: RepeatMode,
+    pub shuffle: ShuffleMode,
+}
+
+impl Session {
+    /// Position in the current track, ignoring values that cannot be one.
+    #[must_use]
+    pub fn position(&self) -> Duration {
+        Duration::try_from_secs_f64(self.position).unwrap_or_default()
+    }
+}
+
+/// Reads the stored session. A session that no longer parses is treated as missing.
+///
+/// # Errors
+///
+/// Returns an error if the settings table cannot be read.
+pub fn load_session(conn: &Connection) -> Result<Option<Session>, String> {
+    let stored = operations::get_setting(conn, SESSION_SETTING).map_err(|e| e.to_string())?;
+    Ok(stored.and_then(|json| match serde_json::from_str(&json) {
+        Ok(session) => Some(session),
+        Err(e) => {
+            eprintln!("Ignoring damaged playback session: {e}");
+            None
+        }
+    }))
+}
+
+/// # Errors
+///
+/// Returns an error if the session cannot be written to the settings table.
+pub fn save_session(conn: &Connection, session: &Session) -> Result<(), String> {
+    let json = serde_json::to_string(session).map_err(|e| e.to_string())?;
+    operations::set_setting(conn, SESSION_SETTING, Some(&json)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema::create_tables;
+
+    #[test]
+    fn test_session_round_trip() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        assert_eq!(load_session(&conn).unwrap(), None);
+
+        let session = Session {
+            queue: SavedQueue {
+                tracks: vec!["a.mp3".to_string(), "b.mp3".to_string()],
+                order: vec![1, 0],
+                current_index: Some(1),
+                shuffled: true,
+            },
+            position: 42.5,
+            volume: 0.25,
+            repeat: RepeatMode::All,
+            shuffle: ShuffleMode::Smart,
+        };
+        save_session(&conn, &session).unwrap();
+        assert_eq!(load_session(&conn).unwrap(), Some(session));
+
+        operations::set_setting(&conn, SESSION_SETTING, Some("{not json")).unwrap();
+        assert_eq!(load_session(&conn).unwrap(), None);
+    }
+
+    #[test]
+    fn test_position_ignores_invalid_values() {
+        let mut session = Session {
+            position: -1.0,
+            ..Session::default()
+        };
+        assert_eq!(session.position(), Duration::ZERO);
+        session.position = f64::NAN;
+        assert_eq!(session.position(), Duration::ZERO);
+        session.position = 1.5;
+        assert_eq!(session.position(), Duration::from_millis(1500));
+    }
+}
@@ -0,0 +1,175 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SleepMode {
+    /// Stop after a number of minutes.
+    Minutes,
+    /// Stop when the current track ends.
+    EndOfTrack,
+    /// Stop when the current track and a number of tracks after it have ended.
+    Tracks,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SleepUntil {
+    Time(Instant),
+    // Natural track ends left after the current track's own
+    Tracks(u32),
+}
+
+/// Stops playback at a point in time or after a number of tracks, fading the volume out
+/// over the final seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepTimer {
+    until: SleepUntil,
+    fade: Duration,
+}
+
+impl SleepTimer {
+    /// Builds a timer started at `now`. `amount` is the number of minutes or of tracks
+    /// after the current one, and is ignored for `EndOfTrack`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `amount` is missing or out of range for the mode.
+    pub fn new(
+        mode: SleepMode,
+        amount: Option<f32>,
+        fade: Duration,
+        now: Instant,
+    ) -> Result<Self, String> {
+        let until = match mode {
+            SleepMode::Minutes => {
+                let minutes = amount
+                    .filter(|m| m.is_finite() && *m > 0.0)
+                    .ok_or("Sleep timer needs a positive number of minutes")?;
+                let at = Duration::try_from_secs_f32(minutes * 60.0)
+                    .ok()
+                    .and_then(|left| now.checked_add(left))
+                    .ok_or("Sleep timer is too long")?;
+                SleepUntil::Time(at)
+            }
+            SleepMode::EndOfTrack => SleepUntil::Tracks(0),
+            SleepMode::Tracks => {
+                let tracks = amount
+                    .filter(|t| t.is_finite() && *t >= 0.0 && t.fract() == 0.0)
+                    .ok_or("Sleep timer needs a whole number of tracks")?;
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                SleepUntil::Tracks(tracks as u32)
+            }
+        };
+        Ok(Self { until, fade })
+    }
+
+    /// True once a time-based timer has run out.
+    #[must_use]
+    pub fn expired(&self, now: Instant) -> bool {
+        matches!(self.until, SleepUntil::Time(at) if now >= at)
+    }
+
+    /// True while the current track is the last one to play.
+    #[must_use]
+    pub fn on_last_track(&self) -> bool {
+        self.until == SleepUntil::Tracks(0)
+    }
+
+    /// Counts a track that played to its end; returns true if playback should stop now.
+    pub fn track_finished(&mut self) -> bool {
+        match &mut self.until {
+            SleepUntil::Time(_) => false,
+            SleepUntil::Tracks(0) => true,
+            SleepUntil::Tracks(left) => {
+                *left -= 1;
+                false
+            }
+        }
+    }
+
+    /// Time until playback stops, if it is known yet. `track_left` is what remains of the
+    /// current track.
+    #[must_use]
+    pub fn remaining(&self, now: Instant, track_left: Option<Duration>) -> Option<Duration> {
+        match self.until {
+            SleepUntil::Time(at) => Some(at.saturating_duration_since(now)),
+            SleepUntil::Tracks(0) => track_left,
+            SleepUntil::Tracks(_) => None,
+        }
+    }
+
+    /// Volume factor for the fade-out, 1.0 until the final `fade` seconds.
+    #[must_use]
+    pub fn volume(&self, now: Instant, track_left: Option<Duration>) -> f32 {
+        match self.remaining(now, track_left) {
+            Some(left) if left < self.fade => left.as_secs_f32() / self.fade.as_secs_f32(),
+            _ => 1.0,
+        }
+    }
+
+    /// Countdown reported in `player-progress`.
+    #[must_use]
+    pub fn countdown(&self, now: Instant, track_left: Option<Duration>) -> serde_json::Value {
+        let seconds = self.remaining(now, track_left).map(|d| d.as_secs_f64());
+        match self.until {
+            SleepUntil::Time(_) => serde_json::json!({ "seconds": seconds }),
+            SleepUntil::Tracks(tracks) => {
+                serde_json::json!({ "seconds": seconds, "tracks": tracks })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minutes_timer_fades_before_expiring() {
+        let start = Instant::now();
+        let timer = SleepTimer::new(
+            SleepMode::Minutes,
+            Some(1.0),
+            Duration::from_secs(10),
+            start,
+        )
+        .unwrap();
+
+        assert_eq!(timer.volume(start, None), 1.0);
+        assert!(!timer.expired(start + Duration::from_secs(50)));
+        let halfway = timer.volume(start + Duration::from_secs(55), None);
+        assert!((halfway - 0.5).abs() < 1e-3);
+        assert!(timer.expired(start + Duration::from_secs(60)));
+        assert_eq!(timer.volume(start + Duration::from_secs(61), None), 0.0);
+    }
+
+    #[test]
+    fn test_tracks_timer_counts_natural_ends() {
+        let now = Instant::now();
+        let mut timer =
+            SleepTimer::new(SleepMode::Tracks, Some(2.0), Duration::from_secs(4), now).unwrap();
+        let left = Some(Duration::from_secs(2));
+
+        // Only the last track fades
+        assert!(!timer.on_last_track());
+        assert_eq!(timer.volume(now, left), 1.0);
+        assert!(!timer.track_finished());
+        assert!(!timer.track_finished());
+        assert!(timer.on_last_track());
+        assert!((timer.volume(now, left) - 0.5).abs() < 1e-3);
+        assert!(timer.track_finished());
+        assert!(!timer.expired(now + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_invalid_amounts_are_rejected() {
+        let now = Instant::now();
+        let fade = Duration::ZERO;
+        assert!(SleepTimer::new(SleepMode::Minutes, None, fade, now).is_err());
+        assert!(SleepTimer::new(SleepMode::Minutes, Some(-1.0), fade, now).is_err());
+        assert!(SleepTimer::new(SleepMode::Minutes, Some(f32::MAX), fade, now).is_err());
+        assert!(SleepTimer::new(SleepMode::Tracks, Some(1.5), fade, now).is_err());
+        let end_of_track = SleepTimer::new(SleepMode::EndOfTrack, None, fade, now).unwrap();
+        assert!(end_of_track.on_last_track());
+    }
+}
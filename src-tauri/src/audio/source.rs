@@ -0,0 +1,524 @@
+use super::player::{AudioCommand, EventSender};
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::f32::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FadeDirection {
+    In,
+    Out,
+}
+
+/// A gain ramp requested by the audio thread for a source that is already in the sink.
+#[derive(Debug, Clone, Copy)]
+pub struct FadeRequest {
+    pub direction: FadeDirection,
+    pub duration: Duration,
+    /// End the source once a fade-out reaches silence.
+    pub end_when_silent: bool,
+}
+
+/// Shared between the audio thread and a `TrackSource` that has been moved into a sink.
+#[derive(Debug)]
+pub struct TrackControl {
+    cancelled: AtomicBool,
+    fade_pending: AtomicBool,
+    fade: Mutex<Option<FadeRequest>>,
+    // How long before its end the track starts fading out on its own, in milliseconds
+    crossfade_ms: AtomicU64,
+    // Linear normalization gain, stored as `f32` bits
+    gain: AtomicU32,
+    // Samples read from the decoder so far and per second, for the position in source time
+    position: AtomicU64,
+    samples_per_sec: AtomicU64,
+    loop_pending: AtomicBool,
+    loop_region: Mutex<Option<(Duration, Duration)>>,
+}
+
+impl Default for TrackControl {
+    fn default() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            fade_pending: AtomicBool::new(false),
+            fade: Mutex::new(None),
+            crossfade_ms: AtomicU64::new(0),
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            position: AtomicU64::new(0),
+            samples_per_sec: AtomicU64::new(0),
+            loop_pending: AtomicBool::new(false),
+            loop_region: Mutex::new(None),
+        }
+    }
+}
+
+impl TrackControl {
+    /// Ends the source immediately without signalling.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Starts a gain ramp from the current gain.
+    pub fn fade(&self, request: FadeRequest) {
+        if let Ok(mut fade) = self.fade.lock() {
+            *fade = Some(request);
+            self.fade_pending.store(true, Ordering::Release);
+        }
+    }
+
+    /// Makes the track fade out over its last `lead` and send `AudioCommand::TrackEnding`
+    /// when that fade starts. `Duration::ZERO` disables it.
+    pub fn set_crossfade(&self, lead: Duration) {
+        let ms = u64::try_from(lead.as_millis()).unwrap_or(u64::MAX);
+        self.crossfade_ms.store(ms, Ordering::Relaxed);
+    }
+
+    /// Repeats the region between `start` and `end` until it is cleared with `None`.
+    ///
+    /// The jump back happens on the first frame at or past `end`, so a region that is set
+    /// behind the current position starts over right away.
+    pub fn set_loop(&self, region: Option<(Duration, Duration)>) {
+        if let Ok(mut loop_region) = self.loop_region.lock() {
+            *loop_region = region;
+            self.loop_pending.store(true, Ordering::Release);
+        }
+    }
+
+    /// Sets the linear gain applied on top of any fade, e.g. from `ReplayGain`.
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    /// How far into the track the decoder is, in source time.
+    ///
+    /// Unlike `Sink::get_pos` this does not drift when the playback speed changes.
+    pub fn position(&self) -> Duration {
+        let rate = self.samples_per_sec.load(Ordering::Relaxed);
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        duration_of(self.position.load(Ordering::Relaxed), rate)
+    }
+
+    fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+}
+
+/// Playing time of `samples` interleaved samples at `rate` samples per second.
+fn duration_of(samples: u64, rate: u64) -> Duration {
+    Duration::from_secs(samples / rate)
+        + Duration::from_nanos((samples % rate) * 1_000_000_000 / rate)
+}
+
+/// Wraps a decoded track before it is appended to the sink.
+///
+/// When the inner source runs out it sends `AudioCommand::TrackEnded(id)` back to the
+/// audio thread, so track boundaries are reported at the sample where they happen
+/// instead of at the next poll. It also applies equal-power fades: the gain is
+/// `sin(angle)` and fades move `angle` linearly between 0 and π/2, so a fade-out
+/// mirrored by another track's fade-in keeps the summed power constant.
+pub struct TrackSource<S> {
+    inner: S,
+    id: u64,
+    events: EventSender,
+    control: Arc<TrackControl>,
+    finished: bool,
+    angle: f32,
+    angle_step: f32,
+    fade_target: f32,
+    fade_remaining: u64,
+    end_when_silent: bool,
+    samples_played: u64,
+    total_samples: Option<u64>,
+    crossfade_started: bool,
+    // Loop region in samples, both on frame boundaries
+    loop_region: Option<(u64, u64)>,
+    // Channel of the next sample, so loops only jump between frames
+    frame_pos: u16,
+}
+
+impl<S: Source> TrackSource<S> {
+    pub fn new(inner: S, id: u64, events: EventSender) -> Self {
+        let mut source = Self {
+            inner,
+            id,
+            events,
+            control: Arc::new(TrackControl::default()),
+            finished: false,
+            angle: FRAC_PI_2,
+            angle_step: 0.0,
+            fade_target: FRAC_PI_2,
+            fade_remaining: 0,
+            end_when_silent: false,
+            samples_played: 0,
+            total_samples: None,
+            crossfade_started: false,
+            loop_region: None,
+            frame_pos: 0,
+        };
+        source.total_samples = source.inner.total_duration().map(|d| source.samples_in(d));
+        let rate = u64::from(source.inner.sample_rate()) * u64::from(source.inner.channels());
+        source
+            .control
+            .samples_per_sec
+            .store(rate, Ordering::Relaxed);
+        source
+    }
+
+    /// Handle used by the audio thread once this source has been moved into the sink.
+    #[must_use]
+    pub fn control(&self) -> Arc<TrackControl> {
+        self.control.clone()
+    }
+
+    #[must_use]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Starts from silence and ramps up over `duration`.
+    pub fn fade_in_from_silence(&mut self, duration: Duration) {
+        self.angle = 0.0;
+        self.start_fade(FadeRequest {
+            direction: FadeDirection::In,
+            duration,
+            end_when_silent: false,
+        });
+    }
+
+    fn samples_in(&self, duration: Duration) -> u64 {
+        let rate = u64::from(self.inner.sample_rate()) * u64::from(self.inner.channels());
+        u64::try_from(duration.as_nanos() * u128::from(rate) / 1_000_000_000).unwrap_or(u64::MAX)
+    }
+
+    /// First sample of the frame that `duration` falls in.
+    fn frame_at(&self, duration: Duration) -> u64 {
+        let samples = self.samples_in(duration);
+        samples - samples % u64::from(self.inner.channels().max(1))
+    }
+
+    /// Jumps back to the loop start once the loop end has been played.
+    fn check_loop(&mut self) {
+        if self.control.loop_pending.swap(false, Ordering::Acquire) {
+            let region = self.control.loop_region.lock().ok().and_then(|r| *r);
+            self.loop_region =
+                region.map(|(start, end)| (self.frame_at(start), self.frame_at(end)));
+        }
+        let Some((start, end)) = self.loop_region else {
+            return;
+        };
+        if self.frame_pos != 0 || self.samples_played < end {
+            return;
+        }
+        let rate = u64::from(self.inner.sample_rate()) * u64::from(self.inner.channels());
+        match self.inner.try_seek(duration_of(start, rate)) {
+            Ok(()) => {
+                self.samples_played = start;
+                self.control.position.store(start, Ordering::Relaxed);
+            }
+            Err(e) => {
+                eprintln!("Loop seek failed: {e}");
+                self.loop_region = None;
+            }
+        }
+    }
+
+    fn start_fade(&mut self, request: FadeRequest) {
+        let target = match request.direction {
+            FadeDirection::In => FRAC_PI_2,
+            FadeDirection::Out => 0.0,
+        };
+        self.end_when_silent = request.end_when_silent;
+        self.ramp_to(target, self.samples_in(request.duration));
+    }
+
+    fn ramp_to(&mut self, target: f32, samples: u64) {
+        self.fade_target = target;
+        self.fade_remaining = samples;
+        if samples == 0 {
+            self.angle = target;
+            self.angle_step = 0.0;
+        } else {
+            #[allow(clippy::cast_precision_loss)]
+            let step = (target - self.angle) / samples as f32;
+            self.angle_step = step;
+        }
+    }
+
+    /// Starts the natural-end fade-out once the remaining samples fit in the crossfade.
+    fn check_crossfade(&mut self) {
+        // A looping track does not reach its end
+        if self.crossfade_started || self.loop_region.is_some() {
+            return;
+        }
+        let lead_ms = self.control.crossfade_ms.load(Ordering::Relaxed);
+        let Some(total) = self.total_samples else {
+            return;
+        };
+        if lead_ms == 0 {
+            return;
+        }
+
+        let remaining = total.saturating_sub(self.samples_played);
+        if remaining > self.samples_in(Duration::from_millis(lead_ms)) {
+            return;
+        }
+
+        self.crossfade_started = true;
+        self.end_when_silent = true;
+        self.ramp_to(0.0, remaining);
+        self.events.send(AudioCommand::TrackEnding(self.id));
+    }
+
+    fn next_gain(&mut self) -> f32 {
+        if self.fade_remaining > 0 {
+            self.fade_remaining -= 1;
+            if self.fade_remaining == 0 {
+                self.angle = self.fade_target;
+            } else {
+                self.angle = (self.angle + self.angle_step).clamp(0.0, FRAC_PI_2);
+            }
+        }
+        self.angle.sin()
+    }
+}
+
+impl<S: Source> Iterator for TrackSource<S> {
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        if self.finished {
+            return None;
+        }
+        if self.control.cancelled.load(Ordering::Relaxed) {
+            self.finished = true;
+            return None;
+        }
+        if self.control.fade_pending.swap(false, Ordering::Acquire) {
+            let request = self.control.fade.lock().ok().and_then(|mut f| f.take());
+            if let Some(request) = request {
+                self.start_fade(request);
+            }
+        }
+        self.check_loop();
+        self.check_crossfade();
+
+        let Some(sample) = self.inner.next() else {
+            self.finished = true;
+            // The audio thread may already be gone during shutdown
+            self.events.send(AudioCommand::TrackEnded(self.id));
+            return None;
+        };
+        self.samples_played += 1;
+        self.frame_pos = (self.frame_pos + 1) % self.inner.channels().max(1);
+        self.control
+            .position
+            .store(self.samples_played, Ordering::Relaxed);
+        let sample = sample * self.control.gain();
+
+        if self.fade_remaining == 0 && self.angle == FRAC_PI_2 {
+            return Some(sample);
+        }
+        let gain = self.next_gain();
+        if self.end_when_silent && self.angle == 0.0 {
+            // Faded out for good; whoever asked for the fade has already moved on
+            self.finished = true;
+        }
+        Some(sample * gain)
+    }
+}
+
+impl<S: Source> Source for TrackSource<S> {
+    #[inline]
+    fn current_span_len(&self) -> Option<usize> {
+        self.inner.current_span_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.samples_played = self.samples_in(pos);
+        self.control
+            .position
+            .store(self.samples_played, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+    use std::sync::mpsc;
+
+    fn buffer(len: usize) -> SamplesBuffer {
+        SamplesBuffer::new(1, 1000, vec![0.5; len])
+    }
+
+    /// A command channel; event senders made from it work while the `Arc` lives.
+    fn channel() -> (
+        Arc<mpsc::Sender<AudioCommand>>,
+        mpsc::Receiver<AudioCommand>,
+    ) {
+        let (tx, rx) = mpsc::channel();
+        (Arc::new(tx), rx)
+    }
+
+    #[test]
+    fn test_signals_end_once() {
+        let (tx, rx) = channel();
+        let mut source = TrackSource::new(buffer(3), 7, EventSender::new(&tx));
+
+        assert_eq!(source.by_ref().count(), 3);
+        assert!(source.next().is_none());
+
+        match rx.try_recv() {
+            Ok(AudioCommand::TrackEnded(id)) => assert_eq!(id, 7),
+            _ => panic!("Expected TrackEnded command"),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_events_do_not_keep_the_channel_open() {
+        let (tx, rx) = channel();
+        let mut source = TrackSource::new(buffer(3), 1, EventSender::new(&tx));
+
+        // Once the player state is gone the audio thread sees the channel close
+        drop(tx);
+        assert_eq!(source.by_ref().count(), 3);
+        assert_eq!(rx.try_recv().err(), Some(mpsc::TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_cancelled_source_ends_silently() {
+        let (tx, rx) = channel();
+        let mut source = TrackSource::new(buffer(3), 1, EventSender::new(&tx));
+        let control = source.control();
+
+        assert!(source.next().is_some());
+        control.cancel();
+        assert!(source.next().is_none());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_gain_applies_live() {
+        let (tx, _rx) = channel();
+        let mut source = TrackSource::new(buffer(3), 1, EventSender::new(&tx));
+        let control = source.control();
+
+        assert!((source.next().unwrap() - 0.5).abs() < 1e-6);
+        control.set_gain(0.5);
+        assert!((source.next().unwrap() - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_position_in_source_time() {
+        let (tx, _rx) = channel();
+        let mut source = TrackSource::new(buffer(3000), 1, EventSender::new(&tx));
+        let control = source.control();
+
+        assert_eq!(source.by_ref().take(1500).count(), 1500);
+        assert_eq!(control.position(), Duration::from_millis(1500));
+        source.try_seek(Duration::from_millis(250)).unwrap();
+        assert_eq!(control.position(), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_loop_repeats_region_exactly() {
+        let (tx, rx) = channel();
+        // Stereo frames numbered 0..100 at 1 kHz
+        let samples = (0..200u16).map(|i| f32::from(i / 2)).collect::<Vec<_>>();
+        let mut source = TrackSource::new(
+            SamplesBuffer::new(2, 1000, samples),
+            1,
+            EventSender::new(&tx),
+        );
+        let control = source.control();
+        control.set_crossfade(Duration::from_millis(50));
+        control.set_loop(Some((Duration::from_millis(20), Duration::from_millis(30))));
+
+        let frames: Vec<f32> = source.by_ref().take(2 * 35).step_by(2).collect();
+        let expected: Vec<f32> = (0..30u16).chain(20..25).map(f32::from).collect();
+        assert_eq!(frames, expected);
+        assert_eq!(control.position(), Duration::from_millis(25));
+
+        // A region behind the position starts over at once
+        control.set_loop(Some((Duration::from_millis(5), Duration::from_millis(10))));
+        assert_eq!(source.next(), Some(5.0));
+        // The crossfade waits for the loop to be cleared
+        assert!(rx.try_recv().is_err());
+
+        control.set_loop(None);
+        assert_eq!(source.by_ref().count(), 200 - 11);
+        assert!(matches!(rx.try_recv(), Ok(AudioCommand::TrackEnding(1))));
+    }
+
+    #[test]
+    fn test_fade_in_is_equal_power() {
+        let (tx, _rx) = channel();
+        let mut source = TrackSource::new(buffer(20), 1, EventSender::new(&tx));
+        source.fade_in_from_silence(Duration::from_millis(10));
+
+        let samples: Vec<f32> = source.collect();
+        assert_eq!(samples.len(), 20);
+        assert!(samples[0] < 0.1);
+        // Halfway through the ramp the gain is sin(π/4)
+        assert!((samples[4] - 0.5 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert!(samples[9..].iter().all(|s| (s - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_crossfade_fades_out_before_end() {
+        let (tx, rx) = channel();
+        let mut source = TrackSource::new(buffer(100), 3, EventSender::new(&tx));
+        source.control().set_crossfade(Duration::from_millis(20));
+
+        let samples: Vec<f32> = source.by_ref().collect();
+        assert!((samples[79] - 0.5).abs() < 1e-6);
+        assert!(samples[90] < 0.5 && samples[90] > 0.0);
+        assert!(samples.len() <= 100);
+
+        match rx.try_recv() {
+            Ok(AudioCommand::TrackEnding(id)) => assert_eq!(id, 3),
+            _ => panic!("Expected TrackEnding command"),
+        }
+    }
+
+    #[test]
+    fn test_requested_fade_out_ends_source() {
+        let (tx, rx) = channel();
+        let mut source = TrackSource::new(buffer(100), 1, EventSender::new(&tx));
+        let control = source.control();
+
+        assert!(source.next().is_some());
+        control.fade(FadeRequest {
+            direction: FadeDirection::Out,
+            duration: Duration::from_millis(10),
+            end_when_silent: true,
+        });
+
+        assert_eq!(source.by_ref().count(), 10);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use serde::Serialize;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const DEFAULT_SPECTRUM_RATE: f32 = 30.0;
+pub const MIN_SPECTRUM_RATE: f32 = 1.0;
+pub const MAX_SPECTRUM_RATE: f32 = 60.0;
+pub const DEFAULT_SPECTRUM_BINS: usize = 64;
+pub const MAX_SPECTRUM_BINS: usize = 256;
+
+/// Frames per FFT window, about 46 ms at 44.1 kHz.
+const FFT_SIZE: usize = 2048;
+/// Frames the tap collects before handing them over, and how often it checks whether it
+/// is enabled.
+const TAP_BLOCK: usize = 256;
+/// Range covered by the bands.
+const MIN_FREQUENCY: f32 = 20.0;
+const MAX_FREQUENCY: f32 = 20_000.0;
+/// Lowest band level reported, in dBFS.
+const FLOOR_DB: f32 = -100.0;
+
+/// How often `player-spectrum` is emitted and how many bands it has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectrumConfig {
+    pub rate: f32,
+    pub bins: usize,
+}
+
+impl SpectrumConfig {
+    /// Uses the defaults for missing values.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `rate` events per second or `bins` bands are out of range.
+    pub fn new(rate: Option<f32>, bins: Option<usize>) -> Result<Self, String> {
+        let rate = rate.unwrap_or(DEFAULT_SPECTRUM_RATE);
+        if !(MIN_SPECTRUM_RATE..=MAX_SPECTRUM_RATE).contains(&rate) {
+            return Err(format!(
+                "Spectrum rate must be between {MIN_SPECTRUM_RATE} and {MAX_SPECTRUM_RATE} per second"
+            ));
+        }
+        let bins = bins.unwrap_or(DEFAULT_SPECTRUM_BINS);
+        if !(1..=MAX_SPECTRUM_BINS).contains(&bins) {
+            return Err(format!(
+                "Spectrum needs between 1 and {MAX_SPECTRUM_BINS} bins"
+            ));
+        }
+        Ok(Self { rate, bins })
+    }
+}
+
+/// Payload of `player-spectrum`.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Spectrum {
+    /// Levels in dBFS of log-spaced bands from 20 Hz to 20 kHz, or Nyquist if lower.
+    pub bins: Vec<f32>,
+    /// Linear RMS over all channels since the previous event.
+    pub rms: f32,
+    /// Linear peak over all channels since the previous event.
+    pub peak: f32,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    // Mono ring buffer of the last `FFT_SIZE` frames; `next` is the oldest
+    samples: Vec<f32>,
+    next: usize,
+    sample_rate: SampleRate,
+    peak: f32,
+    sum_squares: f64,
+    count: u64,
+}
+
+/// What the analyzer takes from the tap for one event.
+struct Snapshot {
+    samples: Vec<f32>,
+    sample_rate: SampleRate,
+    rms: f32,
+    peak: f32,
+}
+
+/// Shared between the output tap and the analyzer thread.
+#[derive(Debug, Default)]
+pub struct SpectrumTap {
+    enabled: AtomicBool,
+    window: Mutex<Window>,
+}
+
+impl SpectrumTap {
+    /// Adds played audio; returns false if the analyzer holds the window right now.
+    fn push(
+        &self,
+        frames: &[f32],
+        sample_rate: SampleRate,
+        peak: f32,
+        sum_squares: f64,
+        count: u64,
+    ) -> bool {
+        let Ok(mut window) = self.window.try_lock() else {
+            return false;
+        };
+        if window.samples.len() != FFT_SIZE {
+            window.samples = vec![0.0; FFT_SIZE];
+            window.next = 0;
+        }
+        for &frame in frames {
+            let next = window.next;
+            window.samples[next] = frame;
+            window.next = (next + 1) % FFT_SIZE;
+        }
+        window.sample_rate = sample_rate;
+        window.peak = window.peak.max(peak);
+        window.sum_squares += sum_squares;
+        window.count += count;
+        true
+    }
+
+    /// The latest window in order and the levels since the last call, if anything was
+    /// played since then.
+    fn take(&self) -> Option<Snapshot> {
+        let mut window = self.window.lock().ok()?;
+        if window.count == 0 {
+            return None;
+        }
+        let (newer, older) = window.samples.split_at(window.next);
+        let samples = older.iter().chain(newer).copied().collect();
+        #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+        let rms = (window.sum_squares / window.count as f64).sqrt() as f32;
+        let snapshot = Snapshot {
+            samples,
+            sample_rate: window.sample_rate,
+            rms,
+            peak: window.peak,
+        };
+        window.peak = 0.0;
+        window.sum_squares = 0.0;
+        window.count = 0;
+        Some(snapshot)
+    }
+}
+
+/// Passes the mixed output through, copying it to a `SpectrumTap` while that is enabled.
+///
+/// When disabled it only checks the flag once per block.
+pub struct Tapped<S> {
+    inner: S,
+    tap: Arc<SpectrumTap>,
+    enabled: bool,
+    until_check: usize,
+    frame: f32,
+    channel: u16,
+    mono: Vec<f32>,
+    peak: f32,
+    sum_squares: f64,
+    count: u64,
+}
+
+impl<S: Source> Tapped<S> {
+    pub fn new(inner: S, tap: Arc<SpectrumTap>) -> Self {
+        Self {
+            inner,
+            tap,
+            enabled: false,
+            until_check: 0,
+            frame: 0.0,
+            channel: 0,
+            mono: Vec::with_capacity(TAP_BLOCK),
+            peak: 0.0,
+            sum_squares: 0.0,
+            count: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.frame = 0.0;
+        self.channel = 0;
+        self.mono.clear();
+        self.peak = 0.0;
+        self.sum_squares = 0.0;
+        self.count = 0;
+    }
+
+    fn record(&mut self, sample: f32) {
+        self.peak = self.peak.max(sample.abs());
+        self.sum_squares += f64::from(sample * sample);
+        self.count += 1;
+        self.frame += sample;
+        self.channel += 1;
+
+        let channels = self.inner.channels().max(1);
+        if self.channel < channels {
+            return;
+        }
+        self.mono.push(self.frame / f32::from(channels));
+        self.frame = 0.0;
+        self.channel = 0;
+        if self.mono.len() < TAP_BLOCK {
+            return;
+        }
+        let sample_rate = self.inner.sample_rate();
+        if self.tap.push(
+            &self.mono,
+            sample_rate,
+            self.peak,
+            self.sum_squares,
+            self.count,
+        ) {
+            self.reset();
+        } else if self.mono.len() > FFT_SIZE {
+            // Only the latest window matters
+            self.mono.drain(..self.mono.len() - FFT_SIZE);
+        }
+    }
+}
+
+impl<S: Source> Iterator for Tapped<S> {
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        let sample = self.inner.next()?;
+        if self.until_check == 0 {
+            let enabled = self.tap.enabled.load(Ordering::Relaxed);
+            if enabled != self.enabled {
+                self.enabled = enabled;
+                self.reset();
+            }
+            // Whole frames, so the check always falls on the first channel
+            self.until_check = TAP_BLOCK * usize::from(self.inner.channels().max(1));
+        }
+        self.until_check -= 1;
+        if self.enabled {
+            self.record(sample);
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Tapped<S> {
+    #[inline]
+    fn current_span_len(&self) -> Option<usize> {
+        self.inner.current_span_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)
+    }
+}
+
+/// Enables a tap and turns what it collects into spectra on its own thread, until dropped.
+pub struct SpectrumAnalyzer {
+    tap: Arc<SpectrumTap>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SpectrumAnalyzer {
+    /// Calls `emit` `config.rate` times per second while audio is being played.
+    pub fn start<F>(tap: Arc<SpectrumTap>, config: SpectrumConfig, emit: F) -> Self
+    where
+        F: Fn(Spectrum) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let source = tap.clone();
+        tap.enabled.store(true, Ordering::Relaxed);
+
+        let thread = thread::spawn(move || {
+            let interval = Duration::from_secs_f32(1.0 / config.rate);
+            let hann = hann_window(FFT_SIZE);
+            loop {
+                thread::sleep(interval);
+                if stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Some(snapshot) = source.take() {
+                    emit(Spectrum {
+                        bins: bands(&snapshot.samples, &hann, snapshot.sample_rate, config.bins),
+                        rms: snapshot.rms,
+                        peak: snapshot.peak,
+                    });
+                }
+            }
+        });
+
+        Self {
+            tap,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for SpectrumAnalyzer {
+    fn drop(&mut self) {
+        self.tap.enabled.store(false, Ordering::Relaxed);
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn hann_window(len: usize) -> Vec<f32> {
+    #[allow(clippy::cast_precision_loss)]
+    let n = len as f32;
+    (0..len)
+        .map(|i| {
+            #[allow(clippy::cast_precision_loss)]
+            let phase = 2.0 * PI * i as f32 / n;
+            0.5 - 0.5 * phase.cos()
+        })
+        .collect()
+}
+
+/// Levels in dBFS of `count` log-spaced bands; a full-scale sine reads 0 dB.
+///
+/// Each band takes the loudest FFT bin it covers, so narrow low bands that share a bin
+/// show the same level.
+#[allow(clippy::cast_precision_loss)]
+fn bands(samples: &[f32], window: &[f32], sample_rate: SampleRate, count: usize) -> Vec<f32> {
+    let mut re: Vec<f32> = samples.iter().zip(window).map(|(s, w)| s * w).collect();
+    let mut im = vec![0.0; re.len()];
+    fft(&mut re, &mut im);
+
+    let half = re.len() / 2;
+    let scale = 2.0 / window.iter().sum::<f32>();
+    let bin_hz = sample_rate as f32 / re.len() as f32;
+    let top = MAX_FREQUENCY.min(sample_rate as f32 / 2.0);
+    let ratio = top / MIN_FREQUENCY;
+    let edge = |i: usize| MIN_FREQUENCY * ratio.powf(i as f32 / count as f32) / bin_hz;
+
+    (0..count)
+        .map(|i| {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let first = (edge(i).floor() as usize).min(half - 1);
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let last = (edge(i + 1).ceil() as usize).clamp(first + 1, half);
+            let magnitude = (first..last)
+                .map(|k| re[k].hypot(im[k]) * scale)
+                .fold(0.0, f32::max);
+            (20.0 * magnitude.log10()).max(FLOOR_DB)
+        })
+        .collect()
+}
+
+/// In-place iterative radix-2 FFT; the length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        #[allow(clippy::cast_precision_loss)]
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                #[allow(clippy::cast_precision_loss)]
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+    use std::sync::mpsc;
+
+    fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| {
+                #[allow(clippy::cast_precision_loss)]
+                let t = i as f32 / 44_100.0;
+                (2.0 * PI * frequency * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fft_finds_sine_bin() {
+        // Exactly 8 periods in 64 samples
+        let mut re: Vec<f32> = (0..64)
+            .map(|i| {
+                #[allow(clippy::cast_precision_loss)]
+                let phase = 2.0 * PI * 8.0 * i as f32 / 64.0;
+                phase.sin()
+            })
+            .collect();
+        let mut im = vec![0.0; 64];
+        fft(&mut re, &mut im);
+        let magnitudes: Vec<f32> = re.iter().zip(&im).map(|(r, i)| r.hypot(*i)).collect();
+        assert!((magnitudes[8] - 32.0).abs() < 1e-3);
+        assert!(magnitudes[..32]
+            .iter()
+            .enumerate()
+            .all(|(k, m)| k == 8 || *m < 1e-3));
+    }
+
+    #[test]
+    fn test_sine_lands_in_its_band() {
+        let levels = bands(&sine(1000.0, FFT_SIZE), &hann_window(FFT_SIZE), 44_100, 32);
+        assert_eq!(levels.len(), 32);
+        let loudest = levels
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i)
+            .unwrap();
+        // 1 kHz sits in band 18 of 32 between 20 Hz and 20 kHz
+        assert_eq!(loudest, 18);
+        assert!(levels[loudest] > -2.0 && levels[loudest] <= 0.5);
+        assert!(levels[0] < -60.0 && levels[31] < -60.0);
+    }
+
+    #[test]
+    fn test_tap_only_collects_while_enabled() {
+        let tap = Arc::new(SpectrumTap::default());
+        let samples = sine(1000.0, FFT_SIZE * 2)
+            .into_iter()
+            .flat_map(|s| [s * 0.5, s * 0.5])
+            .collect::<Vec<_>>();
+        let mut tapped = Tapped::new(SamplesBuffer::new(2, 44_100, samples.clone()), tap.clone());
+
+        // Played unchanged, and nothing reaches the tap
+        let played: Vec<f32> = tapped.by_ref().take(TAP_BLOCK * 4).collect();
+        assert_eq!(played, samples[..TAP_BLOCK * 4]);
+        assert!(tap.take().is_none());
+
+        let (tx, rx) = mpsc::channel();
+        let config = SpectrumConfig::new(Some(MAX_SPECTRUM_RATE), Some(8)).unwrap();
+        let analyzer = SpectrumAnalyzer::start(tap.clone(), config, move |s| {
+            tx.send(s).ok();
+        });
+        assert_eq!(tapped.count(), samples.len() - TAP_BLOCK * 4);
+        let spectrum = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(analyzer);
+
+        assert_eq!(spectrum.bins.len(), 8);
+        assert!((spectrum.peak - 0.5).abs() < 1e-3);
+        assert!((spectrum.rms - 0.5 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-2);
+        assert!(!tap.enabled.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_config_limits() {
+        assert_eq!(
+            SpectrumConfig::new(None, None).unwrap(),
+            SpectrumConfig {
+                rate: DEFAULT_SPECTRUM_RATE,
+                bins: DEFAULT_SPECTRUM_BINS
+            }
+        );
+        assert!(SpectrumConfig::new(Some(0.0), None).is_err());
+        assert!(SpectrumConfig::new(Some(f32::NAN), None).is_err());
+        assert!(SpectrumConfig::new(None, Some(0)).is_err());
+        assert!(SpectrumConfig::new(None, Some(MAX_SPECTRUM_BINS + 1)).is_err());
+    }
+}
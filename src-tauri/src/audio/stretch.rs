@@ -0,0 +1,434 @@
+use rodio::source::SeekError;
+use rodio::{ChannelCount, Sample, SampleRate, Source};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+/// Pitch shift limit in either direction, in semitones.
+pub const MAX_PITCH: f32 = 12.0;
+
+/// Crossfade between consecutive segments; segments are three times as long.
+const OVERLAP: Duration = Duration::from_millis(15);
+/// How far from its nominal position a segment may be taken to line up with the previous one.
+const SEARCH: Duration = Duration::from_millis(10);
+
+/// Speed and pitch shared by every `TimeStretch` the audio thread creates.
+#[derive(Debug)]
+pub struct SpeedControl {
+    speed: AtomicU32,
+    pitch: AtomicU32,
+}
+
+impl Default for SpeedControl {
+    fn default() -> Self {
+        Self {
+            speed: AtomicU32::new(1.0f32.to_bits()),
+            pitch: AtomicU32::new(0.0f32.to_bits()),
+        }
+    }
+}
+
+impl SpeedControl {
+    /// Playback speed without changing pitch, clamped to `MIN_SPEED..=MAX_SPEED`.
+    pub fn set_speed(&self, speed: f32) {
+        let speed = if speed.is_finite() { speed } else { 1.0 };
+        self.speed.store(
+            speed.clamp(MIN_SPEED, MAX_SPEED).to_bits(),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Pitch shift in semitones without changing speed, clamped to `±MAX_PITCH`.
+    pub fn set_pitch(&self, semitones: f32) {
+        let semitones = if semitones.is_finite() {
+            semitones
+        } else {
+            0.0
+        };
+        self.pitch.store(
+            semitones.clamp(-MAX_PITCH, MAX_PITCH).to_bits(),
+            Ordering::Relaxed,
+        );
+    }
+
+    #[must_use]
+    pub fn speed(&self) -> f32 {
+        f32::from_bits(self.speed.load(Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub fn pitch(&self) -> f32 {
+        f32::from_bits(self.pitch.load(Ordering::Relaxed))
+    }
+
+    fn pitch_ratio(&self) -> f32 {
+        2f32.powf(self.pitch() / 12.0)
+    }
+}
+
+/// Changes tempo and pitch independently.
+///
+/// Tempo uses WSOLA: the input is cut into overlapping segments that are laid out at a
+/// different hop than they were taken at, each one shifted slightly so its waveform
+/// lines up with the end of the previous segment before they are crossfaded. Pitch is
+/// shifted by stretching by the pitch ratio as well and resampling the result back.
+/// At normal speed and pitch samples pass through untouched.
+pub struct TimeStretch<S> {
+    inner: S,
+    control: Arc<SpeedControl>,
+    channels: usize,
+    overlap: usize,
+    search: usize,
+    active: bool,
+    inner_done: bool,
+    // Buffered input frames (interleaved); `input_start` is the frame index of the first one
+    input: Vec<f32>,
+    input_start: usize,
+    // Frame index where the next segment would ideally start
+    nominal: f64,
+    // Last `overlap` frames of the previous segment, to be crossfaded into the next one
+    tail: Vec<f32>,
+    // Tempo-stretched frames waiting to be resampled
+    stretched: VecDeque<f32>,
+    resample_pos: f64,
+    frame: Vec<f32>,
+    frame_index: usize,
+}
+
+impl<S: Source> TimeStretch<S> {
+    pub fn new(inner: S, control: Arc<SpeedControl>) -> Self {
+        let channels = usize::from(inner.channels()).max(1);
+        let rate = inner.sample_rate() as usize;
+        let frames = |d: Duration| (rate * d.as_millis() as usize / 1000).max(1);
+        Self {
+            channels,
+            overlap: frames(OVERLAP),
+            search: frames(SEARCH),
+            inner,
+            control,
+            active: false,
+            inner_done: false,
+            input: Vec::new(),
+            input_start: 0,
+            nominal: 0.0,
+            tail: Vec::new(),
+            stretched: VecDeque::new(),
+            resample_pos: 0.0,
+            frame: Vec::new(),
+            frame_index: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.input.clear();
+        self.input_start = 0;
+        self.nominal = 0.0;
+        self.tail.clear();
+        self.stretched.clear();
+        self.resample_pos = 0.0;
+        self.frame.clear();
+        self.frame_index = 0;
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.input_start + self.input.len() / self.channels
+    }
+
+    /// Reads from the inner source until frame `end` is buffered or it runs out.
+    fn fill_to(&mut self, end: usize) {
+        while !self.inner_done && self.buffered_frames() < end {
+            let missing = (end - self.buffered_frames()) * self.channels;
+            let before = self.input.len();
+            self.input.extend(self.inner.by_ref().take(missing));
+            if self.input.len() - before < missing {
+                self.inner_done = true;
+                // Drop a partial frame at the very end
+                self.input
+                    .truncate(self.input.len() - self.input.len() % self.channels);
+            }
+        }
+    }
+
+    fn input_frame(&self, frame: usize) -> &[f32] {
+        let start = (frame - self.input_start) * self.channels;
+        &self.input[start..start + self.channels]
+    }
+
+    /// Frame in `lo..=hi` whose next `overlap` frames best match the current tail.
+    fn best_offset(&self, lo: usize, hi: usize) -> usize {
+        if self.tail.is_empty() {
+            return lo;
+        }
+        let mono = |frame: &[f32]| frame.iter().sum::<f32>();
+        let tail: Vec<f32> = self.tail.chunks(self.channels).map(mono).collect();
+
+        let mut best = lo;
+        let mut best_score = f32::MIN;
+        for candidate in lo..=hi {
+            let mut dot = 0.0;
+            let mut energy = 0.0;
+            for (k, t) in tail.iter().enumerate() {
+                let x = mono(self.input_frame(candidate + k));
+                dot += t * x;
+                energy += x * x;
+            }
+            let score = dot / energy.sqrt().max(1e-9);
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+
+    /// Appends one segment hop to `stretched`. Returns `false` once the input is used up.
+    fn stretch_step(&mut self, rate: f64) -> bool {
+        let overlap = self.overlap;
+        let hop = 2 * overlap;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let nominal = self.nominal.round().max(0.0) as usize;
+        let (lo, mut hi) = if self.tail.is_empty() {
+            (nominal, nominal)
+        } else {
+            (
+                nominal.saturating_sub(self.search).max(self.input_start),
+                nominal + self.search,
+            )
+        };
+
+        self.fill_to(hi + 3 * overlap);
+        let available = self.buffered_frames();
+        if available < lo + 3 * overlap {
+            // Less than a segment left, which is less than 50 ms: end with the tail
+            self.stretched.extend(self.tail.drain(..));
+            self.input.clear();
+            self.input_start = available;
+            return false;
+        }
+        hi = hi.min(available - 3 * overlap);
+
+        let start = self.best_offset(lo, hi);
+        for k in 0..overlap {
+            #[allow(clippy::cast_precision_loss)]
+            let w = (k as f32 + 0.5) / overlap as f32;
+            for ch in 0..self.channels {
+                let x = self.input_frame(start + k)[ch];
+                let sample = match self.tail.get(k * self.channels + ch) {
+                    Some(t) => t * (1.0 - w) + x * w,
+                    None => x,
+                };
+                self.stretched.push_back(sample);
+            }
+        }
+        for f in start + overlap..start + hop {
+            let frame = self.input_frame(f).to_vec();
+            self.stretched.extend(frame);
+        }
+        self.tail = (start + hop..start + hop + overlap)
+            .flat_map(|f| self.input_frame(f).to_vec())
+            .collect();
+
+        #[allow(clippy::cast_precision_loss)]
+        {
+            self.nominal += hop as f64 * rate;
+        }
+        // Keep only what the next search can still reach
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let keep_from = (self.nominal as usize).saturating_sub(self.search);
+        let keep_from = keep_from.min(start + hop).max(self.input_start);
+        let drop = keep_from - self.input_start;
+        self.input.drain(..drop * self.channels);
+        self.input_start = keep_from;
+        true
+    }
+
+    /// Produces the next output frame into `self.frame`. Returns `false` at the end.
+    fn next_frame(&mut self, speed: f32, ratio: f32) -> bool {
+        let channels = self.channels;
+        loop {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let index = self.resample_pos as usize;
+            if (index + 2) * channels <= self.stretched.len() {
+                break;
+            }
+            if !self.stretch_step(f64::from(speed / ratio)) {
+                break;
+            }
+        }
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let index = self.resample_pos as usize;
+        let frames = self.stretched.len() / channels;
+        if index >= frames {
+            return false;
+        }
+        #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+        let frac = (self.resample_pos - index as f64) as f32;
+        let next = (index + 1).min(frames - 1);
+        self.frame.clear();
+        for ch in 0..channels {
+            let a = self.stretched[index * channels + ch];
+            let b = self.stretched[next * channels + ch];
+            self.frame.push(a + (b - a) * frac);
+        }
+        self.frame_index = 0;
+
+        self.resample_pos += f64::from(ratio);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let consumed = (self.resample_pos as usize).min(frames);
+        self.stretched.drain(..consumed * channels);
+        #[allow(clippy::cast_precision_loss)]
+        {
+            self.resample_pos -= consumed as f64;
+        }
+        true
+    }
+}
+
+impl<S: Source> Iterator for TimeStretch<S> {
+    type Item = Sample;
+
+    #[inline]
+    fn next(&mut self) -> Option<Sample> {
+        if let Some(&sample) = self.frame.get(self.frame_index) {
+            self.frame_index += 1;
+            return Some(sample);
+        }
+
+        let speed = self.control.speed();
+        let ratio = self.control.pitch_ratio();
+        let neutral = (speed - 1.0).abs() < 1e-3 && (ratio - 1.0).abs() < 1e-3;
+        if neutral {
+            if self.active {
+                // Whatever is still buffered is dropped; the inner source carries on
+                self.active = false;
+                self.reset();
+            }
+            return self.inner.next();
+        }
+
+        if !self.active {
+            self.active = true;
+            self.reset();
+        }
+        if !self.next_frame(speed, ratio) {
+            return None;
+        }
+        self.frame_index = 1;
+        self.frame.first().copied()
+    }
+}
+
+impl<S: Source> Source for TimeStretch<S> {
+    #[inline]
+    fn current_span_len(&self) -> Option<usize> {
+        // Buffering makes the inner span boundaries meaningless here
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> ChannelCount {
+        self.inner.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> SampleRate {
+        self.inner.sample_rate()
+    }
+
+    /// Length in source time, like positions reported by the player.
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        self.inner_done = false;
+        self.reset();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+    use std::f32::consts::TAU;
+
+    const RATE: u32 = 8000;
+
+    fn sine(frequency: f32, secs: f32) -> SamplesBuffer {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let len = (RATE as f32 * secs) as usize;
+        #[allow(clippy::cast_precision_loss)]
+        let samples = (0..len)
+            .map(|i| 0.5 * (TAU * frequency * i as f32 / RATE as f32).sin())
+            .collect::<Vec<_>>();
+        SamplesBuffer::new(1, RATE, samples)
+    }
+
+    fn stretched(speed: f32, semitones: f32) -> Vec<f32> {
+        let control = Arc::new(SpeedControl::default());
+        control.set_speed(speed);
+        control.set_pitch(semitones);
+        TimeStretch::new(sine(200.0, 2.0), control).collect()
+    }
+
+    /// Estimates the frequency from upward zero crossings, skipping the edges.
+    fn frequency(samples: &[f32]) -> f32 {
+        let body = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        let crossings = body
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        #[allow(clippy::cast_precision_loss)]
+        let secs = body.len() as f32 / RATE as f32;
+        #[allow(clippy::cast_precision_loss)]
+        let hz = crossings as f32 / secs;
+        hz
+    }
+
+    #[test]
+    fn test_neutral_is_passthrough() {
+        let input: Vec<f32> = sine(200.0, 0.5).collect();
+        let output: Vec<f32> =
+            TimeStretch::new(sine(200.0, 0.5), Arc::new(SpeedControl::default())).collect();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_speed_keeps_pitch() {
+        for speed in [0.5, 1.5, 3.0] {
+            let output = stretched(speed, 0.0);
+            #[allow(clippy::cast_precision_loss)]
+            let expected = 16000.0 / speed;
+            #[allow(clippy::cast_precision_loss)]
+            let len = output.len() as f32;
+            assert!((len - expected).abs() / expected < 0.05, "{speed}: {len}");
+            assert!((frequency(&output) - 200.0).abs() < 10.0, "{speed}");
+        }
+    }
+
+    #[test]
+    fn test_pitch_keeps_speed() {
+        let output = stretched(1.0, 12.0);
+        #[allow(clippy::cast_precision_loss)]
+        let len = output.len() as f32;
+        assert!((len - 16000.0).abs() / 16000.0 < 0.05, "{len}");
+        assert!((frequency(&output) - 400.0).abs() < 15.0);
+    }
+
+    #[test]
+    fn test_controls_are_clamped() {
+        let control = SpeedControl::default();
+        control.set_speed(10.0);
+        control.set_pitch(-40.0);
+        assert!((control.speed() - MAX_SPEED).abs() < f32::EPSILON);
+        assert!((control.pitch() + MAX_PITCH).abs() < f32::EPSILON);
+    }
+}
@@ -1,25 +1,44 @@
 use super::commands;
-use super::player::{AudioCommand, AudioPlayerState};
+use super::equalizer::EqBand;
+use super::gain::NormalizationMode;
+use super::output::{WavBackend, RENDER_CHANNELS, RENDER_SAMPLE_RATE};
+use super::player::{init_audio_thread, AudioCommand, AudioPlayerState, EndReason};
+use super::queue::{QueueState, RepeatMode, ShuffleMode};
+use super::sleep::SleepMode;
+use crate::database::schema::create_tables;
+use crate::database::{operations, AppState};
+use crate::scanner::parser::{ReplayGain, TrackMetadata, TrackTags};
+use crate::test_util::write_wav;
+use rodio::Decoder;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
 use std::sync::{mpsc, Mutex};
-use tauri::State;
+use std::time::Duration;
+use tauri::test::{mock_app, MockRuntime};
+use tauri::{App, Listener, Manager};
 
-// Helper to create a dummy state for testing commands
-fn create_test_state() -> (AudioPlayerState, mpsc::Receiver<AudioCommand>) {
+/// A mock app with an empty library, managing a player state whose commands arrive at
+/// the returned receiver instead of an audio thread.
+fn mock_player() -> (App<MockRuntime>, mpsc::Receiver<AudioCommand>) {
     let (tx, rx) = mpsc::channel();
-    let state = AudioPlayerState { tx: Mutex::new(tx) };
-    (state, rx)
+    let app = mock_app();
+    let conn = Connection::open_in_memory().unwrap();
+    create_tables(&conn).unwrap();
+    app.manage(AppState {
+        db: Mutex::new(conn),
+    });
+    app.manage(AudioPlayerState::new(tx));
+    (app, rx)
 }
 
 #[test]
 fn test_play_command() {
-    let (state, rx) = create_test_state();
-    // Safety: State wrapper for testing. In real Tauri, State manages lifetime.
-    // Here we just cast reference to pretend it's State.
-    let state_ref =
-        unsafe { std::mem::transmute::<&AudioPlayerState, State<'_, AudioPlayerState>>(&state) };
+    let (app, rx) = mock_player();
 
     let path = "/path/to/song.mp3".to_string();
-    let result = commands::play(path.clone(), state_ref);
+    let result = commands::play(path.clone(), app.state(), app.state());
 
     assert!(result.is_ok());
 
@@ -31,11 +50,9 @@ fn test_play_command() {
 
 #[test]
 fn test_pause_command() {
-    let (state, rx) = create_test_state();
-    let state_ref =
-        unsafe { std::mem::transmute::<&AudioPlayerState, State<'_, AudioPlayerState>>(&state) };
+    let (app, rx) = mock_player();
 
-    let result = commands::pause(state_ref);
+    let result = commands::pause(app.state());
     assert!(result.is_ok());
 
     match rx.try_recv() {
@@ -46,11 +63,9 @@ fn test_pause_command() {
 
 #[test]
 fn test_resume_command() {
-    let (state, rx) = create_test_state();
-    let state_ref =
-        unsafe { std::mem::transmute::<&AudioPlayerState, State<'_, AudioPlayerState>>(&state) };
+    let (app, rx) = mock_player();
 
-    let result = commands::resume(state_ref);
+    let result = commands::resume(app.state());
     assert!(result.is_ok());
 
     match rx.try_recv() {
@@ -61,11 +76,9 @@ fn test_resume_command() {
 
 #[test]
 fn test_stop_command() {
-    let (state, rx) = create_test_state();
-    let state_ref =
-        unsafe { std::mem::transmute::<&AudioPlayerState, State<'_, AudioPlayerState>>(&state) };
+    let (app, rx) = mock_player();
 
-    let result = commands::stop(state_ref);
+    let result = commands::stop(app.state());
     assert!(result.is_ok());
 
     match rx.try_recv() {
@@ -76,11 +89,9 @@ fn test_stop_command() {
 
 #[test]
 fn test_seek_command() {
-    let (state, rx) = create_test_state();
-    let state_ref =
-        unsafe { std::mem::transmute::<&AudioPlayerState, State<'_, AudioPlayerState>>(&state) };
+    let (app, rx) = mock_player();
 
-    let result = commands::seek(120.5, state_ref);
+    let result = commands::seek(120.5, app.state());
     assert!(result.is_ok());
 
     match rx.try_recv() {
@@ -91,11 +102,9 @@ fn test_seek_command() {
 
 #[test]
 fn test_set_volume_command() {
-    let (state, rx) = create_test_state();
-    let state_ref =
-        unsafe { std::mem::transmute::<&AudioPlayerState, State<'_, AudioPlayerState>>(&state) };
+    let (app, rx) = mock_player();
 
-    let result = commands::set_volume(0.8, state_ref);
+    let result = commands::set_volume(0.8, app.state());
     assert!(result.is_ok());
 
     match rx.try_recv() {
@@ -107,13 +116,393 @@ fn test_set_volume_command() {
 #[test]
 fn test_channel_error_handling() {
     // Test that commands return error when channel receiver is dropped
-    let (tx, rx) = mpsc::channel();
-    let state = AudioPlayerState { tx: Mutex::new(tx) };
+    let (app, rx) = mock_player();
     drop(rx); // Drop receiver to cause SendError
 
-    let state_ref =
-        unsafe { std::mem::transmute::<&AudioPlayerState, State<'_, AudioPlayerState>>(&state) };
-
-    let result = commands::stop(state_ref);
+    let result = commands::stop(app.state());
     assert!(result.is_err());
 }
+
+#[test]
+fn test_set_crossfade_command() {
+    let (app, rx) = mock_player();
+
+    let result = commands::set_crossfade(6.0, true, app.state());
+    assert!(result.is_ok());
+
+    match rx.try_recv() {
+        Ok(AudioCommand::SetCrossfade {
+            seconds,
+            gapless_albums,
+        }) => {
+            assert!((seconds - 6.0).abs() < f32::EPSILON);
+            assert!(gapless_albums);
+        }
+        _ => panic!("Expected SetCrossfade command"),
+    }
+}
+
+#[test]
+fn test_set_transport_fade_command() {
+    let (app, rx) = mock_player();
+
+    // Longer ramps are clamped
+    assert!(commands::set_transport_fade(60_000, app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetTransportFade(ramp)) => assert_eq!(ramp, Duration::from_secs(2)),
+        _ => panic!("Expected SetTransportFade command"),
+    }
+}
+
+#[test]
+fn test_repeat_and_shuffle_commands() {
+    let (app, rx) = mock_player();
+
+    assert!(commands::set_repeat(RepeatMode::All, app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetRepeat(mode)) => assert_eq!(mode, RepeatMode::All),
+        _ => panic!("Expected SetRepeat command"),
+    }
+
+    assert!(commands::set_shuffle(ShuffleMode::Smart, Some(42), app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetShuffle(mode, seed)) => {
+            assert_eq!(mode, ShuffleMode::Smart);
+            assert_eq!(seed, Some(42));
+        }
+        _ => panic!("Expected SetShuffle command"),
+    }
+}
+
+#[test]
+fn test_set_normalization_command() {
+    let (app, rx) = mock_player();
+
+    let result = commands::set_normalization(NormalizationMode::Album, 3.0, true, app.state());
+    assert!(result.is_ok());
+
+    match rx.try_recv() {
+        Ok(AudioCommand::SetNormalization(normalization)) => {
+            assert_eq!(normalization.mode, NormalizationMode::Album);
+            assert!((normalization.preamp_db - 3.0).abs() < f32::EPSILON);
+            assert!(normalization.prevent_clipping);
+        }
+        _ => panic!("Expected SetNormalization command"),
+    }
+
+    // Pre-amps are kept to a range that cannot blow up the gain
+    commands::set_normalization(NormalizationMode::Track, 400.0, false, app.state()).unwrap();
+    match rx.try_recv() {
+        Ok(AudioCommand::SetNormalization(normalization)) => {
+            assert!((normalization.preamp_db - 15.0).abs() < f32::EPSILON);
+        }
+        _ => panic!("Expected SetNormalization command"),
+    }
+    for preamp_db in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        let result =
+            commands::set_normalization(NormalizationMode::Track, preamp_db, false, app.state());
+        assert!(result.is_err());
+    }
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_equalizer_commands() {
+    let (app, rx) = mock_player();
+
+    let band = EqBand {
+        frequency: 250.0,
+        gain_db: -3.0,
+        q: 1.0,
+    };
+    assert!(commands::set_equalizer(vec![band], app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetEqualizer(bands)) => assert_eq!(bands, vec![band]),
+        _ => panic!("Expected SetEqualizer command"),
+    }
+
+    assert!(commands::set_eq_band(2, 250.0, -3.0, 1.0, app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetEqBand(index, b)) => {
+            assert_eq!(index, 2);
+            assert_eq!(b, band);
+        }
+        _ => panic!("Expected SetEqBand command"),
+    }
+
+    // Bands that would blow up the filter never reach the audio thread
+    for (frequency, gain_db, q) in [
+        (250.0, 400.0, 1.0),
+        (250.0, f32::NAN, 1.0),
+        (5.0, 0.0, 1.0),
+        (30_000.0, 0.0, 1.0),
+        (250.0, 0.0, 0.0),
+        (250.0, 0.0, f32::INFINITY),
+    ] {
+        assert!(commands::set_eq_band(0, frequency, gain_db, q, app.state()).is_err());
+        let bad = EqBand {
+            frequency,
+            gain_db,
+            q,
+        };
+        assert!(commands::set_equalizer(vec![band, bad], app.state()).is_err());
+        assert!(commands::save_eq_preset("Loud".to_string(), vec![bad], app.state()).is_err());
+    }
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_speed_and_pitch_commands() {
+    let (app, rx) = mock_player();
+
+    assert!(commands::set_speed(1.5, app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetSpeed(speed)) => assert!((speed - 1.5).abs() < f32::EPSILON),
+        _ => panic!("Expected SetSpeed command"),
+    }
+
+    assert!(commands::set_pitch(-2.0, app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetPitch(semitones)) => assert!((semitones + 2.0).abs() < f32::EPSILON),
+        _ => panic!("Expected SetPitch command"),
+    }
+}
+
+#[test]
+fn test_sleep_timer_commands() {
+    let (app, rx) = mock_player();
+
+    commands::set_sleep_timer(SleepMode::EndOfTrack, None, Some(5.0), app.state()).unwrap();
+    match rx.try_recv() {
+        Ok(AudioCommand::SetSleepTimer(Some(timer))) => assert!(timer.on_last_track()),
+        _ => panic!("Expected SetSleepTimer command"),
+    }
+
+    // Invalid amounts never reach the audio thread
+    assert!(commands::set_sleep_timer(SleepMode::Minutes, None, None, app.state()).is_err());
+    for fade in [-1.0, f32::NAN, f32::INFINITY, f32::MAX] {
+        let result =
+            commands::set_sleep_timer(SleepMode::EndOfTrack, None, Some(fade), app.state());
+        assert!(result.is_err());
+    }
+    assert!(rx.try_recv().is_err());
+
+    commands::cancel_sleep_timer(app.state()).unwrap();
+    assert!(matches!(
+        rx.try_recv(),
+        Ok(AudioCommand::SetSleepTimer(None))
+    ));
+}
+
+#[test]
+fn test_loop_commands() {
+    let (app, rx) = mock_player();
+
+    commands::set_loop(12.5, 20.0, app.state()).unwrap();
+    match rx.try_recv() {
+        Ok(AudioCommand::SetLoop { start, end }) => assert_eq!((start, end), (12.5, 20.0)),
+        _ => panic!("Expected SetLoop command"),
+    }
+
+    // Empty, reversed and negative regions never reach the audio thread
+    for (start, end) in [(5.0, 5.0), (8.0, 2.0), (-1.0, 3.0), (0.0, f32::INFINITY)] {
+        assert!(commands::set_loop(start, end, app.state()).is_err());
+    }
+    assert!(rx.try_recv().is_err());
+
+    commands::clear_loop(app.state()).unwrap();
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::ClearLoop)));
+}
+
+#[test]
+fn test_spectrum_commands() {
+    let (app, rx) = mock_player();
+
+    commands::enable_spectrum(Some(20.0), None, app.state()).unwrap();
+    match rx.try_recv() {
+        Ok(AudioCommand::SetSpectrum(Some(config))) => {
+            assert_eq!(config.rate, 20.0);
+            assert_eq!(config.bins, 64);
+        }
+        _ => panic!("Expected SetSpectrum command"),
+    }
+
+    assert!(commands::enable_spectrum(None, Some(0), app.state()).is_err());
+    assert!(rx.try_recv().is_err());
+
+    commands::disable_spectrum(app.state()).unwrap();
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::SetSpectrum(None))));
+}
+
+#[test]
+fn test_enqueue_commands() {
+    let (app, rx) = mock_player();
+
+    let paths = vec!["/a.mp3".to_string(), "/b.mp3".to_string()];
+    assert!(commands::enqueue(paths.clone(), app.state(), app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::Enqueue(p)) => assert_eq!(p, paths),
+        _ => panic!("Expected Enqueue command"),
+    }
+
+    // Smart shuffle gets the artists of library tracks ahead of the tracks themselves
+    {
+        let library = app.state::<AppState>();
+        let mut conn = library.db.lock().unwrap();
+        let track = TrackMetadata {
+            id: 0,
+            path: "/b.mp3".to_string(),
+            title: None,
+            artist: Some("Artist".to_string()),
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        };
+        operations::add_tracks(&mut conn, &[track]).unwrap();
+    }
+    assert!(commands::enqueue_next(paths.clone(), app.state(), app.state()).is_ok());
+    match rx.try_recv() {
+        Ok(AudioCommand::SetArtists(artists)) => assert_eq!(
+            artists,
+            HashMap::from([("/b.mp3".to_string(), "Artist".to_string())])
+        ),
+        _ => panic!("Expected SetArtists command"),
+    }
+    match rx.try_recv() {
+        Ok(AudioCommand::EnqueueNext(p)) => assert_eq!(p, paths),
+        _ => panic!("Expected EnqueueNext command"),
+    }
+}
+
+#[test]
+fn test_queue_navigation_commands() {
+    let (app, rx) = mock_player();
+
+    assert!(commands::next(app.state()).is_ok());
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::Next)));
+
+    assert!(commands::previous(app.state()).is_ok());
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::Previous)));
+
+    assert!(commands::next_chapter(app.state()).is_ok());
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::NextChapter)));
+
+    assert!(commands::previous_chapter(app.state()).is_ok());
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::PreviousChapter)));
+
+    assert!(commands::seek_chapter(2, app.state()).is_ok());
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::SeekChapter(2))));
+
+    assert!(commands::clear_queue(app.state()).is_ok());
+    assert!(matches!(rx.try_recv(), Ok(AudioCommand::ClearQueue)));
+
+    assert!(commands::move_in_queue(2, 0, app.state()).is_ok());
+    assert!(matches!(
+        rx.try_recv(),
+        Ok(AudioCommand::MoveInQueue { from: 2, to: 0 })
+    ));
+
+    assert!(commands::remove_from_queue(1, app.state()).is_ok());
+    assert!(matches!(
+        rx.try_recv(),
+        Ok(AudioCommand::RemoveFromQueue(1))
+    ));
+}
+
+#[test]
+fn test_get_queue_command() {
+    let (app, rx) = mock_player();
+
+    // Stand in for the audio thread and answer the snapshot request
+    let responder = std::thread::spawn(move || match rx.recv() {
+        Ok(AudioCommand::GetQueue(reply)) => {
+            reply
+                .send(QueueState {
+                    tracks: vec!["/a.mp3".to_string()],
+                    current_index: Some(0),
+                })
+                .unwrap();
+        }
+        _ => panic!("Expected GetQueue command"),
+    });
+
+    let queue = commands::get_queue(app.state()).unwrap();
+    responder.join().unwrap();
+
+    assert_eq!(queue.tracks, vec!["/a.mp3".to_string()]);
+    assert_eq!(queue.current_index, Some(0));
+}
+
+#[test]
+fn test_end_reason_serialization() {
+    // The frontend matches on these strings in `track-ended` and `queue-finished`
+    let reasons = [
+        EndReason::Finished,
+        EndReason::Skipped,
+        EndReason::Error,
+        EndReason::Stopped,
+    ];
+    let names: Vec<String> = reasons
+        .iter()
+        .map(|r| serde_json::to_string(r).unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        vec!["\"finished\"", "\"skipped\"", "\"error\"", "\"stopped\""]
+    );
+}
+
+#[test]
+fn test_gapless_tracks_join_without_a_gap() {
+    let dir = tempfile::tempdir().unwrap();
+    // One second each, at the output format so nothing is resampled at the join
+    let frames = usize::try_from(RENDER_SAMPLE_RATE).unwrap();
+    let track_len = frames * usize::from(RENDER_CHANNELS);
+    let paths: Vec<String> = ["a.wav", "b.wav"]
+        .iter()
+        .map(|name| {
+            let path = dir.path().join(name);
+            write_wav(
+                &path,
+                RENDER_CHANNELS,
+                RENDER_SAMPLE_RATE,
+                &vec![0.5; track_len],
+            );
+            path.to_str().unwrap().to_string()
+        })
+        .collect();
+    let rendered = dir.path().join("rendered.wav");
+
+    let app = mock_app();
+    let (tx, finished) = mpsc::channel();
+    app.listen_any("queue-finished", move |_| {
+        tx.send(()).ok();
+    });
+    let player = init_audio_thread(app.handle().clone(), WavBackend::new(&rendered, 4.0));
+    let send = |cmd| player.tx.lock().unwrap().send(cmd).unwrap();
+    send(AudioCommand::Play(paths[0].clone()));
+    send(AudioCommand::Enqueue(vec![paths[1].clone()]));
+    finished.recv_timeout(Duration::from_secs(30)).unwrap();
+
+    // The second track follows the first sample for sample: no silence, nothing lost
+    let samples: Vec<f32> = Decoder::new(BufReader::new(File::open(&rendered).unwrap()))
+        .unwrap()
+        .collect();
+    let first = samples.iter().position(|s| *s > 0.25).unwrap();
+    let last = samples.iter().rposition(|s| *s > 0.25).unwrap();
+    let gaps = samples[first..=last].iter().filter(|s| **s <= 0.25).count();
+    assert_eq!(gaps, 0);
+    let played = last + 1 - first;
+    assert!(
+        played.abs_diff(2 * track_len) < track_len / 100,
+        "played {played} samples of {}",
+        2 * track_len
+    );
+}
@@ -1,6 +1,11 @@
-use crate::scanner::parser::TrackMetadata;
+use crate::audio::equalizer::{EqBand, EqPreset, EqScope};
+use crate::scanner::chapters::Chapter;
+use crate::scanner::cue::CueRange;
+use crate::scanner::loudness::Loudness;
+use crate::scanner::parser::{FileStamp, ReplayGain, TrackMetadata, TrackTags};
 use rusqlite::{params, Connection, Result};
 use serde::Serialize;
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Clone)]
@@ -10,6 +15,11 @@ pub struct LocalFolder {
     pub path: String,
     #[serde(rename = "songCount")]
     pub song_count: i32,
+    /// Tracks in this folder resume where they were left, whatever their length.
+    #[serde(rename = "rememberPosition")]
+    pub remember_position: bool,
+    /// Changes on disk are picked up while the app runs.
+    pub watch: bool,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -20,34 +30,365 @@ pub struct Playlist {
     pub created_at: String,
 }
 
-/// Adds multiple tracks to the database.
+/// A saved A-B loop of a track.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct LoopRegion {
+    pub id: i64,
+    pub path: String,
+    pub name: String,
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Where a long-form track was left.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ResumePosition {
+    pub path: String,
+    pub title: Option<String>,
+    pub position: f64,
+    pub duration: f64,
+    /// Unix time of the last update.
+    #[serde(rename = "updatedAt")]
+    pub updated_at: i64,
+}
+
+/// Adds multiple tracks to the database and returns the IDs of those that were not in it
+/// yet.
 ///
 /// # Errors
 ///
 /// Returns an error if the transaction fails or if any insertion fails.
-pub fn add_tracks(conn: &mut Connection, tracks: &[TrackMetadata]) -> Result<()> {
+pub fn add_tracks(conn: &mut Connection, tracks: &[TrackMetadata]) -> Result<Vec<i64>> {
     let tx = conn.transaction()?;
+    let mut added = Vec::new();
     {
-        let mut stmt = tx.prepare(
-            "INSERT OR IGNORE INTO tracks (path, title, artist, album, duration, cover_mime, has_cover, cover_img_path) 
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-        )?;
+        let mut stmt = tx.prepare(&format!(
+            "INSERT OR IGNORE INTO tracks ({TRACK_COLUMNS}) VALUES ({})",
+            track_placeholders()
+        ))?;
         for track in tracks {
-            // Cast u64 to i64 for SQLite. Assuming duration fits in i64.
-            let duration_i64 = i64::try_from(track.duration_secs).unwrap_or(0);
-            stmt.execute(params![
-                track.path,
-                track.title,
-                track.artist,
-                track.album,
-                duration_i64,
-                track.cover_mime,
-                track.has_cover,
-                track.cover_img_path
-            ])?;
+            let inserted = stmt.execute(rusqlite::params_from_iter(track_params(track)))?;
+            // Tracks already in the library keep their chapters
+            if inserted == 0 {
+                continue;
+            }
+            let track_id = tx.last_insert_rowid();
+            insert_chapters(&tx, track_id, track)?;
+            added.push(track_id);
         }
     }
-    tx.commit()
+    tx.commit()?;
+    Ok(added)
+}
+
+/// Columns of the `tracks` table other than `id`, in the order of `track_params`.
+const TRACK_COLUMNS: &str = "path, title, artist, album, duration, cover_mime, has_cover,
+    cover_img_path, rg_track_gain, rg_track_peak, rg_album_gain, rg_album_peak, cue_file,
+    cue_start, cue_end, file_size, file_mtime, album_artist, track_number, track_total,
+    disc_number, disc_total, year, date, genres, composer, comment, bpm, title_sort,
+    artist_sort, album_sort, album_artist_sort, composer_sort";
+
+/// `?1, ?2, ...` for every column of `TRACK_COLUMNS`.
+fn track_placeholders() -> String {
+    (1..=TRACK_COLUMNS.split(',').count())
+        .map(|i| format!("?{i}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `TRACK_COLUMNS` after `id`, each qualified with `table`, for reading with `map_track_row`.
+fn track_select_columns(table: &str) -> String {
+    std::iter::once("id")
+        .chain(TRACK_COLUMNS.split(',').map(str::trim))
+        .map(|column| format!("{table}.{column}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Values of a track for the columns of `TRACK_COLUMNS`, numbered from `?1`.
+fn track_params(track: &TrackMetadata) -> Vec<Box<dyn rusqlite::ToSql + '_>> {
+    // Cast u64 to i64 for SQLite. Assuming duration fits in i64.
+    let duration_i64 = i64::try_from(track.duration_secs).unwrap_or(0);
+    vec![
+        Box::new(&track.path),
+        Box::new(&track.title),
+        Box::new(&track.artist),
+        Box::new(&track.album),
+        Box::new(duration_i64),
+        Box::new(&track.cover_mime),
+        Box::new(track.has_cover),
+        Box::new(&track.cover_img_path),
+        Box::new(track.replay_gain.track_gain),
+        Box::new(track.replay_gain.track_peak),
+        Box::new(track.replay_gain.album_gain),
+        Box::new(track.replay_gain.album_peak),
+        Box::new(track.cue.as_ref().map(|c| &c.file)),
+        Box::new(track.cue.as_ref().map(|c| c.start)),
+        Box::new(track.cue.as_ref().and_then(|c| c.end)),
+        Box::new(
+            track
+                .stamp
+                .map(|s| i64::try_from(s.size).unwrap_or(i64::MAX)),
+        ),
+        Box::new(track.stamp.map(|s| s.modified)),
+        Box::new(&track.tags.album_artist),
+        Box::new(track.tags.track_number),
+        Box::new(track.tags.track_total),
+        Box::new(track.tags.disc_number),
+        Box::new(track.tags.disc_total),
+        Box::new(track.tags.year),
+        Box::new(&track.tags.date),
+        Box::new(
+            (!track.tags.genres.is_empty())
+                .then(|| serde_json::to_string(&track.tags.genres).ok())
+                .flatten(),
+        ),
+        Box::new(&track.tags.composer),
+        Box::new(&track.tags.comment),
+        Box::new(track.tags.bpm),
+        Box::new(&track.tags.title_sort),
+        Box::new(&track.tags.artist_sort),
+        Box::new(&track.tags.album_sort),
+        Box::new(&track.tags.album_artist_sort),
+        Box::new(&track.tags.composer_sort),
+    ]
+}
+
+fn insert_chapters(conn: &Connection, track_id: i64, track: &TrackMetadata) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO chapters (track_id, idx, title, start_secs, end_secs)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for (index, chapter) in track.chapters.iter().enumerate() {
+        stmt.execute(params![
+            track_id,
+            i64::try_from(index).unwrap_or(i64::MAX),
+            chapter.title,
+            chapter.start,
+            chapter.end
+        ])?;
+    }
+    Ok(())
+}
+
+/// Track IDs affected by a change to the library.
+#[derive(Debug, Default, Serialize, Clone, PartialEq, Eq)]
+pub struct TrackChanges {
+    pub added: Vec<i64>,
+    pub updated: Vec<i64>,
+    pub removed: Vec<i64>,
+}
+
+impl TrackChanges {
+    pub fn extend(&mut self, other: Self) {
+        self.added.extend(other.added);
+        self.updated.extend(other.updated);
+        self.removed.extend(other.removed);
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Replaces the tracks read from the audio file at `file` with `tracks`; an empty slice
+/// removes the file from the library.
+///
+/// Tracks whose path is already stored are updated in place, keeping their ID, playlist
+/// entries and loops, but losing their loudness analysis since the audio may have changed.
+/// Stored tracks of the file that are not in `tracks`, such as parts of a CUE sheet that
+/// lost an entry, are deleted.
+///
+/// # Errors
+///
+/// Returns an error if the transaction or any statement fails.
+pub fn replace_file_tracks(
+    conn: &mut Connection,
+    file: &str,
+    tracks: &[TrackMetadata],
+) -> Result<TrackChanges> {
+    let tx = conn.transaction()?;
+    let mut changes = TrackChanges::default();
+    {
+        let mut stored: HashMap<String, i64> = {
+            let mut stmt =
+                tx.prepare("SELECT path, id FROM tracks WHERE path = ?1 OR cue_file = ?1")?;
+            let rows = stmt.query_map(params![file], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_>>()?
+        };
+        let mut insert = tx.prepare(&format!(
+            "INSERT INTO tracks ({TRACK_COLUMNS}) VALUES ({})",
+            track_placeholders()
+        ))?;
+        // Every column but the path, which identifies the row
+        let assignments = TRACK_COLUMNS
+            .split(',')
+            .map(str::trim)
+            .enumerate()
+            .skip(1)
+            .map(|(i, column)| format!("{column} = ?{}", i + 1))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut update = tx.prepare(&format!("UPDATE tracks SET {assignments} WHERE path = ?1"))?;
+        for track in tracks {
+            let values = track_params(track);
+            let track_id = if let Some(id) = stored.remove(&track.path) {
+                update.execute(rusqlite::params_from_iter(&values))?;
+                tx.execute("DELETE FROM chapters WHERE track_id = ?1", params![id])?;
+                tx.execute(
+                    "DELETE FROM track_loudness WHERE track_id = ?1",
+                    params![id],
+                )?;
+                changes.updated.push(id);
+                id
+            } else {
+                insert.execute(rusqlite::params_from_iter(&values))?;
+                let id = tx.last_insert_rowid();
+                changes.added.push(id);
+                id
+            };
+            insert_chapters(&tx, track_id, track)?;
+        }
+        for id in stored.into_values() {
+            delete_track_rows(&tx, id)?;
+            changes.removed.push(id);
+        }
+    }
+    tx.commit()?;
+    changes.removed.sort_unstable();
+    Ok(changes)
+}
+
+/// Deletes a track with the rows that refer to it, without relying on foreign keys.
+fn delete_track_rows(conn: &Connection, track_id: i64) -> Result<()> {
+    for table in ["playlist_tracks", "chapters", "track_loudness"] {
+        conn.execute(
+            &format!("DELETE FROM {table} WHERE track_id = ?1"),
+            params![track_id],
+        )?;
+    }
+    conn.execute("DELETE FROM tracks WHERE id = ?1", params![track_id])?;
+    Ok(())
+}
+
+/// SQL condition that holds when the path in `column` is `path` itself or lies inside it,
+/// with `separator` the parameter holding the path separator. Unlike a `LIKE` prefix it
+/// leaves out siblings sharing a name prefix, and is case-sensitive without wildcards.
+fn path_within(column: &str, path: &str, separator: &str) -> String {
+    let dir = format!("rtrim({path}, {separator})");
+    format!("({column} = {path} OR substr({column}, 1, length({dir}) + 1) = {dir} || {separator})")
+}
+
+/// Moves the tracks of the file or directory at `from` to `to`, keeping their IDs.
+///
+/// Virtual CUE tracks follow their file. Tracks already stored at `to`, which the move
+/// replaced on disk, are deleted.
+///
+/// # Errors
+///
+/// Returns an error if the transaction or any statement fails.
+pub fn rename_file_tracks(conn: &mut Connection, from: &str, to: &str) -> Result<TrackChanges> {
+    // Matches the file itself, or everything under it for a directory
+    let under = path_within("COALESCE(cue_file, path)", "?1", "?2");
+    let separator = std::path::MAIN_SEPARATOR_STR;
+    let tx = conn.transaction()?;
+    let mut changes = TrackChanges::default();
+    {
+        let ids = |path: &str| -> Result<Vec<i64>> {
+            let mut stmt = tx.prepare(&format!("SELECT id FROM tracks WHERE {under}"))?;
+            let rows = stmt.query_map(params![path, separator], |row| row.get(0))?;
+            rows.collect()
+        };
+        changes.updated = ids(from)?;
+        if changes.updated.is_empty() {
+            return Ok(changes);
+        }
+        changes.removed = ids(to)?;
+        for id in &changes.removed {
+            delete_track_rows(&tx, *id)?;
+        }
+        tx.execute(
+            &format!(
+                "UPDATE tracks SET path = ?3 || substr(path, length(?1) + 1),
+                    cue_file = ?3 || substr(cue_file, length(?1) + 1)
+                 WHERE {under}"
+            ),
+            params![from, separator, to],
+        )?;
+    }
+    tx.commit()?;
+    Ok(changes)
+}
+
+/// Adds tracks to the "Default" playlist, where everything new to the library goes.
+///
+/// # Errors
+///
+/// Returns an error if the playlists cannot be read or the insertion fails.
+pub fn add_tracks_to_default_playlist(conn: &mut Connection, track_ids: &[i64]) -> Result<()> {
+    if track_ids.is_empty() {
+        return Ok(());
+    }
+    let playlists = get_playlists(conn)?;
+    match playlists.iter().find(|p| p.name == "Default") {
+        Some(playlist) => add_tracks_to_playlist(conn, &playlist.id, track_ids),
+        None => Ok(()),
+    }
+}
+
+/// Retrieves the stamps of the audio files behind the library tracks inside a local
+/// folder, or of the single file at `folder_path`, keyed by file path.
+///
+/// A file maps to `None` if any of its tracks was stored without a stamp.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_file_stamps(
+    conn: &Connection,
+    folder_path: &str,
+) -> Result<HashMap<String, Option<FileStamp>>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT COALESCE(cue_file, path), file_size, file_mtime FROM tracks WHERE {}",
+        path_within("COALESCE(cue_file, path)", "?1", "?2")
+    ))?;
+    let separator = std::path::MAIN_SEPARATOR_STR;
+    let rows = stmt.query_map(params![folder_path, separator], |row| {
+        Ok((row.get::<_, String>(0)?, map_file_stamp(row, 1)?))
+    })?;
+    let mut stamps = HashMap::new();
+    for row in rows {
+        let (file, stamp) = row?;
+        stamps
+            .entry(file)
+            .and_modify(|stored: &mut Option<FileStamp>| {
+                if *stored != stamp {
+                    *stored = None;
+                }
+            })
+            .or_insert(stamp);
+    }
+    Ok(stamps)
+}
+
+/// Retrieves the stamp stored with the tracks of the audio file at `file`.
+///
+/// Returns `None` if the file has no tracks, or if its tracks were stored without a stamp
+/// or with different ones.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_file_stamp(conn: &Connection, file: &str) -> Result<Option<FileStamp>> {
+    let mut stmt =
+        conn.prepare("SELECT file_size, file_mtime FROM tracks WHERE path = ?1 OR cue_file = ?1")?;
+    let rows = stmt.query_map(params![file], |row| map_file_stamp(row, 0))?;
+    let stamps = rows.collect::<Result<Vec<_>>>()?;
+    Ok(match stamps.split_first() {
+        Some((first, rest)) if rest.iter().all(|s| s == first) => *first,
+        _ => None,
+    })
 }
 
 /// Retrieves tracks from the database, optionally filtered by title.
@@ -56,9 +397,7 @@ pub fn add_tracks(conn: &mut Connection, tracks: &[TrackMetadata]) -> Result<()>
 ///
 /// Returns an error if the query fails.
 pub fn get_tracks(conn: &Connection, title_query: Option<String>) -> Result<Vec<TrackMetadata>> {
-    let mut query = String::from(
-        "SELECT id, path, title, artist, album, duration, cover_mime, has_cover, cover_img_path FROM tracks",
-    );
+    let mut query = format!("SELECT {} FROM tracks", track_select_columns("tracks"));
 
     if title_query.is_some() {
         query.push_str(" WHERE title LIKE ?1");
@@ -77,10 +416,61 @@ pub fn get_tracks(conn: &Connection, title_query: Option<String>) -> Result<Vec<
     for track in rows {
         tracks.push(track?);
     }
+    attach_chapters(conn, &mut tracks)?;
 
     Ok(tracks)
 }
 
+/// Fills in the chapters of tracks read from the `tracks` table.
+fn attach_chapters(conn: &Connection, tracks: &mut [TrackMetadata]) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT track_id, title, start_secs, end_secs FROM chapters ORDER BY track_id, idx",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, map_chapter_row(row, 1)?))
+    })?;
+    let mut by_track: HashMap<i64, Vec<Chapter>> = HashMap::new();
+    for row in rows {
+        let (track_id, chapter) = row?;
+        by_track.entry(track_id).or_default().push(chapter);
+    }
+    for track in tracks {
+        track.chapters = by_track.remove(&track.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// Reads a `Chapter` from three columns starting at `first`.
+fn map_chapter_row(row: &rusqlite::Row<'_>, first: usize) -> Result<Chapter> {
+    Ok(Chapter {
+        title: row.get(first)?,
+        start: row.get(first + 1)?,
+        end: row.get(first + 2)?,
+    })
+}
+
+/// Retrieves the chapters of the track at `path`, or `None` if it is not in the library.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_chapters(conn: &Connection, path: &str) -> Result<Option<Vec<Chapter>>> {
+    let mut stmt = conn.prepare("SELECT id FROM tracks WHERE path = ?1")?;
+    let mut ids = stmt.query_map(params![path], |row| row.get::<_, i64>(0))?;
+    let Some(track_id) = ids.next().transpose()? else {
+        return Ok(None);
+    };
+    let mut stmt = conn.prepare(
+        "SELECT title, start_secs, end_secs FROM chapters WHERE track_id = ?1 ORDER BY idx",
+    )?;
+    let rows = stmt.query_map(params![track_id], |row| map_chapter_row(row, 0))?;
+    let mut chapters = Vec::new();
+    for chapter in rows {
+        chapters.push(chapter?);
+    }
+    Ok(Some(chapters))
+}
+
 fn map_track_row(row: &rusqlite::Row<'_>) -> Result<TrackMetadata> {
     let duration_i64: i64 = row.get(5)?;
     let duration_secs = u64::try_from(duration_i64).unwrap_or(0);
@@ -94,9 +484,511 @@ fn map_track_row(row: &rusqlite::Row<'_>) -> Result<TrackMetadata> {
         cover_mime: row.get(6)?,
         has_cover: row.get(7)?,
         cover_img_path: row.get(8)?,
+        replay_gain: ReplayGain {
+            track_gain: row.get(9)?,
+            track_peak: row.get(10)?,
+            album_gain: row.get(11)?,
+            album_peak: row.get(12)?,
+        },
+        tags: map_track_tags(row, 18)?,
+        chapters: Vec::new(),
+        cue: map_cue_range(row, 13)?,
+        stamp: map_file_stamp(row, 16)?,
+    })
+}
+
+/// Reads `TrackTags` from sixteen columns starting at `first`.
+fn map_track_tags(row: &rusqlite::Row<'_>, first: usize) -> Result<TrackTags> {
+    let genres: Option<String> = row.get(first + 7)?;
+    let genres = match genres {
+        Some(json) => serde_json::from_str(&json).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                first + 7,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })?,
+        None => Vec::new(),
+    };
+    Ok(TrackTags {
+        album_artist: row.get(first)?,
+        track_number: row.get(first + 1)?,
+        track_total: row.get(first + 2)?,
+        disc_number: row.get(first + 3)?,
+        disc_total: row.get(first + 4)?,
+        year: row.get(first + 5)?,
+        date: row.get(first + 6)?,
+        genres,
+        composer: row.get(first + 8)?,
+        comment: row.get(first + 9)?,
+        bpm: row.get(first + 10)?,
+        title_sort: row.get(first + 11)?,
+        artist_sort: row.get(first + 12)?,
+        album_sort: row.get(first + 13)?,
+        album_artist_sort: row.get(first + 14)?,
+        composer_sort: row.get(first + 15)?,
+    })
+}
+
+/// Reads a `FileStamp` from two columns starting at `first`.
+fn map_file_stamp(row: &rusqlite::Row<'_>, first: usize) -> Result<Option<FileStamp>> {
+    let size: Option<i64> = row.get(first)?;
+    let modified: Option<i64> = row.get(first + 1)?;
+    Ok(size.zip(modified).map(|(size, modified)| FileStamp {
+        size: u64::try_from(size).unwrap_or(0),
+        modified,
+    }))
+}
+
+/// Reads a `CueRange` from three columns starting at `first`; `None` for whole files.
+fn map_cue_range(row: &rusqlite::Row<'_>, first: usize) -> Result<Option<CueRange>> {
+    let file: Option<String> = row.get(first)?;
+    let start: Option<f64> = row.get(first + 1)?;
+    Ok(match (file, start) {
+        (Some(file), Some(start)) => Some(CueRange {
+            file,
+            start,
+            end: row.get(first + 2)?,
+        }),
+        _ => None,
+    })
+}
+
+/// Retrieves the part of its file that the track at `path` covers.
+///
+/// The outer `None` means the track is not in the library, the inner one that it is a
+/// whole file.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_cue_range(conn: &Connection, path: &str) -> Result<Option<Option<CueRange>>> {
+    let mut stmt =
+        conn.prepare("SELECT cue_file, cue_start, cue_end FROM tracks WHERE path = ?1")?;
+    let mut rows = stmt.query_map(params![path], |row| map_cue_range(row, 0))?;
+    rows.next().transpose()
+}
+
+/// Retrieves the artists of the library tracks among `paths`, by path.
+///
+/// Paths outside the library and tracks without an artist are left out.
+///
+/// # Errors
+///
+/// Returns an error if a query fails.
+pub fn get_artists(conn: &Connection, paths: &[String]) -> Result<HashMap<String, String>> {
+    let mut stmt =
+        conn.prepare("SELECT artist FROM tracks WHERE path = ?1 AND artist IS NOT NULL")?;
+    let mut artists = HashMap::new();
+    for path in paths {
+        let mut rows = stmt.query_map(params![path], |row| row.get(0))?;
+        if let Some(artist) = rows.next().transpose()? {
+            artists.insert(path.clone(), artist);
+        }
+    }
+    Ok(artists)
+}
+
+/// Retrieves the album and album artist of the track at `path`.
+///
+/// Returns `None` if the track is not in the library or has no album.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_album(conn: &Connection, path: &str) -> Result<Option<(String, Option<String>)>> {
+    let mut stmt = conn
+        .prepare("SELECT album, album_artist FROM tracks WHERE path = ?1 AND album IS NOT NULL")?;
+    let mut rows = stmt.query_map(params![path], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.next().transpose()
+}
+
+/// Retrieves the `ReplayGain` values of the track at `path`.
+///
+/// Track gain and peak missing from the tags are filled in from the track's loudness
+/// analysis, if there is one. Returns `None` if the track is not in the library.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_replay_gain(conn: &Connection, path: &str) -> Result<Option<ReplayGain>> {
+    let mut stmt = conn.prepare(
+        "SELECT t.rg_track_gain, t.rg_track_peak, t.rg_album_gain, t.rg_album_peak,
+            l.integrated, l.loudness_range, l.true_peak
+         FROM tracks t
+         LEFT JOIN track_loudness l ON l.track_id = t.id
+         WHERE t.path = ?1",
+    )?;
+    let mut rows = stmt.query_map(params![path], |row| {
+        let mut gain = ReplayGain {
+            track_gain: row.get(0)?,
+            track_peak: row.get(1)?,
+            album_gain: row.get(2)?,
+            album_peak: row.get(3)?,
+        };
+        if let Some(loudness) = map_loudness(row, 4)? {
+            #[allow(clippy::cast_possible_truncation)]
+            {
+                gain.track_gain = gain.track_gain.or(Some(loudness.gain_db() as f32));
+                gain.track_peak = gain.track_peak.or(Some(loudness.peak_linear() as f32));
+            }
+        }
+        Ok(gain)
+    })?;
+    rows.next().transpose()
+}
+
+/// Reads a `Loudness` from three columns starting at `first`; `None` if it was not measured.
+fn map_loudness(row: &rusqlite::Row<'_>, first: usize) -> Result<Option<Loudness>> {
+    let integrated: Option<f64> = row.get(first)?;
+    let range: Option<f64> = row.get(first + 1)?;
+    let true_peak: Option<f64> = row.get(first + 2)?;
+    Ok(match (integrated, range, true_peak) {
+        (Some(integrated), Some(range), Some(true_peak)) => Some(Loudness {
+            integrated,
+            range,
+            true_peak,
+        }),
+        _ => None,
     })
 }
 
+/// Retrieves the tracks without `ReplayGain` tags that have not been analyzed yet.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_tracks_pending_loudness(conn: &Connection) -> Result<Vec<(i64, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT t.id, t.path FROM tracks t
+         WHERE t.rg_track_gain IS NULL
+           AND NOT EXISTS (SELECT 1 FROM track_loudness l WHERE l.track_id = t.id)
+         ORDER BY t.id",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut tracks = Vec::new();
+    for track in rows {
+        tracks.push(track?);
+    }
+    Ok(tracks)
+}
+
+/// Stores the analysis result of a track. `None` records a failed analysis so the
+/// track is not picked up again.
+///
+/// # Errors
+///
+/// Returns an error if the insertion fails.
+pub fn save_loudness(conn: &Connection, track_id: i64, loudness: Option<&Loudness>) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO track_loudness (track_id, integrated, loudness_range, true_peak)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![
+            track_id,
+            loudness.map(|l| l.integrated),
+            loudness.map(|l| l.range),
+            loudness.map(|l| l.true_peak)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Retrieves the analysis result of a track, if it was measured successfully.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_loudness(conn: &Connection, track_id: i64) -> Result<Option<Loudness>> {
+    let mut stmt = conn.prepare(
+        "SELECT integrated, loudness_range, true_peak FROM track_loudness WHERE track_id = ?1",
+    )?;
+    let mut rows = stmt.query_map(params![track_id], |row| map_loudness(row, 0))?;
+    Ok(rows.next().transpose()?.flatten())
+}
+
+/// Retrieves a value from the settings table.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_setting(conn: &Connection, key: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+    let mut rows = stmt.query_map(params![key], |row| row.get(0))?;
+    rows.next().transpose()
+}
+
+/// Stores a value in the settings table; `None` removes it.
+///
+/// # Errors
+///
+/// Returns an error if the statement fails.
+pub fn set_setting(conn: &Connection, key: &str, value: Option<&str>) -> Result<()> {
+    match value {
+        Some(value) => conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?,
+        None => conn.execute("DELETE FROM settings WHERE key = ?1", params![key])?,
+    };
+    Ok(())
+}
+
+/// Retrieves the user-saved equalizer presets.
+///
+/// # Errors
+///
+/// Returns an error if the query fails or a stored preset is not valid JSON.
+pub fn get_eq_presets(conn: &Connection) -> Result<Vec<EqPreset>> {
+    let mut stmt = conn.prepare("SELECT name, bands FROM eq_presets ORDER BY name")?;
+    let rows = stmt.query_map([], |row| {
+        let bands: String = row.get(1)?;
+        Ok(EqPreset {
+            name: row.get(0)?,
+            bands: parse_bands(&bands)?,
+            built_in: false,
+        })
+    })?;
+    let mut presets = Vec::new();
+    for preset in rows {
+        presets.push(preset?);
+    }
+    Ok(presets)
+}
+
+/// Retrieves the bands of a user-saved equalizer preset.
+///
+/// # Errors
+///
+/// Returns an error if the query fails or the stored preset is not valid JSON.
+pub fn get_eq_preset(conn: &Connection, name: &str) -> Result<Option<Vec<EqBand>>> {
+    let mut stmt = conn.prepare("SELECT bands FROM eq_presets WHERE name = ?1")?;
+    let mut rows = stmt.query_map(params![name], |row| {
+        let bands: String = row.get(0)?;
+        parse_bands(&bands)
+    })?;
+    rows.next().transpose()
+}
+
+fn parse_bands(json: &str) -> Result<Vec<EqBand>> {
+    serde_json::from_str(json).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+/// Saves an equalizer preset, replacing any preset with the same name.
+///
+/// # Errors
+///
+/// Returns an error if the insertion fails.
+pub fn save_eq_preset(conn: &Connection, name: &str, bands: &[EqBand]) -> Result<()> {
+    let json = serde_json::to_string(bands)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    conn.execute(
+        "INSERT OR REPLACE INTO eq_presets (name, bands) VALUES (?1, ?2)",
+        params![name, json],
+    )?;
+    Ok(())
+}
+
+/// Deletes an equalizer preset along with the overrides that use it.
+///
+/// # Errors
+///
+/// Returns an error if deletion fails.
+pub fn delete_eq_preset(conn: &Connection, name: &str) -> Result<()> {
+    conn.execute("DELETE FROM eq_presets WHERE name = ?1", params![name])?;
+    conn.execute("DELETE FROM eq_overrides WHERE preset = ?1", params![name])?;
+    Ok(())
+}
+
+/// Sets or, with `None`, removes the preset override for a track path or album name.
+///
+/// # Errors
+///
+/// Returns an error if the statement fails.
+pub fn set_eq_override(
+    conn: &Connection,
+    scope: EqScope,
+    key: &str,
+    preset: Option<&str>,
+) -> Result<()> {
+    match preset {
+        Some(preset) => conn.execute(
+            "INSERT OR REPLACE INTO eq_overrides (scope, key, preset) VALUES (?1, ?2, ?3)",
+            params![scope.as_str(), key, preset],
+        )?,
+        None => conn.execute(
+            "DELETE FROM eq_overrides WHERE scope = ?1 AND key = ?2",
+            params![scope.as_str(), key],
+        )?,
+    };
+    Ok(())
+}
+
+/// Retrieves the preset override for the track at `path`: its own override, or else
+/// the override of its album.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_eq_override(conn: &Connection, path: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT preset FROM eq_overrides WHERE scope = 'track' AND key = ?1
+         UNION ALL
+         SELECT o.preset FROM eq_overrides o
+         JOIN tracks t ON t.album = o.key
+         WHERE o.scope = 'album' AND t.path = ?1",
+    )?;
+    let mut rows = stmt.query_map(params![path], |row| row.get(0))?;
+    rows.next().transpose()
+}
+
+/// Saves a loop region for the track at `path` and returns its ID.
+///
+/// # Errors
+///
+/// Returns an error if the insertion fails.
+pub fn save_loop_region(
+    conn: &Connection,
+    path: &str,
+    name: &str,
+    start: f64,
+    end: f64,
+) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO loop_regions (path, name, start_secs, end_secs) VALUES (?1, ?2, ?3, ?4)",
+        params![path, name, start, end],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Retrieves the loop regions saved for the track at `path`, in playing order.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_loop_regions(conn: &Connection, path: &str) -> Result<Vec<LoopRegion>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, path, name, start_secs, end_secs FROM loop_regions
+         WHERE path = ?1 ORDER BY start_secs, end_secs",
+    )?;
+    let rows = stmt.query_map(params![path], |row| {
+        Ok(LoopRegion {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            name: row.get(2)?,
+            start: row.get(3)?,
+            end: row.get(4)?,
+        })
+    })?;
+    let mut regions = Vec::new();
+    for region in rows {
+        regions.push(region?);
+    }
+    Ok(regions)
+}
+
+/// Deletes a saved loop region.
+///
+/// # Errors
+///
+/// Returns an error if deletion fails.
+pub fn delete_loop_region(conn: &Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM loop_regions WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+/// Stores how far the track at `path` was played.
+///
+/// # Errors
+///
+/// Returns an error if the upsert fails.
+pub fn save_resume_position(
+    conn: &Connection,
+    path: &str,
+    position: f64,
+    duration: f64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO resume_positions
+         (path, position_secs, duration_secs, finished, updated_at)
+         VALUES (?1, ?2, ?3, 0, strftime('%s', 'now'))",
+        params![path, position, duration],
+    )?;
+    Ok(())
+}
+
+/// Marks the track at `path` as played to its end, so it starts over next time.
+///
+/// Without a `duration`, the one stored before is kept.
+///
+/// # Errors
+///
+/// Returns an error if the upsert fails.
+pub fn mark_track_finished(conn: &Connection, path: &str, duration: Option<f64>) -> Result<()> {
+    conn.execute(
+        "INSERT INTO resume_positions (path, position_secs, duration_secs, finished, updated_at)
+         VALUES (?1, 0, COALESCE(?2, 0), 1, strftime('%s', 'now'))
+         ON CONFLICT(path) DO UPDATE SET position_secs = 0, finished = 1,
+         duration_secs = COALESCE(?2, duration_secs), updated_at = strftime('%s', 'now')",
+        params![path, duration],
+    )?;
+    Ok(())
+}
+
+/// Retrieves the position to resume the track at `path` from, unless it was finished.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_resume_position(conn: &Connection, path: &str) -> Result<Option<f64>> {
+    let mut stmt = conn
+        .prepare("SELECT position_secs FROM resume_positions WHERE path = ?1 AND finished = 0")?;
+    let mut rows = stmt.query_map(params![path], |row| row.get(0))?;
+    rows.next().transpose()
+}
+
+/// Retrieves the tracks that were left part way through, most recent first.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_in_progress_tracks(conn: &Connection) -> Result<Vec<ResumePosition>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.path, t.title, r.position_secs, r.duration_secs, r.updated_at
+         FROM resume_positions r LEFT JOIN tracks t ON t.path = r.path
+         WHERE r.finished = 0 AND r.position_secs > 0
+         ORDER BY r.updated_at DESC, r.path",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ResumePosition {
+            path: row.get(0)?,
+            title: row.get(1)?,
+            position: row.get(2)?,
+            duration: row.get(3)?,
+            updated_at: row.get(4)?,
+        })
+    })?;
+    let mut positions = Vec::new();
+    for position in rows {
+        positions.push(position?);
+    }
+    Ok(positions)
+}
+
+/// Forgets the stored position of the track at `path`, finished or not.
+///
+/// # Errors
+///
+/// Returns an error if deletion fails.
+pub fn delete_resume_position(conn: &Connection, path: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM resume_positions WHERE path = ?1",
+        params![path],
+    )?;
+    Ok(())
+}
+
 /// Deletes tracks from the database by their IDs.
 ///
 /// # Errors
@@ -142,7 +1034,9 @@ pub fn add_folder(conn: &Connection, name: &str, path: &str, song_count: i32) ->
 ///
 /// Returns an error if the query fails.
 pub fn get_folders(conn: &Connection, name_query: Option<String>) -> Result<Vec<LocalFolder>> {
-    let mut query = String::from("SELECT id, name, path, song_count FROM local_folders");
+    let mut query = String::from(
+        "SELECT id, name, path, song_count, remember_position, watch FROM local_folders",
+    );
 
     if name_query.is_some() {
         query.push_str(" WHERE name LIKE ?1");
@@ -165,15 +1059,124 @@ pub fn get_folders(conn: &Connection, name_query: Option<String>) -> Result<Vec<
     Ok(folders)
 }
 
+/// Retrieves a local folder by its ID.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_folder(conn: &Connection, folder_id: &str) -> Result<Option<LocalFolder>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, path, song_count, remember_position, watch FROM local_folders
+         WHERE id = ?1",
+    )?;
+    let mut rows = stmt.query_map(params![folder_id], map_folder_row)?;
+    rows.next().transpose()
+}
+
+/// Recounts the library tracks inside a local folder.
+///
+/// # Errors
+///
+/// Returns an error if the update fails.
+pub fn refresh_folder_song_count(conn: &Connection, folder_id: &str) -> Result<()> {
+    conn.execute(
+        &format!(
+            "UPDATE local_folders SET song_count =
+                (SELECT COUNT(*) FROM tracks WHERE {})
+             WHERE id = ?1",
+            path_within("path", "local_folders.path", "?2")
+        ),
+        params![folder_id, std::path::MAIN_SEPARATOR_STR],
+    )?;
+    Ok(())
+}
+
 fn map_folder_row(row: &rusqlite::Row<'_>) -> Result<LocalFolder> {
     Ok(LocalFolder {
         id: row.get(0)?,
         name: row.get(1)?,
         path: row.get(2)?,
         song_count: row.get(3)?,
+        remember_position: row.get(4)?,
+        watch: row.get(5)?,
     })
 }
 
+/// Makes every track in a local folder resume where it was left, or stop doing so.
+///
+/// # Errors
+///
+/// Returns an error if the update fails or no folder has that ID.
+pub fn set_folder_remember_position(
+    conn: &Connection,
+    folder_id: &str,
+    remember: bool,
+) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE local_folders SET remember_position = ?2 WHERE id = ?1",
+        params![folder_id, remember],
+    )?;
+    if updated == 0 {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    Ok(())
+}
+
+/// Turns watching a local folder for changes on disk on or off.
+///
+/// # Errors
+///
+/// Returns an error if the update fails or no folder has that ID.
+pub fn set_folder_watch(conn: &Connection, folder_id: &str, watch: bool) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE local_folders SET watch = ?2 WHERE id = ?1",
+        params![folder_id, watch],
+    )?;
+    if updated == 0 {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+    Ok(())
+}
+
+/// True if the track at `path` lies in a folder flagged to remember positions.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn in_remember_position_folder(conn: &Connection, path: &str) -> Result<bool> {
+    conn.query_row(
+        &format!(
+            "SELECT EXISTS(SELECT 1 FROM local_folders
+             WHERE remember_position = 1 AND {})",
+            path_within("?1", "path", "?2")
+        ),
+        params![path, std::path::MAIN_SEPARATOR_STR],
+        |row| row.get(0),
+    )
+}
+
+/// Retrieves the paths of the library tracks inside a local folder.
+///
+/// # Errors
+///
+/// Returns an error if the query fails.
+pub fn get_folder_track_paths(conn: &Connection, folder_id: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT t.path FROM tracks t
+             JOIN local_folders f ON {}
+             WHERE f.id = ?1 ORDER BY t.path",
+        path_within("t.path", "f.path", "?2")
+    ))?;
+    let rows = stmt.query_map(params![folder_id, std::path::MAIN_SEPARATOR_STR], |row| {
+        row.get(0)
+    })?;
+    let mut paths = Vec::new();
+    for path in rows {
+        paths.push(path?);
+    }
+    Ok(paths)
+}
+
 /// Deletes local folders from the database by their IDs and removes all associated data (tracks and playlist links).
 ///
 /// # Errors
@@ -200,18 +1203,28 @@ pub fn delete_folders(conn: &mut Connection, ids: &[String]) -> Result<()> {
             p
         };
 
+        let inside = path_within("path", "?1", "?2");
+        let separator = std::path::MAIN_SEPARATOR_STR;
         for path in paths {
-            let pattern = format!("{path}%");
-
             // 2. Identify and delete playlist associations for tracks in this folder
             // This satisfies the manual cleanup request.
             tx.execute(
-                "DELETE FROM playlist_tracks WHERE track_id IN (SELECT id FROM tracks WHERE path LIKE ?1)",
-                params![pattern],
+                &format!("DELETE FROM playlist_tracks WHERE track_id IN (SELECT id FROM tracks WHERE {inside})"),
+                params![path, separator],
+            )?;
+
+            tx.execute(
+                &format!(
+                    "DELETE FROM chapters WHERE track_id IN (SELECT id FROM tracks WHERE {inside})"
+                ),
+                params![path, separator],
             )?;
 
             // 3. Delete the tracks themselves
-            tx.execute("DELETE FROM tracks WHERE path LIKE ?1", params![pattern])?;
+            tx.execute(
+                &format!("DELETE FROM tracks WHERE {inside}"),
+                params![path, separator],
+            )?;
         }
 
         // 4. Delete the folder records
@@ -267,19 +1280,20 @@ pub fn get_playlists(conn: &Connection) -> Result<Vec<Playlist>> {
 ///
 /// Returns an error if the query fails.
 pub fn get_tracks_by_playlist(conn: &Connection, playlist_id: &str) -> Result<Vec<TrackMetadata>> {
-    let mut stmt = conn.prepare(
-        "SELECT t.id, t.path, t.title, t.artist, t.album, t.duration, t.cover_mime, t.has_cover, t.cover_img_path 
-         FROM tracks t
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM tracks t
          JOIN playlist_tracks pt ON t.id = pt.track_id
          WHERE pt.playlist_id = ?1
          ORDER BY pt.added_at",
-    )?;
+        track_select_columns("t")
+    ))?;
 
     let rows = stmt.query_map(params![playlist_id], map_track_row)?;
     let mut tracks = Vec::new();
     for track in rows {
         tracks.push(track?);
     }
+    attach_chapters(conn, &mut tracks)?;
     Ok(tracks)
 }
 
@@ -368,9 +1382,14 @@ mod tests {
             cover_mime: None,
             has_cover: false,
             cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
         };
 
-        add_tracks(&mut conn, &[track.clone()]).unwrap();
+        add_tracks(&mut conn, std::slice::from_ref(&track)).unwrap();
 
         let tracks = get_tracks(&conn, None).unwrap();
         assert_eq!(tracks.len(), 1);
@@ -379,6 +1398,632 @@ mod tests {
         assert!(tracks[0].id > 0);
     }
 
+    #[test]
+    fn test_replay_gain_round_trip() {
+        let mut conn = setup_db();
+        let replay_gain = ReplayGain {
+            track_gain: Some(-6.5),
+            track_peak: Some(0.98),
+            album_gain: Some(-7.25),
+            album_peak: None,
+        };
+        let track = TrackMetadata {
+            id: 0,
+            path: "/rg.flac".to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain,
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        };
+        add_tracks(&mut conn, &[track]).unwrap();
+
+        assert_eq!(get_tracks(&conn, None).unwrap()[0].replay_gain, replay_gain);
+        assert_eq!(
+            get_replay_gain(&conn, "/rg.flac").unwrap(),
+            Some(replay_gain)
+        );
+        assert_eq!(get_replay_gain(&conn, "/missing.flac").unwrap(), None);
+    }
+
+    #[test]
+    fn test_track_tags_round_trip() {
+        let mut conn = setup_db();
+        let tags = TrackTags {
+            album_artist: Some("Various Artists".to_string()),
+            track_number: Some(3),
+            track_total: Some(12),
+            disc_number: Some(2),
+            disc_total: Some(2),
+            year: Some(1997),
+            date: Some("1997-06-16".to_string()),
+            genres: vec!["Electronic".to_string(), "Trip Hop".to_string()],
+            composer: Some("Composer".to_string()),
+            comment: Some("Remastered".to_string()),
+            bpm: Some(92.5),
+            title_sort: Some("Title".to_string()),
+            artist_sort: Some("Artist, The".to_string()),
+            album_sort: Some("Album".to_string()),
+            album_artist_sort: Some("Various".to_string()),
+            composer_sort: Some("Composer".to_string()),
+        };
+        let track = |path: &str, tags: TrackTags| TrackMetadata {
+            id: 0,
+            path: path.to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags,
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        };
+        add_tracks(
+            &mut conn,
+            &[
+                track("/tagged.flac", tags.clone()),
+                track("/bare.flac", TrackTags::default()),
+            ],
+        )
+        .unwrap();
+
+        let tracks = get_tracks(&conn, None).unwrap();
+        assert_eq!(tracks[0].tags, tags);
+        assert_eq!(tracks[1].tags, TrackTags::default());
+
+        // Rescans replace every tag
+        let retagged = TrackTags {
+            genres: vec!["Downtempo".to_string()],
+            ..TrackTags::default()
+        };
+        replace_file_tracks(
+            &mut conn,
+            "/tagged.flac",
+            &[track("/tagged.flac", retagged.clone())],
+        )
+        .unwrap();
+        assert_eq!(get_tracks(&conn, None).unwrap()[0].tags, retagged);
+    }
+
+    #[test]
+    fn test_album() {
+        let mut conn = setup_db();
+        let track = |path: &str, album: Option<&str>, album_artist: Option<&str>| TrackMetadata {
+            id: 0,
+            path: path.to_string(),
+            title: None,
+            artist: None,
+            album: album.map(str::to_string),
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags {
+                album_artist: album_artist.map(str::to_string),
+                ..TrackTags::default()
+            },
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        };
+        add_tracks(
+            &mut conn,
+            &[
+                track("/hits/01.mp3", Some("Greatest Hits"), Some("Queen")),
+                track("/hits/02.mp3", Some("Greatest Hits"), None),
+                track("/single.mp3", None, Some("Queen")),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_album(&conn, "/hits/01.mp3").unwrap(),
+            Some(("Greatest Hits".to_string(), Some("Queen".to_string())))
+        );
+        assert_eq!(
+            get_album(&conn, "/hits/02.mp3").unwrap(),
+            Some(("Greatest Hits".to_string(), None))
+        );
+        assert_eq!(get_album(&conn, "/single.mp3").unwrap(), None);
+        assert_eq!(get_album(&conn, "/missing.mp3").unwrap(), None);
+    }
+
+    #[test]
+    fn test_loudness_fills_missing_replay_gain() {
+        let mut conn = setup_db();
+        let track = TrackMetadata {
+            id: 0,
+            path: "/untagged.mp3".to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        };
+        add_tracks(&mut conn, &[track]).unwrap();
+        let pending = get_tracks_pending_loudness(&conn).unwrap();
+        assert_eq!(pending.len(), 1);
+        let (id, _) = pending[0];
+
+        let loudness = Loudness {
+            integrated: -12.0,
+            range: 5.0,
+            true_peak: -6.0,
+        };
+        save_loudness(&conn, id, Some(&loudness)).unwrap();
+
+        assert!(get_tracks_pending_loudness(&conn).unwrap().is_empty());
+        assert_eq!(get_loudness(&conn, id).unwrap(), Some(loudness));
+        let gain = get_replay_gain(&conn, "/untagged.mp3").unwrap().unwrap();
+        assert_eq!(gain.track_gain, Some(-6.0));
+        assert!((gain.track_peak.unwrap() - 0.501).abs() < 1e-3);
+        assert_eq!(gain.album_gain, None);
+
+        // Failed analyses are recorded but not used
+        save_loudness(&conn, id, None).unwrap();
+        assert_eq!(get_loudness(&conn, id).unwrap(), None);
+        assert!(get_tracks_pending_loudness(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_eq_presets_and_overrides() {
+        let mut conn = setup_db();
+        let bands = vec![EqBand {
+            frequency: 100.0,
+            gain_db: 3.0,
+            q: 0.7,
+        }];
+        save_eq_preset(&conn, "Warm", &bands).unwrap();
+        assert_eq!(get_eq_presets(&conn).unwrap()[0].bands, bands);
+        assert_eq!(get_eq_preset(&conn, "Warm").unwrap(), Some(bands));
+
+        let track = TrackMetadata {
+            id: 0,
+            path: "/album/song.flac".to_string(),
+            title: None,
+            artist: None,
+            album: Some("Album".to_string()),
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        };
+        add_tracks(&mut conn, &[track]).unwrap();
+        assert_eq!(get_eq_override(&conn, "/album/song.flac").unwrap(), None);
+
+        set_eq_override(&conn, EqScope::Album, "Album", Some("Rock")).unwrap();
+        assert_eq!(
+            get_eq_override(&conn, "/album/song.flac")
+                .unwrap()
+                .as_deref(),
+            Some("Rock")
+        );
+
+        // Track overrides win over album overrides
+        set_eq_override(&conn, EqScope::Track, "/album/song.flac", Some("Warm")).unwrap();
+        assert_eq!(
+            get_eq_override(&conn, "/album/song.flac")
+                .unwrap()
+                .as_deref(),
+            Some("Warm")
+        );
+
+        delete_eq_preset(&conn, "Warm").unwrap();
+        assert!(get_eq_presets(&conn).unwrap().is_empty());
+        assert_eq!(
+            get_eq_override(&conn, "/album/song.flac")
+                .unwrap()
+                .as_deref(),
+            Some("Rock")
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let conn = setup_db();
+        assert_eq!(get_setting(&conn, "output_device").unwrap(), None);
+
+        set_setting(&conn, "output_device", Some("USB DAC")).unwrap();
+        set_setting(&conn, "output_device", Some("Speakers")).unwrap();
+        assert_eq!(
+            get_setting(&conn, "output_device").unwrap().as_deref(),
+            Some("Speakers")
+        );
+
+        set_setting(&conn, "output_device", None).unwrap();
+        assert_eq!(get_setting(&conn, "output_device").unwrap(), None);
+    }
+
+    #[test]
+    fn test_chapters() {
+        let mut conn = setup_db();
+        let chapter = |title: &str, start: f64, end: f64| Chapter {
+            title: Some(title.to_string()),
+            start,
+            end,
+        };
+        let book = TrackMetadata {
+            id: 0,
+            path: "/books/a.m4b".to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 120,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: vec![chapter("One", 0.0, 60.0), chapter("Two", 60.0, 120.0)],
+            cue: None,
+            stamp: None,
+        };
+        add_tracks(&mut conn, std::slice::from_ref(&book)).unwrap();
+
+        let tracks = get_tracks(&conn, None).unwrap();
+        assert_eq!(tracks[0].chapters, book.chapters);
+        assert_eq!(
+            get_chapters(&conn, "/books/a.m4b").unwrap(),
+            Some(book.chapters.clone())
+        );
+        assert_eq!(get_chapters(&conn, "/books/missing.m4b").unwrap(), None);
+
+        // Adding the same file again does not duplicate its chapters
+        assert!(add_tracks(&mut conn, std::slice::from_ref(&book))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            get_chapters(&conn, "/books/a.m4b").unwrap().unwrap().len(),
+            2
+        );
+
+        let folder = add_folder(&conn, "Books", "/books", 1).unwrap();
+        delete_folders(&mut conn, &[folder]).unwrap();
+        let left: i64 = conn
+            .query_row("SELECT COUNT(*) FROM chapters", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn test_cue_ranges() {
+        let mut conn = setup_db();
+        let track = |path: &str, cue: Option<CueRange>| TrackMetadata {
+            id: 0,
+            path: path.to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue,
+            stamp: None,
+        };
+        let first = CueRange {
+            file: "/rips/album.flac".to_string(),
+            start: 0.0,
+            end: Some(241.5),
+        };
+        let last = CueRange {
+            file: "/rips/album.flac".to_string(),
+            start: 241.5,
+            end: None,
+        };
+        add_tracks(
+            &mut conn,
+            &[
+                track("/rips/album.flac#1", Some(first.clone())),
+                track("/rips/album.flac#2", Some(last.clone())),
+                track("/rips/single.mp3", None),
+            ],
+        )
+        .unwrap();
+
+        let tracks = get_tracks(&conn, None).unwrap();
+        assert_eq!(tracks[0].cue, Some(first));
+        assert_eq!(tracks[2].cue, None);
+        assert_eq!(
+            get_cue_range(&conn, "/rips/album.flac#2").unwrap(),
+            Some(Some(last))
+        );
+        assert_eq!(
+            get_cue_range(&conn, "/rips/single.mp3").unwrap(),
+            Some(None)
+        );
+        assert_eq!(get_cue_range(&conn, "/rips/other.flac#1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_replace_file_tracks() {
+        let mut conn = setup_db();
+        let track = |path: &str, title: &str, size: u64| TrackMetadata {
+            id: 0,
+            path: path.to_string(),
+            title: Some(title.to_string()),
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: Some(FileStamp {
+                size,
+                modified: 1_700_000_000_000_000_000,
+            }),
+        };
+        add_tracks(
+            &mut conn,
+            &[
+                track("/music/a.mp3", "Old", 100),
+                track("/music/b.mp3", "B", 200),
+            ],
+        )
+        .unwrap();
+        let ids: Vec<i64> = get_tracks(&conn, None)
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        let playlist_id = create_playlist(&conn, "Mix").unwrap();
+        add_tracks_to_playlist(&mut conn, &playlist_id, &ids).unwrap();
+        save_loudness(&conn, ids[0], None).unwrap();
+
+        let changes = replace_file_tracks(
+            &mut conn,
+            "/music/a.mp3",
+            &[track("/music/a.mp3", "New", 150)],
+        )
+        .unwrap();
+        assert_eq!(changes.updated, [ids[0]]);
+        let tracks = get_tracks_by_playlist(&conn, &playlist_id).unwrap();
+        assert_eq!(tracks[0].title.as_deref(), Some("New"));
+        assert_eq!(tracks[0].stamp.map(|s| s.size), Some(150));
+        // The analysis of the old audio no longer applies
+        assert_eq!(get_tracks_pending_loudness(&conn).unwrap().len(), 2);
+
+        let stamps = get_file_stamps(&conn, "/music/").unwrap();
+        assert_eq!(stamps["/music/b.mp3"].map(|s| s.size), Some(200));
+        assert_eq!(
+            get_file_stamp(&conn, "/music/b.mp3").unwrap(),
+            stamps["/music/b.mp3"]
+        );
+        assert_eq!(get_file_stamp(&conn, "/music/c.mp3").unwrap(), None);
+
+        let changes = replace_file_tracks(&mut conn, "/music/b.mp3", &[]).unwrap();
+        assert_eq!(changes.removed, [ids[1]]);
+        assert_eq!(
+            get_tracks_by_playlist(&conn, &playlist_id).unwrap().len(),
+            1
+        );
+        assert!(replace_file_tracks(&mut conn, "/music/c.mp3", &[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_sibling_folders_with_a_shared_prefix() {
+        let mut conn = setup_db();
+        let track = |path: &str| TrackMetadata {
+            id: 0,
+            path: path.to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        };
+        let rock = add_folder(&conn, "Rock", "/music/rock", 0).unwrap();
+        let rockabilly = add_folder(&conn, "Rockabilly", "/music/rockabilly", 0).unwrap();
+        add_tracks(
+            &mut conn,
+            &[
+                track("/music/rock/a.mp3"),
+                track("/music/rockabilly/b.mp3"),
+                track("/music/rockabilly/c.mp3"),
+                track("/music/Rock/d.mp3"),
+                track("/music/roc_/e.mp3"),
+            ],
+        )
+        .unwrap();
+
+        let stamps = get_file_stamps(&conn, "/music/rock").unwrap();
+        assert_eq!(stamps.keys().collect::<Vec<_>>(), ["/music/rock/a.mp3"]);
+        assert_eq!(get_file_stamps(&conn, "/music/rock/").unwrap(), stamps);
+        // Underscores and percent signs in folder names are not wildcards
+        assert_eq!(get_file_stamps(&conn, "/music/roc_").unwrap().len(), 1);
+
+        refresh_folder_song_count(&conn, &rock).unwrap();
+        refresh_folder_song_count(&conn, &rockabilly).unwrap();
+        let count = |id: &str| get_folder(&conn, id).unwrap().unwrap().song_count;
+        assert_eq!((count(&rock), count(&rockabilly)), (1, 2));
+
+        delete_folders(&mut conn, &[rock]).unwrap();
+        assert_eq!(get_tracks(&conn, None).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_resume_positions() {
+        let conn = setup_db();
+        save_resume_position(&conn, "/book.m4b", 1800.5, 36000.0).unwrap();
+        save_resume_position(&conn, "/mix.mp3", 0.0, 3600.0).unwrap();
+        assert_eq!(
+            get_resume_position(&conn, "/book.m4b").unwrap(),
+            Some(1800.5)
+        );
+        assert_eq!(get_resume_position(&conn, "/other.mp3").unwrap(), None);
+
+        // Positions at zero are not in progress
+        let in_progress = get_in_progress_tracks(&conn).unwrap();
+        assert_eq!(in_progress.len(), 1);
+        assert_eq!(in_progress[0].path, "/book.m4b");
+        assert_eq!(in_progress[0].duration, 36000.0);
+
+        mark_track_finished(&conn, "/book.m4b", None).unwrap();
+        assert_eq!(get_resume_position(&conn, "/book.m4b").unwrap(), None);
+        mark_track_finished(&conn, "/never-played.mp3", None).unwrap();
+        assert!(get_in_progress_tracks(&conn).unwrap().is_empty());
+
+        save_resume_position(&conn, "/book.m4b", 60.0, 36000.0).unwrap();
+        delete_resume_position(&conn, "/book.m4b").unwrap();
+        assert_eq!(get_resume_position(&conn, "/book.m4b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_remember_position_folders() {
+        let conn = setup_db();
+        let books = add_folder(&conn, "Books", "/audio/books", 0).unwrap();
+        add_folder(&conn, "Music", "/audio/music", 0).unwrap();
+        assert!(!in_remember_position_folder(&conn, "/audio/books/a.m4b").unwrap());
+
+        set_folder_remember_position(&conn, &books, true).unwrap();
+        assert!(in_remember_position_folder(&conn, "/audio/books/a.m4b").unwrap());
+        assert!(!in_remember_position_folder(&conn, "/audio/music/a.mp3").unwrap());
+        // Siblings sharing the name prefix are other folders
+        assert!(!in_remember_position_folder(&conn, "/audio/booksale/a.m4b").unwrap());
+        let folders = get_folders(&conn, Some("Books".to_string())).unwrap();
+        assert!(folders[0].remember_position);
+
+        assert!(set_folder_remember_position(&conn, "missing", true).is_err());
+    }
+
+    #[test]
+    fn test_folder_watch() {
+        let conn = setup_db();
+        let music = add_folder(&conn, "Music", "/audio/music", 0).unwrap();
+        assert!(get_folder(&conn, &music).unwrap().unwrap().watch);
+
+        set_folder_watch(&conn, &music, false).unwrap();
+        assert!(!get_folder(&conn, &music).unwrap().unwrap().watch);
+        assert!(set_folder_watch(&conn, "missing", true).is_err());
+        assert!(get_folder(&conn, "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rename_file_tracks() {
+        let mut conn = setup_db();
+        let track = |path: &str, cue_file: Option<&str>| TrackMetadata {
+            id: 0,
+            path: path.to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: cue_file.map(|file| CueRange {
+                file: file.to_string(),
+                start: 0.0,
+                end: None,
+            }),
+            stamp: None,
+        };
+        add_tracks(
+            &mut conn,
+            &[
+                track("/music/rips/album.flac#1", Some("/music/rips/album.flac")),
+                track("/music/rips/single.mp3", None),
+                track("/music/rips2/other.mp3", None),
+                track("/music/taken.mp3", None),
+            ],
+        )
+        .unwrap();
+        let ids: Vec<i64> = get_tracks(&conn, None)
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        let playlist_id = create_playlist(&conn, "Default").unwrap();
+        add_tracks_to_default_playlist(&mut conn, &ids[..2]).unwrap();
+
+        let changes = rename_file_tracks(&mut conn, "/music/rips", "/music/albums").unwrap();
+        assert_eq!(changes.updated, ids[..2]);
+        let tracks = get_tracks_by_playlist(&conn, &playlist_id).unwrap();
+        assert_eq!(tracks[0].path, "/music/albums/album.flac#1");
+        assert_eq!(
+            tracks[0].cue.as_ref().map(|c| c.file.as_str()),
+            Some("/music/albums/album.flac")
+        );
+        assert_eq!(tracks[1].path, "/music/albums/single.mp3");
+        assert_eq!(
+            get_tracks(&conn, None).unwrap()[2].path,
+            "/music/rips2/other.mp3"
+        );
+
+        // Moving over a file in the library replaces it
+        let changes =
+            rename_file_tracks(&mut conn, "/music/albums/single.mp3", "/music/taken.mp3").unwrap();
+        assert_eq!(changes.updated, [ids[1]]);
+        assert_eq!(changes.removed, [ids[3]]);
+        assert!(
+            rename_file_tracks(&mut conn, "/music/none.mp3", "/music/x.mp3")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_loop_regions() {
+        let conn = setup_db();
+        let chorus = save_loop_region(&conn, "/song.flac", "Chorus", 62.5, 80.0).unwrap();
+        save_loop_region(&conn, "/song.flac", "Intro", 0.0, 12.25).unwrap();
+        save_loop_region(&conn, "/other.flac", "Solo", 30.0, 45.0).unwrap();
+
+        let regions = get_loop_regions(&conn, "/song.flac").unwrap();
+        let names: Vec<&str> = regions.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["Intro", "Chorus"]);
+        assert_eq!(regions[1].id, chorus);
+        assert_eq!((regions[1].start, regions[1].end), (62.5, 80.0));
+
+        delete_loop_region(&conn, chorus).unwrap();
+        assert_eq!(get_loop_regions(&conn, "/song.flac").unwrap().len(), 1);
+        assert_eq!(get_loop_regions(&conn, "/other.flac").unwrap().len(), 1);
+    }
+
     #[test]
     fn test_get_tracks_filtered() {
         let mut conn = setup_db();
@@ -393,6 +2038,11 @@ mod tests {
                 cover_mime: None,
                 has_cover: false,
                 cover_img_path: None,
+                replay_gain: ReplayGain::default(),
+                tags: TrackTags::default(),
+                chapters: Vec::new(),
+                cue: None,
+                stamp: None,
             },
             TrackMetadata {
                 id: 0,
@@ -404,6 +2054,11 @@ mod tests {
                 cover_mime: None,
                 has_cover: false,
                 cover_img_path: None,
+                replay_gain: ReplayGain::default(),
+                tags: TrackTags::default(),
+                chapters: Vec::new(),
+                cue: None,
+                stamp: None,
             },
         ];
         add_tracks(&mut conn, &tracks).unwrap();
@@ -433,6 +2088,11 @@ mod tests {
             cover_mime: None,
             has_cover: false,
             cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
         };
         add_tracks(&mut conn, &[track]).unwrap();
 
@@ -457,6 +2117,43 @@ mod tests {
         assert_eq!(folders[0].song_count, 0);
     }
 
+    #[test]
+    fn test_get_folder_track_paths() {
+        let mut conn = setup_db();
+        let folder_id = add_folder(&conn, "Music", "/music/rock", 2).unwrap();
+        let tracks: Vec<TrackMetadata> = [
+            "/music/rock/b.mp3",
+            "/music/jazz/c.mp3",
+            "/music/rockabilly/d.mp3",
+            "/music/rock/a.mp3",
+        ]
+        .iter()
+        .map(|path| TrackMetadata {
+            id: 0,
+            path: (*path).to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration_secs: 0,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        })
+        .collect();
+        add_tracks(&mut conn, &tracks).unwrap();
+
+        assert_eq!(
+            get_folder_track_paths(&conn, &folder_id).unwrap(),
+            vec!["/music/rock/a.mp3", "/music/rock/b.mp3"]
+        );
+        assert!(get_folder_track_paths(&conn, "missing").unwrap().is_empty());
+    }
+
     #[test]
     fn test_get_folders_filtered() {
         let conn = setup_db();
@@ -485,6 +2182,11 @@ mod tests {
             cover_mime: None,
             has_cover: false,
             cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
         };
         add_tracks(&mut conn, &[track]).unwrap();
         let tracks = get_tracks(&conn, None).unwrap();
@@ -533,6 +2235,11 @@ mod tests {
             cover_mime: None,
             has_cover: false,
             cover_img_path: None,
+            replay_gain: ReplayGain::default(),
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
         };
         add_tracks(&mut conn, &[track]).unwrap();
         let tracks = get_tracks(&conn, None).unwrap();
@@ -27,7 +27,140 @@ pub fn create_tables(conn: &Connection) -> Result<(), rusqlite::Error> {
             duration INTEGER,
             cover_mime TEXT,
             has_cover INTEGER,
-            cover_img_path TEXT
+            cover_img_path TEXT,
+            rg_track_gain REAL,
+            rg_track_peak REAL,
+            rg_album_gain REAL,
+            rg_album_peak REAL,
+            cue_file TEXT,
+            cue_start REAL,
+            cue_end REAL,
+            file_size INTEGER,
+            file_mtime INTEGER,
+            album_artist TEXT,
+            track_number INTEGER,
+            track_total INTEGER,
+            disc_number INTEGER,
+            disc_total INTEGER,
+            year INTEGER,
+            date TEXT,
+            genres TEXT,
+            composer TEXT,
+            comment TEXT,
+            bpm REAL,
+            title_sort TEXT,
+            artist_sort TEXT,
+            album_sort TEXT,
+            album_artist_sort TEXT,
+            composer_sort TEXT
+        )",
+        [],
+    )?;
+    // Databases created before ReplayGain support
+    for column in [
+        "rg_track_gain",
+        "rg_track_peak",
+        "rg_album_gain",
+        "rg_album_peak",
+    ] {
+        add_column_if_missing(conn, "tracks", column, "REAL")?;
+    }
+    // Databases created before CUE sheet support; set for virtual tracks only
+    add_column_if_missing(conn, "tracks", "cue_file", "TEXT")?;
+    add_column_if_missing(conn, "tracks", "cue_start", "REAL")?;
+    add_column_if_missing(conn, "tracks", "cue_end", "REAL")?;
+    // Looks up the virtual tracks of a file when it changes on disk
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_tracks_cue_file ON tracks(cue_file)",
+        [],
+    )?;
+    // Databases created before incremental rescans; NULL makes the next rescan re-read the file
+    add_column_if_missing(conn, "tracks", "file_size", "INTEGER")?;
+    add_column_if_missing(conn, "tracks", "file_mtime", "INTEGER")?;
+    // Databases created before rich tags; genres are stored as a JSON array
+    for (column, definition) in [
+        ("album_artist", "TEXT"),
+        ("track_number", "INTEGER"),
+        ("track_total", "INTEGER"),
+        ("disc_number", "INTEGER"),
+        ("disc_total", "INTEGER"),
+        ("year", "INTEGER"),
+        ("date", "TEXT"),
+        ("genres", "TEXT"),
+        ("composer", "TEXT"),
+        ("comment", "TEXT"),
+        ("bpm", "REAL"),
+        ("title_sort", "TEXT"),
+        ("artist_sort", "TEXT"),
+        ("album_sort", "TEXT"),
+        ("album_artist_sort", "TEXT"),
+        ("composer_sort", "TEXT"),
+    ] {
+        add_column_if_missing(conn, "tracks", column, definition)?;
+    }
+
+    // EBU R128 analysis results; a row with NULL values marks a track that failed to analyze
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS track_loudness (
+            track_id INTEGER PRIMARY KEY,
+            integrated REAL,
+            loudness_range REAL,
+            true_peak REAL,
+            analyzed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (track_id) REFERENCES tracks(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS chapters (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            track_id INTEGER NOT NULL,
+            idx INTEGER NOT NULL,
+            title TEXT,
+            start_secs REAL NOT NULL,
+            end_secs REAL NOT NULL,
+            FOREIGN KEY (track_id) REFERENCES tracks(id) ON DELETE CASCADE
+        )",
+        [],
+    )?;
+
+    // Bands are stored as JSON
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS eq_presets (
+            name TEXT PRIMARY KEY,
+            bands TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Preset names are not foreign keys: built-in presets have no row
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS eq_overrides (
+            scope TEXT NOT NULL,
+            key TEXT NOT NULL,
+            preset TEXT NOT NULL,
+            PRIMARY KEY (scope, key)
+        )",
+        [],
+    )?;
+
+    // Keyed by path like track overrides, so files outside the library can have loops too
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS loop_regions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL,
+            name TEXT NOT NULL,
+            start_secs REAL NOT NULL,
+            end_secs REAL NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
         )",
         [],
     )?;
@@ -37,7 +170,28 @@ pub fn create_tables(conn: &Connection) -> Result<(), rusqlite::Error> {
             id TEXT PRIMARY KEY,
             name TEXT NOT NULL,
             path TEXT NOT NULL UNIQUE,
-            song_count INTEGER DEFAULT 0
+            song_count INTEGER DEFAULT 0,
+            remember_position INTEGER NOT NULL DEFAULT 0,
+            watch INTEGER NOT NULL DEFAULT 1
+        )",
+        [],
+    )?;
+    add_column_if_missing(
+        conn,
+        "local_folders",
+        "remember_position",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(conn, "local_folders", "watch", "INTEGER NOT NULL DEFAULT 1")?;
+
+    // Last position of long-form tracks, keyed by path so files outside the library resume too
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS resume_positions (
+            path TEXT PRIMARY KEY,
+            position_secs REAL NOT NULL,
+            duration_secs REAL NOT NULL,
+            finished INTEGER NOT NULL DEFAULT 0,
+            updated_at INTEGER NOT NULL
         )",
         [],
     )?;
@@ -64,3 +218,28 @@ pub fn create_tables(conn: &Connection) -> Result<(), rusqlite::Error> {
     )?;
     Ok(())
 }
+
+/// Adds `column` to `table` unless it is already there.
+///
+/// # Errors
+///
+/// Returns an error if the table cannot be inspected or altered.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+    Ok(())
+}
@@ -1,16 +1,32 @@
 pub mod audio;
 pub mod database;
 pub mod scanner;
+#[cfg(test)]
+mod test_util;
 
 use audio::commands::{
-    add_folder, add_tracks_to_playlist, create_playlist, delete_folders, delete_playlist,
-    delete_tracks_from_playlist, get_folders, get_playlists, get_tracks, get_tracks_by_playlist,
-    pause, play, resume, seek, set_volume, stop,
+    add_folder, add_tracks_to_playlist, cancel_loudness_analysis, cancel_scan, cancel_sleep_timer,
+    cancel_waveforms, clear_loop, clear_queue, create_playlist, delete_eq_preset, delete_folders,
+    delete_loop_region, delete_playlist, delete_tracks_from_playlist, disable_spectrum,
+    enable_spectrum, enqueue, enqueue_next, generate_folder_waveforms, get_eq_presets, get_folders,
+    get_in_progress_tracks, get_loop_regions, get_playlists, get_queue, get_tracks,
+    get_tracks_by_playlist, get_waveform, list_output_devices, load_eq_preset, mark_track_finished,
+    move_in_queue, next, next_chapter, pause, play, previous, previous_chapter, remove_from_queue,
+    rescan_folder, reset_resume_position, restore_session, resume, save_eq_preset,
+    save_loop_region, seek, seek_chapter, set_crossfade, set_eq_band, set_eq_override,
+    set_equalizer, set_folder_remember_position, set_folder_watch, set_loop, set_normalization,
+    set_output_device, set_pitch, set_repeat, set_resume_threshold, set_shuffle, set_sleep_timer,
+    set_speed, set_transport_fade, set_volume, start_loudness_analysis, stop,
 };
-use audio::player::init_audio_thread;
+use audio::output::RodioBackend;
+use audio::player::{init_audio_thread, AudioPlayerState};
 use database::AppState;
+use scanner::analysis::AnalysisState;
+use scanner::scan::ScanState;
+use scanner::watcher::LibraryWatcher;
+use scanner::waveform::WaveformState;
 use std::sync::Mutex;
-use tauri::Manager;
+use tauri::{Manager, RunEvent};
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
@@ -20,7 +36,7 @@ fn greet(name: &str) -> String {
 
 /// # Panics
 ///
-/// Panics if the Tauri application fails to run or if audio initialization fails.
+/// Panics if the Tauri application fails to build or if audio initialization fails.
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -28,7 +44,7 @@ pub fn run() {
             // Initialize Audio Thread
             // We pass the app handle to the audio thread so it can emit events
             let app_handle = app.handle().clone();
-            let player_state = init_audio_thread(app_handle);
+            let player_state = init_audio_thread(app_handle, RodioBackend);
 
             // Manage the state so commands can access it
             app.manage(player_state);
@@ -61,6 +77,26 @@ pub fn run() {
             app.manage(AppState {
                 db: Mutex::new(conn),
             });
+            app.manage(AnalysisState::default());
+            app.manage(ScanState::default());
+
+            let cache_dir = app
+                .path()
+                .app_cache_dir()
+                .map_err(|e| format!("Failed to get app cache dir: {e}"))?;
+            app.manage(WaveformState::new(
+                app.handle().clone(),
+                cache_dir.join("waveforms"),
+            ));
+            app.manage(LibraryWatcher::new(
+                app.handle().clone(),
+                cache_dir.join("images"),
+            ));
+
+            // Pick up where the last run stopped, paused until the user resumes
+            if let Err(e) = restore_session(app.state(), app.state()) {
+                eprintln!("Failed to restore the playback session: {e}");
+            }
 
             Ok(())
         })
@@ -74,18 +110,75 @@ pub fn run() {
             resume,
             stop,
             seek,
+            set_loop,
+            clear_loop,
             set_volume,
+            set_crossfade,
+            set_repeat,
+            set_shuffle,
+            set_normalization,
+            set_speed,
+            list_output_devices,
+            set_output_device,
+            set_sleep_timer,
+            set_transport_fade,
+            enable_spectrum,
+            disable_spectrum,
+            cancel_sleep_timer,
+            set_pitch,
+            set_equalizer,
+            set_eq_band,
+            enqueue,
+            enqueue_next,
+            next,
+            previous,
+            next_chapter,
+            previous_chapter,
+            seek_chapter,
+            clear_queue,
+            move_in_queue,
+            remove_from_queue,
+            get_queue,
+            restore_session,
             add_folder,
+            cancel_scan,
             get_folders,
             delete_folders,
+            rescan_folder,
             get_tracks,
             create_playlist,
             get_playlists,
             get_tracks_by_playlist,
             add_tracks_to_playlist,
             delete_tracks_from_playlist,
-            delete_playlist
+            delete_playlist,
+            save_loop_region,
+            get_loop_regions,
+            delete_loop_region,
+            get_in_progress_tracks,
+            mark_track_finished,
+            reset_resume_position,
+            set_resume_threshold,
+            set_folder_remember_position,
+            set_folder_watch,
+            get_eq_presets,
+            save_eq_preset,
+            delete_eq_preset,
+            load_eq_preset,
+            set_eq_override,
+            start_loudness_analysis,
+            cancel_loudness_analysis,
+            get_waveform,
+            generate_folder_waveforms,
+            cancel_waveforms
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                if let Err(e) = app.state::<AudioPlayerState>().shutdown() {
+                    eprintln!("Failed to save the playback session: {e}");
+                }
+            }
+        });
 }
@@ -0,0 +1,115 @@
+use crate::database::{operations, AppState};
+use crate::scanner::loudness::analyze_file;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Tracks the running loudness analysis, if any.
+#[derive(Default)]
+pub struct AnalysisState {
+    cancel: Arc<Mutex<Option<Arc<AtomicBool>>>>,
+}
+
+impl AnalysisState {
+    /// Starts analyzing every library track that has no `ReplayGain` tags and no stored
+    /// result yet. Results are saved one track at a time, so a cancelled or interrupted
+    /// run picks up where it stopped.
+    ///
+    /// Emits `analysis-progress` after each track and `analysis-finished` at the end.
+    /// Returns `false` if an analysis is already running.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state mutex is poisoned.
+    pub fn start(&self, app_handle: AppHandle) -> Result<bool, String> {
+        let mut running = self.cancel.lock().map_err(|e| e.to_string())?;
+        if running.is_some() {
+            return Ok(false);
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        *running = Some(cancel.clone());
+
+        let slot = self.cancel.clone();
+        thread::spawn(move || {
+            run(&app_handle, &cancel);
+            if let Ok(mut running) = slot.lock() {
+                *running = None;
+            }
+        });
+        Ok(true)
+    }
+
+    /// Asks the running analysis to stop after the current chunk.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state mutex is poisoned.
+    pub fn cancel(&self) -> Result<(), String> {
+        let running = self.cancel.lock().map_err(|e| e.to_string())?;
+        if let Some(cancel) = running.as_ref() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+fn run(app_handle: &AppHandle, cancel: &AtomicBool) {
+    let Some(state) = app_handle.try_state::<AppState>() else {
+        return;
+    };
+    // The database is only locked between tracks, never while decoding
+    let pending = match state.db.lock() {
+        Ok(conn) => operations::get_tracks_pending_loudness(&conn),
+        Err(e) => {
+            eprintln!("Loudness analysis failed to lock database: {e}");
+            return;
+        }
+    };
+    let pending = match pending {
+        Ok(pending) => pending,
+        Err(e) => {
+            eprintln!("Loudness analysis failed to list tracks: {e}");
+            return;
+        }
+    };
+
+    let total = pending.len();
+    let mut done = 0;
+    for (track_id, path) in pending {
+        let loudness = match analyze_file(&path, cancel) {
+            Ok(Some(loudness)) => Some(loudness),
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Loudness analysis of {path} failed: {e}");
+                None
+            }
+        };
+
+        let saved = state.db.lock().map_err(|e| e.to_string()).and_then(|conn| {
+            operations::save_loudness(&conn, track_id, loudness.as_ref()).map_err(|e| e.to_string())
+        });
+        if let Err(e) = saved {
+            eprintln!("Failed to save loudness of {path}: {e}");
+        }
+
+        done += 1;
+        app_handle
+            .emit(
+                "analysis-progress",
+                serde_json::json!({ "done": done, "total": total, "path": path, "loudness": loudness }),
+            )
+            .ok();
+    }
+
+    app_handle
+        .emit(
+            "analysis-finished",
+            serde_json::json!({
+                "done": done,
+                "total": total,
+                "cancelled": cancel.load(Ordering::Relaxed)
+            }),
+        )
+        .ok();
+}
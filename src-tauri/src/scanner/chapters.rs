@@ -0,0 +1,817 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// A chapter marker; times are in seconds.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: Option<String>,
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Index of the chapter playing at `position` seconds; `None` before the first one.
+#[must_use]
+pub fn chapter_at(chapters: &[Chapter], position: f64) -> Option<usize> {
+    chapters.iter().rposition(|c| c.start <= position)
+}
+
+/// A chapter as stored in the file, before its end is known for sure.
+#[derive(Debug, Clone, PartialEq)]
+struct Marker {
+    title: Option<String>,
+    start: f64,
+    end: Option<f64>,
+}
+
+/// Reads the chapter markers of an MP3 (ID3v2 `CHAP`/`CTOC` frames) or MP4/M4B file
+/// (`QuickTime` chapter track, or Nero `chpl` as a fallback).
+///
+/// Chapters come back in playing order. Missing or inconsistent end times are filled in
+/// from the next chapter or from `duration`, when it is known. Other formats have no
+/// chapters.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read.
+pub fn read_chapters(path: &Path, duration: Duration) -> Result<Vec<Chapter>, String> {
+    let mut file = File::open(path).map_err(|e| format!("Failed to open file: {e}"))?;
+    let mut head = Vec::with_capacity(12);
+    file.by_ref()
+        .take(12)
+        .read_to_end(&mut head)
+        .map_err(|e| e.to_string())?;
+
+    let markers = if head.starts_with(b"ID3") {
+        id3_chapters(&mut file)?
+    } else if head.get(4..8) == Some(b"ftyp") {
+        mp4_chapters(&mut file)?
+    } else {
+        Vec::new()
+    };
+    Ok(finish(markers, duration.as_secs_f64()))
+}
+
+/// Orders the markers and gives each one an end within the track.
+fn finish(mut markers: Vec<Marker>, duration: f64) -> Vec<Chapter> {
+    let known = duration > 0.0;
+    markers.retain(|m| m.start.is_finite() && m.start >= 0.0 && (!known || m.start < duration));
+    markers.sort_by(|a, b| a.start.total_cmp(&b.start));
+    markers.dedup_by(|b, a| a.start == b.start);
+
+    let starts: Vec<f64> = markers.iter().map(|m| m.start).collect();
+    markers
+        .into_iter()
+        .enumerate()
+        .map(|(i, m)| {
+            let next = starts.get(i + 1).copied();
+            let limit = next.or(known.then_some(duration));
+            let end = match (m.end, limit) {
+                (Some(end), Some(limit)) if end > m.start => end.min(limit),
+                (Some(end), None) if end > m.start => end,
+                (_, Some(limit)) => limit,
+                (_, None) => m.start,
+            };
+            Chapter {
+                title: m.title,
+                start: m.start,
+                end,
+            }
+        })
+        .collect()
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn synchsafe(data: &[u8], at: usize) -> Option<usize> {
+    let bytes = data.get(at..at + 4)?;
+    Some(
+        bytes
+            .iter()
+            .fold(0usize, |size, b| (size << 7) | usize::from(b & 0x7F)),
+    )
+}
+
+/// Undoes ID3v2 unsynchronisation: every `FF 00` becomes `FF`.
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut previous = 0;
+    for &b in data {
+        if !(previous == 0xFF && b == 0) {
+            out.push(b);
+        }
+        previous = b;
+    }
+    out
+}
+
+/// Frames of an ID3v2.3 or v2.4 tag body, or of the sub-frames of a `CHAP`/`CTOC`.
+///
+/// Compressed and encrypted frames are skipped.
+fn id3_frames(mut data: &[u8], version: u8) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut frames = Vec::new();
+    while data.len() >= 10 && data[0] != 0 {
+        let mut id = [0; 4];
+        id.copy_from_slice(&data[..4]);
+        let size = if version == 4 {
+            synchsafe(data, 4)
+        } else {
+            be_u32(data, 4).and_then(|s| usize::try_from(s).ok())
+        };
+        let Some(content) = size.and_then(|size| data.get(10..10 + size)) else {
+            break;
+        };
+        let format = data[9];
+        data = &data[10 + content.len()..];
+
+        let (skip, unsync, length_indicator) = if version == 4 {
+            (format & 0x0C != 0, format & 0x02 != 0, format & 0x01 != 0)
+        } else {
+            (format & 0xC0 != 0, false, false)
+        };
+        if skip {
+            continue;
+        }
+        let content = if length_indicator {
+            content.get(4..).unwrap_or_default()
+        } else {
+            content
+        };
+        frames.push((
+            id,
+            if unsync {
+                resynchronise(content)
+            } else {
+                content.to_vec()
+            },
+        ));
+    }
+    frames
+}
+
+/// Splits a null-terminated Latin-1 string off the front of `data`.
+fn null_terminated(data: &[u8]) -> Option<(String, &[u8])> {
+    let end = data.iter().position(|&b| b == 0)?;
+    let text = data[..end].iter().map(|&b| char::from(b)).collect();
+    Some((text, &data[end + 1..]))
+}
+
+/// Decodes the value of an ID3v2 text frame; only the first of several values is kept.
+fn id3_text(data: &[u8]) -> Option<String> {
+    let (&encoding, text) = data.split_first()?;
+    let decoded = match encoding {
+        0 => text.iter().map(|&b| char::from(b)).collect(),
+        1 | 2 => {
+            let mut units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            if encoding == 1 {
+                match units.first() {
+                    Some(0xFEFF) => {
+                        units.remove(0);
+                    }
+                    Some(0xFFFE) => {
+                        units.remove(0);
+                        units.iter_mut().for_each(|u| *u = u.swap_bytes());
+                    }
+                    _ => {}
+                }
+            }
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(text).into_owned(),
+        _ => return None,
+    };
+    let first = decoded.split('\0').next().unwrap_or_default().trim();
+    (!first.is_empty()).then(|| first.to_string())
+}
+
+/// Title from the `TIT2` sub-frame of a `CHAP` or `CTOC`.
+fn sub_frame_title(data: &[u8], version: u8) -> Option<String> {
+    id3_frames(data, version)
+        .into_iter()
+        .find(|(id, _)| id == b"TIT2")
+        .and_then(|(_, content)| id3_text(&content))
+}
+
+struct TableOfContents {
+    top_level: bool,
+    children: Vec<String>,
+}
+
+fn id3_chapters(file: &mut File) -> Result<Vec<Marker>, String> {
+    let mut header = [0; 10];
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_exact(&mut header))
+        .map_err(|e| e.to_string())?;
+    let version = header[3];
+    // ID3v2.2 predates chapters
+    if !(3..=4).contains(&version) {
+        return Ok(Vec::new());
+    }
+    let flags = header[5];
+    let size = synchsafe(&header, 6).unwrap_or(0);
+    let mut body = Vec::with_capacity(size);
+    file.by_ref()
+        .take(size as u64)
+        .read_to_end(&mut body)
+        .map_err(|e| e.to_string())?;
+    if version == 3 && flags & 0x80 != 0 {
+        body = resynchronise(&body);
+    }
+    let mut start = 0;
+    if flags & 0x40 != 0 {
+        start = if version == 3 {
+            be_u32(&body, 0)
+                .and_then(|s| usize::try_from(s).ok())
+                .map(|s| s + 4)
+        } else {
+            synchsafe(&body, 0)
+        }
+        .unwrap_or(body.len());
+    }
+    Ok(id3_markers(body.get(start..).unwrap_or_default(), version))
+}
+
+/// Chapters listed by the top-level table of contents, or every chapter if there is none.
+fn id3_markers(body: &[u8], version: u8) -> Vec<Marker> {
+    let mut chapters = HashMap::new();
+    let mut tables = HashMap::new();
+    for (id, content) in id3_frames(body, version) {
+        let Some((element, rest)) = null_terminated(&content) else {
+            continue;
+        };
+        match &id {
+            b"CHAP" => {
+                let (Some(start), Some(end)) = (be_u32(rest, 0), be_u32(rest, 4)) else {
+                    continue;
+                };
+                let marker = Marker {
+                    title: sub_frame_title(rest.get(16..).unwrap_or_default(), version),
+                    start: f64::from(start) / 1000.0,
+                    end: Some(f64::from(end) / 1000.0),
+                };
+                chapters.insert(element, marker);
+            }
+            b"CTOC" => {
+                let (Some(&flags), Some(&count)) = (rest.first(), rest.get(1)) else {
+                    continue;
+                };
+                let mut entries = rest.get(2..).unwrap_or_default();
+                let mut children = Vec::new();
+                for _ in 0..count {
+                    let Some((child, tail)) = null_terminated(entries) else {
+                        break;
+                    };
+                    children.push(child);
+                    entries = tail;
+                }
+                tables.insert(
+                    element,
+                    TableOfContents {
+                        top_level: flags & 0x02 != 0,
+                        children,
+                    },
+                );
+            }
+            _ => {}
+        }
+    }
+
+    let roots: Vec<&String> = tables
+        .iter()
+        .filter(|(_, t)| t.top_level)
+        .map(|(id, _)| id)
+        .collect();
+    if roots.is_empty() {
+        return chapters.into_values().collect();
+    }
+    // Nested tables of contents are followed; each element is visited once
+    let mut visited: HashSet<&String> = HashSet::new();
+    let mut stack = roots;
+    let mut markers = Vec::new();
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        if let Some(table) = tables.get(id) {
+            stack.extend(table.children.iter());
+        } else if let Some(marker) = chapters.get(id) {
+            markers.push(marker.clone());
+        }
+    }
+    markers
+}
+
+/// Child boxes of an MP4 container, as `(type, content)`.
+fn mp4_boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let mut kind = [0; 4];
+        kind.copy_from_slice(&data[4..8]);
+        let (header, size) = match be_u32(data, 0) {
+            Some(0) => (8, Some(data.len())),
+            Some(1) => (16, be_u64(data, 8).and_then(|s| usize::try_from(s).ok())),
+            size => (8, size.and_then(|s| usize::try_from(s).ok())),
+        };
+        let Some(content) = size
+            .filter(|&s| s >= header)
+            .and_then(|s| data.get(header..s))
+        else {
+            break;
+        };
+        boxes.push((kind, content));
+        data = &data[header + content.len()..];
+    }
+    boxes
+}
+
+/// The content of the first box found by following `path` from `data`.
+fn mp4_find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let (_, content) = mp4_boxes(data).into_iter().find(|(k, _)| k == *first)?;
+    if rest.is_empty() {
+        Some(content)
+    } else {
+        mp4_find(content, rest)
+    }
+}
+
+/// Reads the `moov` box, which holds the track tables.
+fn read_moov(file: &mut File) -> Result<Option<Vec<u8>>, String> {
+    let length = file.metadata().map_err(|e| e.to_string())?.len();
+    let mut offset = 0;
+    while offset + 8 <= length {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut header[..8]))
+            .map_err(|e| e.to_string())?;
+        let (header_len, size) = match be_u32(&header, 0) {
+            Some(0) => (8, length - offset),
+            Some(1) => {
+                file.read_exact(&mut header[8..])
+                    .map_err(|e| e.to_string())?;
+                (16, be_u64(&header, 8).unwrap_or(0))
+            }
+            size => (8, u64::from(size.unwrap_or(0))),
+        };
+        if size < header_len {
+            return Ok(None);
+        }
+        if &header[4..8] == b"moov" {
+            let mut moov = Vec::new();
+            file.by_ref()
+                .take(size - header_len)
+                .read_to_end(&mut moov)
+                .map_err(|e| e.to_string())?;
+            return Ok(Some(moov));
+        }
+        offset += size;
+    }
+    Ok(None)
+}
+
+fn mp4_chapters(file: &mut File) -> Result<Vec<Marker>, String> {
+    let Some(moov) = read_moov(file)? else {
+        return Ok(Vec::new());
+    };
+    let tracks: Vec<&[u8]> = mp4_boxes(&moov)
+        .into_iter()
+        .filter(|(k, _)| k == b"trak")
+        .map(|(_, t)| t)
+        .collect();
+
+    // Audio tracks point at their chapter track through a `chap` track reference
+    let chapter_ids: Vec<u32> = tracks
+        .iter()
+        .filter_map(|t| mp4_find(t, &[b"tref", b"chap"]))
+        .flat_map(|refs| (0..refs.len() / 4).filter_map(move |i| be_u32(refs, i * 4)))
+        .collect();
+    for track in &tracks {
+        if track_id(track).is_some_and(|id| chapter_ids.contains(&id)) {
+            let markers = text_track(file, track)?;
+            if !markers.is_empty() {
+                return Ok(markers);
+            }
+        }
+    }
+
+    Ok(mp4_find(&moov, &[b"udta", b"chpl"])
+        .map(nero_chapters)
+        .unwrap_or_default())
+}
+
+fn track_id(track: &[u8]) -> Option<u32> {
+    let tkhd = mp4_find(track, &[b"tkhd"])?;
+    // Creation and modification times are 64-bit in version 1
+    be_u32(tkhd, if tkhd.first()? == &1 { 20 } else { 12 })
+}
+
+/// Start times and texts of the samples of a `QuickTime` text track.
+fn text_track(file: &mut File, track: &[u8]) -> Result<Vec<Marker>, String> {
+    let Some(mdhd) = mp4_find(track, &[b"mdia", b"mdhd"]) else {
+        return Ok(Vec::new());
+    };
+    let timescale = be_u32(mdhd, if mdhd.first() == Some(&1) { 20 } else { 12 }).unwrap_or(0);
+    let Some(stbl) = mp4_find(track, &[b"mdia", b"minf", b"stbl"]) else {
+        return Ok(Vec::new());
+    };
+    if timescale == 0 {
+        return Ok(Vec::new());
+    }
+
+    let table = |kind: &[u8; 4], width: usize| -> Vec<u64> {
+        let Some(data) = mp4_find(stbl, &[kind]) else {
+            return Vec::new();
+        };
+        let count = be_u32(data, 4).map_or(0, |c| c as usize);
+        (0..count)
+            .map_while(|i| {
+                let at = 8 + i * width;
+                if width == 8 {
+                    be_u64(data, at)
+                } else {
+                    be_u32(data, at).map(u64::from)
+                }
+            })
+            .collect()
+    };
+
+    // Start time of every sample, from the run-length coded durations
+    let mut starts = Vec::new();
+    let mut time = 0u64;
+    if let Some(data) = mp4_find(stbl, &[b"stts"]) {
+        for i in 0..be_u32(data, 4).map_or(0, |c| c as usize) {
+            let (Some(count), Some(delta)) = (be_u32(data, 8 + i * 8), be_u32(data, 12 + i * 8))
+            else {
+                break;
+            };
+            for _ in 0..count {
+                starts.push(time);
+                time += u64::from(delta);
+            }
+        }
+    }
+
+    let sizes: Vec<u64> = match mp4_find(stbl, &[b"stsz"]) {
+        Some(stsz) if be_u32(stsz, 4).is_some_and(|s| s != 0) => {
+            let size = u64::from(be_u32(stsz, 4).unwrap_or(0));
+            vec![size; be_u32(stsz, 8).map_or(0, |c| c as usize)]
+        }
+        Some(stsz) => {
+            let count = be_u32(stsz, 8).map_or(0, |c| c as usize);
+            (0..count)
+                .map_while(|i| be_u32(stsz, 12 + i * 4).map(u64::from))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    let mut chunks = table(b"stco", 4);
+    if chunks.is_empty() {
+        chunks = table(b"co64", 8);
+    }
+    let stsc: Vec<(u64, u64)> = mp4_find(stbl, &[b"stsc"])
+        .map(|data| {
+            let count = be_u32(data, 4).map_or(0, |c| c as usize);
+            (0..count)
+                .map_while(|i| {
+                    let first = be_u32(data, 8 + i * 12)?;
+                    let per_chunk = be_u32(data, 12 + i * 12)?;
+                    Some((u64::from(first), u64::from(per_chunk)))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // File offset of every sample: chunks hold runs of consecutive samples
+    let mut offsets = Vec::with_capacity(sizes.len());
+    for (chunk, &chunk_offset) in chunks.iter().enumerate() {
+        let number = chunk as u64 + 1;
+        let per_chunk = stsc
+            .iter()
+            .take_while(|(first, _)| *first <= number)
+            .last()
+            .map_or(0, |(_, n)| *n);
+        let mut offset = chunk_offset;
+        for _ in 0..per_chunk {
+            let Some(&size) = sizes.get(offsets.len()) else {
+                break;
+            };
+            offsets.push((offset, size));
+            offset += size;
+        }
+    }
+
+    let mut markers = Vec::new();
+    for (&start, &(offset, size)) in starts.iter().zip(&offsets) {
+        let mut sample = Vec::new();
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| e.to_string())?;
+        file.by_ref()
+            .take(size)
+            .read_to_end(&mut sample)
+            .map_err(|e| e.to_string())?;
+        #[allow(clippy::cast_precision_loss)]
+        markers.push(Marker {
+            title: text_sample(&sample),
+            start: start as f64 / f64::from(timescale),
+            end: None,
+        });
+    }
+    Ok(markers)
+}
+
+/// Text of a `QuickTime` text sample: a 16-bit length, then UTF-8 or UTF-16 with a BOM.
+fn text_sample(sample: &[u8]) -> Option<String> {
+    let length = usize::from(be_u16(sample, 0)?);
+    let text = sample.get(2..2 + length)?;
+    let decoded = if let Some(utf16) = text.strip_prefix(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = utf16
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(text).into_owned()
+    };
+    let decoded = decoded.trim();
+    (!decoded.is_empty()).then(|| decoded.to_string())
+}
+
+/// Nero chapter list: 100 ns start times with length-prefixed UTF-8 titles.
+fn nero_chapters(chpl: &[u8]) -> Vec<Marker> {
+    // Version 1 adds four reserved bytes after the version and flags
+    let mut at = if chpl.first() == Some(&1) { 8 } else { 4 };
+    let Some(&count) = chpl.get(at) else {
+        return Vec::new();
+    };
+    at += 1;
+    let mut markers = Vec::new();
+    for _ in 0..count {
+        let (Some(start), Some(&length)) = (be_u64(chpl, at), chpl.get(at + 8)) else {
+            break;
+        };
+        let Some(title) = chpl.get(at + 9..at + 9 + usize::from(length)) else {
+            break;
+        };
+        at += 9 + usize::from(length);
+        let title = String::from_utf8_lossy(title).trim().to_string();
+        #[allow(clippy::cast_precision_loss)]
+        markers.push(Marker {
+            title: (!title.is_empty()).then_some(title),
+            start: start as f64 / 10_000_000.0,
+            end: None,
+        });
+    }
+    markers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn frame(id: &[u8; 4], content: &[u8], version: u8) -> Vec<u8> {
+        let size = u32::try_from(content.len()).unwrap();
+        let size = if version == 4 {
+            (size & 0x7F) | ((size & 0x3F80) << 1) | ((size & 0x1F_C000) << 2)
+        } else {
+            size
+        };
+        let mut out = id.to_vec();
+        out.extend(size.to_be_bytes());
+        out.extend([0, 0]);
+        out.extend(content);
+        out
+    }
+
+    fn title(text: &str, version: u8) -> Vec<u8> {
+        let mut content = vec![3];
+        content.extend(text.as_bytes());
+        frame(b"TIT2", &content, version)
+    }
+
+    fn chap(id: &str, start_ms: u32, end_ms: u32, name: &str, version: u8) -> Vec<u8> {
+        let mut content = format!("{id}\0").into_bytes();
+        content.extend(start_ms.to_be_bytes());
+        content.extend(end_ms.to_be_bytes());
+        content.extend([0xFF; 8]);
+        content.extend(title(name, version));
+        frame(b"CHAP", &content, version)
+    }
+
+    fn id3_file(frames: &[Vec<u8>], version: u8) -> tempfile::NamedTempFile {
+        let body: Vec<u8> = frames.concat();
+        let size = u32::try_from(body.len()).unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[b'I', b'D', b'3', version, 0, 0]).unwrap();
+        file.write_all(&[
+            u8::try_from((size >> 21) & 0x7F).unwrap(),
+            u8::try_from((size >> 14) & 0x7F).unwrap(),
+            u8::try_from((size >> 7) & 0x7F).unwrap(),
+            u8::try_from(size & 0x7F).unwrap(),
+        ])
+        .unwrap();
+        file.write_all(&body).unwrap();
+        // Stand-in for the audio frames
+        file.write_all(&[0xFF, 0xFB, 0x90, 0x00]).unwrap();
+        file
+    }
+
+    fn titles(chapters: &[Chapter]) -> Vec<&str> {
+        chapters
+            .iter()
+            .map(|c| c.title.as_deref().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn test_id3_chapters_in_playing_order() {
+        for version in [3, 4] {
+            let file = id3_file(
+                &[
+                    title("Book", version),
+                    chap("ch2", 60_000, 0, "Two", version),
+                    chap("ch1", 0, 60_000, "One", version),
+                    chap("ch3", 150_000, 200_000, "Three", version),
+                ],
+                version,
+            );
+            let chapters = read_chapters(file.path(), Duration::from_secs(180)).unwrap();
+            assert_eq!(titles(&chapters), ["One", "Two", "Three"]);
+            // A missing end runs to the next chapter, the last one stops at the track end
+            assert_eq!(chapters[1].end, 150.0);
+            assert_eq!(chapters[2].end, 180.0);
+        }
+    }
+
+    #[test]
+    fn test_table_of_contents_selects_chapters() {
+        let mut toc = b"toc\0".to_vec();
+        toc.extend([0x03, 2]);
+        toc.extend(b"a\0b\0");
+        let file = id3_file(
+            &[
+                chap("a", 0, 1000, "Kept", 3),
+                chap("b", 1000, 2000, "Also kept", 3),
+                chap("bonus", 2000, 3000, "Not listed", 3),
+                frame(b"CTOC", &toc, 3),
+            ],
+            3,
+        );
+        let chapters = read_chapters(file.path(), Duration::ZERO).unwrap();
+        assert_eq!(titles(&chapters), ["Kept", "Also kept"]);
+    }
+
+    #[test]
+    fn test_utf16_titles() {
+        let mut content = vec![1, 0xFF, 0xFE];
+        for unit in "Kapitel 1".encode_utf16() {
+            content.extend(unit.to_le_bytes());
+        }
+        content.extend([0, 0]);
+        assert_eq!(id3_text(&content).as_deref(), Some("Kapitel 1"));
+    }
+
+    fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut out = u32::try_from(content.len() + 8)
+            .unwrap()
+            .to_be_bytes()
+            .to_vec();
+        out.extend(kind);
+        out.extend(content);
+        out
+    }
+
+    fn full_box(kind: &[u8; 4], words: &[u32]) -> Vec<u8> {
+        let content: Vec<u8> = [0u32]
+            .iter()
+            .chain(words)
+            .flat_map(|w| w.to_be_bytes())
+            .collect();
+        mp4_box(kind, &content)
+    }
+
+    #[test]
+    fn test_mp4_chapter_track() {
+        let texts = ["Opening", "Middle", "Credits"];
+        let samples: Vec<Vec<u8>> = texts
+            .iter()
+            .map(|t| {
+                let mut s = u16::try_from(t.len()).unwrap().to_be_bytes().to_vec();
+                s.extend(t.as_bytes());
+                s
+            })
+            .collect();
+        let mdat = mp4_box(b"mdat", &samples.concat());
+
+        let build = |mdat_offset: u32| {
+            let sizes: Vec<u32> = samples
+                .iter()
+                .map(|s| u32::try_from(s.len()).unwrap())
+                .collect();
+            let stbl = [
+                // Two samples of 30 s, then one of 15 s, at a timescale of 1000
+                full_box(b"stts", &[2, 2, 30_000, 1, 15_000]),
+                full_box(b"stsz", &[0, 3, sizes[0], sizes[1], sizes[2]]),
+                // First chunk holds two samples, the second one
+                full_box(b"stsc", &[2, 1, 2, 1, 2, 1, 1]),
+                full_box(
+                    b"stco",
+                    &[2, mdat_offset + 8, mdat_offset + 8 + sizes[0] + sizes[1]],
+                ),
+            ]
+            .concat();
+            let mdia = [
+                full_box(b"mdhd", &[0, 0, 1000, 75_000]),
+                mp4_box(b"minf", &mp4_box(b"stbl", &stbl)),
+            ]
+            .concat();
+            let text_track = [full_box(b"tkhd", &[0, 0, 2]), mp4_box(b"mdia", &mdia)].concat();
+            let audio_track = [
+                full_box(b"tkhd", &[0, 0, 1]),
+                mp4_box(b"tref", &mp4_box(b"chap", &2u32.to_be_bytes())),
+            ]
+            .concat();
+            mp4_box(
+                b"moov",
+                &[
+                    mp4_box(b"trak", &audio_track),
+                    mp4_box(b"trak", &text_track),
+                ]
+                .concat(),
+            )
+        };
+        let ftyp = mp4_box(b"ftyp", b"M4B \0\0\0\0");
+        let moov_len = build(0).len();
+        let moov = build(u32::try_from(ftyp.len() + moov_len).unwrap());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[ftyp, moov, mdat].concat()).unwrap();
+        let chapters = read_chapters(file.path(), Duration::from_secs(75)).unwrap();
+        assert_eq!(titles(&chapters), texts);
+        assert_eq!(
+            chapters.iter().map(|c| c.start).collect::<Vec<_>>(),
+            [0.0, 30.0, 60.0]
+        );
+        assert_eq!(chapters[2].end, 75.0);
+    }
+
+    #[test]
+    fn test_nero_chapters() {
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, name) in [(0u64, "Intro"), (1_200_000_000, "Part 2")] {
+            chpl.extend(start.to_be_bytes());
+            chpl.push(u8::try_from(name.len()).unwrap());
+            chpl.extend(name.as_bytes());
+        }
+        let moov = mp4_box(b"moov", &mp4_box(b"udta", &mp4_box(b"chpl", &chpl)));
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&[mp4_box(b"ftyp", b"M4A \0\0\0\0"), moov].concat())
+            .unwrap();
+        let chapters = read_chapters(file.path(), Duration::from_secs(300)).unwrap();
+        assert_eq!(titles(&chapters), ["Intro", "Part 2"]);
+        assert_eq!(chapters[0].end, 120.0);
+        assert_eq!(chapters[1].end, 300.0);
+    }
+
+    #[test]
+    fn test_chapter_at() {
+        let chapters: Vec<Chapter> = [(5.0, 10.0), (10.0, 20.0)]
+            .iter()
+            .map(|&(start, end)| Chapter {
+                title: None,
+                start,
+                end,
+            })
+            .collect();
+        assert_eq!(chapter_at(&chapters, 0.0), None);
+        assert_eq!(chapter_at(&chapters, 5.0), Some(0));
+        assert_eq!(chapter_at(&chapters, 12.0), Some(1));
+        assert_eq!(chapter_at(&chapters, 99.0), Some(1));
+    }
+
+    #[test]
+    fn test_other_formats_have_no_chapters() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"fLaC\0\0\0\"").unwrap();
+        assert!(read_chapters(file.path(), Duration::ZERO)
+            .unwrap()
+            .is_empty());
+        assert!(read_chapters(Path::new("/no/such/file"), Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,556 @@
+use super::parser::{parse_gain_value, ReplayGain, TrackMetadata, TrackTags};
+use lofty::file::{TaggedFile, TaggedFileExt};
+use lofty::probe::Probe;
+use lofty::tag::ItemKey;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Separates the parent file from the track number in a virtual track path.
+pub const CUE_TRACK_MARK: char = '#';
+
+/// CUE sheet times count frames of 1/75 s.
+const FRAMES_PER_SEC: f64 = 75.0;
+
+/// Where a track cut out of a larger file by a CUE sheet lies; times are in seconds.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CueRange {
+    /// The file that holds the audio.
+    pub file: String,
+    pub start: f64,
+    /// `None` plays to the end of the file.
+    pub end: Option<f64>,
+}
+
+/// A `TRACK` of a CUE sheet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// `INDEX 01`, or `INDEX 00` for tracks without one, in seconds.
+    pub start: f64,
+    pub replay_gain: ReplayGain,
+}
+
+/// The tracks a CUE sheet lists for one audio file, with the album-level fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Only the album values are set at this level.
+    pub replay_gain: ReplayGain,
+    /// In playing order.
+    pub tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    /// Part of `file` that the track at `index` covers: up to the next track, or to the
+    /// end of the file for the last one.
+    #[must_use]
+    pub fn range(&self, file: &str, index: usize) -> Option<CueRange> {
+        let track = self.tracks.get(index)?;
+        Some(CueRange {
+            file: file.to_string(),
+            start: track.start,
+            end: self.tracks.get(index + 1).map(|next| next.start),
+        })
+    }
+}
+
+/// Path under which the library stores track `number` of the CUE sheet of `file`.
+#[must_use]
+pub fn virtual_path(file: &str, number: u32) -> String {
+    format!("{file}{CUE_TRACK_MARK}{number}")
+}
+
+/// Splits a virtual track path into its parent file and track number.
+///
+/// Only the syntax is checked; a real file may have a name like this too.
+#[must_use]
+pub fn split_virtual_path(path: &str) -> Option<(&str, u32)> {
+    let (file, number) = path.rsplit_once(CUE_TRACK_MARK)?;
+    if file.is_empty() || number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((file, number.parse().ok()?))
+}
+
+/// The part of a file that `path` plays: `None` for a whole file, or the range of a
+/// virtual CUE track, read from the CUE sheet of its parent file.
+///
+/// # Errors
+///
+/// Returns an error if `path` is neither a file nor a track of a CUE sheet.
+pub fn find_range(path: &str) -> Result<Option<CueRange>, String> {
+    if Path::new(path).exists() {
+        return Ok(None);
+    }
+    let Some((file, number)) = split_virtual_path(path) else {
+        return Err(format!("File not found: {path}"));
+    };
+    let parent = Path::new(file);
+    let embedded = Probe::open(parent)
+        .and_then(Probe::read)
+        .ok()
+        .and_then(|tagged| embedded_cue_sheet(&tagged));
+    let sheet = find_cue_sheet(parent, embedded.as_deref())?
+        .ok_or_else(|| format!("No CUE sheet lists {file}"))?;
+    let index = sheet
+        .tracks
+        .iter()
+        .position(|t| t.number == number)
+        .ok_or_else(|| format!("No track {number} in the CUE sheet of {file}"))?;
+    Ok(sheet.range(file, index))
+}
+
+/// The `CUESHEET` tag of a file (Vorbis comments, APE), if it has one.
+#[must_use]
+pub fn embedded_cue_sheet(tagged_file: &TaggedFile) -> Option<String> {
+    tagged_file.tags().iter().find_map(|tag| {
+        tag.items().find_map(|item| match item.key() {
+            ItemKey::Unknown(key) if key.eq_ignore_ascii_case("CUESHEET") => {
+                item.value().text().map(str::to_string)
+            }
+            _ => None,
+        })
+    })
+}
+
+/// Finds the CUE sheet of the audio file at `path`: the `embedded` one if given, else a
+/// `.cue` file in the same folder that lists it.
+///
+/// # Errors
+///
+/// Returns an error if the folder cannot be listed.
+pub fn find_cue_sheet(path: &Path, embedded: Option<&str>) -> Result<Option<CueSheet>, String> {
+    // An embedded sheet describes its own file, whatever name it gives it
+    if let Some(sheet) = embedded.and_then(|text| parse_cue_sheet(text, None)) {
+        return Ok(Some(sheet));
+    }
+    let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+        return Ok(None);
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let entries = fs::read_dir(dir).map_err(|e| format!("Failed to list {dir:?}: {e}"))?;
+    let mut sheets: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|p| {
+            p.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+        })
+        .collect();
+    // Deterministic when several sheets list the same file
+    sheets.sort();
+    for sheet_path in sheets {
+        let Ok(bytes) = fs::read(&sheet_path) else {
+            continue;
+        };
+        if let Some(sheet) = parse_cue_sheet(&decode_text(&bytes), Some(file_name)) {
+            return Ok(Some(sheet));
+        }
+    }
+    Ok(None)
+}
+
+/// CUE files are often not UTF-8; anything that is not is read as Latin-1.
+fn decode_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| char::from(b)).collect(),
+    }
+}
+
+/// Parses a CUE sheet and keeps the tracks of `file_name`, or of its first `FILE` for
+/// `None`.
+///
+/// Rips are often re-encoded after the sheet was written, so a `FILE` whose name only
+/// differs in the extension matches too. Returns `None` if no tracks are left.
+#[must_use]
+pub fn parse_cue_sheet(text: &str, file_name: Option<&str>) -> Option<CueSheet> {
+    let mut sheet = CueSheet {
+        title: None,
+        performer: None,
+        replay_gain: ReplayGain::default(),
+        tracks: Vec::new(),
+    };
+    // Tracks by `FILE`, in sheet order
+    let mut files: Vec<(String, Vec<CueTrack>)> = Vec::new();
+    // Track being read and whether it has its `INDEX 01` yet
+    let mut track: Option<(CueTrack, bool)> = None;
+    let mut track_start: Option<f64> = None;
+
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                finish_track(&mut track, &mut track_start, &mut files);
+                files.push((unquote(file_argument(rest)), Vec::new()));
+            }
+            "TRACK" => {
+                finish_track(&mut track, &mut track_start, &mut files);
+                let number = rest.split_whitespace().next().and_then(|n| n.parse().ok());
+                track = number.map(|number| {
+                    let track = CueTrack {
+                        number,
+                        title: None,
+                        performer: None,
+                        start: 0.0,
+                        replay_gain: ReplayGain::default(),
+                    };
+                    (track, false)
+                });
+            }
+            "INDEX" => {
+                let mut parts = rest.split_whitespace();
+                let index: Option<u32> = parts.next().and_then(|i| i.parse().ok());
+                let time = parts.next().and_then(parse_time);
+                if let (Some((_, has_start)), Some(index), Some(time)) =
+                    (track.as_mut(), index, time)
+                {
+                    // `INDEX 00` opens the pregap; only used if `INDEX 01` is missing
+                    if index == 1 {
+                        track_start = Some(time);
+                        *has_start = true;
+                    } else if index == 0 && !*has_start {
+                        track_start = Some(time);
+                    }
+                }
+            }
+            "TITLE" | "PERFORMER" => {
+                let value = Some(unquote(rest)).filter(|v| !v.is_empty());
+                let is_title = command.eq_ignore_ascii_case("TITLE");
+                match (track.as_mut(), is_title) {
+                    (Some((t, _)), true) => t.title = value,
+                    (Some((t, _)), false) => t.performer = value,
+                    (None, true) => sheet.title = value,
+                    (None, false) => sheet.performer = value,
+                }
+            }
+            "REM" => {
+                let (key, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                let value = parse_gain_value(&unquote(value.trim()));
+                let gain = match track.as_mut() {
+                    Some((t, _)) => &mut t.replay_gain,
+                    None => &mut sheet.replay_gain,
+                };
+                match key.to_ascii_uppercase().as_str() {
+                    "REPLAYGAIN_TRACK_GAIN" => gain.track_gain = value,
+                    "REPLAYGAIN_TRACK_PEAK" => gain.track_peak = value,
+                    "REPLAYGAIN_ALBUM_GAIN" => gain.album_gain = value,
+                    "REPLAYGAIN_ALBUM_PEAK" => gain.album_peak = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    finish_track(&mut track, &mut track_start, &mut files);
+
+    let tracks = match file_name {
+        None => files.into_iter().next().map(|(_, tracks)| tracks),
+        Some(name) => files
+            .iter()
+            .position(|(file, _)| same_file(file, name, false))
+            .or_else(|| {
+                files
+                    .iter()
+                    .position(|(file, _)| same_file(file, name, true))
+            })
+            .map(|i| files.swap_remove(i).1),
+    }?;
+    sheet.tracks = tracks;
+    sheet.tracks.sort_by(|a, b| a.start.total_cmp(&b.start));
+    sheet.tracks.dedup_by(|b, a| a.start == b.start);
+    (!sheet.tracks.is_empty()).then_some(sheet)
+}
+
+/// Files the track being read under the last `FILE`; tracks without an index are dropped.
+fn finish_track(
+    track: &mut Option<(CueTrack, bool)>,
+    start: &mut Option<f64>,
+    files: &mut [(String, Vec<CueTrack>)],
+) {
+    if let (Some((mut t, _)), Some(s)) = (track.take(), start.take()) {
+        t.start = s;
+        if let Some((_, tracks)) = files.last_mut() {
+            tracks.push(t);
+        }
+    }
+}
+
+/// The file name of a `FILE` line, without the type that follows it.
+fn file_argument(rest: &str) -> &str {
+    if let Some(quoted) = rest.strip_prefix('"') {
+        return quoted.split_once('"').map_or(quoted, |(name, _)| name);
+    }
+    rest.rsplit_once(char::is_whitespace)
+        .map_or(rest, |(name, _)| name.trim())
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .map_or(value, |v| v.strip_suffix('"').unwrap_or(v))
+        .to_string()
+}
+
+/// Compares the last path component of a `FILE` entry with `name`, ignoring case and,
+/// with `any_extension`, the extension.
+fn same_file(entry: &str, name: &str, any_extension: bool) -> bool {
+    // Sheets written on Windows use backslashes
+    let entry = entry.rsplit(['/', '\\']).next().unwrap_or(entry);
+    if !any_extension {
+        return entry.eq_ignore_ascii_case(name);
+    }
+    let stem = |n: &str| -> String {
+        Path::new(n)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or(n)
+            .to_lowercase()
+    };
+    stem(entry) == stem(name)
+}
+
+/// Parses `mm:ss:ff` into seconds.
+fn parse_time(time: &str) -> Option<f64> {
+    let mut parts = time.split(':').map(|p| p.parse::<u32>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if seconds >= 60 || frames >= 75 {
+        return None;
+    }
+    Some(f64::from(minutes * 60 + seconds) + f64::from(frames) / FRAMES_PER_SEC)
+}
+
+/// Turns the file described by `parent` into one virtual track per sheet track.
+///
+/// Tags of the file fill in what the sheet leaves out, except the track's `ReplayGain`,
+/// which is only valid for the whole file. `duration` is the length of the file, used
+/// for the last track; zero if unknown.
+#[must_use]
+pub fn split_tracks(
+    sheet: &CueSheet,
+    parent: &TrackMetadata,
+    duration: Duration,
+) -> Vec<TrackMetadata> {
+    let file_end = duration.as_secs_f64();
+    (0..sheet.tracks.len())
+        .filter_map(|index| {
+            let track = &sheet.tracks[index];
+            let range = sheet.range(&parent.path, index)?;
+            let end = range.end.unwrap_or(file_end);
+            // Starts past the end of the file come from a sheet for another rip
+            if file_end > 0.0 && track.start >= file_end {
+                return None;
+            }
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let duration_secs = (end - track.start).max(0.0).round() as u64;
+            Some(TrackMetadata {
+                id: 0,
+                path: virtual_path(&parent.path, track.number),
+                title: track.title.clone(),
+                artist: track
+                    .performer
+                    .clone()
+                    .or_else(|| sheet.performer.clone())
+                    .or_else(|| parent.artist.clone()),
+                album: sheet.title.clone().or_else(|| parent.album.clone()),
+                duration_secs,
+                cover_mime: parent.cover_mime.clone(),
+                has_cover: parent.has_cover,
+                cover_img_path: parent.cover_img_path.clone(),
+                replay_gain: ReplayGain {
+                    track_gain: track.replay_gain.track_gain,
+                    track_peak: track.replay_gain.track_peak,
+                    album_gain: sheet
+                        .replay_gain
+                        .album_gain
+                        .or(parent.replay_gain.album_gain),
+                    album_peak: sheet
+                        .replay_gain
+                        .album_peak
+                        .or(parent.replay_gain.album_peak),
+                },
+                tags: TrackTags {
+                    album_artist: sheet
+                        .performer
+                        .clone()
+                        .or_else(|| parent.tags.album_artist.clone()),
+                    track_number: Some(track.number),
+                    // Sorts the whole rip, not its parts
+                    title_sort: None,
+                    ..parent.tags.clone()
+                },
+                chapters: Vec::new(),
+                cue: Some(range),
+                stamp: parent.stamp,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "REM GENRE Rock\r
+REM REPLAYGAIN_ALBUM_GAIN -7.50 dB\r
+PERFORMER \"The Band\"\r
+TITLE \"Live Album\"\r
+FILE \"Live Album.wav\" WAVE\r
+  TRACK 01 AUDIO\r
+    TITLE \"Opening\"\r
+    REM REPLAYGAIN_TRACK_GAIN -6.00 dB\r
+    INDEX 01 00:00:00\r
+  TRACK 02 AUDIO\r
+    TITLE \"Encore\"\r
+    PERFORMER \"Guest\"\r
+    INDEX 00 03:58:00\r
+    INDEX 01 04:00:37\r
+FILE \"Bonus.wav\" WAVE\r
+  TRACK 03 AUDIO\r
+    TITLE \"Bonus\"\r
+    INDEX 01 00:00:00\r
+";
+
+    fn parent(path: &str) -> TrackMetadata {
+        TrackMetadata {
+            id: 0,
+            path: path.to_string(),
+            title: Some("Whole rip".to_string()),
+            artist: Some("Tagged Artist".to_string()),
+            album: None,
+            duration_secs: 600,
+            cover_mime: None,
+            has_cover: false,
+            cover_img_path: None,
+            replay_gain: ReplayGain {
+                track_gain: Some(-9.0),
+                ..ReplayGain::default()
+            },
+            tags: TrackTags::default(),
+            chapters: Vec::new(),
+            cue: None,
+            stamp: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_cue_sheet() {
+        let sheet = parse_cue_sheet(SHEET, Some("Live Album.wav")).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Live Album"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.replay_gain.album_gain, Some(-7.5));
+        assert_eq!(sheet.tracks.len(), 2);
+        assert_eq!(sheet.tracks[0].replay_gain.track_gain, Some(-6.0));
+        assert_eq!(sheet.tracks[1].number, 2);
+        assert_eq!(sheet.tracks[1].performer.as_deref(), Some("Guest"));
+        // The pregap belongs to the track before
+        assert!((sheet.tracks[1].start - (240.0 + 37.0 / 75.0)).abs() < 1e-9);
+
+        let range = sheet.range("/rip.wav", 0).unwrap();
+        assert_eq!(range.end, Some(sheet.tracks[1].start));
+        assert_eq!(sheet.range("/rip.wav", 1).unwrap().end, None);
+    }
+
+    #[test]
+    fn test_sheet_matches_file_by_name() {
+        // A re-encoded rip keeps the sheet written for the WAV
+        let flac = parse_cue_sheet(SHEET, Some("live album.flac")).unwrap();
+        assert_eq!(flac.tracks.len(), 2);
+        let bonus = parse_cue_sheet(SHEET, Some("Bonus.wav")).unwrap();
+        assert_eq!(bonus.tracks[0].title.as_deref(), Some("Bonus"));
+        assert!(parse_cue_sheet(SHEET, Some("Other.flac")).is_none());
+        // Embedded sheets use their first file
+        assert_eq!(parse_cue_sheet(SHEET, None).unwrap().tracks.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("00:02:00"), Some(2.0));
+        assert_eq!(parse_time("120:00:00"), Some(7200.0));
+        assert_eq!(parse_time("00:00:15"), Some(0.2));
+        assert_eq!(parse_time("00:60:00"), None);
+        assert_eq!(parse_time("00:00:75"), None);
+        assert_eq!(parse_time("00:00"), None);
+    }
+
+    #[test]
+    fn test_virtual_paths() {
+        let path = virtual_path("/music/a#b.flac", 7);
+        assert_eq!(path, "/music/a#b.flac#7");
+        assert_eq!(split_virtual_path(&path), Some(("/music/a#b.flac", 7)));
+        assert_eq!(split_virtual_path("/music/a#b.flac"), None);
+        assert_eq!(split_virtual_path("/music/a.flac#"), None);
+    }
+
+    #[test]
+    fn test_split_tracks() {
+        let sheet = parse_cue_sheet(SHEET, Some("Live Album.flac")).unwrap();
+        let tracks = split_tracks(
+            &sheet,
+            &parent("/music/Live Album.flac"),
+            Duration::from_secs(600),
+        );
+
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].path, "/music/Live Album.flac#1");
+        assert_eq!(tracks[0].title.as_deref(), Some("Opening"));
+        assert_eq!(tracks[0].artist.as_deref(), Some("The Band"));
+        assert_eq!(tracks[0].album.as_deref(), Some("Live Album"));
+        assert_eq!(tracks[0].duration_secs, 240);
+        assert_eq!(tracks[0].replay_gain.track_gain, Some(-6.0));
+        assert_eq!(tracks[0].replay_gain.album_gain, Some(-7.5));
+        assert_eq!(tracks[1].artist.as_deref(), Some("Guest"));
+        assert_eq!(tracks[1].tags.track_number, Some(2));
+        assert_eq!(tracks[1].tags.album_artist.as_deref(), Some("The Band"));
+        // The whole-file gain does not apply to a part of it
+        assert_eq!(tracks[1].replay_gain.track_gain, None);
+        assert_eq!(tracks[1].duration_secs, 360);
+        assert_eq!(
+            tracks[1].cue,
+            Some(CueRange {
+                file: "/music/Live Album.flac".to_string(),
+                start: sheet.tracks[1].start,
+                end: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_find_sheet_next_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let audio = dir.path().join("Live Album.flac");
+        std::fs::write(&audio, b"").unwrap();
+        // Latin-1, as written by older rippers
+        let mut sheet = SHEET.replace("Opening", "Op\u{e9}ning").into_bytes();
+        let pos = sheet
+            .windows(2)
+            .position(|w| w == "\u{e9}".as_bytes())
+            .unwrap();
+        sheet.splice(pos..pos + 2, [0xE9]);
+        std::fs::write(dir.path().join("rip.cue"), sheet).unwrap();
+
+        let found = find_cue_sheet(&audio, None).unwrap().unwrap();
+        assert_eq!(found.tracks[0].title.as_deref(), Some("Op\u{e9}ning"));
+        assert!(find_cue_sheet(&dir.path().join("Single.flac"), None)
+            .unwrap()
+            .is_none());
+
+        let embedded = "FILE \"x.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n";
+        let found = find_cue_sheet(&audio, Some(embedded)).unwrap().unwrap();
+        assert_eq!(found.tracks.len(), 1);
+    }
+}
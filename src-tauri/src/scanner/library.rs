@@ -0,0 +1,186 @@
+use crate::database::operations::{self, LocalFolder, TrackChanges};
+use crate::scanner::parser::{file_stamp, parse_tracks};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+pub const SUPPORTED_EXTENSIONS: [&str; 7] = ["mp3", "flac", "wav", "ogg", "m4a", "m4b", "aac"];
+
+/// True if `path` has the extension of a format the library reads.
+#[must_use]
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// The audio files under `root`, at any depth.
+pub fn audio_files(root: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file() && is_audio_file(entry.path()))
+        .map(walkdir::DirEntry::into_path)
+}
+
+/// True if the folder at `root` can be scanned. An empty folder counts as missing: it is
+/// usually the mount point of a drive that is not attached, and scanning it would drop
+/// every track on the drive.
+#[must_use]
+pub fn is_available(root: &Path) -> bool {
+    fs::read_dir(root).is_ok_and(|mut entries| entries.next().is_some())
+}
+
+/// How many tracks a rescan added, updated and removed.
+#[derive(Debug, Default, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct RescanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+impl From<&TrackChanges> for RescanSummary {
+    fn from(changes: &TrackChanges) -> Self {
+        Self {
+            added: changes.added.len(),
+            updated: changes.updated.len(),
+            removed: changes.removed.len(),
+        }
+    }
+}
+
+/// Brings the library tracks of `folder` in line with the files on disk.
+///
+/// Only files whose size or modification time differ from the stamp stored with their
+/// tracks are read again. Files that can no longer be parsed keep their tracks, since
+/// they are usually still being written; files that are gone lose theirs. The database is
+/// only locked to read the stamps and to store the results, not while files are parsed.
+///
+/// # Errors
+///
+/// Returns an error if the folder is not available, or if the database cannot be read or
+/// written.
+pub fn rescan(
+    db: &Mutex<Connection>,
+    folder: &LocalFolder,
+    images_dir: &Path,
+) -> Result<TrackChanges, String> {
+    let root = Path::new(&folder.path);
+    if !is_available(root) {
+        return Err(format!("Directory is missing or empty: {}", folder.path));
+    }
+    let mut stored = {
+        let conn = db.lock().map_err(|e| e.to_string())?;
+        operations::get_file_stamps(&conn, &folder.path).map_err(|e| e.to_string())?
+    };
+
+    let mut parsed = Vec::new();
+    let mut seen = HashSet::new();
+    for file in audio_files(root) {
+        let Some(file) = file.to_str().map(str::to_owned) else {
+            continue;
+        };
+        let stamp = file_stamp(Path::new(&file)).ok();
+        let unchanged = stamp.is_some() && stored.get(&file).is_some_and(|s| *s == stamp);
+        seen.insert(file.clone());
+        if unchanged {
+            continue;
+        }
+        match parse_tracks(&file, Some(images_dir)) {
+            Ok(tracks) => parsed.push((file, tracks)),
+            Err(e) => eprintln!("Error parsing file {file:?}: {e}"),
+        }
+    }
+    stored.retain(|file, _| !seen.contains(file));
+
+    let mut conn = db.lock().map_err(|e| e.to_string())?;
+    let mut changes = TrackChanges::default();
+    for (file, tracks) in &parsed {
+        let replaced =
+            operations::replace_file_tracks(&mut conn, file, tracks).map_err(|e| e.to_string())?;
+        changes.extend(replaced);
+    }
+    for file in stored.keys() {
+        let removed =
+            operations::replace_file_tracks(&mut conn, file, &[]).map_err(|e| e.to_string())?;
+        changes.extend(removed);
+    }
+    operations::refresh_folder_song_count(&conn, &folder.id).map_err(|e| e.to_string())?;
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema::create_tables;
+    use crate::test_util::write_wav;
+
+    /// A silent mono WAV file of `samples` samples.
+    fn write_silence(path: &Path, samples: usize) {
+        write_wav(path, 1, 8000, &vec![0.0; samples]);
+    }
+
+    #[test]
+    fn test_rescan_reads_only_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let images = dir.path().join("images");
+        let music = dir.path().join("music");
+        // A sibling whose name starts with the same letters is not part of the folder
+        let sibling = dir.path().join("musicbox");
+        fs::create_dir_all(music.join("album")).unwrap();
+        fs::create_dir_all(&sibling).unwrap();
+        write_silence(&sibling.join("other.wav"), 800);
+        let edited = music.join("album/edited.wav");
+        let deleted = music.join("deleted.wav");
+        for path in [&music.join("album/kept.wav"), &edited, &deleted] {
+            write_silence(path, 800);
+        }
+        fs::write(music.join("notes.txt"), "not audio").unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        let folder_id = operations::add_folder(&conn, "Music", music.to_str().unwrap(), 0).unwrap();
+        let folder = operations::get_folder(&conn, &folder_id).unwrap().unwrap();
+        let sibling_id =
+            operations::add_folder(&conn, "Box", sibling.to_str().unwrap(), 0).unwrap();
+        let sibling = operations::get_folder(&conn, &sibling_id).unwrap().unwrap();
+        let db = Mutex::new(conn);
+        assert_eq!(
+            RescanSummary::from(&rescan(&db, &sibling, &images).unwrap()).added,
+            1
+        );
+
+        let first = rescan(&db, &folder, &images).unwrap();
+        assert_eq!(RescanSummary::from(&first).added, 3);
+        let edited_id = operations::get_tracks(&db.lock().unwrap(), None)
+            .unwrap()
+            .into_iter()
+            .find(|t| t.path == edited.to_str().unwrap())
+            .unwrap()
+            .id;
+
+        write_silence(&edited, 1600);
+        fs::remove_file(&deleted).unwrap();
+        write_silence(&music.join("album/new.wav"), 800);
+        let second = rescan(&db, &folder, &images).unwrap();
+        assert_eq!(
+            RescanSummary::from(&second),
+            RescanSummary {
+                added: 1,
+                updated: 1,
+                removed: 1
+            }
+        );
+        assert_eq!(second.updated, [edited_id]);
+
+        assert!(rescan(&db, &folder, &images).unwrap().is_empty());
+        let conn = db.lock().unwrap();
+        let folder = operations::get_folder(&conn, &folder_id).unwrap().unwrap();
+        assert_eq!(folder.song_count, 3);
+        assert_eq!(operations::get_tracks(&conn, None).unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,151 @@
+use super::cue::find_range;
+use crate::audio::segment::open_segment;
+use ebur128::{EbuR128, Mode};
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// `ReplayGain` 2.0 reference level that analyzed tracks are normalized to, in LUFS.
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+/// Frames handed to the meter at once; cancellation is checked between chunks.
+const CHUNK_FRAMES: usize = 4096;
+
+/// EBU R128 measurement of a whole track.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// Loudness range in LU.
+    pub range: f64,
+    /// Maximum true peak over all channels in dBTP.
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// Gain in dB that brings the track to `REFERENCE_LUFS`.
+    #[must_use]
+    pub fn gain_db(&self) -> f64 {
+        REFERENCE_LUFS - self.integrated
+    }
+
+    /// True peak as a linear amplitude (1.0 = full scale).
+    #[must_use]
+    pub fn peak_linear(&self) -> f64 {
+        10f64.powf(self.true_peak / 20.0)
+    }
+}
+
+/// Decodes `path` and measures it; a virtual CUE track is measured over its part of the
+/// file.
+///
+/// Returns `Ok(None)` if `cancel` was set before the measurement finished.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or decoded, or if it is silent.
+pub fn analyze_file(path: &str, cancel: &AtomicBool) -> Result<Option<Loudness>, String> {
+    let range = find_range(path)?;
+    analyze_source(open_segment(path, range.as_ref())?, cancel)
+}
+
+/// Measures every sample of `source`.
+///
+/// # Errors
+///
+/// Returns an error if the meter rejects the stream format or if the source is silent.
+pub fn analyze_source<S: Source>(
+    mut source: S,
+    cancel: &AtomicBool,
+) -> Result<Option<Loudness>, String> {
+    let channels = u32::from(source.channels());
+    let mut meter = EbuR128::new(
+        channels,
+        source.sample_rate(),
+        Mode::I | Mode::LRA | Mode::TRUE_PEAK,
+    )
+    .map_err(|e| format!("Failed to create loudness meter: {e}"))?;
+
+    let chunk_len = CHUNK_FRAMES * channels as usize;
+    let mut chunk = Vec::with_capacity(chunk_len);
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        chunk.clear();
+        chunk.extend(source.by_ref().take(chunk_len));
+        // A partial frame at the very end cannot be measured
+        chunk.truncate(chunk.len() - chunk.len() % channels as usize);
+        if chunk.is_empty() {
+            break;
+        }
+        meter
+            .add_frames_f32(&chunk)
+            .map_err(|e| format!("Loudness measurement failed: {e}"))?;
+    }
+
+    let integrated = meter
+        .loudness_global()
+        .map_err(|e| format!("Loudness measurement failed: {e}"))?;
+    if !integrated.is_finite() {
+        return Err("Track is silent".to_string());
+    }
+    let range = meter
+        .loudness_range()
+        .map_err(|e| format!("Loudness measurement failed: {e}"))?;
+    let mut peak = 0.0f64;
+    for channel in 0..channels {
+        let channel_peak = meter
+            .true_peak(channel)
+            .map_err(|e| format!("Loudness measurement failed: {e}"))?;
+        peak = peak.max(channel_peak);
+    }
+
+    Ok(Some(Loudness {
+        integrated,
+        range,
+        true_peak: 20.0 * peak.max(f64::MIN_POSITIVE).log10(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+    use std::f32::consts::TAU;
+
+    fn sine(amplitude: f32, secs: usize) -> SamplesBuffer {
+        let rate = 48_000;
+        #[allow(clippy::cast_precision_loss)]
+        let samples = (0..rate * secs)
+            .map(|i| amplitude * (TAU * 997.0 * i as f32 / rate as f32).sin())
+            .collect::<Vec<_>>();
+        SamplesBuffer::new(1, 48_000, samples)
+    }
+
+    #[test]
+    fn test_sine_loudness() {
+        // A full-scale 1 kHz sine in one channel reads -3.01 LUFS; half amplitude is 6 dB lower
+        let loudness = analyze_source(sine(0.5, 5), &AtomicBool::new(false))
+            .unwrap()
+            .unwrap();
+
+        assert!((loudness.integrated + 9.03).abs() < 0.2, "{loudness:?}");
+        assert!(loudness.range.abs() < 0.5);
+        assert!((loudness.true_peak + 6.02).abs() < 0.2);
+        assert!((loudness.gain_db() + 8.97).abs() < 0.2);
+        assert!((loudness.peak_linear() - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_silence_is_an_error() {
+        let silence = SamplesBuffer::new(1, 48_000, vec![0.0; 48_000]);
+        assert!(analyze_source(silence, &AtomicBool::new(false)).is_err());
+    }
+
+    #[test]
+    fn test_cancelled_analysis() {
+        let result = analyze_source(sine(0.5, 1), &AtomicBool::new(true));
+        assert_eq!(result, Ok(None));
+    }
+}
@@ -1 +1,9 @@
+pub mod analysis;
+pub mod chapters;
+pub mod cue;
+pub mod library;
+pub mod loudness;
 pub mod parser;
+pub mod scan;
+pub mod watcher;
+pub mod waveform;
@@ -1,12 +1,77 @@
+use super::chapters::{read_chapters, Chapter};
+use super::cue::{embedded_cue_sheet, find_cue_sheet, split_tracks, CueRange};
 use hex;
 use image::imageops::FilterType;
 use lofty::file::{AudioFile, TaggedFileExt};
 use lofty::probe::Probe;
-use lofty::tag::Accessor;
+use lofty::tag::{Accessor, ItemKey, Tag};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// `ReplayGain` values read from tags. Gains are in dB, peaks are linear (1.0 = full scale).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+/// Descriptive tags beyond title, artist and album.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct TrackTags {
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    /// Taken from the date when no year is tagged on its own.
+    pub year: Option<u32>,
+    /// Release date as tagged, e.g. `2019` or `2019-05-03`.
+    pub date: Option<String>,
+    pub genres: Vec<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub bpm: Option<f32>,
+    /// Names to sort by instead of the displayed ones, e.g. `Beatles, The`.
+    pub title_sort: Option<String>,
+    pub artist_sort: Option<String>,
+    pub album_sort: Option<String>,
+    pub album_artist_sort: Option<String>,
+    pub composer_sort: Option<String>,
+}
+
+/// Size and modification time of a file, to tell whether it changed since it was read.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified: i64,
+}
+
+/// Reads the stamp of the file at `path`.
+///
+/// # Errors
+///
+/// Returns an error if the file's metadata cannot be read.
+pub fn file_stamp(path: &Path) -> Result<FileStamp, String> {
+    let metadata = fs::metadata(path).map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    let modified = metadata
+        .modified()
+        .map_err(|e| format!("Failed to read {path:?}: {e}"))?;
+    // Times before the epoch only come from broken clocks; they still compare fine
+    let modified = match modified.duration_since(UNIX_EPOCH) {
+        Ok(after) => i64::try_from(after.as_nanos()).unwrap_or(i64::MAX),
+        Err(e) => -i64::try_from(e.duration().as_nanos()).unwrap_or(i64::MAX),
+    };
+    Ok(FileStamp {
+        size: metadata.len(),
+        modified,
+    })
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TrackMetadata {
@@ -20,6 +85,18 @@ pub struct TrackMetadata {
     pub cover_mime: Option<String>,
     pub has_cover: bool,
     pub cover_img_path: Option<String>,
+    #[serde(default)]
+    pub replay_gain: ReplayGain,
+    #[serde(default)]
+    pub tags: TrackTags,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+    /// Set for a track cut out of a larger file by a CUE sheet.
+    #[serde(default)]
+    pub cue: Option<CueRange>,
+    /// Of the file the track was read from, when it was read.
+    #[serde(default)]
+    pub stamp: Option<FileStamp>,
 }
 
 /// Parses a media file and extracts metadata.
@@ -32,6 +109,38 @@ pub struct TrackMetadata {
 /// - There are issues extracting the tags.
 /// - There are issues saving the cover art (if `images_dir` is provided).
 pub fn parse_file(path: &str, images_dir: Option<&Path>) -> Result<TrackMetadata, String> {
+    read_file(path, images_dir).map(|(metadata, _, _)| metadata)
+}
+
+/// Parses a media file into the tracks it holds: one virtual track per entry of its CUE
+/// sheet (a `CUESHEET` tag, or a `.cue` file next to it that lists it), or else the file
+/// itself.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be parsed; see `parse_file`.
+pub fn parse_tracks(path: &str, images_dir: Option<&Path>) -> Result<Vec<TrackMetadata>, String> {
+    let (metadata, duration, embedded) = read_file(path, images_dir)?;
+    let sheet = find_cue_sheet(Path::new(path), embedded.as_deref()).unwrap_or_else(|e| {
+        println!("Failed to look for a CUE sheet of {path}: {e}");
+        None
+    });
+    let tracks = sheet
+        .map(|sheet| split_tracks(&sheet, &metadata, duration))
+        .unwrap_or_default();
+    if tracks.is_empty() {
+        Ok(vec![metadata])
+    } else {
+        Ok(tracks)
+    }
+}
+
+/// Reads the metadata of a media file, along with its exact duration and embedded CUE
+/// sheet.
+fn read_file(
+    path: &str,
+    images_dir: Option<&Path>,
+) -> Result<(TrackMetadata, Duration, Option<String>), String> {
     let path_obj = Path::new(path);
     if !path_obj.exists() {
         return Err(format!("File not found: {path}"));
@@ -50,6 +159,18 @@ pub fn parse_file(path: &str, images_dir: Option<&Path>) -> Result<TrackMetadata
     let album = tag.and_then(|t| t.album().map(std::borrow::Cow::into_owned));
 
     let duration_secs = properties.duration().as_secs();
+    let chapters = read_chapters(path_obj, properties.duration()).unwrap_or_else(|e| {
+        println!("Failed to read chapters of {path}: {e}");
+        Vec::new()
+    });
+
+    // Taggers often write ReplayGain to a secondary tag (e.g. APE next to ID3v1)
+    let mut replay_gain = ReplayGain::default();
+    let mut tags = TrackTags::default();
+    for t in tag.into_iter().chain(tagged_file.tags()) {
+        read_replay_gain(t, &mut replay_gain);
+        read_tags(t, &mut tags);
+    }
 
     let mut cover_mime = None;
     let mut has_cover = false;
@@ -131,7 +252,7 @@ pub fn parse_file(path: &str, images_dir: Option<&Path>) -> Result<TrackMetadata
         }
     }
 
-    Ok(TrackMetadata {
+    let metadata = TrackMetadata {
         id: 0,
         path: path.to_string(),
         title,
@@ -141,7 +262,111 @@ pub fn parse_file(path: &str, images_dir: Option<&Path>) -> Result<TrackMetadata
         cover_mime,
         has_cover,
         cover_img_path,
-    })
+        replay_gain,
+        tags,
+        chapters,
+        cue: None,
+        stamp: file_stamp(path_obj).ok(),
+    };
+    Ok((
+        metadata,
+        properties.duration(),
+        embedded_cue_sheet(&tagged_file),
+    ))
+}
+
+/// Fills the values still missing in `gain` from `tag`.
+fn read_replay_gain(tag: &Tag, gain: &mut ReplayGain) {
+    let value = |key: ItemKey| tag.get_string(&key).and_then(parse_gain_value);
+    gain.track_gain = gain
+        .track_gain
+        .or_else(|| value(ItemKey::ReplayGainTrackGain));
+    gain.track_peak = gain
+        .track_peak
+        .or_else(|| value(ItemKey::ReplayGainTrackPeak));
+    gain.album_gain = gain
+        .album_gain
+        .or_else(|| value(ItemKey::ReplayGainAlbumGain));
+    gain.album_peak = gain
+        .album_peak
+        .or_else(|| value(ItemKey::ReplayGainAlbumPeak));
+}
+
+/// Fills the values still missing in `tags` from `tag`.
+fn read_tags(tag: &Tag, tags: &mut TrackTags) {
+    let text = |key: ItemKey| {
+        tag.get_string(&key)
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+    };
+    let fill = |value: &mut Option<String>, key: ItemKey| {
+        if value.is_none() {
+            *value = text(key);
+        }
+    };
+    fill(&mut tags.album_artist, ItemKey::AlbumArtist);
+    fill(&mut tags.composer, ItemKey::Composer);
+    fill(&mut tags.comment, ItemKey::Comment);
+    fill(&mut tags.title_sort, ItemKey::TrackTitleSortOrder);
+    fill(&mut tags.artist_sort, ItemKey::TrackArtistSortOrder);
+    fill(&mut tags.album_sort, ItemKey::AlbumTitleSortOrder);
+    fill(&mut tags.album_artist_sort, ItemKey::AlbumArtistSortOrder);
+    fill(&mut tags.composer_sort, ItemKey::ComposerSortOrder);
+    fill(&mut tags.date, ItemKey::RecordingDate);
+    fill(&mut tags.date, ItemKey::ReleaseDate);
+
+    tags.track_number = tags.track_number.or_else(|| tag.track());
+    tags.track_total = tags.track_total.or_else(|| tag.track_total());
+    tags.disc_number = tags.disc_number.or_else(|| tag.disk());
+    tags.disc_total = tags.disc_total.or_else(|| tag.disk_total());
+    tags.year = tags
+        .year
+        .or_else(|| tags.date.as_deref().and_then(parse_year))
+        .or_else(|| tag.year());
+    tags.bpm = tags.bpm.or_else(|| {
+        text(ItemKey::Bpm)
+            .or_else(|| text(ItemKey::IntegerBpm))
+            .and_then(|bpm| bpm.parse::<f32>().ok())
+            .filter(|bpm| bpm.is_finite() && *bpm > 0.0)
+    });
+    if tags.genres.is_empty() {
+        tags.genres = split_genres(tag.get_strings(&ItemKey::Genre));
+    }
+}
+
+/// The year a date like `"2019-05-03"` or `"2019"` starts with.
+fn parse_year(date: &str) -> Option<u32> {
+    let digits = date.trim().get(..4)?;
+    if digits.bytes().all(|b| b.is_ascii_digit()) {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Genre values, with values holding several genres (`"Rock; Pop"`) split up and
+/// duplicates dropped.
+fn split_genres<'a>(values: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut genres: Vec<String> = Vec::new();
+    for genre in values.flat_map(|v| v.split(['\0', ';'])) {
+        let genre = genre.trim();
+        if !genre.is_empty() && !genres.iter().any(|g| g.eq_ignore_ascii_case(genre)) {
+            genres.push(genre.to_string());
+        }
+    }
+    genres
+}
+
+/// Parses values like `"-6.54 dB"` or `"0.988312"`.
+pub(crate) fn parse_gain_value(raw: &str) -> Option<f32> {
+    let raw = raw.trim();
+    let number = raw
+        .strip_suffix("dB")
+        .or_else(|| raw.strip_suffix("DB"))
+        .or_else(|| raw.strip_suffix("db"))
+        .unwrap_or(raw);
+    number.trim().parse::<f32>().ok().filter(|v| v.is_finite())
 }
 
 #[cfg(test)]
@@ -170,6 +395,85 @@ mod tests {
         assert!(err.contains("Failed to probe file") || err.contains("Failed to read file tags"));
     }
 
+    #[test]
+    fn test_parse_gain_value() {
+        assert_eq!(parse_gain_value("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain_value("+2.10 db"), Some(2.1));
+        assert_eq!(parse_gain_value(" 0.988312 "), Some(0.988_312));
+        assert_eq!(parse_gain_value("loud"), None);
+    }
+
+    #[test]
+    fn test_read_replay_gain_keeps_first_value() {
+        let mut tag = Tag::new(lofty::tag::TagType::VorbisComments);
+        tag.insert_text(ItemKey::ReplayGainTrackGain, "-7.00 dB".to_string());
+        tag.insert_text(ItemKey::ReplayGainTrackPeak, "0.95".to_string());
+
+        let mut gain = ReplayGain {
+            track_gain: Some(-3.0),
+            ..ReplayGain::default()
+        };
+        read_replay_gain(&tag, &mut gain);
+
+        assert_eq!(gain.track_gain, Some(-3.0));
+        assert_eq!(gain.track_peak, Some(0.95));
+        assert_eq!(gain.album_gain, None);
+    }
+
+    #[test]
+    fn test_read_tags_fills_missing_values() {
+        let mut tag = Tag::new(lofty::tag::TagType::VorbisComments);
+        tag.insert_text(ItemKey::AlbumArtist, "Various Artists".to_string());
+        tag.insert_text(ItemKey::TrackNumber, "3".to_string());
+        tag.insert_text(ItemKey::TrackTotal, "12".to_string());
+        tag.insert_text(ItemKey::DiscNumber, "2".to_string());
+        tag.insert_text(ItemKey::RecordingDate, "1997-06-16".to_string());
+        tag.insert_text(ItemKey::Genre, "Electronic; Trip Hop".to_string());
+        tag.insert_text(ItemKey::Bpm, "92".to_string());
+        tag.insert_text(ItemKey::AlbumArtistSortOrder, "Various".to_string());
+
+        let mut tags = TrackTags {
+            composer: Some("Already Set".to_string()),
+            ..TrackTags::default()
+        };
+        read_tags(&tag, &mut tags);
+
+        assert_eq!(tags.album_artist.as_deref(), Some("Various Artists"));
+        assert_eq!((tags.track_number, tags.track_total), (Some(3), Some(12)));
+        assert_eq!((tags.disc_number, tags.disc_total), (Some(2), None));
+        assert_eq!(tags.date.as_deref(), Some("1997-06-16"));
+        assert_eq!(tags.year, Some(1997));
+        assert_eq!(tags.genres, ["Electronic", "Trip Hop"]);
+        assert_eq!(tags.bpm, Some(92.0));
+        assert_eq!(tags.album_artist_sort.as_deref(), Some("Various"));
+        assert_eq!(tags.composer.as_deref(), Some("Already Set"));
+    }
+
+    #[test]
+    fn test_read_tags_falls_back_to_the_year_tag() {
+        let mut tag = Tag::new(lofty::tag::TagType::VorbisComments);
+        tag.insert_text(ItemKey::ReleaseDate, "Spring '97".to_string());
+        tag.insert_text(ItemKey::Year, "1997".to_string());
+
+        let mut tags = TrackTags::default();
+        read_tags(&tag, &mut tags);
+
+        assert_eq!(tags.date.as_deref(), Some("Spring '97"));
+        assert_eq!(tags.year, Some(1997));
+    }
+
+    #[test]
+    fn test_parse_year_and_genres() {
+        assert_eq!(parse_year("2019"), Some(2019));
+        assert_eq!(parse_year(" 2019-05-03T10:00"), Some(2019));
+        assert_eq!(parse_year("May 2019"), None);
+        assert_eq!(parse_year("19"), None);
+        assert_eq!(
+            split_genres(["Rock\0Pop", "rock", " "].into_iter()),
+            ["Rock", "Pop"]
+        );
+    }
+
     #[test]
     fn test_parse_valid_asset_file() {
         // Path relative to src-tauri directory where tests run
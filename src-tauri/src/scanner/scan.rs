@@ -0,0 +1,291 @@
+use crate::database::operations::{self, LocalFolder, TrackChanges};
+use crate::database::AppState;
+use crate::scanner::library::{self, audio_files};
+use crate::scanner::parser::{parse_tracks, TrackMetadata};
+use crate::scanner::watcher::LibraryWatcher;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+/// Tracks stored per transaction.
+const BATCH_SIZE: usize = 500;
+/// Longest parsed tracks wait to be stored, so slow disks still show progress.
+const BATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// Least time between two `scan-progress` events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tracks the running folder scans, by folder ID.
+#[derive(Default)]
+pub struct ScanState {
+    running: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+}
+
+impl ScanState {
+    /// Starts reading the audio files of `folder` into the library on background threads,
+    /// reading cover art into `images_dir`. Files are parsed in parallel and stored in
+    /// batches, each emitting `library-changed`, so tracks show up while the scan runs.
+    ///
+    /// Emits `scan-progress` as files are found and parsed, and `scan-finished` at the
+    /// end. Returns `false` if the folder is already being scanned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state mutex is poisoned.
+    pub fn start<R: Runtime>(
+        &self,
+        app_handle: AppHandle<R>,
+        folder: LocalFolder,
+        images_dir: PathBuf,
+    ) -> Result<bool, String> {
+        let mut running = self.running.lock().map_err(|e| e.to_string())?;
+        if running.contains_key(&folder.id) {
+            return Ok(false);
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        running.insert(folder.id.clone(), cancel.clone());
+
+        let slots = self.running.clone();
+        thread::spawn(move || {
+            run(&app_handle, &folder, &images_dir, &cancel);
+            if let Ok(mut running) = slots.lock() {
+                running.remove(&folder.id);
+            }
+            // The watcher leaves folders alone while they are being scanned
+            if let Some(watcher) = app_handle.try_state::<LibraryWatcher>() {
+                watcher.sync().ok();
+            }
+        });
+        Ok(true)
+    }
+
+    /// Asks the scan of a folder, or with `None` every scan, to stop. Tracks stored
+    /// so far stay in the library.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state mutex is poisoned.
+    pub fn cancel(&self, folder_id: Option<&str>) -> Result<(), String> {
+        let running = self.running.lock().map_err(|e| e.to_string())?;
+        for (id, cancel) in running.iter() {
+            if folder_id.is_none_or(|folder_id| folder_id == id) {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings `folder` in line with its files on disk on the calling thread, see
+    /// [`library::rescan`]. New tracks also go to the "Default" playlist, and the changes
+    /// are announced with `library-changed`. No scan of the folder starts meanwhile.
+    ///
+    /// Returns `None` if the folder is already being scanned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the folder is not available, the database cannot be read or
+    /// written, or the state mutex is poisoned.
+    pub fn rescan<R: Runtime>(
+        &self,
+        app_handle: &AppHandle<R>,
+        folder: &LocalFolder,
+        images_dir: &Path,
+    ) -> Result<Option<TrackChanges>, String> {
+        {
+            let mut running = self.running.lock().map_err(|e| e.to_string())?;
+            if running.contains_key(&folder.id) {
+                return Ok(None);
+            }
+            running.insert(folder.id.clone(), Arc::new(AtomicBool::new(false)));
+        }
+        let changes = app_handle
+            .try_state::<AppState>()
+            .ok_or_else(|| "The library is not available".to_string())
+            .and_then(|state| {
+                let changes = library::rescan(&state.db, folder, images_dir)?;
+                let mut conn = state.db.lock().map_err(|e| e.to_string())?;
+                operations::add_tracks_to_default_playlist(&mut conn, &changes.added)
+                    .map_err(|e| e.to_string())?;
+                Ok(changes)
+            });
+        if let Ok(mut running) = self.running.lock() {
+            running.remove(&folder.id);
+        }
+        // The watcher leaves folders alone while they are being scanned
+        if let Some(watcher) = app_handle.try_state::<LibraryWatcher>() {
+            watcher.sync().ok();
+        }
+        let changes = changes?;
+        app_handle.emit("library-changed", &changes).ok();
+        Ok(Some(changes))
+    }
+
+    /// True while the folder with this ID is being scanned.
+    #[must_use]
+    pub fn is_scanning(&self, folder_id: &str) -> bool {
+        self.running
+            .lock()
+            .is_ok_and(|running| running.contains_key(folder_id))
+    }
+}
+
+enum Found {
+    File,
+    Parsed(PathBuf, Result<Vec<TrackMetadata>, String>),
+}
+
+#[derive(Default)]
+struct Counts {
+    seen: usize,
+    parsed: usize,
+    failed: usize,
+}
+
+fn run<R: Runtime>(
+    app_handle: &AppHandle<R>,
+    folder: &LocalFolder,
+    images_dir: &Path,
+    cancel: &AtomicBool,
+) {
+    let Some(state) = app_handle.try_state::<AppState>() else {
+        return;
+    };
+    let workers = thread::available_parallelism().map_or(4, NonZeroUsize::get);
+    let (paths_tx, paths_rx) = mpsc::sync_channel::<PathBuf>(workers * 4);
+    let paths_rx = Mutex::new(paths_rx);
+    let (found_tx, found_rx) = mpsc::channel();
+
+    let mut counts = Counts::default();
+    thread::scope(|scope| {
+        let walker_tx = found_tx.clone();
+        scope.spawn(move || {
+            for file in audio_files(Path::new(&folder.path)) {
+                if cancel.load(Ordering::Relaxed) || walker_tx.send(Found::File).is_err() {
+                    break;
+                }
+                if paths_tx.send(file).is_err() {
+                    break;
+                }
+            }
+        });
+        for _ in 0..workers {
+            let found_tx = found_tx.clone();
+            let paths_rx = &paths_rx;
+            scope.spawn(move || {
+                while let Some(file) = paths_rx.lock().ok().and_then(|rx| rx.recv().ok()) {
+                    // Files already queued are skipped, so the walker is never left waiting
+                    if cancel.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    // A file split by a CUE sheet adds one virtual track per entry
+                    let tracks = parse_tracks(file.to_str().unwrap_or_default(), Some(images_dir));
+                    if found_tx.send(Found::Parsed(file, tracks)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(found_tx);
+
+        let mut batch = Vec::new();
+        let mut last_store = Instant::now();
+        let mut last_progress: Option<Instant> = None;
+        for found in found_rx {
+            let path = match found {
+                Found::File => {
+                    counts.seen += 1;
+                    None
+                }
+                Found::Parsed(path, Ok(tracks)) => {
+                    counts.parsed += 1;
+                    batch.extend(tracks);
+                    Some(path)
+                }
+                Found::Parsed(path, Err(e)) => {
+                    eprintln!("Error parsing file {path:?}: {e}");
+                    counts.failed += 1;
+                    Some(path)
+                }
+            };
+            if batch.len() >= BATCH_SIZE
+                || (!batch.is_empty() && last_store.elapsed() >= BATCH_INTERVAL)
+            {
+                store(app_handle, &state.db, folder, &std::mem::take(&mut batch));
+                last_store = Instant::now();
+            }
+            if last_progress.is_none_or(|at| at.elapsed() >= PROGRESS_INTERVAL) {
+                emit_progress(app_handle, folder, &counts, path.as_deref());
+                last_progress = Some(Instant::now());
+            }
+        }
+        // Whatever was parsed before a cancel is kept
+        store(app_handle, &state.db, folder, &batch);
+    });
+
+    emit_progress(app_handle, folder, &counts, None);
+    app_handle
+        .emit(
+            "scan-finished",
+            serde_json::json!({
+                "folderId": folder.id,
+                "seen": counts.seen,
+                "parsed": counts.parsed,
+                "failed": counts.failed,
+                "cancelled": cancel.load(Ordering::Relaxed)
+            }),
+        )
+        .ok();
+}
+
+/// Adds a batch of tracks to the library and the "Default" playlist in one go.
+fn store<R: Runtime>(
+    app_handle: &AppHandle<R>,
+    db: &Mutex<rusqlite::Connection>,
+    folder: &LocalFolder,
+    tracks: &[TrackMetadata],
+) {
+    if tracks.is_empty() {
+        return;
+    }
+    let stored = db.lock().map_err(|e| e.to_string()).and_then(|mut conn| {
+        let added = operations::add_tracks(&mut conn, tracks).map_err(|e| e.to_string())?;
+        operations::add_tracks_to_default_playlist(&mut conn, &added).map_err(|e| e.to_string())?;
+        operations::refresh_folder_song_count(&conn, &folder.id).map_err(|e| e.to_string())?;
+        Ok(added)
+    });
+    match stored {
+        Ok(added) if !added.is_empty() => {
+            let changes = TrackChanges {
+                added,
+                ..TrackChanges::default()
+            };
+            app_handle.emit("library-changed", &changes).ok();
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to store scanned tracks of {}: {e}", folder.path),
+    }
+}
+
+fn emit_progress<R: Runtime>(
+    app_handle: &AppHandle<R>,
+    folder: &LocalFolder,
+    counts: &Counts,
+    path: Option<&Path>,
+) {
+    app_handle
+        .emit(
+            "scan-progress",
+            serde_json::json!({
+                "folderId": folder.id,
+                "seen": counts.seen,
+                "parsed": counts.parsed,
+                "failed": counts.failed,
+                "path": path
+            }),
+        )
+        .ok();
+}
@@ -0,0 +1,399 @@
+use crate::database::operations::{self, LocalFolder, TrackChanges};
+use crate::database::AppState;
+use crate::scanner::library::{self, is_audio_file};
+use crate::scanner::parser::{file_stamp, parse_tracks};
+use crate::scanner::scan::ScanState;
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Quiet time after the last change before a burst of changes is applied.
+const DEBOUNCE: Duration = Duration::from_millis(1500);
+/// Longest a burst is held back, so a long copy shows up part by part.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+/// How often folders are checked for having been unmounted or mounted again.
+const MOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+enum Message {
+    Event(notify::Result<notify::Event>),
+    Sync,
+    Stop,
+}
+
+/// Background watcher that keeps the library in line with the local folders on disk.
+///
+/// Changes are applied once a burst of them has settled, and each applied burst emits
+/// `library-changed` with the IDs of the added, updated and removed tracks. Folders that
+/// disappear, such as an unplugged drive, keep their tracks and are caught up with a
+/// rescan when they come back.
+pub struct LibraryWatcher {
+    tx: Mutex<Sender<Message>>,
+}
+
+impl LibraryWatcher {
+    /// Starts watching every local folder that has watching turned on, reading cover
+    /// art of new files into `images_dir`.
+    #[must_use]
+    pub fn new(app_handle: AppHandle, images_dir: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let events = tx.clone();
+        thread::spawn(move || {
+            let watcher = notify::recommended_watcher(move |event| {
+                events.send(Message::Event(event)).ok();
+            });
+            match watcher {
+                Ok(watcher) => Worker {
+                    app_handle,
+                    images_dir,
+                    watcher,
+                    watched: HashMap::new(),
+                    pending: Pending::default(),
+                }
+                .run(&rx),
+                Err(e) => eprintln!("Failed to start the library watcher: {e}"),
+            }
+        });
+        Self { tx: Mutex::new(tx) }
+    }
+
+    /// Picks up folders that were added, deleted or had watching turned on or off.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the watcher has stopped or its mutex is poisoned.
+    pub fn sync(&self) -> Result<(), String> {
+        let tx = self.tx.lock().map_err(|e| e.to_string())?;
+        tx.send(Message::Sync).map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for LibraryWatcher {
+    fn drop(&mut self) {
+        if let Ok(tx) = self.tx.lock() {
+            tx.send(Message::Stop).ok();
+        }
+    }
+}
+
+/// Changes seen since the last burst was applied.
+#[derive(Default)]
+struct Pending {
+    paths: HashSet<PathBuf>,
+    // Renames reported with both ends, so the tracks can keep their IDs
+    renames: Vec<(PathBuf, PathBuf)>,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl Pending {
+    fn add(&mut self, event: notify::Event, now: Instant) {
+        match event.kind {
+            // Includes reading files to parse them
+            EventKind::Access(_) => return,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                self.renames
+                    .push((event.paths[0].clone(), event.paths[1].clone()));
+            }
+            _ => {}
+        }
+        self.paths.extend(event.paths);
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    /// When the changes should be applied, if there are any.
+    fn deadline(&self) -> Option<Instant> {
+        Some((self.last? + DEBOUNCE).min(self.first? + MAX_DELAY))
+    }
+}
+
+struct Worker {
+    app_handle: AppHandle,
+    images_dir: PathBuf,
+    watcher: RecommendedWatcher,
+    // Roots being watched, by folder ID
+    watched: HashMap<String, PathBuf>,
+    pending: Pending,
+}
+
+impl Worker {
+    fn run(mut self, rx: &Receiver<Message>) {
+        self.sync();
+        let mut next_check = Instant::now() + MOUNT_CHECK_INTERVAL;
+        loop {
+            let deadline = self
+                .pending
+                .deadline()
+                .map_or(next_check, |d| d.min(next_check));
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Message::Event(Ok(event))) => self.pending.add(event, Instant::now()),
+                Ok(Message::Event(Err(e))) => eprintln!("Library watcher error: {e}"),
+                Ok(Message::Sync) => self.sync(),
+                Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            let now = Instant::now();
+            if self.pending.deadline().is_some_and(|d| d <= now) {
+                let pending = std::mem::take(&mut self.pending);
+                self.apply(&pending);
+            }
+            if next_check <= now {
+                self.sync();
+                next_check = now + MOUNT_CHECK_INTERVAL;
+            }
+        }
+    }
+
+    /// Watches the folders that should be and can be watched, and no others.
+    fn sync(&mut self) {
+        let Some(state) = self.app_handle.try_state::<AppState>() else {
+            return;
+        };
+        let folders = match state.db.lock() {
+            Ok(conn) => operations::get_folders(&conn, None),
+            Err(e) => {
+                eprintln!("Library watcher failed to lock database: {e}");
+                return;
+            }
+        };
+        let folders: Vec<LocalFolder> = match folders {
+            Ok(folders) => folders.into_iter().filter(|f| f.watch).collect(),
+            Err(e) => {
+                eprintln!("Library watcher failed to list folders: {e}");
+                return;
+            }
+        };
+
+        // Unmounted folders are dropped until they come back
+        let watcher = &mut self.watcher;
+        self.watched.retain(|id, root| {
+            let keep = folders.iter().any(|f| f.id == *id) && library::is_available(root);
+            if !keep {
+                watcher.unwatch(root).ok();
+            }
+            keep
+        });
+        let scans = self.app_handle.try_state::<ScanState>();
+        for folder in folders {
+            // A folder being scanned is watched once the scan is done
+            let scanning = scans.as_ref().is_some_and(|s| s.is_scanning(&folder.id));
+            if scanning || self.watched.contains_key(&folder.id) {
+                continue;
+            }
+            let root = PathBuf::from(&folder.path);
+            if !library::is_available(&root) {
+                continue;
+            }
+            if let Err(e) = self.watcher.watch(&root, RecursiveMode::Recursive) {
+                eprintln!("Failed to watch {}: {e}", folder.path);
+                continue;
+            }
+            self.watched.insert(folder.id.clone(), root);
+            // Catch up on whatever changed while nobody was watching
+            match library::rescan(&state.db, &folder, &self.images_dir) {
+                Ok(changes) => self.publish(changes),
+                Err(e) => eprintln!("Failed to rescan {}: {e}", folder.path),
+            }
+        }
+    }
+
+    /// The root of the watched folder that `path` lies in.
+    fn root_of(&self, path: &Path) -> Option<&Path> {
+        self.watched
+            .values()
+            .map(PathBuf::as_path)
+            .find(|root| path.starts_with(root))
+    }
+
+    fn apply(&self, pending: &Pending) {
+        let Some(state) = self.app_handle.try_state::<AppState>() else {
+            return;
+        };
+        let db = &state.db;
+        let mut changes = TrackChanges::default();
+        for (from, to) in &pending.renames {
+            // Moving out of the library is a removal, handled with the other paths
+            if self.root_of(to).is_none() {
+                continue;
+            }
+            let (Some(from), Some(to)) = (from.to_str(), to.to_str()) else {
+                continue;
+            };
+            let renamed = db.lock().map_err(|e| e.to_string()).and_then(|mut conn| {
+                operations::rename_file_tracks(&mut conn, from, to).map_err(|e| e.to_string())
+            });
+            match renamed {
+                Ok(renamed) => changes.extend(renamed),
+                Err(e) => eprintln!("Failed to move tracks of {from} to {to}: {e}"),
+            }
+        }
+        for path in &pending.paths {
+            if let Err(e) = self.refresh(db, path, &mut changes) {
+                eprintln!("Failed to update the library for {}: {e}", path.display());
+            }
+        }
+        self.publish(changes);
+    }
+
+    /// Brings the tracks of the file or directory at `path` in line with the disk.
+    fn refresh(
+        &self,
+        db: &Mutex<rusqlite::Connection>,
+        path: &Path,
+        changes: &mut TrackChanges,
+    ) -> Result<(), String> {
+        let Some(root) = self.root_of(path) else {
+            return Ok(());
+        };
+        if path.is_dir() {
+            for file in library::audio_files(path) {
+                self.refresh_file(db, &file, changes)?;
+            }
+            return Ok(());
+        }
+        if path.exists() {
+            if is_audio_file(path) {
+                self.refresh_file(db, path, changes)?;
+            }
+            return Ok(());
+        }
+        // Gone with the drive, not deleted
+        if !library::is_available(root) {
+            return Ok(());
+        }
+        let Some(gone) = path.to_str() else {
+            return Ok(());
+        };
+        let mut conn = db.lock().map_err(|e| e.to_string())?;
+        let files = operations::get_file_stamps(&conn, gone).map_err(|e| e.to_string())?;
+        for file in files.keys() {
+            let removed =
+                operations::replace_file_tracks(&mut conn, file, &[]).map_err(|e| e.to_string())?;
+            changes.extend(removed);
+        }
+        Ok(())
+    }
+
+    /// Reads the audio file at `path` again unless it is unchanged since it was stored.
+    fn refresh_file(
+        &self,
+        db: &Mutex<rusqlite::Connection>,
+        path: &Path,
+        changes: &mut TrackChanges,
+    ) -> Result<(), String> {
+        let Some(file) = path.to_str() else {
+            return Ok(());
+        };
+        let stamp = file_stamp(path).ok();
+        let stored = {
+            let conn = db.lock().map_err(|e| e.to_string())?;
+            operations::get_file_stamp(&conn, file).map_err(|e| e.to_string())?
+        };
+        if stamp.is_some() && stored == stamp {
+            return Ok(());
+        }
+        // A file still being written fails to parse; its next change brings it back
+        let tracks = match parse_tracks(file, Some(&self.images_dir)) {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                eprintln!("Error parsing file {file:?}: {e}");
+                return Ok(());
+            }
+        };
+        let mut conn = db.lock().map_err(|e| e.to_string())?;
+        let replaced =
+            operations::replace_file_tracks(&mut conn, file, &tracks).map_err(|e| e.to_string())?;
+        changes.extend(replaced);
+        Ok(())
+    }
+
+    /// Files new tracks in the "Default" playlist, recounts the folders and tells the
+    /// frontend.
+    fn publish(&self, changes: TrackChanges) {
+        if changes.is_empty() {
+            return;
+        }
+        let Some(state) = self.app_handle.try_state::<AppState>() else {
+            return;
+        };
+        let updated = state
+            .db
+            .lock()
+            .map_err(|e| e.to_string())
+            .and_then(|mut conn| {
+                operations::add_tracks_to_default_playlist(&mut conn, &changes.added)
+                    .map_err(|e| e.to_string())?;
+                for folder_id in self.watched.keys() {
+                    operations::refresh_folder_song_count(&conn, folder_id)
+                        .map_err(|e| e.to_string())?;
+                }
+                Ok(())
+            });
+        if let Err(e) = updated {
+            eprintln!("Failed to file new library tracks: {e}");
+        }
+        self.app_handle.emit("library-changed", &changes).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind};
+
+    #[test]
+    fn test_bursts_settle_before_they_apply() {
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        assert_eq!(pending.deadline(), None);
+
+        pending.add(
+            notify::Event::new(EventKind::Access(AccessKind::Any)).add_path("/music/a.mp3".into()),
+            start,
+        );
+        assert_eq!(pending.deadline(), None);
+
+        let create = |path: &str| {
+            notify::Event::new(EventKind::Create(CreateKind::File)).add_path(path.into())
+        };
+        pending.add(create("/music/a.mp3"), start);
+        assert_eq!(pending.deadline(), Some(start + DEBOUNCE));
+        pending.add(create("/music/b.mp3"), start + Duration::from_secs(1));
+        assert_eq!(
+            pending.deadline(),
+            Some(start + Duration::from_secs(1) + DEBOUNCE)
+        );
+
+        // A copy that never pauses is still applied bit by bit
+        let late = start + MAX_DELAY - Duration::from_millis(100);
+        pending.add(create("/music/c.mp3"), late);
+        assert_eq!(pending.deadline(), Some(start + MAX_DELAY));
+        assert_eq!(pending.paths.len(), 3);
+    }
+
+    #[test]
+    fn test_renames_keep_both_ends() {
+        let mut pending = Pending::default();
+        pending.add(
+            notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                .add_path("/music/old.flac".into())
+                .add_path("/music/new.flac".into()),
+            Instant::now(),
+        );
+        assert_eq!(
+            pending.renames,
+            [(
+                PathBuf::from("/music/old.flac"),
+                PathBuf::from("/music/new.flac")
+            )]
+        );
+        assert!(pending.paths.contains(Path::new("/music/old.flac")));
+        assert!(pending.paths.contains(Path::new("/music/new.flac")));
+    }
+}
@@ -0,0 +1,502 @@
+use super::cue::find_range;
+use crate::audio::segment::open_segment;
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use tauri::{AppHandle, Emitter, Runtime};
+use tokio::sync::oneshot;
+
+pub const DEFAULT_WAVEFORM_BUCKETS: usize = 1000;
+pub const MAX_WAVEFORM_BUCKETS: usize = 10_000;
+
+/// Frames summarized together while decoding, before they are spread over the buckets.
+const BLOCK_FRAMES: usize = 64;
+
+/// Peak overview of a track for drawing a seek bar.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Waveform {
+    /// Length of the decoded audio in seconds.
+    pub duration: f64,
+    /// Lowest sample of each bucket over all channels.
+    pub min: Vec<f32>,
+    /// Highest sample of each bucket over all channels.
+    pub max: Vec<f32>,
+}
+
+/// Checks a requested bucket count, using the default for `None`.
+///
+/// # Errors
+///
+/// Returns an error if `buckets` is zero or above `MAX_WAVEFORM_BUCKETS`.
+pub fn bucket_count(buckets: Option<usize>) -> Result<usize, String> {
+    let buckets = buckets.unwrap_or(DEFAULT_WAVEFORM_BUCKETS);
+    if (1..=MAX_WAVEFORM_BUCKETS).contains(&buckets) {
+        Ok(buckets)
+    } else {
+        Err(format!(
+            "Waveforms need between 1 and {MAX_WAVEFORM_BUCKETS} buckets"
+        ))
+    }
+}
+
+/// Decodes `source` into `buckets` min/max pairs of equal length.
+///
+/// Returns `Ok(None)` if `cancel` was set before decoding finished.
+///
+/// # Errors
+///
+/// Returns an error if the source has no audio.
+pub fn compute_waveform<S: Source>(
+    mut source: S,
+    buckets: usize,
+    cancel: &AtomicBool,
+) -> Result<Option<Waveform>, String> {
+    let channels = usize::from(source.channels().max(1));
+    let sample_rate = source.sample_rate();
+    let block_len = BLOCK_FRAMES * channels;
+
+    // The length is often unknown up front, so blocks are collected first
+    let mut blocks = Vec::new();
+    let mut samples = 0usize;
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let (mut low, mut high, mut len) = (f32::MAX, f32::MIN, 0);
+        for sample in source.by_ref().take(block_len) {
+            low = low.min(sample);
+            high = high.max(sample);
+            len += 1;
+        }
+        if len == 0 {
+            break;
+        }
+        samples += len;
+        blocks.push((low, high));
+    }
+    if blocks.is_empty() || sample_rate == 0 {
+        return Err("Track has no audio".to_string());
+    }
+
+    let mut min = Vec::with_capacity(buckets);
+    let mut max = Vec::with_capacity(buckets);
+    for bucket in 0..buckets {
+        // Short tracks repeat blocks rather than leaving buckets empty
+        let first = bucket * blocks.len() / buckets;
+        let last = ((bucket + 1) * blocks.len() / buckets).max(first + 1);
+        let (low, high) = blocks[first..last]
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(lo, hi), (l, h)| {
+                (lo.min(*l), hi.max(*h))
+            });
+        min.push(low);
+        max.push(high);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    let duration = samples as f64 / channels as f64 / f64::from(sample_rate);
+    Ok(Some(Waveform { duration, min, max }))
+}
+
+/// Returns the waveform of the file at `path`, computing and caching it in `cache_dir`
+/// unless a cached copy exists. Cache files are named by the file's content hash, so
+/// moved or duplicated files share one entry. Virtual CUE tracks cover only their part
+/// of the file, and add its start to the name.
+///
+/// Returns `Ok(None)` if `cancel` was set before decoding finished.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or decoded, or the cache cannot be written.
+pub fn waveform_for(
+    path: &str,
+    buckets: usize,
+    cache_dir: &Path,
+    cancel: &AtomicBool,
+) -> Result<Option<Waveform>, String> {
+    let range = find_range(path)?;
+    let file_path = range.as_ref().map_or(path, |r| r.file.as_str());
+    let mut file = File::open(file_path).map_err(|e| format!("Failed to open file: {e}"))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|e| format!("Failed to read file: {e}"))?;
+    let hash_hex = hex::encode(hasher.finalize());
+    let part = range
+        .as_ref()
+        .map(|r| format!("-{}", r.start))
+        .unwrap_or_default();
+    let cache_path = cache_dir.join(format!("{hash_hex}{part}-{buckets}.json"));
+
+    if let Ok(cached) = fs::read_to_string(&cache_path) {
+        match serde_json::from_str(&cached) {
+            Ok(waveform) => return Ok(Some(waveform)),
+            // Overwritten below
+            Err(e) => eprintln!(
+                "Ignoring damaged waveform cache {}: {e}",
+                cache_path.display()
+            ),
+        }
+    }
+
+    let source = open_segment(path, range.as_ref())?;
+    let Some(waveform) = compute_waveform(source, buckets, cancel)? else {
+        return Ok(None);
+    };
+
+    fs::create_dir_all(cache_dir)
+        .map_err(|e| format!("Failed to create waveform cache directory: {e}"))?;
+    let json = serde_json::to_string(&waveform).map_err(|e| e.to_string())?;
+    // Written aside first so a reader never sees half a file
+    let partial = cache_path.with_extension("part");
+    fs::write(&partial, json).map_err(|e| format!("Failed to save waveform: {e}"))?;
+    fs::rename(&partial, &cache_path).map_err(|e| format!("Failed to save waveform: {e}"))?;
+    Ok(Some(waveform))
+}
+
+type Reply = oneshot::Sender<Result<Waveform, String>>;
+type Emit = Box<dyn Fn(&str, serde_json::Value) + Send + Sync>;
+
+#[derive(Default)]
+struct Jobs {
+    // Someone is waiting for these, so they go before the batch
+    requests: VecDeque<(String, usize, Reply)>,
+    batch: VecDeque<(String, usize)>,
+    // Bumped by a cancel, so a track that finishes anyway is not counted
+    generation: u64,
+    total: usize,
+    done: usize,
+    closed: bool,
+}
+
+struct Shared {
+    jobs: Mutex<Jobs>,
+    ready: Condvar,
+    // Interrupts the batch track being decoded
+    interrupt: AtomicBool,
+    emit: Emit,
+}
+
+impl Shared {
+    /// Reports the end of the batch and starts counting afresh.
+    fn finish_batch(&self, jobs: &mut Jobs, cancelled: bool) {
+        (self.emit)(
+            "waveform-finished",
+            serde_json::json!({ "done": jobs.done, "total": jobs.total, "cancelled": cancelled }),
+        );
+        jobs.batch.clear();
+        jobs.total = 0;
+        jobs.done = 0;
+        jobs.generation += 1;
+    }
+}
+
+/// Background worker that generates waveforms one at a time.
+///
+/// Batches emit `waveform-progress` after each track and `waveform-finished` when the
+/// queue runs dry or is cancelled.
+pub struct WaveformState {
+    shared: Arc<Shared>,
+}
+
+impl WaveformState {
+    /// Starts the worker, caching waveforms in `cache_dir`.
+    pub fn new<R: Runtime>(app_handle: AppHandle<R>, cache_dir: PathBuf) -> Self {
+        let shared = Arc::new(Shared {
+            jobs: Mutex::new(Jobs::default()),
+            ready: Condvar::new(),
+            interrupt: AtomicBool::new(false),
+            emit: Box::new(move |event, payload| {
+                app_handle.emit(event, payload).ok();
+            }),
+        });
+        let worker = shared.clone();
+        thread::spawn(move || run(&cache_dir, &worker));
+        Self { shared }
+    }
+
+    /// Queues a waveform ahead of any batch; the result arrives on the returned channel.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the job queue mutex is poisoned.
+    pub fn request(
+        &self,
+        path: String,
+        buckets: usize,
+    ) -> Result<oneshot::Receiver<Result<Waveform, String>>, String> {
+        let (reply, result) = oneshot::channel();
+        let mut jobs = self.shared.jobs.lock().map_err(|e| e.to_string())?;
+        jobs.requests.push_back((path, buckets, reply));
+        self.shared.ready.notify_one();
+        Ok(result)
+    }
+
+    /// Adds tracks to the batch and returns how many are waiting in total.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the job queue mutex is poisoned.
+    pub fn queue_batch(&self, paths: Vec<String>, buckets: usize) -> Result<usize, String> {
+        let mut jobs = self.shared.jobs.lock().map_err(|e| e.to_string())?;
+        jobs.total += paths.len();
+        jobs.batch
+            .extend(paths.into_iter().map(|path| (path, buckets)));
+        self.shared.ready.notify_one();
+        Ok(jobs.batch.len())
+    }
+
+    /// Drops the queued batch and stops the track being generated for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the job queue mutex is poisoned.
+    pub fn cancel_batch(&self) -> Result<(), String> {
+        let mut jobs = self.shared.jobs.lock().map_err(|e| e.to_string())?;
+        if jobs.total > 0 {
+            self.shared.interrupt.store(true, Ordering::Relaxed);
+            self.shared.finish_batch(&mut jobs, true);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WaveformState {
+    fn drop(&mut self) {
+        if let Ok(mut jobs) = self.shared.jobs.lock() {
+            jobs.closed = true;
+        }
+        self.shared.interrupt.store(true, Ordering::Relaxed);
+        self.shared.ready.notify_one();
+    }
+}
+
+enum Job {
+    Request(String, usize, Reply),
+    Batch(String, usize, u64),
+}
+
+fn next_job(shared: &Shared) -> Option<Job> {
+    let mut jobs = shared.jobs.lock().ok()?;
+    loop {
+        if jobs.closed {
+            return None;
+        }
+        if let Some((path, buckets, reply)) = jobs.requests.pop_front() {
+            return Some(Job::Request(path, buckets, reply));
+        }
+        if let Some((path, buckets)) = jobs.batch.pop_front() {
+            // Cancels only reach tracks taken after them
+            shared.interrupt.store(false, Ordering::Relaxed);
+            return Some(Job::Batch(path, buckets, jobs.generation));
+        }
+        jobs = shared.ready.wait(jobs).ok()?;
+    }
+}
+
+fn run(cache_dir: &Path, shared: &Shared) {
+    let never = AtomicBool::new(false);
+    while let Some(job) = next_job(shared) {
+        let (path, buckets, generation) = match job {
+            Job::Request(path, buckets, reply) => {
+                let result = waveform_for(&path, buckets, cache_dir, &never)
+                    .and_then(|w| w.ok_or_else(|| "Waveform generation stopped".to_string()));
+                // The requester may have given up
+                reply.send(result).ok();
+                continue;
+            }
+            Job::Batch(path, buckets, generation) => (path, buckets, generation),
+        };
+
+        if let Err(e) = waveform_for(&path, buckets, cache_dir, &shared.interrupt) {
+            eprintln!("Waveform of {path} failed: {e}");
+        }
+
+        let Ok(mut jobs) = shared.jobs.lock() else {
+            return;
+        };
+        if jobs.generation != generation {
+            continue;
+        }
+        jobs.done += 1;
+        (shared.emit)(
+            "waveform-progress",
+            serde_json::json!({ "done": jobs.done, "total": jobs.total, "path": path }),
+        );
+        if jobs.batch.is_empty() {
+            shared.finish_batch(&mut jobs, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::cue::virtual_path;
+    use crate::test_util::write_wav;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn test_buckets_hold_min_and_max() {
+        // Four quarters of a stereo track, each louder than the one before
+        let samples = (0..4 * BLOCK_FRAMES * 10)
+            .flat_map(|i| {
+                #[allow(clippy::cast_precision_loss)]
+                let level = (i / (BLOCK_FRAMES * 10) + 1) as f32 / 4.0;
+                [level, -level / 2.0]
+            })
+            .collect::<Vec<_>>();
+        let source = SamplesBuffer::new(2, 1000, samples);
+        let waveform = compute_waveform(source, 4, &AtomicBool::new(false))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(waveform.max, vec![0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(waveform.min, vec![-0.125, -0.25, -0.375, -0.5]);
+        assert!((waveform.duration - 2.56).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_short_tracks_fill_every_bucket() {
+        let source = SamplesBuffer::new(1, 1000, vec![0.5; 10]);
+        let waveform = compute_waveform(source, 100, &AtomicBool::new(false))
+            .unwrap()
+            .unwrap();
+        assert_eq!(waveform.max, vec![0.5; 100]);
+
+        let empty = SamplesBuffer::new(1, 1000, Vec::new());
+        assert!(compute_waveform(empty, 100, &AtomicBool::new(false)).is_err());
+        let cancelled = SamplesBuffer::new(1, 1000, vec![0.5; 10]);
+        assert_eq!(
+            compute_waveform(cancelled, 100, &AtomicBool::new(true)),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_waveforms_are_cached_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("waveforms");
+        let track = dir.path().join("a.wav");
+        write_wav(&track, 1, 8000, &[0.5; 8000]);
+        let never = AtomicBool::new(false);
+
+        let path = track.to_str().unwrap();
+        let first = waveform_for(path, 10, &cache, &never).unwrap().unwrap();
+        assert!((first.duration - 1.0).abs() < 1e-6);
+        assert!(first.max.iter().all(|m| (m - 0.5).abs() < 1e-3));
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+
+        // A copy of the same audio uses the cached entry, even if that was edited
+        let copy = dir.path().join("b.wav");
+        fs::copy(&track, &copy).unwrap();
+        let entry = fs::read_dir(&cache)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut edited = first.clone();
+        edited.max[0] = 0.9;
+        fs::write(&entry, serde_json::to_string(&edited).unwrap()).unwrap();
+        let cached = waveform_for(copy.to_str().unwrap(), 10, &cache, &never)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached, edited);
+
+        // Other bucket counts are separate entries
+        waveform_for(path, 20, &cache, &never).unwrap().unwrap();
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_cue_tracks_cover_their_part() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("waveforms");
+        write_wav(&dir.path().join("rip.wav"), 1, 8000, &[0.5; 8000]);
+        fs::write(
+            dir.path().join("rip.cue"),
+            "FILE \"rip.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nTRACK 02 AUDIO\nINDEX 01 00:00:60\n",
+        )
+        .unwrap();
+        let never = AtomicBool::new(false);
+
+        let rip = dir.path().join("rip.wav");
+        let first = virtual_path(rip.to_str().unwrap(), 1);
+        let second = virtual_path(rip.to_str().unwrap(), 2);
+        let first = waveform_for(&first, 10, &cache, &never).unwrap().unwrap();
+        let second = waveform_for(&second, 10, &cache, &never).unwrap().unwrap();
+        assert!((first.duration - 0.8).abs() < 1e-3);
+        assert!((second.duration - 0.2).abs() < 1e-3);
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_worker_runs_batches_and_requests() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+        use tauri::Listener;
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = (0..3u8)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}.wav"));
+                write_wav(&path, 1, 8000, &[0.1 * f32::from(i + 1); 4000]);
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+
+        let app = tauri::test::mock_app();
+        let (tx, rx) = mpsc::channel();
+        for event in ["waveform-progress", "waveform-finished"] {
+            let tx = tx.clone();
+            app.listen_any(event, move |e| {
+                let payload: serde_json::Value = serde_json::from_str(e.payload()).unwrap();
+                tx.send((event, payload)).ok();
+            });
+        }
+        let state = WaveformState::new(app.handle().clone(), dir.path().join("cache"));
+
+        assert_eq!(state.queue_batch(paths.clone(), 50).unwrap(), 3);
+        let events: Vec<_> = (0..4)
+            .map(|_| rx.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect();
+        assert!(events[..3].iter().all(|(e, _)| *e == "waveform-progress"));
+        assert_eq!(events[2].1["done"], 3);
+        assert_eq!(events[3].0, "waveform-finished");
+        assert_eq!(events[3].1["cancelled"], false);
+
+        // Served from the cache the batch filled
+        let waveform = state
+            .request(paths[1].clone(), 50)
+            .unwrap()
+            .blocking_recv()
+            .unwrap()
+            .unwrap();
+        assert_eq!(waveform.max.len(), 50);
+        assert_eq!(fs::read_dir(dir.path().join("cache")).unwrap().count(), 3);
+
+        let missing = state.request("/no/such.wav".to_string(), 50).unwrap();
+        assert!(missing.blocking_recv().unwrap().is_err());
+
+        // Cancelling reports right away, and nothing of that batch is counted afterwards
+        state.queue_batch(paths, 60).unwrap();
+        state.cancel_batch().unwrap();
+        let (event, payload) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(event, "waveform-finished");
+        assert_eq!(payload["cancelled"], true);
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn test_bucket_count_limits() {
+        assert_eq!(bucket_count(None), Ok(DEFAULT_WAVEFORM_BUCKETS));
+        assert_eq!(bucket_count(Some(200)), Ok(200));
+        assert!(bucket_count(Some(0)).is_err());
+        assert!(bucket_count(Some(MAX_WAVEFORM_BUCKETS + 1)).is_err());
+    }
+}
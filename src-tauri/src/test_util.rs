@@ -0,0 +1,12 @@
+//! Fixtures shared by the unit tests.
+
+use crate::audio::output::WavWriter;
+use std::fs::File;
+use std::path::Path;
+
+/// Writes `samples`, interleaved over `channels`, as a float WAV file at `sample_rate`.
+pub fn write_wav(path: &Path, channels: u16, sample_rate: u32, samples: &[f32]) {
+    let file = File::create(path).unwrap();
+    let mut writer = WavWriter::new(file, channels, sample_rate).unwrap();
+    writer.write(samples).unwrap();
+}
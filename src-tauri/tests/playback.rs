@@ -0,0 +1,644 @@
+//! Plays the bundled sample through the headless output backends.
+
+use music_player_lib::audio::commands::restore_session;
+use music_player_lib::audio::output::{NullBackend, WavBackend};
+use music_player_lib::audio::player::{init_audio_thread, AudioCommand, AudioPlayerState};
+use music_player_lib::audio::queue::RepeatMode;
+use music_player_lib::audio::resume::RESUME_THRESHOLD_SETTING;
+use music_player_lib::audio::session::load_session;
+use music_player_lib::audio::sleep::{SleepMode, SleepTimer};
+use music_player_lib::audio::spectrum::SpectrumConfig;
+use music_player_lib::database::schema::create_tables;
+use music_player_lib::database::{operations, AppState};
+use music_player_lib::scanner::cue::virtual_path;
+use music_player_lib::scanner::scan::ScanState;
+use rodio::Decoder;
+use rusqlite::Connection;
+use serde_json::Value;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::test::{mock_app, MockRuntime};
+use tauri::{App, Listener, Manager};
+
+const SAMPLE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../assets/01 TempleOS Hymn Risen (Remix).mp3"
+);
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Forwards the named events to a channel as `(name, payload)`.
+fn listen(app: &App<MockRuntime>, names: &[&'static str]) -> mpsc::Receiver<(&'static str, Value)> {
+    let (tx, rx) = mpsc::channel();
+    for &name in names {
+        let tx = tx.clone();
+        app.listen_any(name, move |event| {
+            let payload = serde_json::from_str(event.payload()).unwrap_or(Value::Null);
+            tx.send((name, payload)).ok();
+        });
+    }
+    rx
+}
+
+/// Waits for the first `name` event whose payload satisfies `pred`.
+fn wait_for(
+    rx: &mpsc::Receiver<(&'static str, Value)>,
+    name: &str,
+    pred: impl Fn(&Value) -> bool,
+) -> Value {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(left) {
+            Ok((event, payload)) if event == name && pred(&payload) => return payload,
+            Ok(_) => {}
+            Err(e) => panic!("No {name} event: {e}"),
+        }
+    }
+}
+
+/// A mock app with an empty library database.
+fn mock_app_with_db() -> App<MockRuntime> {
+    let app = mock_app();
+    let conn = Connection::open_in_memory().unwrap();
+    create_tables(&conn).unwrap();
+    app.manage(AppState {
+        db: Mutex::new(conn),
+    });
+    app
+}
+
+fn synchsafe(size: usize) -> [u8; 4] {
+    let size = u32::try_from(size).unwrap();
+    [21, 14, 7, 0].map(|shift| u8::try_from((size >> shift) & 0x7F).unwrap())
+}
+
+fn id3_frame(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    [id.as_slice(), &synchsafe(content.len()), &[0, 0], content].concat()
+}
+
+/// Copies the sample to `dest` with ID3v2.4 chapters starting at the given seconds.
+fn sample_with_chapters(dest: &Path, chapters: &[(u32, &str)]) {
+    let sample = std::fs::read(SAMPLE).unwrap();
+    assert_eq!(&sample[..4], b"ID3\x04");
+    let size = sample[6..10]
+        .iter()
+        .fold(0usize, |size, b| (size << 7) | usize::from(*b));
+    let frames: Vec<u8> = chapters
+        .iter()
+        .enumerate()
+        .flat_map(|(i, (start, title))| {
+            let mut content = format!("ch{i}\0").into_bytes();
+            content.extend((start * 1000).to_be_bytes());
+            content.extend(0u32.to_be_bytes());
+            content.extend([0xFF; 8]);
+            content.extend(id3_frame(b"TIT2", &[&[3], title.as_bytes()].concat()));
+            id3_frame(b"CHAP", &content)
+        })
+        .collect();
+    let file = [
+        &sample[..6],
+        &synchsafe(size + frames.len()),
+        &frames,
+        &sample[10..],
+    ]
+    .concat();
+    std::fs::write(dest, file).unwrap();
+}
+
+fn send(player: &AudioPlayerState, cmd: AudioCommand) {
+    player.tx.lock().unwrap().send(cmd).unwrap();
+}
+
+#[test]
+fn test_track_plays_to_the_end() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("rendered.wav");
+    let app = mock_app();
+    let events = listen(
+        &app,
+        &[
+            "player-status",
+            "player-progress",
+            "track-ended",
+            "queue-finished",
+        ],
+    );
+    let player = init_audio_thread(app.handle().clone(), WavBackend::new(&wav, 4.0));
+
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    let playing = wait_for(&events, "player-status", |p| p["status"] == "playing");
+    assert_eq!(playing["path"], SAMPLE);
+    let duration = playing["duration"].as_f64().unwrap();
+    assert!(duration > 10.0);
+
+    wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > 2.0
+    });
+    // Skip to the last seconds rather than rendering the whole track
+    #[allow(clippy::cast_possible_truncation)]
+    send(&player, AudioCommand::Seek((duration - 3.0) as f32));
+    let progress = wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > duration - 3.0
+    });
+    assert!(progress["position"].as_f64().unwrap() <= duration);
+    assert_eq!(progress["duration"].as_f64(), Some(duration));
+
+    let ended = wait_for(&events, "track-ended", |_| true);
+    assert_eq!(ended["path"], SAMPLE);
+    assert_eq!(ended["reason"], "finished");
+    wait_for(&events, "player-status", |p| p["status"] == "stopped");
+    let finished = wait_for(&events, "queue-finished", |_| true);
+    assert_eq!(finished["reason"], "finished");
+
+    // The rendered file holds the beginning of the track and its last seconds
+    let rendered: Vec<f32> = Decoder::new(BufReader::new(File::open(&wav).unwrap()))
+        .unwrap()
+        .collect();
+    let audible = rendered.iter().filter(|s| s.abs() > 0.01).count();
+    assert!(audible > 44_100, "only {audible} audible samples");
+    assert!(rendered.iter().all(|s| s.abs() <= 1.0));
+}
+
+#[test]
+fn test_stop_reports_position() {
+    let app = mock_app();
+    let events = listen(&app, &["player-progress", "track-ended", "queue-finished"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    let progress = wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > 1.0
+    });
+    let reached = progress["position"].as_f64().unwrap();
+
+    send(&player, AudioCommand::Stop);
+    let ended = wait_for(&events, "track-ended", |_| true);
+    assert_eq!(ended["reason"], "stopped");
+    assert!(ended["position"].as_f64().unwrap() >= reached);
+
+    // Stopping is not running out of tracks
+    assert!(events
+        .try_iter()
+        .all(|(event, _)| event != "queue-finished"));
+}
+
+#[test]
+fn test_loop_repeats_region() {
+    let app = mock_app();
+    let events = listen(&app, &["player-status", "player-progress", "track-ended"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    wait_for(&events, "player-status", |p| p["status"] == "playing");
+    send(
+        &player,
+        AudioCommand::SetLoop {
+            start: 10.0,
+            end: 11.0,
+        },
+    );
+    let status = wait_for(&events, "player-status", |p| !p["loop"].is_null());
+    assert_eq!(status["loop"]["start"].as_f64(), Some(10.0));
+    assert_eq!(status["loop"]["end"].as_f64(), Some(11.0));
+
+    // Playback runs into the region, then stays in it
+    wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > 10.0
+    });
+    let mut positions = Vec::new();
+    while positions.len() < 15 {
+        let progress = wait_for(&events, "player-progress", |_| true);
+        positions.push(progress["position"].as_f64().unwrap());
+    }
+    assert!(
+        positions.iter().all(|p| (10.0..11.1).contains(p)),
+        "left the loop: {positions:?}"
+    );
+    assert!(positions.windows(2).any(|w| w[1] < w[0]));
+
+    send(&player, AudioCommand::ClearLoop);
+    let status = wait_for(&events, "player-status", |_| true);
+    assert!(status["loop"].is_null());
+    wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > 11.5
+    });
+    assert!(events.try_iter().all(|(event, _)| event != "track-ended"));
+}
+
+#[test]
+fn test_spectrum_follows_playback() {
+    let app = mock_app();
+    let events = listen(&app, &["player-progress", "player-spectrum"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    send(
+        &player,
+        AudioCommand::SetSpectrum(Some(SpectrumConfig::new(Some(20.0), Some(16)).unwrap())),
+    );
+    let spectrum = wait_for(&events, "player-spectrum", |p| {
+        p["peak"].as_f64().unwrap() > 0.01
+    });
+    assert_eq!(spectrum["bins"].as_array().unwrap().len(), 16);
+    assert!(spectrum["rms"].as_f64().unwrap() > 0.0);
+
+    // The analyzer has stopped by the time the next progress event is sent
+    send(&player, AudioCommand::SetSpectrum(None));
+    wait_for(&events, "player-progress", |_| true);
+    thread::sleep(Duration::from_millis(500));
+    assert!(events
+        .try_iter()
+        .all(|(event, _)| event != "player-spectrum"));
+}
+
+#[test]
+fn test_session_survives_a_restart() {
+    let app = mock_app_with_db();
+    let events = listen(&app, &["player-status", "player-progress", "queue-changed"]);
+
+    let first = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+    send(&first, AudioCommand::Play(SAMPLE.to_string()));
+    send(&first, AudioCommand::Enqueue(vec![SAMPLE.to_string()]));
+    send(&first, AudioCommand::SetVolume(0.5));
+    send(&first, AudioCommand::SetRepeat(RepeatMode::All));
+    send(&first, AudioCommand::Seek(8.0));
+    wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > 8.0
+    });
+    send(&first, AudioCommand::Pause);
+    wait_for(&events, "player-status", |p| p["status"] == "paused");
+    first.shutdown().unwrap();
+    // The audio thread has exited
+    assert!(first.shutdown().is_err());
+    drop(first);
+
+    let session = {
+        let state = app.state::<AppState>();
+        let conn = state.db.lock().unwrap();
+        load_session(&conn).unwrap().unwrap()
+    };
+    assert_eq!(session.queue.tracks, [SAMPLE, SAMPLE]);
+    assert_eq!(session.queue.current_index, Some(0));
+    assert!(session.position > 8.0);
+    assert!((session.volume - 0.5).abs() < f32::EPSILON);
+    assert_eq!(session.repeat, RepeatMode::All);
+
+    // A new player comes back paused where the old one was
+    app.manage(init_audio_thread(
+        app.handle().clone(),
+        NullBackend::new(4.0),
+    ));
+    assert!(restore_session(app.state(), app.state()).unwrap());
+    let queue = wait_for(&events, "queue-changed", |_| true);
+    assert_eq!(queue["tracks"].as_array().unwrap().len(), 2);
+    let paused = wait_for(&events, "player-status", |p| p["status"] == "paused");
+    assert_eq!(paused["path"], SAMPLE);
+    assert_eq!(paused["repeat"], "all");
+    assert!((paused["position"].as_f64().unwrap() - session.position).abs() < 0.01);
+    thread::sleep(Duration::from_millis(300));
+    assert!(events
+        .try_iter()
+        .all(|(event, _)| event != "player-progress"));
+
+    send(&app.state::<AudioPlayerState>(), AudioCommand::Resume);
+    let progress = wait_for(&events, "player-progress", |_| true);
+    assert!(progress["position"].as_f64().unwrap() >= session.position);
+}
+
+#[test]
+fn test_long_tracks_resume_where_they_were_left() {
+    let app = mock_app_with_db();
+    {
+        let state = app.state::<AppState>();
+        let conn = state.db.lock().unwrap();
+        // The sample is long enough to count as long-form
+        operations::set_setting(&conn, RESUME_THRESHOLD_SETTING, Some("0.1")).unwrap();
+        operations::save_resume_position(&conn, SAMPLE, 6.0, 0.0).unwrap();
+    }
+    let events = listen(&app, &["player-progress", "track-ended"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    let progress = wait_for(&events, "player-progress", |_| true);
+    assert!(progress["position"].as_f64().unwrap() >= 6.0);
+
+    wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > 7.0
+    });
+    send(&player, AudioCommand::Stop);
+    let ended = wait_for(&events, "track-ended", |_| true);
+    let stopped_at = ended["position"].as_f64().unwrap();
+
+    let state = app.state::<AppState>();
+    let conn = state.db.lock().unwrap();
+    assert_eq!(
+        operations::get_resume_position(&conn, SAMPLE).unwrap(),
+        Some(stopped_at)
+    );
+    let in_progress = operations::get_in_progress_tracks(&conn).unwrap();
+    assert_eq!(in_progress[0].path, SAMPLE);
+    assert!(in_progress[0].duration > 10.0);
+}
+
+#[test]
+fn test_chapter_navigation() {
+    let dir = tempfile::tempdir().unwrap();
+    let book = dir.path().join("book.mp3");
+    sample_with_chapters(&book, &[(0, "Intro"), (5, "Middle"), (10, "End")]);
+    let book = book.to_str().unwrap().to_string();
+
+    let app = mock_app();
+    let events = listen(
+        &app,
+        &[
+            "player-status",
+            "chapter-changed",
+            "player-error",
+            "track-ended",
+        ],
+    );
+    let player = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+
+    send(&player, AudioCommand::Play(book.clone()));
+    let playing = wait_for(&events, "player-status", |p| p["status"] == "playing");
+    let chapters = playing["chapters"].as_array().unwrap();
+    assert_eq!(chapters.len(), 3);
+    assert_eq!(chapters[1]["title"], "Middle");
+    assert_eq!(chapters[1]["end"].as_f64(), Some(10.0));
+    let changed = wait_for(&events, "chapter-changed", |_| true);
+    assert_eq!(changed["index"], 0);
+    assert_eq!(changed["path"], book.as_str());
+
+    send(&player, AudioCommand::NextChapter);
+    let changed = wait_for(&events, "chapter-changed", |_| true);
+    assert_eq!(changed["index"], 1);
+    assert_eq!(changed["chapter"]["title"], "Middle");
+
+    send(&player, AudioCommand::SeekChapter(2));
+    wait_for(&events, "chapter-changed", |p| p["index"] == 2);
+    // Right after a chapter starts, "previous" goes to the chapter before it
+    send(&player, AudioCommand::PreviousChapter);
+    wait_for(&events, "chapter-changed", |p| p["index"] == 1);
+
+    send(&player, AudioCommand::SeekChapter(9));
+    assert_eq!(wait_for(&events, "player-error", |_| true), "No chapter 9");
+
+    // Past the last chapter the track is skipped
+    send(&player, AudioCommand::SeekChapter(2));
+    wait_for(&events, "chapter-changed", |p| p["index"] == 2);
+    send(&player, AudioCommand::NextChapter);
+    let ended = wait_for(&events, "track-ended", |_| true);
+    assert_eq!(ended["reason"], "skipped");
+}
+
+#[test]
+fn test_cue_tracks_play_their_part_of_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let rip = dir.path().join("album.mp3");
+    std::fs::copy(SAMPLE, &rip).unwrap();
+    std::fs::write(
+        dir.path().join("album.cue"),
+        "TITLE \"Album\"\nFILE \"album.mp3\" MP3\n\
+         TRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+         TRACK 02 AUDIO\nTITLE \"Second\"\nINDEX 01 00:05:00\n\
+         TRACK 03 AUDIO\nINDEX 01 00:10:00\n",
+    )
+    .unwrap();
+    let rip = rip.to_str().unwrap();
+    let second = virtual_path(rip, 2);
+    let third = virtual_path(rip, 3);
+
+    let app = mock_app();
+    let events = listen(&app, &["player-status", "player-progress", "track-ended"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+
+    send(&player, AudioCommand::Play(second.clone()));
+    send(&player, AudioCommand::Enqueue(vec![third.clone()]));
+    let playing = wait_for(&events, "player-status", |p| p["status"] == "playing");
+    assert_eq!(playing["path"], second.as_str());
+    assert!((playing["duration"].as_f64().unwrap() - 5.0).abs() < 0.01);
+    let progress = wait_for(&events, "player-progress", |_| true);
+    assert!(progress["position"].as_f64().unwrap() < 1.0);
+
+    send(&player, AudioCommand::Seek(4.0));
+    let ended = wait_for(&events, "track-ended", |_| true);
+    assert_eq!(ended["path"], second.as_str());
+    assert_eq!(ended["reason"], "finished");
+
+    // The next part of the file follows, starting from its own beginning
+    let playing = wait_for(&events, "player-status", |p| p["status"] == "playing");
+    assert_eq!(playing["path"], third.as_str());
+    let progress = wait_for(&events, "player-progress", |_| true);
+    assert!(progress["position"].as_f64().unwrap() < 1.0);
+}
+
+#[test]
+fn test_missing_file_ends_the_queue_with_an_error() {
+    let app = mock_app();
+    let events = listen(&app, &["player-error", "track-ended", "queue-finished"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::default());
+
+    send(
+        &player,
+        AudioCommand::Play("/no/such/track.mp3".to_string()),
+    );
+    wait_for(&events, "player-error", |_| true);
+    let ended = wait_for(&events, "track-ended", |_| true);
+    assert_eq!(ended["reason"], "error");
+    assert_eq!(ended["position"].as_f64(), Some(0.0));
+    let finished = wait_for(&events, "queue-finished", |_| true);
+    assert_eq!(finished["path"], "/no/such/track.mp3");
+    assert_eq!(finished["reason"], "error");
+}
+
+#[test]
+fn test_removing_the_paused_track_stays_paused() {
+    let dir = tempfile::tempdir().unwrap();
+    let next = dir.path().join("next.mp3");
+    std::fs::copy(SAMPLE, &next).unwrap();
+    let next = next.to_str().unwrap().to_string();
+
+    let app = mock_app();
+    let events = listen(&app, &["player-status", "track-ended"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::default());
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    send(&player, AudioCommand::Enqueue(vec![next.clone()]));
+    wait_for(&events, "player-status", |p| p["status"] == "playing");
+    send(&player, AudioCommand::Pause);
+    wait_for(&events, "player-status", |p| p["status"] == "paused");
+
+    send(&player, AudioCommand::RemoveFromQueue(0));
+    let ended = wait_for(&events, "track-ended", |_| true);
+    assert_eq!(ended["path"], SAMPLE);
+    let paused = wait_for(&events, "player-status", |p| p["status"] == "paused");
+    assert_eq!(paused["path"], next.as_str());
+    assert_eq!(paused["position"].as_f64(), Some(0.0));
+    thread::sleep(Duration::from_millis(300));
+    assert!(events
+        .try_iter()
+        .all(|(event, p)| event != "player-status" || p["status"] != "playing"));
+}
+
+#[test]
+fn test_out_of_range_values_keep_the_player_running() {
+    let app = mock_app();
+    let events = listen(&app, &["player-status", "player-error"]);
+    let player = init_audio_thread(app.handle().clone(), NullBackend::default());
+
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    wait_for(&events, "player-status", |p| p["status"] == "playing");
+    send(
+        &player,
+        AudioCommand::SetCrossfade {
+            seconds: f32::INFINITY,
+            gapless_albums: false,
+        },
+    );
+    send(&player, AudioCommand::Seek(f32::INFINITY));
+    send(&player, AudioCommand::Seek(f32::NAN));
+    for (start, end) in [
+        (f32::NAN, 1.0),
+        (0.0, f32::INFINITY),
+        (f32::MAX, f32::MAX),
+        (2.0, 1.0),
+    ] {
+        send(&player, AudioCommand::SetLoop { start, end });
+        wait_for(&events, "player-error", |_| true);
+    }
+
+    // The audio thread still answers
+    send(&player, AudioCommand::Pause);
+    wait_for(&events, "player-status", |p| p["status"] == "paused");
+}
+
+#[test]
+fn test_sleep_timer_stops_at_end_of_track() {
+    let app = mock_app();
+    let events = listen(
+        &app,
+        &[
+            "player-status",
+            "player-progress",
+            "track-ended",
+            "sleep-timer-finished",
+        ],
+    );
+    let player = init_audio_thread(app.handle().clone(), NullBackend::new(4.0));
+
+    send(&player, AudioCommand::Play(SAMPLE.to_string()));
+    send(&player, AudioCommand::Enqueue(vec![SAMPLE.to_string()]));
+    let timer = SleepTimer::new(
+        SleepMode::EndOfTrack,
+        None,
+        Duration::from_secs(2),
+        Instant::now(),
+    )
+    .unwrap();
+    send(&player, AudioCommand::SetSleepTimer(Some(timer)));
+    let playing = wait_for(&events, "player-status", |p| p["status"] == "playing");
+    let duration = playing["duration"].as_f64().unwrap();
+
+    #[allow(clippy::cast_possible_truncation)]
+    send(&player, AudioCommand::Seek((duration - 3.0) as f32));
+    let progress = wait_for(&events, "player-progress", |p| {
+        p["position"].as_f64().unwrap() > duration - 3.0
+    });
+    assert_eq!(progress["sleep"]["tracks"], 0);
+    assert!(progress["sleep"]["seconds"].as_f64().unwrap() <= 3.0);
+
+    let ended = wait_for(&events, "track-ended", |_| true);
+    assert_eq!(ended["reason"], "finished");
+    wait_for(&events, "sleep-timer-finished", |_| true);
+
+    // The second queue entry never starts
+    thread::sleep(Duration::from_millis(500));
+    assert!(events
+        .try_iter()
+        .all(|(event, p)| event != "player-status" || p["status"] == "stopped"));
+}
+
+#[test]
+fn test_folder_scans_run_in_the_background() {
+    let dir = tempfile::tempdir().unwrap();
+    let music = dir.path().join("music");
+    std::fs::create_dir_all(music.join("album")).unwrap();
+    for name in ["a.mp3", "album/b.mp3", "album/c.mp3"] {
+        std::fs::copy(SAMPLE, music.join(name)).unwrap();
+    }
+    std::fs::write(music.join("album/broken.mp3"), b"not audio").unwrap();
+    std::fs::write(music.join("album/cover.jpg"), b"not audio either").unwrap();
+
+    let app = mock_app_with_db();
+    let events = listen(&app, &["scan-progress", "scan-finished", "library-changed"]);
+    let folder = {
+        let state = app.state::<AppState>();
+        let conn = state.db.lock().unwrap();
+        let id = operations::add_folder(&conn, "Music", music.to_str().unwrap(), 0).unwrap();
+        operations::get_folder(&conn, &id).unwrap().unwrap()
+    };
+    let scans = ScanState::default();
+    assert!(scans
+        .start(
+            app.handle().clone(),
+            folder.clone(),
+            dir.path().join("images")
+        )
+        .unwrap());
+    assert!(!scans
+        .start(
+            app.handle().clone(),
+            folder.clone(),
+            dir.path().join("images")
+        )
+        .unwrap());
+    // Nor does a rescan
+    assert!(scans
+        .rescan(app.handle(), &folder, &dir.path().join("images"))
+        .unwrap()
+        .is_none());
+
+    // Tracks are announced batch by batch before the scan finishes
+    let mut added = 0;
+    let finished = loop {
+        let (event, payload) = events.recv_timeout(TIMEOUT).unwrap();
+        match event {
+            "library-changed" => added += payload["added"].as_array().unwrap().len(),
+            "scan-finished" => break payload,
+            _ => {}
+        }
+    };
+    assert_eq!(added, 3);
+    assert_eq!(finished["folderId"], folder.id.as_str());
+    assert_eq!(finished["seen"], 4);
+    assert_eq!(finished["parsed"], 3);
+    assert_eq!(finished["failed"], 1);
+    assert_eq!(finished["cancelled"], false);
+
+    {
+        let state = app.state::<AppState>();
+        let conn = state.db.lock().unwrap();
+        assert_eq!(operations::get_tracks(&conn, None).unwrap().len(), 3);
+        let folders = operations::get_folders(&conn, None).unwrap();
+        assert_eq!(folders[0].song_count, 3);
+    }
+
+    // Once the scan is done, a rescan picks up new files and announces them
+    let deadline = Instant::now() + TIMEOUT;
+    while scans.is_scanning(&folder.id) {
+        assert!(Instant::now() < deadline, "Scan did not finish");
+        thread::sleep(Duration::from_millis(10));
+    }
+    std::fs::copy(SAMPLE, music.join("album/d.mp3")).unwrap();
+    let changes = scans
+        .rescan(app.handle(), &folder, &dir.path().join("images"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(changes.added.len(), 1);
+    let changed = wait_for(&events, "library-changed", |_| true);
+    assert_eq!(changed["added"].as_array().unwrap().len(), 1);
+}